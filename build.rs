@@ -1,4 +1,3 @@
-use std::error::Error;
 use std::io;
 use std::io::Write;
 use std::fs::File;
@@ -32,7 +31,7 @@ fn main_real (
 
 		format! (
 			"Error writing metadata: {}",
-			io_error.description ())
+			io_error)
 
 	) ?;
 
@@ -55,7 +54,6 @@ fn write_metadata (
 
 	writeln! (
 		& mut file,
-		"",
 	) ?;
 
 	writeln! (
@@ -66,13 +64,11 @@ fn write_metadata (
 
 	writeln! (
 		& mut file,
-		"pub const AUTHOR: & 'static str = \"{}\";",
-		"James Pharaoh <james@pharaoh.uk>",
+		"pub const AUTHOR: & 'static str = \"James Pharaoh <james@pharaoh.uk>\";",
 	) ?;
 
 	writeln! (
 		& mut file,
-		"",
 	) ?;
 
 	writeln! (
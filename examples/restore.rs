@@ -5,7 +5,7 @@ use std::env;
 use std::ffi::OsString;
 use std::io;
 
-use rzbackup::Repository;
+use rzbackup::ZBackupRepository as Repository;
 
 fn main () {
 
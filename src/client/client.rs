@@ -1,14 +1,31 @@
 use ::misc::*;
 
+/// Checks a server address, which is either in 'host:port' format, or a unix
+/// domain socket path with a 'unix:' prefix.
+
 pub fn parse_server_address <
 	ServerAddress: AsRef <str>,
 > (
 	server_address: ServerAddress,
-) -> (String, u16) {
+) -> String {
 
 	let server_address =
 		server_address.as_ref ();
 
+	if server_address.starts_with (UNIX_ADDRESS_PREFIX) {
+
+		if server_address.len () == UNIX_ADDRESS_PREFIX.len () {
+
+			args::error_exit (
+				format! (
+					"Server socket path must not be empty"));
+
+		}
+
+		return server_address.to_string ();
+
+	}
+
 	let server_address_parts: Vec <& str> =
 		server_address.split (":").collect ();
 
@@ -16,28 +33,21 @@ pub fn parse_server_address <
 
 		args::error_exit (
 			format! (
-				"Server address must be in 'host:port' format"));
+				"Server address must be in 'host:port' or 'unix:/path' format"));
 
 	}
 
-	let server_hostname =
-		server_address_parts [0];
+	server_address_parts [1].parse::<u16> (
+	).unwrap_or_else (
+		|_|
 
-	let server_port =
-		server_address_parts [1].parse::<u16> (
-		).unwrap_or_else (
-			|_|
-
-			args::error_exit (
-				format! (
-					"Error parsing server port"))
+		args::error_exit (
+			format! (
+				"Error parsing server port"))
 
-		);
+	);
 
-	(
-		server_hostname.to_string (),
-		server_port,
-	)
+	server_address.to_string ()
 
 }
 
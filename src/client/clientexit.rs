@@ -1,7 +1,6 @@
 use std::io::BufRead;
 use std::io::BufReader;
 use std::io::Write;
-use std::net::TcpStream;
 
 use clap;
 
@@ -19,12 +18,8 @@ fn do_client_exit (
 		io_result_with_prefix (
 			|| format! (
 				"Connection error: "),
-			TcpStream::connect (
-				(
-					arguments.server_hostname.as_str (),
-					arguments.server_port,
-				),
-			),
+			SocketStream::connect (
+				& arguments.server_address),
 		) ?;
 
 	io_result_with_prefix (
@@ -73,8 +68,7 @@ command! (
 	export = client_exit_command,
 
 	arguments = ClientExitArguments {
-		server_hostname: String,
-		server_port: u16,
+		server_address: String,
 	},
 
 	clap_subcommand = {
@@ -88,7 +82,7 @@ command! (
 				.long ("server-address")
 				.value_name ("SERVER-ADDRESS")
 				.required (true)
-				.help ("Server address, in 'host:port' format")
+				.help ("Server address, in 'host:port' or 'unix:/path' format")
 
 			)
 
@@ -96,7 +90,7 @@ command! (
 
 	clap_arguments_parse = |clap_matches| {
 
-		let (server_hostname, server_port) =
+		let server_address =
 			parse_server_address (
 				args::string_required (
 					clap_matches,
@@ -104,8 +98,7 @@ command! (
 			);
 
 		ClientExitArguments {
-			server_hostname: server_hostname,
-			server_port: server_port,
+			server_address: server_address,
 		}
 
 	},
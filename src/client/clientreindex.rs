@@ -1,7 +1,6 @@
 use std::io::BufRead;
 use std::io::BufReader;
 use std::io::Write;
-use std::net::TcpStream;
 
 use clap;
 
@@ -19,21 +18,27 @@ pub fn do_client_reindex (
 		io_result_with_prefix (
 			|| format! (
 				"Connection error: "),
-			TcpStream::connect (
-				(
-					arguments.server_hostname.as_str (),
-					arguments.server_port,
-				),
-			),
+			SocketStream::connect (
+				& arguments.server_address),
 		) ?;
 
 	io_result_with_prefix (
 		|| format! (
 			"Communication error: "),
-		stream.write_fmt (
-			format_args! (
-				"reindex\n"),
-		),
+		match arguments.repository_name {
+
+			Some (ref repository_name) =>
+				stream.write_fmt (
+					format_args! (
+						"reindex {}\n",
+						repository_name)),
+
+			None =>
+				stream.write_fmt (
+					format_args! (
+						"reindex\n")),
+
+		},
 	) ?;
 
 	let mut reader =
@@ -73,8 +78,8 @@ command! (
 	export = client_reindex_command,
 
 	arguments = ClientReindexArguments {
-		server_hostname: String,
-		server_port: u16,
+		server_address: String,
+		repository_name: Option <String>,
 	},
 
 	clap_subcommand = {
@@ -88,7 +93,18 @@ command! (
 				.long ("server-address")
 				.value_name ("SERVER-ADDRESS")
 				.required (true)
-				.help ("Server address, in 'host:port' format")
+				.help ("Server address, in 'host:port' or 'unix:/path' format")
+
+			)
+
+			.arg (
+				clap::Arg::with_name ("repository-name")
+
+				.long ("repository-name")
+				.value_name ("REPOSITORY-NAME")
+				.required (false)
+				.help ("Name of the repository to reindex, if the server has more than \
+					one. All repositories are reindexed if omitted.")
 
 			)
 
@@ -96,7 +112,7 @@ command! (
 
 	clap_arguments_parse = |clap_matches| {
 
-		let (server_hostname, server_port) =
+		let server_address =
 			parse_server_address (
 				args::string_required (
 					clap_matches,
@@ -104,8 +120,14 @@ command! (
 			);
 
 		ClientReindexArguments {
-			server_hostname: server_hostname,
-			server_port: server_port,
+
+			server_address: server_address,
+
+			repository_name:
+				args::string_optional (
+					clap_matches,
+					"repository-name"),
+
 		}
 
 	},
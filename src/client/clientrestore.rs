@@ -2,7 +2,6 @@ use std::io;
 use std::io::BufRead;
 use std::io::BufReader;
 use std::io::Write;
-use std::net::TcpStream;
 
 use clap;
 
@@ -20,40 +19,65 @@ fn do_client_restore (
 		io_result_with_prefix (
 			|| format! (
 				"Connection error: "),
-			TcpStream::connect (
-				(
-					arguments.server_hostname.as_str (),
-					arguments.server_port,
-				),
-			),
+			SocketStream::connect (
+				& arguments.server_address),
 		) ?;
 
 	io_result_with_prefix (
 		|| format! (
 			"Communication error: "),
-		stream.write_fmt (
-			format_args! (
-				"restore {}\n",
-				arguments.backup_name),
-		),
+		match arguments.repository_name {
+
+			Some (ref repository_name) =>
+				stream.write_fmt (
+					format_args! (
+						"restore {} {}\n",
+						repository_name,
+						arguments.backup_name)),
+
+			None =>
+				stream.write_fmt (
+					format_args! (
+						"restore {}\n",
+						arguments.backup_name)),
+
+		},
 	) ?;
 
 	let mut reader =
 		BufReader::new (
 			stream);
 
-	let mut response_line =
-		String::new ();
+	// the server may queue the restore, and reports our position until it
+	// starts
 
-	io_result_with_prefix (
-		|| format! (
-			"Communication error: "),
-		reader.read_line (
-			& mut response_line,
-		),
-	) ?;
+	loop {
+
+		let mut response_line =
+			String::new ();
+
+		io_result_with_prefix (
+			|| format! (
+				"Communication error: "),
+			reader.read_line (
+				& mut response_line,
+			),
+		) ?;
 
-	if response_line != "OK\n" {
+		if response_line == "OK\n" {
+			break;
+		}
+
+		if let Some (position) = response_line.strip_prefix ("QUEUED ") {
+
+			output_message! (
+				output,
+				"Restore queued at position {}",
+				position.trim ());
+
+			continue;
+
+		}
 
 		return Err (
 			format! (
@@ -84,8 +108,8 @@ command! (
 	export = client_restore_command,
 
 	arguments = ClientRestoreArguments {
-		server_hostname: String,
-		server_port: u16,
+		server_address: String,
+		repository_name: Option <String>,
 		backup_name: String,
 	},
 
@@ -100,7 +124,18 @@ command! (
 				.long ("server-address")
 				.value_name ("SERVER-ADDRESS")
 				.required (true)
-				.help ("Server address, in 'host:port' format")
+				.help ("Server address, in 'host:port' or 'unix:/path' format")
+
+			)
+
+			.arg (
+				clap::Arg::with_name ("repository-name")
+
+				.long ("repository-name")
+				.value_name ("REPOSITORY-NAME")
+				.required (false)
+				.help ("Name of the repository to restore from, required if the \
+					server has more than one")
 
 			)
 
@@ -118,7 +153,7 @@ command! (
 
 	clap_arguments_parse = |clap_matches| {
 
-		let (server_hostname, server_port) =
+		let server_address =
 			parse_server_address (
 				args::string_required (
 					clap_matches,
@@ -127,8 +162,12 @@ command! (
 
 		ClientRestoreArguments {
 
-			server_hostname: server_hostname,
-			server_port: server_port,
+			server_address: server_address,
+
+			repository_name:
+				args::string_optional (
+					clap_matches,
+					"repository-name"),
 
 			backup_name:
 				args::string_required (
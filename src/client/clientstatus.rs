@@ -2,7 +2,6 @@ use std::io;
 use std::io::BufRead;
 use std::io::BufReader;
 use std::io::Write;
-use std::net::TcpStream;
 
 use clap;
 
@@ -20,12 +19,8 @@ fn do_client_status (
 		io_result_with_prefix (
 			|| format! (
 				"Connection error: "),
-			TcpStream::connect (
-				(
-					arguments.server_hostname.as_str (),
-					arguments.server_port,
-				),
-			),
+			SocketStream::connect (
+				& arguments.server_address),
 		) ?;
 
 	io_result_with_prefix (
@@ -82,8 +77,7 @@ command! (
 	export = client_status_command,
 
 	arguments = ClientStatusArguments {
-		server_hostname: String,
-		server_port: u16,
+		server_address: String,
 	},
 
 	clap_subcommand = {
@@ -97,7 +91,7 @@ command! (
 				.long ("server-address")
 				.value_name ("SERVER-ADDRESS")
 				.required (true)
-				.help ("Server address, in 'host:port' format")
+				.help ("Server address, in 'host:port' or 'unix:/path' format")
 
 			)
 
@@ -105,7 +99,7 @@ command! (
 
 	clap_arguments_parse = |clap_matches| {
 
-		let (server_hostname, server_port) =
+		let server_address =
 			parse_server_address (
 				args::string_required (
 					clap_matches,
@@ -114,8 +108,7 @@ command! (
 
 		ClientStatusArguments {
 
-			server_hostname: server_hostname,
-			server_port: server_port,
+			server_address: server_address,
 
 		}
 
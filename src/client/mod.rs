@@ -27,15 +27,22 @@ pub fn client_command (
 		ParentCommand::new (
 			"client",
 			"Connects to a server and performs various operations",
-			vec! [
-				client_exit_command (),
-				client_reindex_command (),
-				client_restore_command (),
-				client_status_command (),
-			],
+			client_commands (),
 		)
 	)
 
 }
 
+pub fn client_commands (
+) -> Vec <Box <Command>> {
+
+	vec! [
+		client_exit_command (),
+		client_reindex_command (),
+		client_restore_command (),
+		client_status_command (),
+	]
+
+}
+
 // ex: noet ts=4 filetype=rust
@@ -0,0 +1,275 @@
+use std::path::PathBuf;
+use std::time::Duration;
+use std::time::SystemTime;
+use std::time::UNIX_EPOCH;
+
+use clap;
+
+use output::Output;
+
+use ::convert::events::ClapSubCommandConvertArgs;
+use ::convert::events::OutputFormat;
+use ::convert::events::output_format;
+use ::misc::*;
+
+pub fn do_audit (
+	output: & Output,
+	arguments: & AuditArguments,
+) -> Result <bool, String> {
+
+	let action =
+		match arguments.action {
+
+		Some (ref action_name) =>
+			Some (
+				AuditAction::parse (
+					action_name,
+				).ok_or_else (
+					|| format! (
+						"Invalid action: {}",
+						action_name),
+				) ?),
+
+		None =>
+			None,
+
+	};
+
+	let since_timestamp =
+		arguments.since.map (
+			|since|
+
+			SystemTime::now ().duration_since (
+				UNIX_EPOCH,
+			).map (
+				|duration| duration.as_secs ()
+			).unwrap_or (0).saturating_sub (
+				since.as_secs ())
+
+		);
+
+	let (records, invalid_line_numbers) =
+		audit_log_read (
+			& arguments.repository_path,
+		) ?;
+
+	for line_number in invalid_line_numbers.iter () {
+
+		output_message! (
+			output,
+			"Skipping invalid record on line {} of {}",
+			line_number,
+			arguments.repository_path.join (
+				AUDIT_LOG_FILE_NAME,
+			).to_string_lossy ());
+
+	}
+
+	for mut record in records {
+
+		if let Some (since_timestamp) = since_timestamp {
+
+			if record.timestamp < since_timestamp {
+				continue;
+			}
+
+		}
+
+		// only show the operations which match, and skip records with none
+
+		record.operations.retain (
+			|operation| {
+
+			let action_matches =
+				action.map (
+					|action| action == operation.action
+				).unwrap_or (true);
+
+			let path_matches =
+				arguments.path.as_ref ().map (
+					|path|
+
+					operation.path.to_string_lossy ().contains (
+						path.as_str ())
+					|| operation.target_path.as_ref ().map (
+						|target_path|
+						target_path.to_string_lossy ().contains (
+							path.as_str ())
+					).unwrap_or (false)
+
+				).unwrap_or (true);
+
+			action_matches && path_matches
+
+		});
+
+		if record.operations.is_empty () {
+			continue;
+		}
+
+		match arguments.output_format {
+
+			OutputFormat::Text => {
+
+				println! (
+					"{} UTC {} pid {} version {}: {}",
+					audit_format_timestamp (
+						record.timestamp),
+					record.host,
+					record.pid,
+					record.version,
+					record.command_line.join (" "));
+
+				for operation in record.operations.iter () {
+
+					if let Some (ref target_path) = operation.target_path {
+
+						println! (
+							"  {} {} to {}",
+							operation.action.name (),
+							operation.path.to_string_lossy (),
+							target_path.to_string_lossy ());
+
+					} else {
+
+						println! (
+							"  {} {}",
+							operation.action.name (),
+							operation.path.to_string_lossy ());
+
+					}
+
+				}
+
+				if let Some (ref error) = record.error {
+
+					println! (
+						"  failed: {}",
+						error);
+
+				}
+
+			},
+
+			OutputFormat::JsonLines => {
+
+				println! (
+					"{}",
+					record.to_json_string ());
+
+			},
+
+		}
+
+	}
+
+	Ok (true)
+
+}
+
+command! (
+
+	name = audit,
+	export = audit_command,
+
+	arguments = AuditArguments {
+		repository_path: PathBuf,
+		since: Option <Duration>,
+		path: Option <String>,
+		action: Option <String>,
+		output_format: OutputFormat,
+	},
+
+	clap_subcommand = {
+
+		clap::SubCommand::with_name ("audit")
+			.about ("Shows the changes which have been made to a repository, \
+				from its audit log")
+
+			.arg (
+				clap::Arg::with_name ("repository")
+
+				.long ("repository")
+				.value_name ("REPOSITORY")
+				.required (false)
+				.help ("Path to the repository, or the name \
+					of a repository in the config file")
+
+			)
+
+			.arg (
+				clap::Arg::with_name ("since")
+
+				.long ("since")
+				.value_name ("DURATION")
+				.required (false)
+				.help ("Only show changes made more recently than this, such \
+					as \"7 days\"")
+
+			)
+
+			.arg (
+				clap::Arg::with_name ("path")
+
+				.long ("path")
+				.value_name ("PATH")
+				.required (false)
+				.help ("Only show changes to files whose path, relative to the \
+					repository, contains this")
+
+			)
+
+			.arg (
+				clap::Arg::with_name ("action")
+
+				.long ("action")
+				.value_name ("ACTION")
+				.possible_values (& ["create", "replace", "delete", "rename"])
+				.required (false)
+				.help ("Only show changes of this kind")
+
+			)
+
+			.output_format_args ()
+
+	},
+
+	clap_arguments_parse = |clap_matches| {
+
+		AuditArguments {
+
+			repository_path:
+				args::path_required (
+					& clap_matches,
+					"repository"),
+
+			since:
+				args::duration_optional (
+					& clap_matches,
+					"since"),
+
+			path:
+				args::string_optional (
+					& clap_matches,
+					"path"),
+
+			action:
+				args::string_optional (
+					& clap_matches,
+					"action"),
+
+			output_format:
+				output_format (
+					& clap_matches),
+
+		}
+
+	},
+
+	action = |output, arguments| {
+		do_audit (output, arguments)
+	},
+
+);
+
+// ex: noet ts=4 filetype=rust
@@ -0,0 +1,354 @@
+use std::fs::File;
+use std::io;
+use std::io::BufReader;
+use std::io::Read;
+use std::io::Write;
+use std::path::PathBuf;
+
+use clap;
+
+use output::Output;
+
+use zbackup::repository::Repository;
+use ::misc::*;
+
+pub fn do_compare (
+	output: & Output,
+	arguments: & CompareArguments,
+) -> Result <bool, String> {
+
+	let repository =
+		string_result_with_prefix (
+			|| format! (
+				"Error opening repository: "),
+			Repository::open (
+				& output,
+				Repository::default_config (),
+				& arguments.repository_path,
+				arguments.password_file_path.as_ref (),
+			),
+		) ?;
+
+	let local_file =
+		io_result_with_prefix (
+			|| format! (
+				"Error opening {}: ",
+				arguments.local_file_path.to_string_lossy ()),
+			File::open (
+				& arguments.local_file_path),
+		) ?;
+
+	let local_size =
+		io_result_with_prefix (
+			|| format! (
+				"Error reading {}: ",
+				arguments.local_file_path.to_string_lossy ()),
+			local_file.metadata (),
+		) ?.len ();
+
+	let mut compare_writer =
+		CompareWriter {
+			local: BufReader::new (local_file),
+			local_size: local_size,
+			position: 0,
+			buffer: Vec::new (),
+			mismatches: Vec::new (),
+		};
+
+	string_result_with_prefix (
+		|| format! (
+			"Error performing compare: "),
+		repository.restore (
+			& output,
+			& arguments.backup_name,
+			& mut compare_writer),
+	) ?;
+
+	// local data beyond the end of the backup is also a mismatch
+
+	let backup_size =
+		compare_writer.position;
+
+	if local_size > backup_size {
+
+		compare_writer.add_mismatch (
+			backup_size,
+			local_size);
+
+	}
+
+	// report results
+
+	for & (start, end) in compare_writer.mismatches.iter () {
+
+		output_message! (
+			output,
+			"Mismatch at bytes {} to {} ({} bytes)",
+			start,
+			end,
+			end - start);
+
+	}
+
+	let matched =
+		compare_writer.mismatches.is_empty ();
+
+	if matched {
+
+		output_message! (
+			output,
+			"Local file {} matches backup {}, {} bytes, sha256 checksum \
+				verified",
+			arguments.local_file_path.to_string_lossy (),
+			arguments.backup_name,
+			backup_size);
+
+	} else {
+
+		output_message! (
+			output,
+			"Local file {} differs from backup {} in {} ranges, {} bytes \
+				total (backup sha256 checksum verified)",
+			arguments.local_file_path.to_string_lossy (),
+			arguments.backup_name,
+			compare_writer.mismatches.len (),
+			compare_writer.mismatches.iter ().map (
+				|& (start, end)| end - start,
+			).sum::<u64> ());
+
+	}
+
+	// clean up and return
+
+	repository.close (
+		output);
+
+	Ok (matched)
+
+}
+
+struct CompareWriter {
+	local: BufReader <File>,
+	local_size: u64,
+	position: u64,
+	buffer: Vec <u8>,
+	mismatches: Vec <(u64, u64)>,
+}
+
+impl CompareWriter {
+
+	fn add_mismatch (
+		& mut self,
+		start: u64,
+		end: u64,
+	) {
+
+		if let Some (last_mismatch) =
+			self.mismatches.last_mut () {
+
+			if last_mismatch.1 == start {
+
+				last_mismatch.1 = end;
+
+				return;
+
+			}
+
+		}
+
+		self.mismatches.push (
+			(start, end));
+
+	}
+
+}
+
+impl Write for CompareWriter {
+
+	fn write (
+		& mut self,
+		buffer: & [u8],
+	) -> io::Result <usize> {
+
+		let start =
+			self.position;
+
+		let available =
+			if start < self.local_size {
+				::std::cmp::min (
+					buffer.len () as u64,
+					self.local_size - start,
+				) as usize
+			} else { 0 };
+
+		self.buffer.resize (
+			available,
+			0u8);
+
+		self.local.read_exact (
+			& mut self.buffer,
+		) ?;
+
+		// find mismatched runs in the overlapping part
+
+		if self.buffer [..] != buffer [0 .. available] {
+
+			let mut run_start: Option <usize> = None;
+
+			for index in 0 .. available {
+
+				let same =
+					self.buffer [index] == buffer [index];
+
+				match (same, run_start) {
+
+					(false, None) =>
+						run_start = Some (index),
+
+					(true, Some (run_index)) => {
+
+						self.add_mismatch (
+							start + run_index as u64,
+							start + index as u64);
+
+						run_start = None;
+
+					},
+
+					_ => (),
+
+				}
+
+			}
+
+			if let Some (run_index) = run_start {
+
+				self.add_mismatch (
+					start + run_index as u64,
+					start + available as u64);
+
+			}
+
+		}
+
+		// anything beyond the end of the local file is missing
+
+		if available < buffer.len () {
+
+			self.add_mismatch (
+				start + available as u64,
+				start + buffer.len () as u64);
+
+		}
+
+		self.position +=
+			buffer.len () as u64;
+
+		Ok (buffer.len ())
+
+	}
+
+	fn flush (
+		& mut self,
+	) -> io::Result <()> {
+
+		Ok (())
+
+	}
+
+}
+
+command! (
+
+	name = compare,
+	export = compare_command,
+
+	arguments = CompareArguments {
+		repository_path: PathBuf,
+		password_file_path: Option <PathBuf>,
+		backup_name: String,
+		local_file_path: PathBuf,
+	},
+
+	clap_subcommand = {
+
+		clap::SubCommand::with_name ("compare")
+			.about ("Compares a local file against a backup without writing")
+
+			.arg (
+				clap::Arg::with_name ("repository")
+
+				.long ("repository")
+				.value_name ("REPOSITORY")
+				.required (true)
+				.help ("Path to the repository")
+
+			)
+
+			.arg (
+				clap::Arg::with_name ("password-file")
+
+				.long ("password-file")
+				.value_name ("PASSWORD-FILE")
+				.required (false)
+				.help ("Path to the password file")
+
+			)
+
+			.arg (
+				clap::Arg::with_name ("backup-name")
+
+				.long ("backup-name")
+				.value_name ("BACKUP-NAME")
+				.required (true)
+				.help ("Name of backup to compare against")
+
+			)
+
+			.arg (
+				clap::Arg::with_name ("local-file")
+
+				.long ("local-file")
+				.value_name ("LOCAL-FILE")
+				.required (true)
+				.help ("Path to the local file to compare")
+
+			)
+
+	},
+
+	clap_arguments_parse = |clap_matches| {
+
+		CompareArguments {
+
+			repository_path:
+				args::path_required (
+					& clap_matches,
+					"repository"),
+
+			password_file_path:
+				args::path_optional (
+					& clap_matches,
+					"password-file"),
+
+			backup_name:
+				args::string_required (
+					& clap_matches,
+					"backup-name"),
+
+			local_file_path:
+				args::path_required (
+					& clap_matches,
+					"local-file"),
+
+		}
+
+	},
+
+	action = |output, arguments| {
+		do_compare (output, arguments)
+	},
+
+);
+
+// ex: noet ts=4 filetype=rust
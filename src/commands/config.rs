@@ -0,0 +1,538 @@
+use std::fs;
+
+use clap;
+
+use output::Output;
+
+use ::client::*;
+use ::convert::*;
+use ::misc::*;
+use ::misc::config::*;
+use ::server::*;
+use ::zbackup::cache_tier::CachePolicy;
+
+pub fn config_command (
+) -> Box <Command> {
+
+	Box::new (
+		ParentCommand::new (
+			"config",
+			"Works with the config file given with --config",
+			vec! [
+				config_check_command (),
+			],
+		)
+	)
+
+}
+
+fn config_check (
+	output: & Output,
+	arguments: & ConfigCheckArguments,
+) -> Result <bool, String> {
+
+	let config_file =
+		args::config_file ().ok_or_else (
+			|| "No config file given, use --config".to_string (),
+		) ?;
+
+	let mut errors: Vec <String> =
+		Vec::new ();
+
+	for section in config_file.sections () {
+
+		let name =
+			section.name ();
+
+		let section_result =
+			if name.is_empty () {
+
+			section.check_keys (
+				& [])
+
+		} else if name.starts_with ("repositories.") {
+
+			check_repository_section (
+				section,
+				arguments.skip_paths)
+
+		} else if name == "server" {
+
+			check_command_section (
+				section,
+				& [ "server" ],
+				& [ server_command () ])
+
+		} else if name == "client" || name.starts_with ("client.") {
+
+			check_command_section (
+				section,
+				& [ "client" ],
+				& client_commands ())
+
+		} else if name == "convert" || name.starts_with ("convert.") {
+
+			check_command_section (
+				section,
+				& [ "convert" ],
+				& convert_commands ())
+
+		} else {
+
+			Err (
+				format! (
+					"Line {}: Unknown section [{}]",
+					section.line_number (),
+					name))
+
+		};
+
+		if let Err (error) = section_result {
+			errors.push (error);
+		}
+
+	}
+
+	for error in errors.iter () {
+
+		output_message! (
+			output,
+			"{}: {}",
+			config_file.path ().to_string_lossy (),
+			error);
+
+	}
+
+	if errors.is_empty () {
+
+		output_message! (
+			output,
+			"Config file {} is valid",
+			config_file.path ().to_string_lossy ());
+
+		Ok (true)
+
+	} else {
+
+		output_message! (
+			output,
+			"Config file {} has {} errors",
+			config_file.path ().to_string_lossy (),
+			errors.len ());
+
+		Ok (false)
+
+	}
+
+}
+
+fn check_repository_section (
+	section: & ConfigSection,
+	skip_paths: bool,
+) -> Result <(), String> {
+
+	let mut allowed_keys =
+		vec! [ "path", "password-file" ];
+
+	allowed_keys.extend_from_slice (
+		args::REPOSITORY_CONFIG_KEYS);
+
+	section.check_keys (
+		& allowed_keys,
+	) ?;
+
+	let path =
+		section.get_path (
+			"path",
+		) ?.ok_or_else (
+			|| format! (
+				"Line {}: No path for repository in section [{}]",
+				section.line_number (),
+				section.name ()),
+		) ?;
+
+	if skip_paths {
+
+		// only check the values
+
+	} else if ! path.join ("info").is_file () {
+
+		return Err (
+			format! (
+				"Line {}: No repository found at {}",
+				section.line_number (),
+				path.to_string_lossy ()));
+
+	}
+
+	let password_file_path =
+		section.get_path (
+			"password-file",
+		) ?;
+
+	if let (false, Some (password_file_path)) = (skip_paths, password_file_path) {
+
+		io_result_with_prefix (
+			|| format! (
+				"Line {}: Error reading {}: ",
+				section.line_number (),
+				password_file_path.to_string_lossy ()),
+			fs::metadata (
+				& password_file_path),
+		) ?;
+
+	}
+
+	// check the values have the right types
+
+	if let Some (cache_policy) = section.get_string ("cache-policy") ? {
+
+		CachePolicy::parse (
+			& cache_policy,
+		) ?;
+
+	}
+
+	args::repository_config_section (
+		section,
+		& ::zbackup::repository::Repository::default_config (),
+	).map (|_| ())
+
+}
+
+/// Checks the options in a command's section, by passing each of them to the
+/// command's argument parser. A section for a parent command, such as
+/// `[convert]`, may contain any option accepted by one of its subcommands.
+
+fn check_command_section (
+	section: & ConfigSection,
+	command_path: & [& str],
+	commands: & [Box <Command>],
+) -> Result <(), String> {
+
+	let section_path: Vec <& str> =
+		section.name ().split ('.').collect ();
+
+	let section_commands: Vec <& Box <Command>> =
+		if section_path.len () > command_path.len () {
+
+		let command_name =
+			section_path [command_path.len () ..].join (".");
+
+		let matching_commands: Vec <& Box <Command>> =
+			commands.iter ().filter (
+				|command| command.name () == command_name,
+			).collect ();
+
+		if matching_commands.is_empty () {
+
+			return Err (
+				format! (
+					"Line {}: Unknown command {} in section [{}]",
+					section.line_number (),
+					command_name,
+					section.name ()));
+
+		}
+
+		matching_commands
+
+	} else {
+
+		commands.iter ().collect ()
+
+	};
+
+	for key in section.keys () {
+
+		let value =
+			section.get (key).unwrap ();
+
+		let mut key_known = false;
+
+		for command in section_commands.iter () {
+
+			let mut arguments: Vec <String> =
+				command_path.iter ().map (
+					|name| name.to_string (),
+				).collect ();
+
+			if command_path [0] != command.name () {
+
+				arguments.push (
+					command.name ().to_string ());
+
+			}
+
+			arguments.push (
+				format! (
+					"--{}",
+					key));
+
+			// flags do not take a value
+
+			match * value {
+				ConfigValue::Boolean (_) => (),
+				_ => arguments.push (value.to_string ()),
+			}
+
+			let clap_application =
+				clap::App::new ("rzbackup").subcommand (
+					if command_path [0] == command.name () {
+						command.clap_subcommand ()
+					} else {
+						clap::SubCommand::with_name (command_path [0])
+							.subcommand (command.clap_subcommand ())
+					});
+
+			let mut clap_arguments =
+				vec! [ "rzbackup".to_string () ];
+
+			clap_arguments.extend (
+				arguments);
+
+			match clap_application.get_matches_from_safe (
+				clap_arguments) {
+
+				Err (ref error)
+					if error.kind == clap::ErrorKind::UnknownArgument =>
+					(),
+
+				Err (ref error)
+					if error.kind == clap::ErrorKind::InvalidValue
+					|| error.kind == clap::ErrorKind::EmptyValue
+					|| error.kind == clap::ErrorKind::ValueValidation =>
+					return Err (
+						format! (
+							"Line {}: Invalid value for {} in section [{}]",
+							section.line_number (),
+							key,
+							section.name ())),
+
+				// other errors, such as missing options, are expected
+
+				_ =>
+					key_known = true,
+
+			}
+
+		}
+
+		if ! key_known {
+
+			return Err (
+				format! (
+					"Line {}: Unknown option {} in section [{}]",
+					section.line_number (),
+					key,
+					section.name ()));
+
+		}
+
+	}
+
+	Ok (())
+
+}
+
+command! (
+
+	name = check,
+	export = config_check_command,
+
+	arguments = ConfigCheckArguments {
+		skip_paths: bool,
+	},
+
+	clap_subcommand = {
+
+		clap::SubCommand::with_name ("check")
+			.about ("Checks the config file for errors")
+
+			.arg (
+				clap::Arg::with_name ("skip-paths")
+
+				.long ("skip-paths")
+				.help ("Don't check that repositories and password files \
+					exist, for checking a config file on another machine")
+
+			)
+
+	},
+
+	clap_arguments_parse = |clap_matches| {
+
+		ConfigCheckArguments {
+
+			skip_paths:
+				args::bool_flag (
+					clap_matches,
+					"skip-paths"),
+
+		}
+
+	},
+
+	action = |output, arguments| {
+		config_check (output, arguments)
+	},
+
+);
+
+#[ cfg (test) ]
+mod tests {
+
+	use super::*;
+
+	fn check_section (
+		source: & str,
+	) -> Result <(), String> {
+
+		let config_file =
+			ConfigFile::parse (
+				"test.conf",
+				source,
+			).unwrap ();
+
+		let section =
+			config_file.sections ().last ().unwrap ();
+
+		let name =
+			section.name ().to_string ();
+
+		if name.starts_with ("repositories.") {
+
+			check_repository_section (
+				section,
+				true)
+
+		} else if name == "server" {
+
+			check_command_section (
+				section,
+				& [ "server" ],
+				& [ server_command () ])
+
+		} else {
+
+			check_command_section (
+				section,
+				& [ "convert" ],
+				& convert_commands ())
+
+		}
+
+	}
+
+	#[ test ]
+	fn server_section () {
+
+		assert_eq! (
+			check_section (
+				"[server]\n\
+				listen-address = \"unix:/run/rzbackup.sock\"\n\
+				memory-cache-size = \"16G\"\n\
+				max-threads = 8\n\
+				watch-indexes = true\n\
+				idle-timeout = \"5 minutes\"\n\
+				read-timeout = \"30 seconds\"\n"),
+			Ok (()));
+
+		assert_eq! (
+			check_section (
+				"[server]\n\
+				max-threads = 8\n\
+				no-such-option = 1\n"),
+			Err ("Line 1: Unknown option no-such-option in section [server]"
+				.to_string ()));
+
+	}
+
+	#[ test ]
+	fn convert_sections () {
+
+		assert_eq! (
+			check_section (
+				"[convert]\n\
+				repository = \"main\"\n\
+				sleep-time = \"1 second\"\n"),
+			Ok (()));
+
+		assert_eq! (
+			check_section (
+				"[convert.gc-bundles]\n\
+				grace-period = \"7 days\"\n\
+				dry-run = true\n"),
+			Ok (()));
+
+		// only options for the named command are allowed in its section
+
+		assert_eq! (
+			check_section (
+				"[convert.gc-bundles]\n\
+				chunks-per-bundle = 512\n"),
+			Err ("Line 1: Unknown option chunks-per-bundle in section \
+				[convert.gc-bundles]".to_string ()));
+
+		assert_eq! (
+			check_section (
+				"[convert.no-such-command]\n\
+				dry-run = true\n"),
+			Err ("Line 1: Unknown command no-such-command in section \
+				[convert.no-such-command]".to_string ()));
+
+		assert_eq! (
+			check_section (
+				"[convert]\n\
+				output-format = \"xml\"\n"),
+			Err ("Line 1: Invalid value for output-format in section \
+				[convert]".to_string ()));
+
+	}
+
+	#[ test ]
+	fn repository_section () {
+
+		assert_eq! (
+			check_section (
+				"[repositories.main]\n\
+				path = \"/backups/main\"\n\
+				password-file = \"/etc/rzbackup/main.key\"\n\
+				memory-cache-size = \"4G\"\n\
+				cache-policy = \"2q\"\n"),
+			Ok (()));
+
+		assert_eq! (
+			check_section (
+				"[repositories.main]\n\
+				password-file = \"/etc/rzbackup/main.key\"\n"),
+			Err ("Line 1: No path for repository in section \
+				[repositories.main]".to_string ()));
+
+		assert_eq! (
+			check_section (
+				"[repositories.main]\n\
+				path = \"/backups/main\"\n\
+				listen-address = \"localhost:4152\"\n"),
+			Err ("Line 3: Unknown key listen-address in section \
+				[repositories.main]".to_string ()));
+
+		assert! (
+			check_section (
+				"[repositories.main]\n\
+				path = \"/backups/main\"\n\
+				cache-policy = \"fifo\"\n",
+			).is_err ());
+
+		assert! (
+			check_section (
+				"[repositories.main]\n\
+				path = \"/backups/main\"\n\
+				memory-cache-size = \"lots\"\n",
+			).is_err ());
+
+	}
+
+}
+
+// ex: noet ts=4 filetype=rust
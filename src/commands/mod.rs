@@ -1,6 +1,12 @@
+pub mod audit;
+pub mod compare;
+pub mod config;
 pub mod decrypt;
 pub mod restore;
 
+pub use self::audit::*;
+pub use self::compare::*;
+pub use self::config::*;
 pub use self::decrypt::*;
 pub use self::restore::*;
 
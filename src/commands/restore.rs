@@ -1,3 +1,5 @@
+use std::fs::File;
+use std::fs::OpenOptions;
 use std::io::stdout;
 use std::path::PathBuf;
 
@@ -25,20 +27,81 @@ pub fn do_restore (
 			),
 		) ?;
 
-	let stdout_value =
-		stdout ();
+	if let Some (ref update_existing_path) =
+		arguments.update_existing_path {
+
+		let mut target =
+			io_result_with_prefix (
+				|| format! (
+					"Error opening {}: ",
+					update_existing_path.to_string_lossy ()),
+				OpenOptions::new ()
+					.read (true)
+					.write (true)
+					.create (true)
+					.truncate (false)
+					.open (update_existing_path),
+			) ?;
 
-	let mut stdout_lock =
-		stdout_value.lock ();
+		string_result_with_prefix (
+			|| format! (
+				"Error performing restore: "),
+			repository.restore_update_existing (
+				& output,
+				& arguments.backup_name,
+				& mut target),
+		) ?;
+
+	} else if let Some (ref sparse_path) =
+		arguments.sparse_path {
+
+		let target_file =
+			io_result_with_prefix (
+				|| format! (
+					"Error creating {}: ",
+					sparse_path.to_string_lossy ()),
+				File::create (
+					sparse_path),
+			) ?;
 
-	string_result_with_prefix (
-		|| format! (
-			"Error performing restore: "),
-		repository.restore (
-			& output,
-			& arguments.backup_name,
-			& mut stdout_lock),
-	) ?;
+		let mut target =
+			SparseFileWriter::wrap (
+				target_file);
+
+		string_result_with_prefix (
+			|| format! (
+				"Error performing restore: "),
+			repository.restore (
+				& output,
+				& arguments.backup_name,
+				& mut target),
+		) ?;
+
+		io_result_with_prefix (
+			|| format! (
+				"Error closing {}: ",
+				sparse_path.to_string_lossy ()),
+			target.close (),
+		) ?;
+
+	} else {
+
+		let stdout_value =
+			stdout ();
+
+		let mut stdout_lock =
+			stdout_value.lock ();
+
+		string_result_with_prefix (
+			|| format! (
+				"Error performing restore: "),
+			repository.restore (
+				& output,
+				& arguments.backup_name,
+				& mut stdout_lock),
+		) ?;
+
+	}
 
 	// clean up and return
 
@@ -58,6 +121,8 @@ command! (
 		repository_path: PathBuf,
 		password_file_path: Option <PathBuf>,
 		backup_name: String,
+		update_existing_path: Option <PathBuf>,
+		sparse_path: Option <PathBuf>,
 	},
 
 	clap_subcommand = {
@@ -95,6 +160,29 @@ command! (
 
 			)
 
+			.arg (
+				clap::Arg::with_name ("update-existing")
+
+				.long ("update-existing")
+				.value_name ("FILE")
+				.required (false)
+				.help ("Update an existing file in place, only writing ranges \
+					which differ from the backup")
+
+			)
+
+			.arg (
+				clap::Arg::with_name ("sparse")
+
+				.long ("sparse")
+				.value_name ("FILE")
+				.required (false)
+				.conflicts_with ("update-existing")
+				.help ("Restore to a sparse file, seeking over zero ranges \
+					instead of writing them")
+
+			)
+
 	},
 
 	clap_arguments_parse = |clap_matches| {
@@ -116,6 +204,16 @@ command! (
 					& clap_matches,
 					"backup-name"),
 
+			update_existing_path:
+				args::path_optional (
+					& clap_matches,
+					"update-existing"),
+
+			sparse_path:
+				args::path_optional (
+					& clap_matches,
+					"sparse"),
+
 		}
 
 	},
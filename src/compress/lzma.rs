@@ -69,7 +69,7 @@ const LZMA_CHECK_CRC64: c_int = 4;
 //const LZMA_CHECK_SHA256: c_int = 10;
 
 #[ link (name = "lzma") ]
-extern {
+extern "C" {
 
 	fn lzma_code (
 		strm: * mut LzmaStream,
@@ -139,7 +139,7 @@ impl <'a> LzmaReader <'a> {
 		let init_result = unsafe {
 			lzma_stream_decoder (
 				& mut lzma_stream,
-				u64::max_value (),
+				u64::MAX,
 				0,
 			)
 		};
@@ -202,7 +202,7 @@ impl <'a> Read for LzmaReader <'a> {
 				let input_buffer =
 					self.input.fill_buf () ?;
 
-				if input_buffer.len () == 0 {
+				if input_buffer.is_empty () {
 
 					self.error = true;
 
@@ -241,8 +241,8 @@ impl <'a> Read for LzmaReader <'a> {
 				self.eof = true;
 
 				return Ok (
-					output_buffer.len () as usize
-						- self.lzma_stream.avail_out as usize
+					output_buffer.len ()
+						- self.lzma_stream.avail_out
 				);
 
 			}
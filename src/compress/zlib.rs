@@ -29,7 +29,7 @@ struct ZlibStream {
 }
 
 #[ link (name = "z") ]
-extern {
+extern "C" {
 
 	fn deflateInit2_ (
 		strm: * mut ZlibStream,
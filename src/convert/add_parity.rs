@@ -0,0 +1,485 @@
+use std::fs::File;
+use std::io::Read;
+use std::io::Write;
+use std::path::PathBuf;
+use std::vec;
+
+use clap;
+
+use futures::Future;
+use futures_cpupool::CpuPool;
+
+use num_cpus;
+
+use output::Output;
+use output::OutputJob;
+
+use ::convert::events::*;
+use ::convert::utils::*;
+use ::misc::*;
+use ::zbackup::data::*;
+use ::zbackup::disk_format::*;
+use ::zbackup::repository_core::*;
+
+pub fn add_parity (
+	output: & Output,
+	events: & ConvertEvents,
+	arguments: & AddParityArguments,
+) -> Result <bool, String> {
+
+	events.phase_start (
+		"scan");
+
+	// open repository
+
+	let repository_core =
+		string_result_with_prefix (
+			|| format! (
+				"Error opening repository {}: ",
+				arguments.repository_path.to_string_lossy ()),
+			RepositoryCore::open (
+				& output,
+				& arguments.repository_path,
+				arguments.password_file_path.clone ()),
+		) ?;
+
+	// begin transaction
+
+	let atomic_file_writer =
+		AtomicFileWriter::new (
+			output,
+			& arguments.repository_path,
+			None,
+		) ?;
+
+	// existing parity files can't be replaced in an append-only repository
+
+	if arguments.rebuild {
+
+		atomic_file_writer.append_only_check () ?;
+
+	}
+
+	// find bundles without parity
+
+	let output_job =
+		output_job_start! (
+			output,
+			"Scanning bundles");
+
+	let bundle_ids_and_sizes =
+		scan_bundle_files_with_sizes (
+			& arguments.repository_path,
+		) ?;
+
+	let (bundles_to_protect, bundles_to_skip): (Vec <_>, Vec <_>) =
+		bundle_ids_and_sizes.into_iter ().partition (
+			|& (bundle_id, _)|
+			arguments.rebuild
+				|| ! parity_path (
+					repository_core.bundle_path (
+						bundle_id),
+				).exists ()
+		);
+
+	let total_size: u64 =
+		bundles_to_protect.iter ().map (
+			|& (_, bundle_size)| bundle_size
+		).sum ();
+
+	output_job_replace! (
+		output_job,
+		"Found {} bundles to protect, skipping {} which already have parity",
+		bundles_to_protect.len (),
+		bundles_to_skip.len ());
+
+	// calculate parity
+
+	events.phase_start (
+		"add-parity");
+
+	let num_threads =
+		(num_cpus::get () - 1) * 5 / 3 + 1;
+
+	let cpu_pool =
+		CpuPool::new (
+			num_threads);
+
+	let (invalid_count, parity_size) =
+		add_parity_to_bundles (
+			output,
+			events,
+			& cpu_pool,
+			num_threads,
+			repository_core.clone (),
+			& atomic_file_writer,
+			arguments.redundancy,
+			bundles_to_protect.clone (),
+			total_size,
+		) ?;
+
+	// commit changes
+
+	events.phase_start (
+		"commit");
+
+	atomic_file_writer.commit () ?;
+
+	output_message! (
+		output,
+		"Added parity to {} bundles, using {} bytes",
+		bundles_to_protect.len () as u64 - invalid_count,
+		parity_size);
+
+	events.summary (
+		if invalid_count == 0 {
+			SummaryStatus::Ok
+		} else {
+			SummaryStatus::ProblemsFound
+		},
+		& [
+			("bundles-protected",
+				bundles_to_protect.len () as u64 - invalid_count),
+			("bundles-skipped", bundles_to_skip.len () as u64),
+			("bundles-invalid", invalid_count),
+			("parity-bytes", parity_size),
+		]);
+
+	// return
+
+	Ok (invalid_count == 0)
+
+}
+
+fn add_parity_to_bundles (
+	output: & Output,
+	events: & ConvertEvents,
+	cpu_pool: & CpuPool,
+	max_tasks: usize,
+	repository_core: RepositoryCore,
+	atomic_file_writer: & AtomicFileWriter,
+	redundancy: f64,
+	bundles_to_protect: Vec <(BundleId, u64)>,
+	total_size: u64,
+) -> Result <(u64, u64), String> {
+
+	struct Task {
+		bundle_id: BundleId,
+		bundle_size: u64,
+		output_job: OutputJob,
+		result: Result <Vec <u8>, String>,
+	}
+
+	struct State {
+		bundles_iterator: vec::IntoIter <(BundleId, u64)>,
+		bundles_count: u64,
+		bundles_total: u64,
+		bundles_size: u64,
+		invalid_count: u64,
+		parity_size: u64,
+		output_job: OutputJob,
+	}
+
+	let output_job =
+		output_job_start! (
+			output,
+			"Adding parity to bundles");
+
+	let mut state = State {
+		bundles_total: bundles_to_protect.len () as u64,
+		bundles_iterator: bundles_to_protect.into_iter (),
+		bundles_count: 0,
+		bundles_size: 0,
+		invalid_count: 0,
+		parity_size: 0,
+		output_job: output_job,
+	};
+
+	// concurrent operation
+
+	concurrent_controller (
+		output,
+		max_tasks,
+		& mut state,
+
+		|state| {
+
+			if let Some ((bundle_id, bundle_size)) =
+				state.bundles_iterator.next () {
+
+				let output =
+					output.clone ();
+
+				let repository_core =
+					repository_core.clone ();
+
+				Some (cpu_pool.spawn_fn (move || {
+
+					let output_job =
+						output_job_start! (
+							output,
+							"Calculating parity for bundle {}",
+							bundle_id);
+
+					let result =
+						calculate_bundle_parity (
+							& repository_core,
+							bundle_id,
+							redundancy);
+
+					Ok (Task {
+						bundle_id: bundle_id,
+						bundle_size: bundle_size,
+						output_job: output_job,
+						result: result,
+					})
+
+				}).boxed ())
+
+			} else { None }
+
+		},
+
+		|state, task_value| {
+
+			state.bundles_count += 1;
+
+			state.bundles_size +=
+				task_value.bundle_size;
+
+			state.output_job.progress (
+				state.bundles_size,
+				total_size);
+
+			events.progress_bytes (
+				state.bundles_count,
+				state.bundles_total,
+				state.bundles_size,
+				total_size);
+
+			match task_value.result {
+
+				Ok (parity_bytes) => {
+
+					let mut parity_file =
+						atomic_file_writer.create (
+							parity_path (
+								repository_core.bundle_path (
+									task_value.bundle_id)),
+						) ?;
+
+					io_result (
+						parity_file.write_all (
+							& parity_bytes),
+					) ?;
+
+					state.parity_size +=
+						parity_bytes.len () as u64;
+
+				},
+
+				Err (error) => {
+
+					events.problem (
+						"invalid-bundle",
+						& task_value.bundle_id.to_string (),
+						false,
+						& error);
+
+					output.message (
+						error);
+
+					state.invalid_count += 1;
+
+				},
+
+			}
+
+			task_value.output_job.remove ();
+
+			Ok (())
+
+		},
+
+	) ?;
+
+	state.output_job.complete ();
+
+	Ok ((state.invalid_count, state.parity_size))
+
+}
+
+/// Reads the bundle, and checks it is valid, before calculating its parity.
+/// When rebuilding, a bundle which can only be read with the help of its
+/// existing parity file is reported rather than given new parity, since that
+/// would make the damage permanent. A parity file which can't be used is simply
+/// replaced.
+
+fn calculate_bundle_parity (
+	repository_core: & RepositoryCore,
+	bundle_id: BundleId,
+	redundancy: f64,
+) -> Result <Vec <u8>, String> {
+
+	let bundle_path =
+		repository_core.bundle_path (
+			bundle_id);
+
+	bundle_read_path (
+		& bundle_path,
+		repository_core.encryption_key (),
+	) ?;
+
+	if let Ok (Some (_)) =
+		parity_repair_path (
+			& bundle_path) {
+
+		return Err (
+			format! (
+				"Bundle {} is damaged, and can only be read using its \
+				parity file",
+				bundle_id));
+
+	}
+
+	let mut bundle_file =
+		io_result_with_prefix (
+			|| format! (
+				"Error opening {}: ",
+				bundle_path.to_string_lossy ()),
+			File::open (
+				& bundle_path),
+		) ?;
+
+	let mut bundle_bytes: Vec <u8> =
+		Vec::new ();
+
+	io_result_with_prefix (
+		|| format! (
+			"Error reading {}: ",
+			bundle_path.to_string_lossy ()),
+		bundle_file.read_to_end (
+			& mut bundle_bytes),
+	) ?;
+
+	parity_create (
+		& bundle_bytes,
+		redundancy,
+	)
+
+}
+
+command! (
+
+	name = add_parity,
+	export = add_parity_command,
+
+	arguments = AddParityArguments {
+		repository_path: PathBuf,
+		password_file_path: Option <PathBuf>,
+		redundancy: f64,
+		rebuild: bool,
+		output_format: OutputFormat,
+	},
+
+	clap_subcommand = {
+
+		clap::SubCommand::with_name ("add-parity")
+			.about ("Writes a parity file alongside each bundle, which is \
+				used to repair it if it is damaged")
+
+			.arg (
+				clap::Arg::with_name ("repository")
+
+				.long ("repository")
+				.value_name ("REPOSITORY")
+				.required (false)
+				.help ("Path to the repository, or the name \
+					of a repository in the config file")
+
+			)
+
+			.arg (
+				clap::Arg::with_name ("password-file")
+
+				.long ("password-file")
+				.value_name ("PASSWORD-FILE")
+				.required (false)
+				.help ("Path to the password file")
+
+			)
+
+			.arg (
+				clap::Arg::with_name ("redundancy")
+
+				.long ("redundancy")
+				.value_name ("PERCENTAGE")
+				.default_value ("10%")
+				.help ("Size of the parity as a percentage of the bundle, \
+					which is also roughly how much damage it can repair")
+
+			)
+
+			.arg (
+				clap::Arg::with_name ("rebuild")
+
+				.long ("rebuild")
+				.help ("Replace existing parity files, for example to change \
+					the redundancy")
+
+			)
+
+			.output_format_args ()
+
+	},
+
+	clap_arguments_parse = |clap_matches| {
+
+		AddParityArguments {
+
+			repository_path:
+				args::path_required (
+					& clap_matches,
+					"repository"),
+
+			password_file_path:
+				args::path_optional (
+					& clap_matches,
+					"password-file"),
+
+			redundancy:
+				parse_percentage (
+					& args::string_required (
+						& clap_matches,
+						"redundancy"),
+				).unwrap_or_else (
+					|error| args::error_exit (error)),
+
+			rebuild:
+				args::bool_flag (
+					& clap_matches,
+					"rebuild"),
+
+			output_format:
+				output_format (
+					& clap_matches),
+
+		}
+
+	},
+
+	action = |output, arguments| {
+
+		let events =
+			ConvertEvents::new (
+				"add-parity",
+				arguments.output_format);
+
+		events.finish (
+			add_parity (output, & events, arguments))
+
+	},
+
+);
+
+// ex: noet ts=4 filetype=rust
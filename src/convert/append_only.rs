@@ -0,0 +1,22 @@
+use ::convert::append_only_disable::*;
+use ::convert::append_only_enable::*;
+use ::misc::*;
+
+pub fn append_only_command (
+) -> Box <Command> {
+
+	Box::new (
+		ParentCommand::new (
+			"append-only",
+			"Enables or disables append-only mode, which prevents removing or \
+				overwriting any backup, bundle or index files",
+			vec! [
+				append_only_disable_command (),
+				append_only_enable_command (),
+			],
+		)
+	)
+
+}
+
+// ex: noet ts=4 filetype=rust
@@ -0,0 +1,129 @@
+use std::path::PathBuf;
+
+use clap;
+
+use output::Output;
+
+use ::convert::events::*;
+use ::misc::*;
+
+pub fn append_only_disable (
+	output: & Output,
+	events: & ConvertEvents,
+	arguments: & AppendOnlyDisableArguments,
+) -> Result <bool, String> {
+
+	let unlock_key =
+		append_only_read_key (
+			& arguments.unlock_key_file_path,
+		) ?;
+
+	// the atomic file writer checks the key and removes the append-only file,
+	// while holding the lock
+
+	let atomic_file_writer =
+		AtomicFileWriter::new (
+			output,
+			& arguments.repository_path,
+			None,
+		) ?;
+
+	atomic_file_writer.append_only_unlock (
+		& unlock_key,
+	) ?;
+
+	atomic_file_writer.commit () ?;
+
+	output_message! (
+		output,
+		"Repository {} is no longer append-only",
+		arguments.repository_path.to_string_lossy ());
+
+	events.summary (
+		SummaryStatus::Ok,
+		& []);
+
+	Ok (true)
+
+}
+
+command! (
+
+	name = disable,
+	export = append_only_disable_command,
+
+	arguments = AppendOnlyDisableArguments {
+		repository_path: PathBuf,
+		unlock_key_file_path: PathBuf,
+		output_format: OutputFormat,
+	},
+
+	clap_subcommand = {
+
+		clap::SubCommand::with_name ("disable")
+			.about ("Allows files in the repository to be removed and \
+				overwritten again, given the unlock key")
+
+			.arg (
+				clap::Arg::with_name ("repository")
+
+				.long ("repository")
+				.value_name ("REPOSITORY")
+				.required (false)
+				.help ("Path to the repository, or the name \
+					of a repository in the config file")
+
+			)
+
+			.arg (
+				clap::Arg::with_name ("unlock-key-file")
+
+				.long ("unlock-key-file")
+				.value_name ("UNLOCK-KEY-FILE")
+				.required (true)
+				.help ("Path to the file containing the unlock key which was \
+					used to enable append-only mode")
+
+			)
+
+			.output_format_args ()
+
+	},
+
+	clap_arguments_parse = |clap_matches| {
+
+		AppendOnlyDisableArguments {
+
+			repository_path:
+				args::path_required (
+					& clap_matches,
+					"repository"),
+
+			unlock_key_file_path:
+				args::path_required (
+					& clap_matches,
+					"unlock-key-file"),
+
+			output_format:
+				output_format (
+					& clap_matches),
+
+		}
+
+	},
+
+	action = |output, arguments| {
+
+		let events =
+			ConvertEvents::new (
+				"append-only-disable",
+				arguments.output_format);
+
+		events.finish (
+			append_only_disable (output, & events, arguments))
+
+	},
+
+);
+
+// ex: noet ts=4 filetype=rust
@@ -0,0 +1,149 @@
+use std::io::Write;
+use std::path::PathBuf;
+
+use clap;
+
+use output::Output;
+
+use ::convert::events::*;
+use ::misc::*;
+
+pub fn append_only_enable (
+	output: & Output,
+	events: & ConvertEvents,
+	arguments: & AppendOnlyEnableArguments,
+) -> Result <bool, String> {
+
+	let unlock_key =
+		append_only_read_key (
+			& arguments.unlock_key_file_path,
+		) ?;
+
+	// begin transaction, so the flag is never set part way through a change
+
+	let atomic_file_writer =
+		AtomicFileWriter::new (
+			output,
+			& arguments.repository_path,
+			None,
+		) ?;
+
+	if append_only_enabled (
+		& arguments.repository_path,
+	) {
+
+		return Err (
+			format! (
+				"Repository {} is already append-only",
+				arguments.repository_path.to_string_lossy ()));
+
+	}
+
+	let mut append_only_file =
+		atomic_file_writer.create (
+			arguments.repository_path.join (
+				APPEND_ONLY_FILE_NAME),
+		) ?;
+
+	io_result (
+		append_only_file.write_all (
+			append_only_file_contents (
+				& unlock_key,
+			).as_bytes ()),
+	) ?;
+
+	atomic_file_writer.commit () ?;
+
+	output_message! (
+		output,
+		"Repository {} is now append-only",
+		arguments.repository_path.to_string_lossy ());
+
+	events.summary (
+		SummaryStatus::Ok,
+		& []);
+
+	Ok (true)
+
+}
+
+command! (
+
+	name = enable,
+	export = append_only_enable_command,
+
+	arguments = AppendOnlyEnableArguments {
+		repository_path: PathBuf,
+		unlock_key_file_path: PathBuf,
+		output_format: OutputFormat,
+	},
+
+	clap_subcommand = {
+
+		clap::SubCommand::with_name ("enable")
+			.about ("Makes the repository append-only, until it is disabled \
+				again with the same unlock key")
+
+			.arg (
+				clap::Arg::with_name ("repository")
+
+				.long ("repository")
+				.value_name ("REPOSITORY")
+				.required (false)
+				.help ("Path to the repository, or the name \
+					of a repository in the config file")
+
+			)
+
+			.arg (
+				clap::Arg::with_name ("unlock-key-file")
+
+				.long ("unlock-key-file")
+				.value_name ("UNLOCK-KEY-FILE")
+				.required (true)
+				.help ("Path to a file containing the key needed to disable \
+					append-only mode, which is not stored in the repository")
+
+			)
+
+			.output_format_args ()
+
+	},
+
+	clap_arguments_parse = |clap_matches| {
+
+		AppendOnlyEnableArguments {
+
+			repository_path:
+				args::path_required (
+					& clap_matches,
+					"repository"),
+
+			unlock_key_file_path:
+				args::path_required (
+					& clap_matches,
+					"unlock-key-file"),
+
+			output_format:
+				output_format (
+					& clap_matches),
+
+		}
+
+	},
+
+	action = |output, arguments| {
+
+		let events =
+			ConvertEvents::new (
+				"append-only-enable",
+				arguments.output_format);
+
+		events.finish (
+			append_only_enable (output, & events, arguments))
+
+	},
+
+);
+
+// ex: noet ts=4 filetype=rust
@@ -1,4 +1,5 @@
 use std::collections::HashSet;
+use std::fs;
 use std::mem;
 use std::path::PathBuf;
 use std::thread;
@@ -8,6 +9,7 @@ use std::vec;
 
 use clap;
 
+use futures;
 use futures::Future;
 use futures_cpupool::CpuPool;
 
@@ -16,6 +18,7 @@ use num_cpus;
 use output::Output;
 use output::OutputJob;
 
+use convert::events::*;
 use convert::utils::*;
 use misc::*;
 use misc::args::ClapSubCommandRzbackupArgs;
@@ -26,6 +29,7 @@ use zbackup::repository_core::*;
 
 pub fn balance_bundles (
 	output: & Output,
+	events: & ConvertEvents,
 	arguments: & BalanceBundlesArguments,
 ) -> Result <bool, String> {
 
@@ -37,6 +41,9 @@ pub fn balance_bundles (
 
 	if arguments.cluster_backups {
 
+		events.phase_start (
+			"read-backups");
+
 		// open repository
 
 		let repository =
@@ -68,15 +75,44 @@ pub fn balance_bundles (
 				& arguments.repository_path,
 			) ?;
 
-		// get a list of chunks used by backups
+		// get a list of chunks used by backups, only expanding backups which
+		// were not seen by a previous run
+
+		let mut backup_references =
+			read_backup_references (
+				output,
+				repository.core (),
+				arguments.rebuild_references);
 
 		backup_chunk_ids =
 			get_recursive_chunks (
 				output,
 				& repository,
 				& backup_files,
+				& mut backup_references,
 			) ?;
 
+		// save backup references for the next run
+
+		if ! arguments.dry_run {
+
+			let atomic_file_writer =
+				AtomicFileWriter::new (
+					output,
+					& arguments.repository_path,
+					None,
+				) ?;
+
+			write_backup_references (
+				repository.core (),
+				& atomic_file_writer,
+				& backup_references,
+			) ?;
+
+			atomic_file_writer.commit () ?;
+
+		}
+
 	} else {
 
 		// open repository
@@ -106,18 +142,37 @@ pub fn balance_bundles (
 		CpuPool::new (
 			num_threads);
 
+	let mut checkpoint_count: u64 = 0;
+	let mut new_bundles_written: u64 = 0;
+
 	loop {
 
 		{
 
-			// begin transaction
+			// begin transaction, unless nothing will be changed
 
 			let atomic_file_writer =
-				AtomicFileWriter::new (
-					output,
-					& arguments.repository_path,
-					Some (arguments.sleep_time),
-				) ?;
+				if arguments.dry_run {
+
+					None
+
+				} else {
+
+					let atomic_file_writer =
+						AtomicFileWriter::new (
+							output,
+							& arguments.repository_path,
+							Some (arguments.sleep_time),
+						) ?;
+
+					// refuse to change an append-only repository, now we hold
+					// the lock
+
+					atomic_file_writer.append_only_check () ?;
+
+					Some (atomic_file_writer)
+
+				};
 
 			// get list of index files
 
@@ -140,6 +195,7 @@ pub fn balance_bundles (
 
 			read_indexes_find_unbalanced (
 				output,
+				events,
 				& repository_core,
 				& arguments,
 				minimum_chunk_count,
@@ -151,12 +207,34 @@ pub fn balance_bundles (
 
 			// do nothing if there is only one unbalanced bundle
 
-			if count_unbalanced_bundles (
-				minimum_chunk_count,
-				arguments.chunks_per_bundle,
-				& backup_chunk_ids,
-				& unbalanced_indexes,
-			) < 2 {
+			let unbalanced_bundle_count = {
+
+				let unbalanced_bundles =
+					find_unbalanced_bundles (
+						minimum_chunk_count,
+						arguments.chunks_per_bundle,
+						& backup_chunk_ids,
+						& unbalanced_indexes,
+					);
+
+				if arguments.dry_run && unbalanced_bundles.len () >= 2 {
+
+					plan_balance_bundles (
+						output,
+						events,
+						& repository_core,
+						& unbalanced_indexes,
+						& unbalanced_bundles,
+						new_bundles_total,
+					) ?;
+
+				}
+
+				unbalanced_bundles.len ()
+
+			};
+
+			if unbalanced_bundle_count < 2 {
 
 				output_message! (
 					output,
@@ -166,10 +244,21 @@ pub fn balance_bundles (
 
 			}
 
+			// with --dry-run, only show what would be done
+
+			let atomic_file_writer =
+				match atomic_file_writer {
+					Some (atomic_file_writer) => atomic_file_writer,
+					None => break,
+				};
+
 			// balance bundles
 
+			checkpoint_count += 1;
+
 			if balance_bundles_real (
 				output,
+				events,
 				& cpu_pool,
 				num_threads,
 				& repository_core,
@@ -179,6 +268,7 @@ pub fn balance_bundles (
 				& backup_chunk_ids,
 				unbalanced_indexes,
 				new_bundles_total,
+				& mut new_bundles_written,
 			) ? {
 				break;
 			}
@@ -203,6 +293,17 @@ pub fn balance_bundles (
 
 	}
 
+	if ! arguments.dry_run {
+
+		events.summary (
+			SummaryStatus::Ok,
+			& [
+				("checkpoints", checkpoint_count),
+				("bundles-written", new_bundles_written),
+			]);
+
+	}
+
 	// return
 
 	Ok (true)
@@ -211,10 +312,11 @@ pub fn balance_bundles (
 
 fn read_indexes_find_unbalanced (
 	output: & Output,
+	events: & ConvertEvents,
 	repository_core: & RepositoryCore,
 	arguments: & BalanceBundlesArguments,
 	minimum_chunk_count: u64,
-	old_index_ids_and_sizes: & Vec <(IndexId, u64)>,
+	old_index_ids_and_sizes: & [(IndexId, u64)],
 	backup_chunk_ids: & HashSet <ChunkId>,
 	unbalanced_indexes: & mut Vec <(IndexId, Vec <RawIndexEntry>)>,
 	new_bundles_total: & mut u64,
@@ -225,6 +327,9 @@ fn read_indexes_find_unbalanced (
 			output,
 			"Loading indexes");
 
+	events.phase_start (
+		"read-indexes");
+
 	let total_index_size =
 		old_index_ids_and_sizes.iter ().map (
 			|& (_, old_index_size)|
@@ -237,15 +342,21 @@ fn read_indexes_find_unbalanced (
 	let mut read_index_size: u64 = 0;
 	let mut unbalanced_chunks_count: u64 = 0;
 
-	for & (
-		old_index_id,
-		old_index_size,
-	) in old_index_ids_and_sizes.iter () {
+	for (
+		old_index_index,
+		& (old_index_id, old_index_size),
+	) in old_index_ids_and_sizes.iter ().enumerate () {
 
 		output_job.progress (
 			read_index_size,
 			total_index_size);
 
+		events.progress_bytes (
+			old_index_index as u64,
+			old_index_ids_and_sizes.len () as u64,
+			read_index_size,
+			total_index_size);
+
 		let old_index_path =
 			repository_core.index_path (
 				old_index_id);
@@ -277,7 +388,7 @@ fn read_indexes_find_unbalanced (
 
 		}
 
-		let old_index_unbalanced_chunks_count =
+		let old_index_unbalanced_chunks_count: u64 =
 			old_index_entries.iter ().map (
 				|& RawIndexEntry {
 					index_bundle_header: ref _old_index_bundle_header,
@@ -355,8 +466,14 @@ fn read_indexes_find_unbalanced (
 	}
 
 	* new_bundles_total =
-		(unbalanced_chunks_count + arguments.chunks_per_bundle - 1)
-			/ arguments.chunks_per_bundle;
+		unbalanced_chunks_count.div_ceil (
+			arguments.chunks_per_bundle);
+
+	events.progress_bytes (
+		old_index_ids_and_sizes.len () as u64,
+		old_index_ids_and_sizes.len () as u64,
+		read_index_size,
+		total_index_size);
 
 	output_job.complete ();
 
@@ -372,70 +489,140 @@ fn read_indexes_find_unbalanced (
 
 }
 
-fn count_unbalanced_bundles (
+fn find_unbalanced_bundles <'a> (
 	minimum_chunk_count: u64,
 	maximum_chunk_count: u64,
 	backup_chunk_ids: & HashSet <ChunkId>,
-	unbalanced_indexes: & [(IndexId, Vec <RawIndexEntry>)],
-) -> u64 {
+	unbalanced_indexes: & 'a [(IndexId, Vec <RawIndexEntry>)],
+) -> Vec <& 'a RawIndexEntry> {
 
-	let unbalanced_bundle_ids: HashSet <BundleId> =
-		unbalanced_indexes.iter ().flat_map (
-			|& (ref _index_id, ref index_entries)|
+	unbalanced_indexes.iter ().flat_map (
+		|& (ref _index_id, ref index_entries)|
 
-			index_entries.iter ().filter (
-				|&& RawIndexEntry {
-					ref bundle_info,
-					..
-				}| {
+		index_entries.iter ().filter (
+			|&& RawIndexEntry {
+				ref bundle_info,
+				..
+			}| {
 
-				let bundle_backup_chunk_ids: HashSet <ChunkId> =
-					bundle_info.chunks ().map (
-						|bundle_info_chunk|
+			let bundle_backup_chunk_ids: HashSet <ChunkId> =
+				bundle_info.chunks ().map (
+					|bundle_info_chunk|
 
-						bundle_info_chunk.chunk_id ()
+					bundle_info_chunk.chunk_id ()
 
-					).collect ();
+				).collect ();
 
-				let bundle_num_backup_chunks =
-					bundle_backup_chunk_ids.intersection (
-						backup_chunk_ids,
-					).count () as u64;
+			let bundle_num_backup_chunks =
+				bundle_backup_chunk_ids.intersection (
+					backup_chunk_ids,
+				).count () as u64;
 
-				let bundle_num_non_backup_chunks =
-					bundle_info.num_chunks ()
-						- bundle_num_backup_chunks;
+			let bundle_num_non_backup_chunks =
+				bundle_info.num_chunks ()
+					- bundle_num_backup_chunks;
 
-				(
+			(
 
-					bundle_num_backup_chunks > 0
-					&& bundle_num_non_backup_chunks > 0
+				bundle_num_backup_chunks > 0
+				&& bundle_num_non_backup_chunks > 0
 
-				) || (
+			) || (
 
-					bundle_info.num_chunks () < minimum_chunk_count
-					|| bundle_info.num_chunks () > maximum_chunk_count
+				bundle_info.num_chunks () < minimum_chunk_count
+				|| bundle_info.num_chunks () > maximum_chunk_count
 
-				)
+			)
 
-			}).map (
-				|& RawIndexEntry {
-					ref index_bundle_header,
-					..
-				}|
+		})
 
-				index_bundle_header.bundle_id ()
+	).collect ()
 
-			)
+}
 
-		).collect ();
+/// Reports the bundles and indexes which would be rewritten, for `--dry-run`.
+
+fn plan_balance_bundles (
+	output: & Output,
+	events: & ConvertEvents,
+	repository_core: & RepositoryCore,
+	unbalanced_indexes: & [(IndexId, Vec <RawIndexEntry>)],
+	unbalanced_bundles: & [& RawIndexEntry],
+	new_bundles_total: u64,
+) -> Result <(), String> {
+
+	let mut unbalanced_bundles_size: u64 = 0;
+
+	for & & RawIndexEntry {
+		ref index_bundle_header,
+		ref bundle_info,
+	} in unbalanced_bundles.iter () {
+
+		let bundle_size =
+			io_result (
+				fs::metadata (
+					repository_core.bundle_path (
+						index_bundle_header.bundle_id ())),
+			) ?.len ();
+
+		output_message! (
+			output,
+			"Would rewrite bundle {} with {} chunks",
+			index_bundle_header.bundle_id (),
+			bundle_info.num_chunks ());
+
+		events.plan (
+			"rewrite-bundle",
+			& index_bundle_header.bundle_id ().to_string (),
+			& [
+				("chunks", bundle_info.num_chunks ()),
+				("bytes", bundle_size),
+			]);
+
+		unbalanced_bundles_size += bundle_size;
 
-	unbalanced_bundle_ids.len () as u64
+	}
+
+	for & (index_id, ref index_entries) in unbalanced_indexes.iter () {
+
+		output_message! (
+			output,
+			"Would rewrite index {} with {} bundles",
+			index_id,
+			index_entries.len ());
+
+		events.plan (
+			"rewrite-index",
+			& index_id.to_string (),
+			& [
+				("bundles", index_entries.len () as u64),
+			]);
+
+	}
+
+	output_message! (
+		output,
+		"Would rewrite {} bundles, {} bytes in total, into {} bundles",
+		unbalanced_bundles.len (),
+		unbalanced_bundles_size,
+		new_bundles_total);
+
+	events.summary (
+		SummaryStatus::Ok,
+		& [
+			("bundles-to-rewrite", unbalanced_bundles.len () as u64),
+			("bytes-to-rewrite", unbalanced_bundles_size),
+			("bundles-to-write", new_bundles_total),
+			("indexes-to-rewrite", unbalanced_indexes.len () as u64),
+		]);
+
+	Ok (())
 
 }
 
 fn balance_bundles_real (
 	output: & Output,
+	events: & ConvertEvents,
 	cpu_pool: & CpuPool,
 	max_tasks: usize,
 	repository_core: & RepositoryCore,
@@ -445,6 +632,7 @@ fn balance_bundles_real (
 	backup_chunk_ids: & HashSet <ChunkId>,
 	unbalanced_indexes: Vec <(IndexId, Vec <RawIndexEntry>)>,
 	new_bundles_total: u64,
+	new_bundles_written: & mut u64,
 ) -> Result <bool, String> {
 
 	let output_job =
@@ -452,6 +640,9 @@ fn balance_bundles_real (
 			output,
 			"Balancing bundles");
 
+	events.phase_start (
+		"balance-bundles");
+
 	let start_time =
 		Instant::now ();
 
@@ -474,6 +665,7 @@ fn balance_bundles_real (
 
 	struct State {
 		new_bundles_count: u64,
+		written_bundles_count: u64,
 		pending_backup_chunks: Vec <(ChunkId, Vec <u8>)>,
 		pending_non_backup_chunks: Vec <(ChunkId, Vec <u8>)>,
 		pending_index_entries: Vec <RawIndexEntry>,
@@ -483,6 +675,7 @@ fn balance_bundles_real (
 
 	let mut state = State {
 		new_bundles_count: 0,
+		written_bundles_count: 0,
 		pending_backup_chunks: Vec::new (),
 		pending_non_backup_chunks: Vec::new (),
 		pending_index_entries: Vec::new (),
@@ -629,8 +822,29 @@ fn balance_bundles_real (
 							repository_core.bundle_path (
 								index_bundle_header.bundle_id ());
 
-						atomic_file_writer.delete (
-							bundle_path.clone ());
+						let bundle_parity_path =
+							parity_path (
+								& bundle_path);
+
+						let delete_result =
+							atomic_file_writer.delete (
+								bundle_path.clone (),
+							).and_then (
+								|()|
+								if bundle_parity_path.exists () {
+									atomic_file_writer.delete (
+										bundle_parity_path)
+								} else {
+									Ok (())
+								}
+							);
+
+						if let Err (error) = delete_result {
+
+							return Some (
+								futures::failed::<Task, String> (error).boxed ());
+
+						}
 
 						let encryption_key =
 							repository_core.encryption_key ();
@@ -662,9 +876,15 @@ fn balance_bundles_real (
 				} else if let Some ((index_id, index_entries)) =
 					state.index_iterator.next () {
 
-					atomic_file_writer.delete (
-						repository_core.index_path (
-							index_id));
+					if let Err (error) =
+						atomic_file_writer.delete (
+							repository_core.index_path (
+								index_id)) {
+
+						return Some (
+							futures::failed::<Task, String> (error).boxed ());
+
+					}
 
 					state.index_entry_iterator =
 						index_entries.into_iter ();
@@ -733,6 +953,12 @@ fn balance_bundles_real (
 					state.pending_index_entries.push (
 						index_entry);
 
+					state.written_bundles_count += 1;
+
+					events.progress (
+						state.written_bundles_count,
+						new_bundles_total);
+
 				},
 
 			}
@@ -775,9 +1001,17 @@ fn balance_bundles_real (
 		output_job_final_bundle.remove ();
 
 		state.new_bundles_count += 1;
+		state.written_bundles_count += 1;
 
 	}
 
+	events.progress (
+		state.written_bundles_count,
+		new_bundles_total);
+
+	* new_bundles_written +=
+		state.written_bundles_count;
+
 	output_job_replace! (
 		output_job,
 		"Balanced {} out of {} bundles",
@@ -828,6 +1062,9 @@ fn balance_bundles_real (
 
 	// commit changes
 
+	events.phase_start (
+		"commit");
+
 	{
 
 		let output_job_commit =
@@ -948,6 +1185,9 @@ command! (
 		checkpoint_time: Duration,
 		sleep_time: Duration,
 		cluster_backups: bool,
+		rebuild_references: bool,
+		dry_run: bool,
+		output_format: OutputFormat,
 	},
 
 	clap_subcommand = {
@@ -960,8 +1200,9 @@ command! (
 
 				.long ("repository")
 				.value_name ("REPOSITORY")
-				.required (true)
-				.help ("Path to the repository, used to obtain encryption key")
+				.required (false)
+				.help ("Path to the repository, used to obtain encryption key, \
+					or the name of a repository in the config file")
 
 			)
 
@@ -1023,8 +1264,20 @@ command! (
 
 			)
 
+			.arg (
+				clap::Arg::with_name ("rebuild-references")
+
+				.long ("rebuild-references")
+				.help ("Expand every backup, instead of reusing the chunk \
+					lists saved by a previous run, with --cluster-backups")
+
+			)
+
 			.repository_config_args ()
 
+			.dry_run_args ()
+			.output_format_args ()
+
 	},
 
 	clap_arguments_parse = |clap_matches| {
@@ -1070,6 +1323,20 @@ command! (
 					& clap_matches,
 					"cluster-backups"),
 
+			rebuild_references:
+				args::bool_flag (
+					& clap_matches,
+					"rebuild-references"),
+
+			dry_run:
+				args::bool_flag (
+					& clap_matches,
+					"dry-run"),
+
+			output_format:
+				output_format (
+					& clap_matches),
+
 		};
 
 		if arguments.fill_factor > 100 {
@@ -1085,7 +1352,15 @@ command! (
 	},
 
 	action = |output, arguments| {
-		balance_bundles (output, arguments)
+
+		let events =
+			ConvertEvents::new (
+				"balance-bundles",
+				arguments.output_format);
+
+		events.finish (
+			balance_bundles (output, & events, arguments))
+
 	},
 
 );
@@ -6,6 +6,7 @@ use clap;
 use output::Output;
 
 use ::RawIndexEntry;
+use ::convert::events::*;
 use ::convert::utils::*;
 use ::misc::*;
 use zbackup::disk_format::*;
@@ -13,9 +14,13 @@ use zbackup::repository_core::*;
 
 pub fn balance_indexes (
 	output: & Output,
+	events: & ConvertEvents,
 	arguments: & BalanceIndexesArguments,
 ) -> Result <bool, String> {
 
+	events.phase_start (
+		"scan");
+
 	// open repository
 
 	let repository_core =
@@ -29,14 +34,29 @@ pub fn balance_indexes (
 				arguments.password_file_path.clone ()),
 		) ?;
 
-	// begin transaction
+	// begin transaction, unless nothing will be changed
 
 	let atomic_file_writer =
-		AtomicFileWriter::new (
-			output,
-			& arguments.repository_path,
-			None,
-		) ?;
+		if arguments.dry_run {
+
+			None
+
+		} else {
+
+			let atomic_file_writer =
+				AtomicFileWriter::new (
+					output,
+					& arguments.repository_path,
+					None,
+				) ?;
+
+			// refuse to change an append-only repository, now we hold the lock
+
+			atomic_file_writer.append_only_check () ?;
+
+			Some (atomic_file_writer)
+
+		};
 
 	// get list of index files
 
@@ -63,6 +83,14 @@ pub fn balance_indexes (
 		Vec::new ();
 
 	let mut balanced_index_size: u64 = 0;
+	let mut balanced_index_count: u64 = 0;
+	let mut new_index_count: u64 = 0;
+
+	let old_index_count =
+		old_index_ids_and_sizes.len () as u64;
+
+	events.phase_start (
+		"balance-indexes");
 
 	let output_job =
 		output_job_start! (
@@ -78,14 +106,31 @@ pub fn balance_indexes (
 			repository_core.index_path (
 				old_index_id);
 
-		for old_index_entry in (
-
+		let old_index_entries =
 			index_read_path (
 				& old_index_path,
 				repository_core.encryption_key (),
-			)
+			) ?;
+
+		if arguments.dry_run {
 
-		) ? {
+			output_message! (
+				output,
+				"Would rewrite index {} with {} bundles",
+				old_index_id,
+				old_index_entries.len ());
+
+			events.plan (
+				"rewrite-index",
+				& old_index_id.to_string (),
+				& [
+					("bundles", old_index_entries.len () as u64),
+					("bytes", old_index_size),
+				]);
+
+		}
+
+		for old_index_entry in old_index_entries {
 
 			entries_buffer.push (
 				old_index_entry);
@@ -93,48 +138,103 @@ pub fn balance_indexes (
 			if entries_buffer.len () as u64 == arguments.bundles_per_index {
 
 				let index_entries =
-					mem::replace (
-						& mut entries_buffer,
-						Vec::new ());
+					mem::take (
+						& mut entries_buffer);
 
-				flush_index_entries (
-					output,
-					& repository_core,
-					& atomic_file_writer,
-					& index_entries,
-				) ?;
+				if let Some (ref atomic_file_writer) = atomic_file_writer {
+
+					flush_index_entries (
+						output,
+						& repository_core,
+						atomic_file_writer,
+						& index_entries,
+					) ?;
+
+				}
+
+				new_index_count += 1;
 
 			}
 
 		}
 
-		atomic_file_writer.delete (
-			old_index_path);
+		if let Some (ref atomic_file_writer) = atomic_file_writer {
+
+			atomic_file_writer.delete (
+				old_index_path,
+			) ?;
+
+		}
 
 		balanced_index_size +=
 			old_index_size;
 
+		balanced_index_count += 1;
+
 		output_job.progress (
 			balanced_index_size,
 			total_index_size);
 
+		events.progress_bytes (
+			balanced_index_count,
+			old_index_count,
+			balanced_index_size,
+			total_index_size);
+
 	}
 
 	if ! entries_buffer.is_empty () {
 
-		flush_index_entries (
-			output,
-			& repository_core,
-			& atomic_file_writer,
-			& mut entries_buffer,
-		) ?;
+		if let Some (ref atomic_file_writer) = atomic_file_writer {
+
+			flush_index_entries (
+				output,
+				& repository_core,
+				atomic_file_writer,
+				& entries_buffer,
+			) ?;
+
+		}
+
+		new_index_count += 1;
 
 	}
 
 	output_job.complete ();
 
+	let atomic_file_writer =
+		match atomic_file_writer {
+
+		Some (atomic_file_writer) =>
+			atomic_file_writer,
+
+		None => {
+
+			output_message! (
+				output,
+				"Would replace {} index files with {}",
+				old_index_count,
+				new_index_count);
+
+			events.summary (
+				SummaryStatus::Ok,
+				& [
+					("indexes-to-rewrite", old_index_count),
+					("indexes-to-write", new_index_count),
+					("bytes-to-read", total_index_size),
+				]);
+
+			return Ok (true);
+
+		},
+
+	};
+
 	// write changes to disk
 
+	events.phase_start (
+		"commit");
+
 	let output_job =
 		output_job_start! (
 			output,
@@ -144,6 +244,14 @@ pub fn balance_indexes (
 
 	output_job.complete ();
 
+	events.summary (
+		SummaryStatus::Ok,
+		& [
+			("indexes-read", old_index_count),
+			("indexes-written", new_index_count),
+			("bytes-read", total_index_size),
+		]);
+
 	// return
 
 	Ok (true)
@@ -159,6 +267,8 @@ command! (
 		repository_path: PathBuf,
 		password_file_path: Option <PathBuf>,
 		bundles_per_index: u64,
+		dry_run: bool,
+		output_format: OutputFormat,
 	},
 
 	clap_subcommand = {
@@ -171,8 +281,9 @@ command! (
 
 				.long ("repository")
 				.value_name ("REPOSITORY")
-				.required (true)
-				.help ("Path to the repository, used to obtain encryption key")
+				.required (false)
+				.help ("Path to the repository, used to obtain encryption key, \
+					or the name of a repository in the config file")
 
 			)
 
@@ -196,6 +307,9 @@ command! (
 
 			)
 
+			.dry_run_args ()
+			.output_format_args ()
+
 	},
 
 	clap_arguments_parse = |clap_matches| {
@@ -217,12 +331,29 @@ command! (
 					& clap_matches,
 					"bundles-per-index"),
 
+			dry_run:
+				args::bool_flag (
+					& clap_matches,
+					"dry-run"),
+
+			output_format:
+				output_format (
+					& clap_matches),
+
 		}
 
 	},
 
 	action = |output, arguments| {
-		balance_indexes (output, arguments)
+
+		let events =
+			ConvertEvents::new (
+				"balance-indexes",
+				arguments.output_format);
+
+		events.finish (
+			balance_indexes (output, & events, arguments))
+
 	},
 
 );
@@ -1,27 +1,51 @@
+use std::collections::HashMap;
 use std::collections::HashSet;
-use std::fs;
+use std::io;
+use std::io::Cursor;
+use std::io::Write;
 use std::os::unix::ffi::OsStrExt;
+use std::path::Path;
 use std::path::PathBuf;
+use std::slice;
+use std::sync::Arc;
+use std::sync::Condvar;
+use std::sync::Mutex;
 
 use clap;
 
+use futures::Future;
+use futures_cpupool::CpuPool;
+
+use num_cpus;
+
+use protobuf::stream::CodedInputStream;
+
 use rust_crypto::digest::Digest;
 use rust_crypto::sha1::Sha1;
+use rust_crypto::sha2::Sha256;
 
 use output::Output;
+use output::OutputJob;
 
 use rustc_serialize::hex::ToHex;
 
+use convert::events::*;
 use convert::utils::*;
 use misc::*;
+use misc::args::ClapSubCommandRzbackupArgs;
 use zbackup::data::*;
+use zbackup::disk_format::*;
 use zbackup::repository::*;
 
 pub fn check_backups (
 	output: & Output,
+	events: & ConvertEvents,
 	arguments: & CheckBackupsArguments,
 ) -> Result <bool, String> {
 
+	events.phase_start (
+		"load-indexes");
+
 	// open repository
 
 	let repository =
@@ -31,20 +55,28 @@ pub fn check_backups (
 				arguments.repository_path.to_string_lossy ()),
 			Repository::open (
 				& output,
-				Repository::default_config (),
+				arguments.repository_config.clone (),
 				& arguments.repository_path,
 				arguments.password_file_path.clone ()),
 		) ?;
 
 	// begin transaction
 
-	let _atomic_file_writer =
+	let atomic_file_writer =
 		AtomicFileWriter::new (
 			output,
 			& arguments.repository_path,
 			None,
 		) ?;
 
+	// refuse to change an append-only repository, now we hold the lock
+
+	if arguments.move_broken {
+
+		atomic_file_writer.append_only_check () ?;
+
+	}
+
 	// load indexes
 
 	repository.load_indexes (
@@ -96,6 +128,9 @@ pub fn check_backups (
 
 	// check backups
 
+	events.phase_start (
+		"check-backups");
+
 	let output_job =
 		output_job_start! (
 			output,
@@ -104,29 +139,40 @@ pub fn check_backups (
 	let mut checked_backup_count: u64 = 0;
 	let mut error_backup_count: u64 = 0;
 
+	// backups to restore with --deep, along with the bundle each chunk they
+	// need is in, and the number of times it will be read
+
+	let mut deep_backups: Vec <PathBuf> =
+		Vec::new ();
+
+	let mut deep_chunks: HashMap <ChunkId, (BundleId, u64)> =
+		HashMap::new ();
+
+	let mut deep_bundle_ids: HashSet <BundleId> =
+		HashSet::new ();
+
 	for backup_name in backup_names.iter () {
 
 		output_job.progress (
 			checked_backup_count,
 			backup_names.len () as u64);
 
-		let backup_path =
-			repository.path ()
-				.join ("backups")
-				.join (backup_name);
+		events.progress (
+			checked_backup_count,
+			backup_names.len () as u64);
 
-		let mut backup_chunks: HashSet <ChunkId> =
-			HashSet::new ();
+		let mut backup_chunks: HashMap <ChunkId, u64> =
+			HashMap::new ();
 
 		let backup_expand_error =
-			collect_chunks_from_backup (
+			count_chunks_from_backup (
 				& repository,
 				& mut backup_chunks,
 				& backup_name,
 			).err ();
 
 		let missing_chunks: Vec <ChunkId> =
-			backup_chunks.iter ().filter (
+			backup_chunks.keys ().filter (
 				|chunk_id|
 
 				! repository.has_chunk (
@@ -134,51 +180,77 @@ pub fn check_backups (
 
 			).map (|c| * c).collect ();
 
-		if let Some (ref error) = backup_expand_error {
+		let backup_problem =
+			if let Some (ref error) = backup_expand_error {
 
-			output.message_format (
-				format_args! (
+			Some ((
+				"unexpandable-backup",
+				format! (
 					"Backup {} could not be expanded: {}",
 					backup_name.to_string_lossy (),
-					error));
+					error),
+			))
 
 		} else if ! missing_chunks.is_empty () {
 
-			output.message_format (
-				format_args! (
+			Some ((
+				"missing-chunks",
+				format! (
 					"Backup {} is missing {} out of {} chunks",
 					backup_name.to_string_lossy (),
 					missing_chunks.len (),
-					backup_chunks.len ()));
+					backup_chunks.len ()),
+			))
 
-		}
+		} else {
+			None
+		};
+
+		if let Some ((problem_kind, problem_message)) = backup_problem {
 
-		if backup_expand_error.is_some () || ! missing_chunks.is_empty () {
+			output.message (
+				problem_message.clone ());
 
 			if arguments.move_broken {
 
-				let backups_broken_path =
-					repository.path ()
-						.join ("backups-broken");
+				move_broken_backup (
+					& repository,
+					& atomic_file_writer,
+					& backup_name,
+				) ?;
 
-				let backup_broken_path =
-					backups_broken_path.join (
-						backup_name);
+			}
 
-				io_result (
-					fs::create_dir_all (
-						backup_broken_path.parent ().unwrap ()),
-				) ?;
+			events.problem (
+				problem_kind,
+				& backup_name.to_string_lossy (),
+				arguments.move_broken,
+				& problem_message);
 
-				io_result (
-					fs::rename (
-						backup_path,
-						backup_broken_path),
-				) ?;
+			error_backup_count += 1;
+
+		} else if arguments.deep {
+
+			for (& chunk_id, & chunk_count) in backup_chunks.iter () {
+
+				let bundle_id =
+					repository.get_index_entry (
+						chunk_id,
+					) ?.bundle_id ();
+
+				deep_chunks.entry (
+					chunk_id,
+				).or_insert (
+					(bundle_id, 0),
+				).1 += chunk_count;
+
+				deep_bundle_ids.insert (
+					bundle_id);
 
 			}
 
-			error_backup_count += 1;
+			deep_backups.push (
+				backup_name.clone ());
 
 		}
 
@@ -186,6 +258,10 @@ pub fn check_backups (
 
 	}
 
+	events.progress (
+		checked_backup_count,
+		backup_names.len () as u64);
+
 	if error_backup_count > 0 {
 
 		output_job_replace! (
@@ -213,12 +289,620 @@ pub fn check_backups (
 
 	}
 
+	// restore backups and verify their checksums
+
+	let mut deep_error_backup_count: u64 = 0;
+	let mut bundles_loaded: u64 = 0;
+
+	if arguments.deep {
+
+		events.phase_start (
+			"deep-check-backups");
+
+		let deep_check_chunks =
+			Arc::new (
+				DeepCheckChunks::new (
+					deep_chunks));
+
+		deep_error_backup_count =
+			deep_check_backups (
+				output,
+				events,
+				arguments,
+				& repository,
+				& atomic_file_writer,
+				& deep_check_chunks,
+				deep_backups,
+			) ?;
+
+		error_backup_count +=
+			deep_error_backup_count;
+
+		bundles_loaded =
+			deep_check_chunks.bundles_loaded ();
+
+		output_message! (
+			output,
+			"Loaded {} bundles, out of {} needed to restore backups",
+			bundles_loaded,
+			deep_bundle_ids.len ());
+
+		// every bundle is loaded at most once, and all of them are needed
+		// unless a backup failed part way through
+
+		if deep_error_backup_count == 0
+			&& bundles_loaded != deep_bundle_ids.len () as u64 {
+
+			return Err (
+				format! (
+					"Loaded {} bundles, but {} were needed to restore backups",
+					bundles_loaded,
+					deep_bundle_ids.len ()));
+
+		}
+
+	}
+
 	// clean up and return
 
 	repository.close (
 		output);
 
-	Ok (error_backup_count == 0)
+	events.summary (
+		if error_backup_count == 0 {
+			SummaryStatus::Ok
+		} else if arguments.move_broken {
+			SummaryStatus::ProblemsFixed
+		} else {
+			SummaryStatus::ProblemsFound
+		},
+		& [
+			("backups-checked", checked_backup_count),
+			("broken-backups", error_backup_count),
+			("backups-failed-restore", deep_error_backup_count),
+			("bundles-needed", deep_bundle_ids.len () as u64),
+			("bundles-loaded", bundles_loaded),
+		]);
+
+	Ok (
+		error_backup_count == 0
+		|| arguments.move_broken
+	)
+
+}
+
+/// Holds the decoded chunks needed to restore the backups in a deep check. The
+/// bundle holding a chunk is loaded the first time any backup needs it, and
+/// every chunk from it which will be read is kept, along with the number of
+/// reads still to come. Once the last of them has taken it, it is dropped, so
+/// no bundle ever has to be loaded twice.
+
+struct DeepCheckChunks {
+	state: Mutex <DeepCheckChunksState>,
+	condvar: Condvar,
+}
+
+struct DeepCheckChunksState {
+	chunk_plan: HashMap <ChunkId, (BundleId, u64)>,
+	chunks: HashMap <ChunkId, ChunkData>,
+	bundles: HashMap <BundleId, DeepCheckBundle>,
+	bundles_loaded: u64,
+}
+
+enum DeepCheckBundle {
+	Loading,
+	Loaded,
+	Failed (String),
+}
+
+impl DeepCheckChunks {
+
+	fn new (
+		chunk_plan: HashMap <ChunkId, (BundleId, u64)>,
+	) -> DeepCheckChunks {
+
+		DeepCheckChunks {
+			state: Mutex::new (DeepCheckChunksState {
+				chunk_plan: chunk_plan,
+				chunks: HashMap::new (),
+				bundles: HashMap::new (),
+				bundles_loaded: 0,
+			}),
+			condvar: Condvar::new (),
+		}
+
+	}
+
+	/// Returns a chunk's data, loading its bundle if this is the first time
+	/// it is needed, or waiting if another backup is already loading it.
+
+	fn take (
+		& self,
+		repository: & Repository,
+		chunk_id: ChunkId,
+	) -> Result <ChunkData, String> {
+
+		let mut self_state =
+			self.state.lock ().unwrap ();
+
+		loop {
+
+			let bundle_id =
+				match self_state.chunk_plan.get (
+					& chunk_id,
+				) {
+
+				Some (& (bundle_id, chunk_count)) if chunk_count > 0 =>
+					bundle_id,
+
+				_ =>
+					return Err (
+						format! (
+							"Chunk {} was read more times than expected",
+							chunk_id)),
+
+			};
+
+			if let Some (chunk_data) =
+				self_state.chunks.get (
+					& chunk_id,
+				).cloned () {
+
+				let chunk_count = {
+
+					let chunk_plan_entry =
+						self_state.chunk_plan.get_mut (
+							& chunk_id,
+						).unwrap ();
+
+					chunk_plan_entry.1 -= 1;
+					chunk_plan_entry.1
+
+				};
+
+				if chunk_count == 0 {
+
+					self_state.chunks.remove (
+						& chunk_id);
+
+				}
+
+				return Ok (chunk_data);
+
+			}
+
+			let bundle_loading =
+				match self_state.bundles.get (
+					& bundle_id,
+				) {
+
+				None =>
+					false,
+
+				Some (& DeepCheckBundle::Loading) =>
+					true,
+
+				Some (& DeepCheckBundle::Loaded) =>
+					return Err (
+						format! (
+							"Chunk {} not found in bundle {}",
+							chunk_id,
+							bundle_id)),
+
+				Some (& DeepCheckBundle::Failed (ref error)) =>
+					return Err (
+						error.clone ()),
+
+			};
+
+			if bundle_loading {
+
+				self_state =
+					self.condvar.wait (
+						self_state,
+					).unwrap ();
+
+				continue;
+
+			}
+
+			// load the bundle without holding the lock
+
+			self_state.bundles.insert (
+				bundle_id,
+				DeepCheckBundle::Loading);
+
+			drop (
+				self_state);
+
+			let bundle_result =
+				string_result_with_prefix (
+					|| format! (
+						"Error reading bundle {}: ",
+						bundle_id),
+					bundle_read_path (
+						repository.bundle_path (
+							bundle_id),
+						repository.encryption_key ()),
+				);
+
+			self_state =
+				self.state.lock ().unwrap ();
+
+			self_state.bundles_loaded += 1;
+
+			match bundle_result {
+
+				Ok (bundle_chunks) => {
+
+					for (bundle_chunk_id, bundle_chunk_data)
+					in bundle_chunks.into_iter () {
+
+						let wanted =
+							match self_state.chunk_plan.get (
+								& bundle_chunk_id,
+							) {
+								Some (& (_, chunk_count)) => chunk_count > 0,
+								None => false,
+							};
+
+						if wanted {
+
+							self_state.chunks.insert (
+								bundle_chunk_id,
+								Arc::new (
+									bundle_chunk_data));
+
+						}
+
+					}
+
+					self_state.bundles.insert (
+						bundle_id,
+						DeepCheckBundle::Loaded);
+
+				},
+
+				Err (error) => {
+
+					self_state.bundles.insert (
+						bundle_id,
+						DeepCheckBundle::Failed (
+							error));
+
+				},
+
+			}
+
+			self.condvar.notify_all ();
+
+		}
+
+	}
+
+	fn bundles_loaded (
+		& self,
+	) -> u64 {
+
+		let self_state =
+			self.state.lock ().unwrap ();
+
+		self_state.bundles_loaded
+
+	}
+
+}
+
+/// Restores every backup to a null sink, in parallel, which verifies the
+/// SHA-256 checksum of its contents. Chunks are read from the shared
+/// `DeepCheckChunks`, so each bundle is loaded only once for the whole run.
+
+fn deep_check_backups (
+	output: & Output,
+	events: & ConvertEvents,
+	arguments: & CheckBackupsArguments,
+	repository: & Repository,
+	atomic_file_writer: & AtomicFileWriter,
+	deep_check_chunks: & Arc <DeepCheckChunks>,
+	deep_backups: Vec <PathBuf>,
+) -> Result <u64, String> {
+
+	struct Task {
+		backup_name: PathBuf,
+		output_job: OutputJob,
+		result: Result <(), String>,
+	}
+
+	struct State <'a> {
+		backups_iterator: slice::Iter <'a, PathBuf>,
+		restored_backup_count: u64,
+		error_backup_count: u64,
+		output_job: OutputJob,
+	}
+
+	let output_job =
+		output_job_start! (
+			output,
+			"Restoring backups");
+
+	let mut state = State {
+		backups_iterator: deep_backups.iter (),
+		restored_backup_count: 0,
+		error_backup_count: 0,
+		output_job: output_job,
+	};
+
+	let backup_total =
+		deep_backups.len () as u64;
+
+	let num_threads =
+		(num_cpus::get () - 1) * 5 / 3 + 1;
+
+	let cpu_pool =
+		CpuPool::new (
+			num_threads);
+
+	concurrent_controller (
+		output,
+		num_threads,
+		& mut state,
+
+		|state| {
+
+			if let Some (backup_name) =
+				state.backups_iterator.next () {
+
+				let output =
+					output.clone ();
+
+				let repository =
+					repository.clone ();
+
+				let deep_check_chunks =
+					deep_check_chunks.clone ();
+
+				let backup_name =
+					backup_name.clone ();
+
+				Some (cpu_pool.spawn_fn (move || {
+
+					let output_job =
+						output_job_start! (
+							output,
+							"Restoring {}",
+							backup_name.to_string_lossy ());
+
+					let result =
+						deep_restore_backup (
+							& repository,
+							& deep_check_chunks,
+							& backup_name);
+
+					Ok (Task {
+						backup_name: backup_name,
+						output_job: output_job,
+						result: result,
+					})
+
+				}).boxed ())
+
+			} else { None }
+
+		},
+
+		|state, task_value| {
+
+			state.restored_backup_count += 1;
+
+			state.output_job.progress (
+				state.restored_backup_count,
+				backup_total);
+
+			events.progress (
+				state.restored_backup_count,
+				backup_total);
+
+			if let Err (error) = task_value.result {
+
+				let problem_message =
+					format! (
+						"Backup {} could not be restored: {}",
+						task_value.backup_name.to_string_lossy (),
+						error);
+
+				output.message (
+					problem_message.clone ());
+
+				if arguments.move_broken {
+
+					move_broken_backup (
+						repository,
+						atomic_file_writer,
+						& task_value.backup_name,
+					) ?;
+
+				}
+
+				events.problem (
+					"unrestorable-backup",
+					& task_value.backup_name.to_string_lossy (),
+					arguments.move_broken,
+					& problem_message);
+
+				state.error_backup_count += 1;
+
+			}
+
+			task_value.output_job.remove ();
+
+			Ok (())
+
+		},
+
+	) ?;
+
+	output_job_replace! (
+		state.output_job,
+		"Restored {} backups, {} with errors",
+		backup_total,
+		state.error_backup_count);
+
+	Ok (state.error_backup_count)
+
+}
+
+/// Expands and restores a single backup, reading its chunks from the shared
+/// `DeepCheckChunks`, and verifies its SHA-256 checksum.
+
+fn deep_restore_backup (
+	repository: & Repository,
+	deep_check_chunks: & DeepCheckChunks,
+	backup_name: & Path,
+) -> Result <(), String> {
+
+	let backup_info =
+		backup_read_path (
+			repository.path ()
+				.join ("backups")
+				.join (backup_name),
+			repository.encryption_key (),
+		) ?;
+
+	// expand backup data
+
+	let mut instructions =
+		backup_info.backup_data ().to_owned ();
+
+	for _iteration in 0 .. backup_info.iterations () {
+
+		let mut expanded_instructions: Vec <u8> =
+			Vec::new ();
+
+		deep_follow_instructions (
+			repository,
+			deep_check_chunks,
+			& instructions,
+			& mut expanded_instructions,
+			& mut Sha1::new (),
+		) ?;
+
+		instructions =
+			expanded_instructions;
+
+	}
+
+	// restore backup
+
+	let mut sha256_sum =
+		Sha256::new ();
+
+	deep_follow_instructions (
+		repository,
+		deep_check_chunks,
+		& instructions,
+		& mut io::sink (),
+		& mut sha256_sum,
+	) ?;
+
+	// verify checksum
+
+	let mut sha256_sum_bytes: [u8; 32] =
+		[0u8; 32];
+
+	sha256_sum.result (
+		& mut sha256_sum_bytes);
+
+	if backup_info.sha256 () != sha256_sum_bytes {
+
+		return Err (
+			format! (
+				"Expected sha256 checksum {} but calculated {}",
+				backup_info.sha256 ().to_hex (),
+				sha256_sum_bytes.to_hex ()));
+
+	}
+
+	Ok (())
+
+}
+
+fn deep_follow_instructions (
+	repository: & Repository,
+	deep_check_chunks: & DeepCheckChunks,
+	instructions: & [u8],
+	target: & mut Write,
+	digest: & mut Digest,
+) -> Result <(), String> {
+
+	let mut instructions_cursor =
+		Cursor::new (
+			instructions);
+
+	let mut coded_input_stream =
+		CodedInputStream::new (
+			& mut instructions_cursor);
+
+	while ! protobuf_result (
+		coded_input_stream.eof (),
+	) ? {
+
+		let backup_instruction =
+			DiskBackupInstruction::read (
+				& mut coded_input_stream,
+			) ?;
+
+		if backup_instruction.has_chunk_to_emit () {
+
+			let chunk_data =
+				deep_check_chunks.take (
+					repository,
+					backup_instruction.chunk_to_emit (),
+				) ?;
+
+			digest.input (
+				& chunk_data);
+
+			io_result (
+				target.write_all (
+					& chunk_data),
+			) ?;
+
+		}
+
+		if backup_instruction.has_bytes_to_emit () {
+
+			digest.input (
+				backup_instruction.bytes_to_emit ());
+
+			io_result (
+				target.write_all (
+					backup_instruction.bytes_to_emit ()),
+			) ?;
+
+		}
+
+	}
+
+	Ok (())
+
+}
+
+fn move_broken_backup (
+	repository: & Repository,
+	atomic_file_writer: & AtomicFileWriter,
+	backup_name: & PathBuf,
+) -> Result <(), String> {
+
+	atomic_file_writer.rename (
+		repository.path ()
+			.join ("backups")
+			.join (backup_name),
+		repository.path ()
+			.join ("backups-broken")
+			.join (backup_name),
+	) ?;
+
+	atomic_file_writer.commit ()
 
 }
 
@@ -230,8 +914,11 @@ command! (
 	arguments = CheckBackupsArguments {
 		repository_path: PathBuf,
 		password_file_path: Option <PathBuf>,
+		repository_config: RepositoryConfig,
 		backup_name_hash_prefix: Option <String>,
 		move_broken: bool,
+		deep: bool,
+		output_format: OutputFormat,
 	},
 
 	clap_subcommand = {
@@ -244,8 +931,9 @@ command! (
 
 				.long ("repository")
 				.value_name ("REPOSITORY")
-				.required (true)
-				.help ("Path to the repository")
+				.required (false)
+				.help ("Path to the repository, or the name \
+					of a repository in the config file")
 
 			)
 
@@ -278,6 +966,18 @@ command! (
 
 			)
 
+			.arg (
+				clap::Arg::with_name ("deep")
+
+				.long ("deep")
+				.help ("Also restore every backup and verify its checksum")
+
+			)
+
+			.repository_config_args ()
+
+			.output_format_args ()
+
 	},
 
 	clap_arguments_parse = |clap_matches| {
@@ -294,6 +994,10 @@ command! (
 					& clap_matches,
 					"password-file"),
 
+			repository_config:
+				args::repository_config (
+					clap_matches),
+
 			move_broken:
 				args::bool_flag (
 					& clap_matches,
@@ -304,12 +1008,29 @@ command! (
 					& clap_matches,
 					"backup-name-hash-prefix"),
 
+			deep:
+				args::bool_flag (
+					& clap_matches,
+					"deep"),
+
+			output_format:
+				output_format (
+					& clap_matches),
+
 		}
 
 	},
 
 	action = |output, arguments| {
-		check_backups (output, arguments)
+
+		let events =
+			ConvertEvents::new (
+				"check-backups",
+				arguments.output_format);
+
+		events.finish (
+			check_backups (output, & events, arguments))
+
 	},
 
 );
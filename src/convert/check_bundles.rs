@@ -1,4 +1,3 @@
-use std::fs;
 use std::path::PathBuf;
 use std::slice;
 
@@ -12,6 +11,7 @@ use num_cpus;
 use output::Output;
 use output::OutputJob;
 
+use ::convert::events::*;
 use ::convert::utils::*;
 use ::misc::*;
 use ::zbackup::data::*;
@@ -20,9 +20,13 @@ use ::zbackup::repository_core::*;
 
 pub fn check_bundles (
 	output: & Output,
+	events: & ConvertEvents,
 	arguments: & CheckBundlesArguments,
 ) -> Result <bool, String> {
 
+	events.phase_start (
+		"scan");
+
 	// open repository
 
 	let repository_core =
@@ -45,6 +49,14 @@ pub fn check_bundles (
 			None,
 		) ?;
 
+	// refuse to change an append-only repository, now we hold the lock
+
+	if arguments.move_broken {
+
+		atomic_file_writer.append_only_check () ?;
+
+	}
+
 	// get a list of index files
 
 	let mut bundle_ids_and_sizes: Vec <(BundleId, u64)> =
@@ -66,7 +78,7 @@ pub fn check_bundles (
 	let bundle_total_size: u64 =
 		bundle_ids_and_sizes.iter ().map (
 			|& (_, bundle_size)|
-			bundle_size as u64
+			bundle_size
 		).sum ();
 
 	output.message_format (
@@ -83,13 +95,18 @@ pub fn check_bundles (
 		CpuPool::new (
 			num_threads);
 
+	events.phase_start (
+		"check-bundles");
+
 	let invalid_bundle_count =
 		check_bundles_real (
 			output,
+			events,
 			& cpu_pool,
 			num_threads,
 			arguments,
 			repository_core.clone (),
+			& atomic_file_writer,
 			& bundle_ids_and_sizes,
 			bundle_total_size,
 		) ?;
@@ -98,25 +115,45 @@ pub fn check_bundles (
 
 	atomic_file_writer.commit () ?;
 
+	events.summary (
+		if invalid_bundle_count == 0 {
+			SummaryStatus::Ok
+		} else if arguments.move_broken {
+			SummaryStatus::ProblemsFixed
+		} else {
+			SummaryStatus::ProblemsFound
+		},
+		& [
+			("bundles-checked", bundle_ids_and_sizes.len () as u64),
+			("bytes-checked", bundle_total_size),
+			("invalid-bundles", invalid_bundle_count),
+		]);
+
 	// return
 
-	Ok (invalid_bundle_count == 0)
+	Ok (
+		invalid_bundle_count == 0
+		|| arguments.move_broken
+	)
 
 }
 
 fn check_bundles_real (
 	output: & Output,
+	events: & ConvertEvents,
 	cpu_pool: & CpuPool,
 	max_tasks: usize,
 	arguments: & CheckBundlesArguments,
 	repository_core: RepositoryCore,
-	bundle_ids_and_sizes: & Vec <(BundleId, u64)>,
+	atomic_file_writer: & AtomicFileWriter,
+	bundle_ids_and_sizes: & [(BundleId, u64)],
 	bundle_total_size: u64,
 ) -> Result <u64, String> {
 
 	// check bundles
 
 	struct Task {
+		bundle_id: BundleId,
 		bundle_size: u64,
 		output_job: OutputJob,
 		result: Result <(), String>,
@@ -124,6 +161,7 @@ fn check_bundles_real (
 
 	struct State <'a> {
 		bundle_ids_and_sizes_iterator: slice::Iter <'a, (BundleId, u64)>,
+		checked_bundle_count: u64,
 		checked_bundle_size: u64,
 		invalid_bundle_count: u64,
 		output_job: OutputJob,
@@ -137,6 +175,7 @@ fn check_bundles_real (
 	let mut state = State {
 		output_job: output_job,
 		bundle_ids_and_sizes_iterator: bundle_ids_and_sizes.iter (),
+		checked_bundle_count: 0,
 		checked_bundle_size: 0,
 		invalid_bundle_count: 0,
 	};
@@ -159,6 +198,9 @@ fn check_bundles_real (
 				let repository_core =
 					repository_core.clone ();
 
+				let atomic_file_writer =
+					atomic_file_writer.clone ();
+
 				let move_broken =
 					arguments.move_broken;
 
@@ -171,10 +213,12 @@ fn check_bundles_real (
 							bundle_id);
 
 					Ok (Task {
+						bundle_id: bundle_id,
 						bundle_size: bundle_size,
 						output_job: output_job,
 						result: check_bundle (
 							repository_core,
+							atomic_file_writer,
 							move_broken,
 							bundle_id,
 						),
@@ -188,6 +232,8 @@ fn check_bundles_real (
 
 		|state, task_value| {
 
+			state.checked_bundle_count += 1;
+
 			state.checked_bundle_size +=
 				task_value.bundle_size;
 
@@ -195,8 +241,20 @@ fn check_bundles_real (
 				state.checked_bundle_size,
 				bundle_total_size);
 
+			events.progress_bytes (
+				state.checked_bundle_count,
+				bundle_ids_and_sizes.len () as u64,
+				state.checked_bundle_size,
+				bundle_total_size);
+
 			if let Err (error) = task_value.result {
 
+				events.problem (
+					"invalid-bundle",
+					& task_value.bundle_id.to_string (),
+					arguments.move_broken,
+					& error);
+
 				output.message (
 					error);
 
@@ -233,6 +291,7 @@ fn check_bundles_real (
 
 fn check_bundle (
 	repository_core: RepositoryCore,
+	atomic_file_writer: AtomicFileWriter,
 	move_broken: bool,
 	bundle_id: BundleId,
 ) -> Result <(), String> {
@@ -253,40 +312,31 @@ fn check_bundle (
 
 			if move_broken {
 
-				let bundles_broken_path =
+				let bundle_broken_path =
 					repository_core.path ()
-						.join ("bundles-broken");
-
-				io_result (
-					fs::create_dir_all (
-						& bundles_broken_path),
-				).map_err (
-					|error|
-
-					format! (
-						"Error creating {}: {}",
-						bundles_broken_path.to_string_lossy (),
-						error)
+						.join ("bundles-broken")
+						.join (bundle_path.file_name ().unwrap ());
 
+				atomic_file_writer.rename (
+					bundle_path.clone (),
+					bundle_broken_path.clone (),
 				) ?;
 
-				let bundle_broken_path =
-					bundles_broken_path.join (
-						bundle_path.file_name ().unwrap ());
+				let bundle_parity_path =
+					parity_path (
+						& bundle_path);
 
-				rename_or_copy_and_delete (
-					& bundle_path,
-					& bundle_broken_path,
-				).map_err (
-					|error|
+				if bundle_parity_path.exists () {
 
-					format! (
-						"Error moving {} to {}: {}",
-						bundle_path.to_string_lossy (),
-						bundle_broken_path.to_string_lossy (),
-						error)
+					atomic_file_writer.rename (
+						bundle_parity_path,
+						parity_path (
+							& bundle_broken_path),
+					) ?;
 
-				) ?;
+				}
+
+				atomic_file_writer.commit () ?;
 
 			}
 
@@ -308,6 +358,7 @@ command! (
 		password_file_path: Option <PathBuf>,
 		move_broken: bool,
 		bundle_name_prefix: Option <String>,
+		output_format: OutputFormat,
 	},
 
 	clap_subcommand = {
@@ -320,8 +371,9 @@ command! (
 
 				.long ("repository")
 				.value_name ("REPOSITORY")
-				.required (true)
-				.help ("Path to the repository")
+				.required (false)
+				.help ("Path to the repository, or the name \
+					of a repository in the config file")
 
 			)
 
@@ -353,6 +405,8 @@ command! (
 
 			)
 
+			.output_format_args ()
+
 	},
 
 	clap_arguments_parse = |clap_matches| {
@@ -379,12 +433,24 @@ command! (
 					& clap_matches,
 					"bundle-name-prefix"),
 
+			output_format:
+				output_format (
+					& clap_matches),
+
 		}
 
 	},
 
 	action = |output, arguments| {
-		check_bundles (output, arguments)
+
+		let events =
+			ConvertEvents::new (
+				"check-bundles",
+				arguments.output_format);
+
+		events.finish (
+			check_bundles (output, & events, arguments))
+
 	},
 
 );
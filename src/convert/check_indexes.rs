@@ -5,6 +5,7 @@ use clap;
 
 use output::Output;
 
+use convert::events::*;
 use convert::utils::*;
 use misc::*;
 use zbackup::data::*;
@@ -13,9 +14,13 @@ use zbackup::repository_core::*;
 
 pub fn check_indexes (
 	output: & Output,
+	events: & ConvertEvents,
 	arguments: & CheckIndexesArguments,
 ) -> Result <bool, String> {
 
+	events.phase_start (
+		"scan");
+
 	// open repository
 
 	let repository_core =
@@ -38,6 +43,14 @@ pub fn check_indexes (
 			None,
 		) ?;
 
+	// refuse to change an append-only repository, now we hold the lock
+
+	if arguments.repair {
+
+		atomic_file_writer.append_only_check () ?;
+
+	}
+
 	// get list of index files
 
 	let old_index_ids_and_sizes = (
@@ -48,7 +61,7 @@ pub fn check_indexes (
 	let old_index_total_size: u64 =
 		old_index_ids_and_sizes.iter ().map (
 			|& (_, old_index_size)|
-			old_index_size as u64
+			old_index_size
 		).sum ();
 
 	output.message_format (
@@ -86,15 +99,27 @@ pub fn check_indexes (
 	let mut missing_chunk_count: u64 = 0;
 	let mut duplicated_chunk_count: u64 = 0;
 
+	events.phase_start (
+		if arguments.repair { "repair-indexes" } else { "check-indexes" });
+
+	let old_index_count =
+		old_index_ids_and_sizes.len () as u64;
+
 	for (
-		old_index_id,
-		old_index_size,
-	) in old_index_ids_and_sizes {
+		old_index_index,
+		(old_index_id, old_index_size),
+	) in old_index_ids_and_sizes.into_iter ().enumerate () {
 
 		output_job.progress (
 			checked_index_size,
 			old_index_total_size);
 
+		events.progress_bytes (
+			old_index_index as u64,
+			old_index_count,
+			checked_index_size,
+			old_index_total_size);
+
 		let old_index_path =
 			repository_core.index_path (
 				old_index_id);
@@ -128,6 +153,15 @@ pub fn check_indexes (
 
 				}
 
+				events.problem (
+					"missing-bundle",
+					& old_index_id.to_string (),
+					arguments.repair,
+					& format! (
+						"Index {} refers to nonexistant bundle {}",
+						old_index_id,
+						old_index_bundle_header.bundle_id ()));
+
 				missing_chunk_count += 1;
 				changes = true;
 
@@ -154,6 +188,15 @@ pub fn check_indexes (
 
 					}
 
+					events.problem (
+						"duplicated-chunk",
+						& old_index_id.to_string (),
+						arguments.repair,
+						& format! (
+							"Index {} contains duplicated chunk {}",
+							old_index_id,
+							old_index_chunk_record.chunk_id ()));
+
 					duplicated_chunk_count += 1;
 					changes = true;
 
@@ -200,7 +243,8 @@ pub fn check_indexes (
 							old_index_id));
 
 					atomic_file_writer.delete (
-						old_index_path);
+						old_index_path,
+					) ?;
 
 				} else if ! arguments.verbose {
 
@@ -221,7 +265,8 @@ pub fn check_indexes (
 							old_index_id));
 
 					atomic_file_writer.delete (
-						old_index_path);
+						old_index_path,
+					) ?;
 
 					index_write_auto (
 						& repository_core,
@@ -247,6 +292,12 @@ pub fn check_indexes (
 
 	}
 
+	events.progress_bytes (
+		old_index_count,
+		old_index_count,
+		checked_index_size,
+		old_index_total_size);
+
 	if missing_chunk_count + duplicated_chunk_count > 0 {
 
 		if duplicated_chunk_count == 0 {
@@ -278,6 +329,9 @@ pub fn check_indexes (
 
 		if arguments.repair {
 
+			events.phase_start (
+				"commit");
+
 			let output_job =
 				output_job_start! (
 					output,
@@ -298,11 +352,28 @@ pub fn check_indexes (
 
 	}
 
+	let problem_count =
+		missing_chunk_count + duplicated_chunk_count;
+
+	events.summary (
+		if problem_count == 0 {
+			SummaryStatus::Ok
+		} else if arguments.repair {
+			SummaryStatus::ProblemsFixed
+		} else {
+			SummaryStatus::ProblemsFound
+		},
+		& [
+			("indexes-checked", old_index_count),
+			("missing-chunks", missing_chunk_count),
+			("duplicated-chunks", duplicated_chunk_count),
+		]);
+
 	// return
 
 	Ok (
-		missing_chunk_count + duplicated_chunk_count > 0
-		&& ! arguments.repair
+		problem_count == 0
+		|| arguments.repair
 	)
 
 }
@@ -317,6 +388,7 @@ command! (
 		password_file_path: Option <PathBuf>,
 		repair: bool,
 		verbose: bool,
+		output_format: OutputFormat,
 	},
 
 	clap_subcommand = {
@@ -329,8 +401,9 @@ command! (
 
 				.long ("repository")
 				.value_name ("REPOSITORY")
-				.required (true)
-				.help ("Path to the repository")
+				.required (false)
+				.help ("Path to the repository, or the name \
+					of a repository in the config file")
 
 			)
 
@@ -360,6 +433,8 @@ command! (
 
 			)
 
+			.output_format_args ()
+
 	},
 
 	clap_arguments_parse = |clap_matches| {
@@ -386,12 +461,24 @@ command! (
 					& clap_matches,
 					"verbose"),
 
+			output_format:
+				output_format (
+					& clap_matches),
+
 		}
 
 	},
 
 	action = |output, arguments| {
-		check_indexes (output, arguments)
+
+		let events =
+			ConvertEvents::new (
+				"check-indexes",
+				arguments.output_format);
+
+		events.finish (
+			check_indexes (output, & events, arguments))
+
 	},
 
 );
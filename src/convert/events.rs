@@ -0,0 +1,520 @@
+use std::collections::BTreeMap;
+use std::io;
+use std::io::Write;
+use std::sync::Arc;
+use std::sync::Mutex;
+use std::time::Duration;
+use std::time::Instant;
+use std::time::SystemTime;
+use std::time::UNIX_EPOCH;
+
+use clap;
+
+use rustc_serialize::json::Json;
+
+use misc::*;
+
+/// Convert commands always report to the terminal using `output`. With the
+/// json-lines output format, they also write one JSON object per line to
+/// standard output, describing what they are doing, for use by other programs.
+///
+/// Every event has `event`, `command` and `timestamp` fields. The events are:
+///
+/// - `phase-start`, with `phase`, when the command starts a new step
+/// - `progress`, with `phase`, `done` and `total`, counting items such as
+///   bundles or indexes, and optionally `bytes-done` and `bytes-total`
+/// - `problem`, with `kind`, `item`, `fixed` and `message`, for each problem
+///   found, or fixed, in a single bundle, index or backup
+/// - `plan`, with `action` and `item`, and sizes or counts depending on the
+///   action, for each change a command run with `--dry-run` would make
+/// - `summary`, with `status`, `exit-status` and `counts`, and `error` if the
+///   command failed. This is always the last event.
+
+#[ derive (Clone, Copy, Debug, Eq, PartialEq) ]
+pub enum OutputFormat {
+	Text,
+	JsonLines,
+}
+
+/// The final status of a convert command. The process exits with status zero
+/// if the command completed and left no problems behind, and one otherwise.
+
+#[ derive (Clone, Copy, Debug, Eq, PartialEq) ]
+pub enum SummaryStatus {
+
+	/// Completed, and found nothing to do or nothing wrong
+	Ok,
+
+	/// Completed, and found problems which it did not fix
+	ProblemsFound,
+
+	/// Completed, and fixed or removed everything it found
+	ProblemsFixed,
+
+	/// Stopped because of an error
+	Failed,
+
+}
+
+impl SummaryStatus {
+
+	pub fn name (& self) -> & 'static str {
+
+		match * self {
+			SummaryStatus::Ok => "ok",
+			SummaryStatus::ProblemsFound => "problems-found",
+			SummaryStatus::ProblemsFixed => "problems-fixed",
+			SummaryStatus::Failed => "failed",
+		}
+
+	}
+
+}
+
+#[ derive (Clone) ]
+pub struct ConvertEvents {
+	command: & 'static str,
+	format: OutputFormat,
+	state: Arc <Mutex <ConvertEventsState>>,
+}
+
+struct ConvertEventsState {
+	phase: String,
+	last_progress: Option <Instant>,
+	summary: Option <(SummaryStatus, Vec <(String, u64)>)>,
+}
+
+/// Progress events are limited to one per interval, apart from the last one in
+/// each phase.
+
+const PROGRESS_INTERVAL_MILLIS: u64 = 1000;
+
+impl ConvertEvents {
+
+	pub fn new (
+		command: & 'static str,
+		format: OutputFormat,
+	) -> ConvertEvents {
+
+		ConvertEvents {
+			command: command,
+			format: format,
+			state: Arc::new (Mutex::new (
+				ConvertEventsState {
+					phase: String::new (),
+					last_progress: None,
+					summary: None,
+				},
+			)),
+		}
+
+	}
+
+	pub fn phase_start (
+		& self,
+		phase: & str,
+	) {
+
+		if self.format == OutputFormat::Text {
+			return;
+		}
+
+		{
+
+			let mut state =
+				self.state.lock ().unwrap ();
+
+			state.phase = phase.to_string ();
+			state.last_progress = None;
+
+		}
+
+		let mut event =
+			BTreeMap::new ();
+
+		event.insert (
+			"phase".to_string (),
+			Json::String (phase.to_string ()));
+
+		self.write ("phase-start", event);
+
+	}
+
+	pub fn progress (
+		& self,
+		done: u64,
+		total: u64,
+	) {
+
+		self.progress_real (
+			done,
+			total,
+			None);
+
+	}
+
+	pub fn progress_bytes (
+		& self,
+		done: u64,
+		total: u64,
+		bytes_done: u64,
+		bytes_total: u64,
+	) {
+
+		self.progress_real (
+			done,
+			total,
+			Some ((bytes_done, bytes_total)));
+
+	}
+
+	fn progress_real (
+		& self,
+		done: u64,
+		total: u64,
+		bytes: Option <(u64, u64)>,
+	) {
+
+		if self.format == OutputFormat::Text {
+			return;
+		}
+
+		let phase = {
+
+			let mut state =
+				self.state.lock ().unwrap ();
+
+			let now =
+				Instant::now ();
+
+			let throttled =
+				state.last_progress.map (
+					|last_progress|
+					now.duration_since (last_progress)
+						< Duration::from_millis (PROGRESS_INTERVAL_MILLIS)
+				).unwrap_or (false);
+
+			if throttled && done < total {
+				return;
+			}
+
+			state.last_progress = Some (now);
+
+			state.phase.clone ()
+
+		};
+
+		let mut event =
+			BTreeMap::new ();
+
+		event.insert (
+			"phase".to_string (),
+			Json::String (phase));
+
+		event.insert (
+			"done".to_string (),
+			Json::U64 (done));
+
+		event.insert (
+			"total".to_string (),
+			Json::U64 (total));
+
+		if let Some ((bytes_done, bytes_total)) = bytes {
+
+			event.insert (
+				"bytes-done".to_string (),
+				Json::U64 (bytes_done));
+
+			event.insert (
+				"bytes-total".to_string (),
+				Json::U64 (bytes_total));
+
+		}
+
+		self.write ("progress", event);
+
+	}
+
+	/// Reports a problem with a single item, such as a bundle, index or backup,
+	/// and whether it has been fixed.
+
+	pub fn problem (
+		& self,
+		kind: & str,
+		item: & str,
+		fixed: bool,
+		message: & str,
+	) {
+
+		if self.format == OutputFormat::Text {
+			return;
+		}
+
+		let mut event =
+			BTreeMap::new ();
+
+		event.insert (
+			"kind".to_string (),
+			Json::String (kind.to_string ()));
+
+		event.insert (
+			"item".to_string (),
+			Json::String (item.to_string ()));
+
+		event.insert (
+			"fixed".to_string (),
+			Json::Boolean (fixed));
+
+		event.insert (
+			"message".to_string (),
+			Json::String (message.to_string ()));
+
+		self.write ("problem", event);
+
+	}
+
+	/// Reports a single change which a command run with `--dry-run` would
+	/// make, such as deleting a bundle, along with any sizes or counts which
+	/// describe it.
+
+	pub fn plan (
+		& self,
+		action: & str,
+		item: & str,
+		details: & [(& str, u64)],
+	) {
+
+		if self.format == OutputFormat::Text {
+			return;
+		}
+
+		let mut event =
+			BTreeMap::new ();
+
+		event.insert (
+			"action".to_string (),
+			Json::String (action.to_string ()));
+
+		event.insert (
+			"item".to_string (),
+			Json::String (item.to_string ()));
+
+		for & (name, value) in details.iter () {
+
+			event.insert (
+				name.to_string (),
+				Json::U64 (value));
+
+		}
+
+		self.write ("plan", event);
+
+	}
+
+	/// Records the outcome of the command, which is written by `finish`.
+
+	pub fn summary (
+		& self,
+		status: SummaryStatus,
+		counts: & [(& str, u64)],
+	) {
+
+		let mut state =
+			self.state.lock ().unwrap ();
+
+		state.summary = Some ((
+			status,
+			counts.iter ().map (
+				|& (name, count)| (name.to_string (), count),
+			).collect (),
+		));
+
+	}
+
+	/// Writes the summary event, for the result of the command, and passes the
+	/// result through. A command which does not call `summary` is reported as
+	/// ok or problems-found, according to its result.
+
+	pub fn finish (
+		& self,
+		result: Result <bool, String>,
+	) -> Result <bool, String> {
+
+		if self.format == OutputFormat::Text {
+			return result;
+		}
+
+		let summary =
+			self.state.lock ().unwrap ().summary.take ();
+
+		let (status, counts) =
+			match (& result, summary) {
+
+			(& Err (_), Some ((_, counts))) =>
+				(SummaryStatus::Failed, counts),
+
+			(& Err (_), None) =>
+				(SummaryStatus::Failed, Vec::new ()),
+
+			(& Ok (_), Some (summary)) =>
+				summary,
+
+			(& Ok (true), None) =>
+				(SummaryStatus::Ok, Vec::new ()),
+
+			(& Ok (false), None) =>
+				(SummaryStatus::ProblemsFound, Vec::new ()),
+
+		};
+
+		let mut event =
+			BTreeMap::new ();
+
+		event.insert (
+			"status".to_string (),
+			Json::String (status.name ().to_string ()));
+
+		event.insert (
+			"exit-status".to_string (),
+			Json::U64 (
+				if let Ok (true) = result { 0 } else { 1 }));
+
+		event.insert (
+			"counts".to_string (),
+			Json::Object (
+				counts.into_iter ().map (
+					|(name, count)| (name, Json::U64 (count)),
+				).collect ()));
+
+		if let Err (ref error) = result {
+
+			event.insert (
+				"error".to_string (),
+				Json::String (error.clone ()));
+
+		}
+
+		self.write ("summary", event);
+
+		result
+
+	}
+
+	fn write (
+		& self,
+		event_name: & str,
+		mut event: BTreeMap <String, Json>,
+	) {
+
+		event.insert (
+			"event".to_string (),
+			Json::String (event_name.to_string ()));
+
+		event.insert (
+			"command".to_string (),
+			Json::String (self.command.to_string ()));
+
+		event.insert (
+			"timestamp".to_string (),
+			Json::U64 (
+				SystemTime::now ().duration_since (
+					UNIX_EPOCH,
+				).map (
+					|duration| duration.as_secs ()
+				).unwrap_or (0)));
+
+		// lock stdout so events from different threads stay on separate lines
+
+		let stdout =
+			io::stdout ();
+
+		let mut stdout_lock =
+			stdout.lock ();
+
+		writeln! (
+			stdout_lock,
+			"{}",
+			Json::Object (event),
+		).and_then (
+			|_| stdout_lock.flush ()
+		).unwrap_or (());
+
+	}
+
+}
+
+pub trait ClapSubCommandConvertArgs {
+
+	fn output_format_args (
+		self,
+	) -> Self;
+
+	fn dry_run_args (
+		self,
+	) -> Self;
+
+}
+
+impl <'a, 'b> ClapSubCommandConvertArgs
+for clap::App <'a, 'b> {
+
+	fn output_format_args (
+		self,
+	) -> Self {
+
+		self.arg (
+			clap::Arg::with_name ("output-format")
+
+			.long ("output-format")
+			.value_name ("FORMAT")
+			.possible_values (& ["text", "json-lines"])
+			.default_value ("text")
+			.help ("Also write progress and results to standard output as \
+				one JSON object per line, with json-lines")
+
+		)
+
+	}
+
+	fn dry_run_args (
+		self,
+	) -> Self {
+
+		self.arg (
+			clap::Arg::with_name ("dry-run")
+
+			.long ("dry-run")
+			.help ("Show what would be changed, without changing anything")
+
+		)
+
+	}
+
+}
+
+pub fn output_format (
+	clap_matches: & clap::ArgMatches,
+) -> OutputFormat {
+
+	match args::string_required (
+		clap_matches,
+		"output-format",
+	).as_str () {
+
+		"text" =>
+			OutputFormat::Text,
+
+		"json-lines" =>
+			OutputFormat::JsonLines,
+
+		output_format =>
+			args::error_exit (
+				format! (
+					"Invalid output format: {}",
+					output_format)),
+
+	}
+
+}
+
+// ex: noet ts=4 filetype=rust
@@ -1,22 +1,42 @@
+use std::collections::HashMap;
 use std::collections::HashSet;
 use std::fs;
+use std::fs::File;
+use std::io::BufRead;
+use std::io::BufReader;
+use std::io::Write;
+use std::path::Path;
 use std::path::PathBuf;
+use std::time::Duration;
+use std::time::SystemTime;
+use std::time::UNIX_EPOCH;
 
 use clap;
 
 use output::Output;
 
+use convert::events::*;
 use convert::utils::*;
 use misc::*;
 use zbackup::data::*;
 use zbackup::disk_format::*;
 use zbackup::repository_core::*;
 
+/// Bundles found to be unused by `gc-bundles --condemn` are listed in this file,
+/// in the repository, and are only deleted or compacted if they are still
+/// unused on a later run, at least the grace period after they were condemned.
+
+const CONDEMNED_BUNDLES_FILE_NAME: & 'static str = "gc-condemned-bundles";
+
 pub fn gc_bundles (
 	output: & Output,
+	events: & ConvertEvents,
 	arguments: & GcBundlesArguments,
 ) -> Result <bool, String> {
 
+	events.phase_start (
+		"scan");
+
 	// open repository
 
 	let repository_core =
@@ -30,14 +50,29 @@ pub fn gc_bundles (
 				arguments.password_file_path.clone ()),
 		) ?;
 
-	// begin transaction
+	// begin transaction, unless nothing will be changed
 
 	let atomic_file_writer =
-		AtomicFileWriter::new (
-			output,
-			& arguments.repository_path,
-			None,
-		) ?;
+		if arguments.dry_run {
+
+			None
+
+		} else {
+
+			let atomic_file_writer =
+				AtomicFileWriter::new (
+					output,
+					& arguments.repository_path,
+					None,
+				) ?;
+
+			// refuse to change an append-only repository, now we hold the lock
+
+			atomic_file_writer.append_only_check () ?;
+
+			Some (atomic_file_writer)
+
+		};
 
 	// get list of bundle files
 
@@ -71,6 +106,7 @@ pub fn gc_bundles (
 
 	get_all_index_entries (
 		output,
+		events,
 		& repository_core,
 		& index_ids_and_sizes,
 		& mut all_index_entries,
@@ -87,34 +123,202 @@ pub fn gc_bundles (
 	let mut other_chunks_seen: HashSet <ChunkId> =
 		HashSet::new ();
 
-	read_bundles_metadata (
-		output,
-		& repository_core,
-		& old_bundles,
-		& all_index_entries,
-		& mut bundles_to_compact,
-		& mut bundles_to_delete,
-		& mut other_chunks_seen,
-	) ?;
+	let bundles_in_grace_period =
+		read_bundles_metadata (
+			output,
+			events,
+			& repository_core,
+			& old_bundles,
+			& all_index_entries,
+			arguments.grace_period,
+			& mut bundles_to_compact,
+			& mut bundles_to_delete,
+			& mut other_chunks_seen,
+		) ?;
+
+	// with --condemn, only act on bundles condemned by an earlier run, at least
+	// the grace period ago, and keep the rest condemned
+
+	let mut bundles_condemned: Vec <(BundleId, u64)> =
+		Vec::new ();
+
+	let mut bundles_still_condemned: Vec <(BundleId, u64)> =
+		Vec::new ();
+
+	if arguments.condemn {
+
+		let previously_condemned: HashMap <BundleId, u64> =
+			read_condemned_bundles (
+				& arguments.repository_path,
+			) ?.into_iter ().collect ();
+
+		let now =
+			SystemTime::now ().duration_since (
+				UNIX_EPOCH,
+			).map (
+				|duration| duration.as_secs ()
+			).unwrap_or (0);
+
+		let condemn_age =
+			arguments.grace_period.map (
+				|grace_period| grace_period.as_secs ()
+			).ok_or_else (
+				|| "Option --condemn requires --grace-period".to_string ()
+			) ?;
+
+		let mut bundles_to_act_on: HashSet <BundleId> =
+			HashSet::new ();
+
+		for & bundle_id in bundles_to_delete.iter ().chain (
+			bundles_to_compact.iter ()) {
+
+			if let Some (& condemned_time) =
+				previously_condemned.get (& bundle_id) {
+
+				if now.saturating_sub (condemned_time) >= condemn_age {
+
+					bundles_to_act_on.insert (
+						bundle_id);
+
+				} else {
+
+					bundles_still_condemned.push (
+						(bundle_id, condemned_time));
+
+				}
+
+				continue;
+
+			}
+
+			if arguments.dry_run {
+
+				output_message! (
+					output,
+					"Would condemn bundle {}",
+					bundle_id);
+
+				events.plan (
+					"condemn-bundle",
+					& bundle_id.to_string (),
+					& []);
+
+			}
+
+			bundles_condemned.push (
+				(bundle_id, now));
+
+		}
+
+		bundles_to_delete.retain (
+			|bundle_id| bundles_to_act_on.contains (bundle_id));
+
+		bundles_to_compact.retain (
+			|bundle_id| bundles_to_act_on.contains (bundle_id));
+
+		output_message! (
+			output,
+			"{} {} bundles, to be deleted or compacted on a later run",
+			if arguments.dry_run { "Would condemn" } else { "Condemning" },
+			bundles_condemned.len ());
+
+		if ! bundles_still_condemned.is_empty () {
+
+			output_message! (
+				output,
+				"Keeping {} bundles which were condemned less than the grace \
+				period ago",
+				bundles_still_condemned.len ());
+
+		}
+
+	}
 
 	// delete bundles
 
-	delete_bundles (
-		output,
-		& repository_core,
-		& bundles_to_delete,
-	) ?;
+	let deleted_bundle_size =
+		delete_bundles (
+			output,
+			events,
+			& repository_core,
+			atomic_file_writer.as_ref (),
+			& bundles_to_delete,
+		) ?;
 
 	// compact bundles
 
-	compact_bundles (
-		output,
-		& repository_core,
-		atomic_file_writer,
-		& all_index_entries,
-		& bundles_to_compact,
-		& other_chunks_seen,
-	) ?;
+	let compacted_bundle_size =
+		compact_bundles (
+			output,
+			events,
+			& repository_core,
+			atomic_file_writer.as_ref (),
+			& all_index_entries,
+			& bundles_to_compact,
+			& other_chunks_seen,
+		) ?;
+
+	// write the new list of condemned bundles, replacing the old one
+
+	if let Some (ref atomic_file_writer) = atomic_file_writer {
+
+		if arguments.condemn {
+
+			write_condemned_bundles (
+				& arguments.repository_path,
+				atomic_file_writer,
+				& [
+					bundles_still_condemned.as_slice (),
+					bundles_condemned.as_slice (),
+				].concat (),
+			) ?;
+
+			atomic_file_writer.commit () ?;
+
+		}
+
+	}
+
+	if arguments.dry_run {
+
+		output_message! (
+			output,
+			"Would delete {} and compact {} bundles, saving {} bytes",
+			bundles_to_delete.len (),
+			bundles_to_compact.len (),
+			deleted_bundle_size + compacted_bundle_size);
+
+		events.summary (
+			SummaryStatus::Ok,
+			& [
+				("bundles-scanned", old_bundles.len () as u64),
+				("bundles-to-delete", bundles_to_delete.len () as u64),
+				("bundles-to-compact", bundles_to_compact.len () as u64),
+				("bundles-to-condemn", bundles_condemned.len () as u64),
+				("bundles-still-condemned", bundles_still_condemned.len () as u64),
+				("bundles-in-grace-period", bundles_in_grace_period),
+				("bytes-to-free", deleted_bundle_size + compacted_bundle_size),
+			]);
+
+	} else {
+
+		events.summary (
+			if bundles_to_delete.is_empty () && bundles_to_compact.is_empty () {
+				SummaryStatus::Ok
+			} else {
+				SummaryStatus::ProblemsFixed
+			},
+			& [
+				("bundles-scanned", old_bundles.len () as u64),
+				("bundles-deleted", bundles_to_delete.len () as u64),
+				("bundles-compacted", bundles_to_compact.len () as u64),
+				("bundles-condemned", bundles_condemned.len () as u64),
+				("bundles-still-condemned", bundles_still_condemned.len () as u64),
+				("bundles-in-grace-period", bundles_in_grace_period),
+				("bytes-freed", deleted_bundle_size + compacted_bundle_size),
+			]);
+
+	}
 
 	// return
 
@@ -124,8 +328,9 @@ pub fn gc_bundles (
 
 fn get_all_index_entries (
 	output: & Output,
+	events: & ConvertEvents,
 	repository_core: & RepositoryCore,
-	index_ids_and_sizes: & Vec <(IndexId, u64)>,
+	index_ids_and_sizes: & [(IndexId, u64)],
 	all_index_entries: & mut HashSet <(BundleId, ChunkId)>,
 ) -> Result <(), String> {
 
@@ -134,6 +339,9 @@ fn get_all_index_entries (
 			output,
 			"Reading indexes");
 
+	events.phase_start (
+		"read-indexes");
+
 	let total_index_size: u64 =
 		index_ids_and_sizes.iter ().map (
 			|& (_, index_size)|
@@ -142,15 +350,21 @@ fn get_all_index_entries (
 
 	let mut read_index_size: u64 = 0;
 
-	for & (
-		index_id,
-		index_size,
-	) in index_ids_and_sizes.iter () {
+	for (
+		index_index,
+		& (index_id, index_size),
+	) in index_ids_and_sizes.iter ().enumerate () {
 
 		output_job.progress (
 			read_index_size,
 			total_index_size);
 
+		events.progress_bytes (
+			index_index as u64,
+			index_ids_and_sizes.len () as u64,
+			read_index_size,
+			total_index_size);
+
 		let index_path =
 			repository_core.index_path (
 				index_id);
@@ -181,10 +395,16 @@ fn get_all_index_entries (
 		}
 
 		read_index_size +=
-			index_size as u64;
+			index_size;
 
 	}
 
+	events.progress_bytes (
+		index_ids_and_sizes.len () as u64,
+		index_ids_and_sizes.len () as u64,
+		read_index_size,
+		total_index_size);
+
 	output_job.complete ();
 
 	Ok (())
@@ -193,35 +413,61 @@ fn get_all_index_entries (
 
 fn read_bundles_metadata (
 	output: & Output,
+	events: & ConvertEvents,
 	repository_core: & RepositoryCore,
 	old_bundles: & Vec <BundleId>,
 	all_index_entries: & HashSet <(BundleId, ChunkId)>,
+	grace_period: Option <Duration>,
 	bundles_to_compact: & mut Vec <BundleId>,
 	bundles_to_delete: & mut Vec <BundleId>,
 	other_chunks_seen: & mut HashSet <ChunkId>,
-) -> Result <(), String> {
+) -> Result <u64, String> {
 
 	let output_job =
 		output_job_start! (
 			output,
 			"Reading bundle metadata");
 
+	events.phase_start (
+		"read-bundle-metadata");
+
 	let mut old_bundles_count: u64 = 0;
 	let old_bundles_total = old_bundles.len () as u64;
 
 	let mut seen_chunk_ids: HashSet <ChunkId> =
 		HashSet::new ();
 
+	let mut bundles_in_grace_period: u64 = 0;
+
 	for & old_bundle_id in old_bundles {
 
 		output_job.progress (
 			old_bundles_count,
 			old_bundles_total);
 
+		events.progress (
+			old_bundles_count,
+			old_bundles_total);
+
 		let old_bundle_path =
 			repository_core.bundle_path (
 				old_bundle_id);
 
+		// leave new bundles alone, since their index may not be written yet,
+		// and don't count their chunks as seen, in case it never is
+
+		if file_in_grace_period (
+			& old_bundle_path,
+			grace_period,
+		) ? {
+
+			bundles_in_grace_period += 1;
+			old_bundles_count += 1;
+
+			continue;
+
+		}
+
 		let old_bundle_info =
 			bundle_info_read_path (
 				old_bundle_path,
@@ -286,33 +532,61 @@ fn read_bundles_metadata (
 
 	}
 
-	output_job_replace! (
-		output_job,
-		"Found {} bundles to compact and {} to delete",
-		bundles_to_compact.len (),
-		bundles_to_delete.len ());
+	events.progress (
+		old_bundles_count,
+		old_bundles_total);
 
-	Ok (())
+	if bundles_in_grace_period > 0 {
+
+		output_job_replace! (
+			output_job,
+			"Found {} bundles to compact and {} to delete, skipped {} new \
+			bundles",
+			bundles_to_compact.len (),
+			bundles_to_delete.len (),
+			bundles_in_grace_period);
+
+	} else {
+
+		output_job_replace! (
+			output_job,
+			"Found {} bundles to compact and {} to delete",
+			bundles_to_compact.len (),
+			bundles_to_delete.len ());
+
+	}
+
+	Ok (bundles_in_grace_period)
 
 }
 
 fn delete_bundles (
 	output: & Output,
+	events: & ConvertEvents,
 	repository_core: & RepositoryCore,
+	atomic_file_writer: Option <& AtomicFileWriter>,
 	bundles_to_delete: & Vec <BundleId>,
-) -> Result <(), String> {
+) -> Result <u64, String> {
 
 	if bundles_to_delete.is_empty () {
-		return Ok (());
+		return Ok (0);
 	}
 
+	let dry_run =
+		atomic_file_writer.is_none ();
+
 	let output_job =
 		output_job_start! (
 			output,
-			"Deleting bundles");
+			"{} bundles",
+			if dry_run { "Planning to delete" } else { "Deleting" });
+
+	events.phase_start (
+		"delete-bundles");
 
 	let bundles_to_delete_total = bundles_to_delete.len () as u64;
 	let mut bundles_to_delete_count: u64 = 0;
+	let mut deleted_bundle_size: u64 = 0;
 
 	for & bundle_to_delete in bundles_to_delete {
 
@@ -320,43 +594,133 @@ fn delete_bundles (
 			bundles_to_delete_count,
 			bundles_to_delete_total);
 
-		io_result (
-			fs::remove_file (
-				repository_core.bundle_path (
-					bundle_to_delete)),
-		) ?;
+		events.progress (
+			bundles_to_delete_count,
+			bundles_to_delete_total);
+
+		let bundle_path =
+			repository_core.bundle_path (
+				bundle_to_delete);
+
+		let bundle_size =
+			io_result (
+				fs::metadata (
+					& bundle_path),
+			) ?.len ();
+
+		match atomic_file_writer {
+
+			None => {
+
+				output_message! (
+					output,
+					"Would delete bundle {}, saving {} bytes",
+					bundle_to_delete,
+					bundle_size);
+
+				events.plan (
+					"delete-bundle",
+					& bundle_to_delete.to_string (),
+					& [
+						("bytes-saved", bundle_size),
+					]);
+
+			},
+
+			Some (atomic_file_writer) => {
+
+				atomic_file_writer.delete (
+					bundle_path.clone (),
+				) ?;
+
+				let bundle_parity_path =
+					parity_path (
+						& bundle_path);
+
+				if bundle_parity_path.exists () {
+
+					atomic_file_writer.delete (
+						bundle_parity_path,
+					) ?;
+
+				}
+
+				atomic_file_writer.commit () ?;
+
+				events.problem (
+					"unreferenced-bundle",
+					& bundle_to_delete.to_string (),
+					true,
+					& format! (
+						"Deleted bundle {} with no indexed chunks",
+						bundle_to_delete));
+
+			},
+
+		}
 
 		bundles_to_delete_count += 1;
+		deleted_bundle_size += bundle_size;
 
 	}
 
+	events.progress (
+		bundles_to_delete_count,
+		bundles_to_delete_total);
+
 	output_job.complete ();
 
-	Ok (())
+	Ok (deleted_bundle_size)
 
 }
 
+/// Rewrites each bundle, leaving out unreferenced and duplicated chunks. With no
+/// atomic file writer, for a dry run, the compacted bundles are written to a
+/// `CountingWriter`, so the size saved is exact, but nothing is changed.
+
 fn compact_bundles (
 	output: & Output,
+	events: & ConvertEvents,
 	repository_core: & RepositoryCore,
-	atomic_file_writer: AtomicFileWriter,
+	atomic_file_writer: Option <& AtomicFileWriter>,
 	all_index_entries: & HashSet <(BundleId, ChunkId)>,
 	bundles_to_compact: & Vec <BundleId>,
 	other_chunks_seen: & HashSet <ChunkId>,
-) -> Result <(), String> {
+) -> Result <u64, String> {
+
+	let dry_run =
+		atomic_file_writer.is_none ();
+
+	if ! bundles_to_compact.is_empty () {
+
+		events.phase_start (
+			"compact-bundles");
+
+	}
 
 	let bundles_to_compact_total = bundles_to_compact.len () as u64;
 	let mut bundles_to_compact_count: u64 = 0;
+	let mut compacted_bundle_size: u64 = 0;
 
 	let mut seen_chunk_ids: HashSet <ChunkId> =
 		other_chunks_seen.iter ().map (|&c| c).collect ();
 
 	for & bundle_to_compact in bundles_to_compact {
 
+		events.progress (
+			bundles_to_compact_count,
+			bundles_to_compact_total);
+
 		let bundle_path =
 			repository_core.bundle_path (
 				bundle_to_compact);
 
+		let uncompacted_bundle_size =
+			io_result (
+				fs::metadata (
+					& bundle_path),
+			) ?.len ();
+
 		let output_job =
 			output_job_start! (
 				output,
@@ -375,15 +739,11 @@ fn compact_bundles (
 		let output_job =
 			output_job_start! (
 				output,
-				"Compacting bundle {} of {}",
+				"{} bundle {} of {}",
+				if dry_run { "Measuring" } else { "Compacting" },
 				bundles_to_compact_count + 1,
 				bundles_to_compact_total);
 
-		let mut compacted_bundle_file =
-			atomic_file_writer.create (
-				bundle_path,
-			) ?;
-
 		let mut compacted_bundle: Vec <(ChunkId, Vec <u8>)> =
 			Vec::new ();
 
@@ -415,28 +775,251 @@ fn compact_bundles (
 		let total_chunks =
 			compacted_bundle.len () as u64;
 
-		bundle_write_direct (
-			& mut compacted_bundle_file,
-			repository_core.encryption_key (),
-			& compacted_bundle,
-			|chunks_written| {
+		let new_bundle_size =
+			match atomic_file_writer {
 
-				output_job.progress (
-					chunks_written,
-					total_chunks)
+				None => {
 
-			}
-		) ?;
+					let mut counting_writer =
+						CountingWriter::new ();
+
+					bundle_write_direct (
+						& mut counting_writer,
+						repository_core.encryption_key (),
+						& compacted_bundle,
+						|chunks_written| {
+
+							output_job.progress (
+								chunks_written,
+								total_chunks)
+
+						}
+					) ?;
+
+					counting_writer.count ()
+
+				},
+
+				Some (atomic_file_writer) => {
+
+					let mut compacted_bundle_file =
+						atomic_file_writer.create (
+							bundle_path.clone (),
+						) ?;
+
+					bundle_write_direct (
+						& mut compacted_bundle_file,
+						repository_core.encryption_key (),
+						& compacted_bundle,
+						|chunks_written| {
+
+							output_job.progress (
+								chunks_written,
+								total_chunks)
+
+						}
+					) ?;
+
+					// the parity no longer matches the bundle
 
-		atomic_file_writer.commit () ?;
+					let bundle_parity_path =
+						parity_path (
+							& bundle_path);
+
+					if bundle_parity_path.exists () {
+
+						atomic_file_writer.delete (
+							bundle_parity_path,
+						) ?;
+
+					}
+
+					atomic_file_writer.commit () ?;
+
+					io_result (
+						fs::metadata (
+							& bundle_path),
+					) ?.len ()
+
+				},
+
+			};
+
+		let bundle_size_saved =
+			uncompacted_bundle_size.saturating_sub (
+				new_bundle_size);
+
+		if dry_run {
+
+			output_message! (
+				output,
+				"Would compact bundle {}, keeping {} chunks and saving {} \
+				bytes",
+				bundle_to_compact,
+				total_chunks,
+				bundle_size_saved);
+
+			events.plan (
+				"compact-bundle",
+				& bundle_to_compact.to_string (),
+				& [
+					("chunks-kept", total_chunks),
+					("bytes-before", uncompacted_bundle_size),
+					("bytes-after", new_bundle_size),
+					("bytes-saved", bundle_size_saved),
+				]);
+
+		} else {
+
+			events.problem (
+				"unreferenced-chunks",
+				& bundle_to_compact.to_string (),
+				true,
+				& format! (
+					"Compacted bundle {}, saving {} bytes",
+					bundle_to_compact,
+					bundle_size_saved));
+
+		}
 
 		output_job.complete ();
 
 		bundles_to_compact_count += 1;
+		compacted_bundle_size += bundle_size_saved;
 
 	}
 
-	Ok (())
+	events.progress (
+		bundles_to_compact_count,
+		bundles_to_compact_total);
+
+	Ok (compacted_bundle_size)
+
+}
+
+/// Reads the bundles condemned by the previous run, along with the time they
+/// were condemned, in seconds since the epoch. The file is missing if there
+/// are none.
+
+fn read_condemned_bundles (
+	repository_path: & Path,
+) -> Result <Vec <(BundleId, u64)>, String> {
+
+	let condemned_path =
+		repository_path.join (
+			CONDEMNED_BUNDLES_FILE_NAME);
+
+	if ! condemned_path.exists () {
+		return Ok (Vec::new ());
+	}
+
+	let condemned_file =
+		io_result_with_prefix (
+			|| format! (
+				"Error opening {}: ",
+				condemned_path.to_string_lossy ()),
+			File::open (
+				& condemned_path),
+		) ?;
+
+	let mut condemned_bundles: Vec <(BundleId, u64)> =
+		Vec::new ();
+
+	for line in BufReader::new (condemned_file).lines () {
+
+		let line =
+			io_result_with_prefix (
+				|| format! (
+					"Error reading {}: ",
+					condemned_path.to_string_lossy ()),
+				line,
+			) ?;
+
+		let line =
+			line.trim ();
+
+		if line.is_empty () || line.starts_with ("#") {
+			continue;
+		}
+
+		let line_parts: Vec <& str> =
+			line.split_whitespace ().collect ();
+
+		let condemned_bundle =
+			if line_parts.len () == 2 {
+
+			BundleId::parse (
+				line_parts [0],
+			).ok ().and_then (
+				|bundle_id|
+				line_parts [1].parse::<u64> ().ok ().map (
+					|condemned_time| (bundle_id, condemned_time))
+			)
+
+		} else { None };
+
+		condemned_bundles.push (
+			condemned_bundle.ok_or_else (
+				|| format! (
+					"Invalid line in {}: {}",
+					condemned_path.to_string_lossy (),
+					line),
+			) ?);
+
+	}
+
+	Ok (condemned_bundles)
+
+}
+
+fn write_condemned_bundles (
+	repository_path: & Path,
+	atomic_file_writer: & AtomicFileWriter,
+	condemned_bundles: & [(BundleId, u64)],
+) -> Result <(), String> {
+
+	let condemned_path =
+		repository_path.join (
+			CONDEMNED_BUNDLES_FILE_NAME);
+
+	if condemned_bundles.is_empty () {
+
+		if condemned_path.exists () {
+
+			atomic_file_writer.delete (
+				condemned_path,
+			) ?;
+
+		}
+
+		return Ok (());
+
+	}
+
+	let mut condemned_file =
+		atomic_file_writer.create (
+			condemned_path,
+		) ?;
+
+	let mut contents =
+		String::from (
+			"# written by gc-bundles --condemn, these bundles will be deleted \
+			or\n# compacted on the next run if they are still unused\n");
+
+	for & (bundle_id, condemned_time) in condemned_bundles.iter () {
+
+		contents.push_str (
+			& format! (
+				"{} {}\n",
+				bundle_id,
+				condemned_time));
+
+	}
+
+	io_result (
+		condemned_file.write_all (
+			contents.as_bytes ()),
+	)
 
 }
 
@@ -448,6 +1031,10 @@ command! (
 	arguments = GcBundlesArguments {
 		repository_path: PathBuf,
 		password_file_path: Option <PathBuf>,
+		grace_period: Option <Duration>,
+		condemn: bool,
+		dry_run: bool,
+		output_format: OutputFormat,
 	},
 
 	clap_subcommand = {
@@ -460,8 +1047,9 @@ command! (
 
 				.long ("repository")
 				.value_name ("REPOSITORY")
-				.required (true)
-				.help ("Path to the repository")
+				.required (false)
+				.help ("Path to the repository, or the name \
+					of a repository in the config file")
 
 			)
 
@@ -475,11 +1063,35 @@ command! (
 
 			)
 
+			.arg (
+				clap::Arg::with_name ("grace-period")
+
+				.long ("grace-period")
+				.value_name ("DURATION")
+				.required (false)
+				.help ("Never delete or compact bundles modified more recently \
+					than this, such as \"1 day\"")
+
+			)
+
+			.arg (
+				clap::Arg::with_name ("condemn")
+
+				.long ("condemn")
+				.help ("Only delete or compact bundles which were condemned as \
+					unused by an earlier run, at least the grace period ago, and \
+					remember the rest for next time, requires --grace-period")
+
+			)
+
+			.dry_run_args ()
+			.output_format_args ()
+
 	},
 
 	clap_arguments_parse = |clap_matches| {
 
-		GcBundlesArguments {
+		let arguments = GcBundlesArguments {
 
 			repository_path:
 				args::path_required (
@@ -491,12 +1103,52 @@ command! (
 					& clap_matches,
 					"password-file"),
 
+			grace_period:
+				args::duration_optional (
+					& clap_matches,
+					"grace-period"),
+
+			condemn:
+				args::bool_flag (
+					& clap_matches,
+					"condemn"),
+
+			dry_run:
+				args::bool_flag (
+					& clap_matches,
+					"dry-run"),
+
+			output_format:
+				output_format (
+					& clap_matches),
+
+		};
+
+		// without a grace period, bundles would be acted on by the very next
+		// run, which defeats the point of condemning them
+
+		if arguments.condemn && arguments.grace_period.is_none () {
+
+			args::error_exit (
+				format! (
+					"Option --condemn requires --grace-period"));
+
 		}
 
+		arguments
+
 	},
 
 	action = |output, arguments| {
-		gc_bundles (output, arguments)
+
+		let events =
+			ConvertEvents::new (
+				"gc-bundles",
+				arguments.output_format);
+
+		events.finish (
+			gc_bundles (output, & events, arguments))
+
 	},
 
 );
@@ -1,4 +1,5 @@
 use std::path::PathBuf;
+use std::time::Duration;
 
 use clap;
 
@@ -9,6 +10,7 @@ use rand::Rng;
 
 use rustc_serialize::hex::ToHex;
 
+use convert::events::*;
 use convert::utils::*;
 use misc::*;
 use zbackup::data::*;
@@ -17,9 +19,13 @@ use zbackup::repository::*;
 
 pub fn gc_indexes (
 	output: & Output,
+	events: & ConvertEvents,
 	arguments: & GcIndexesArguments,
 ) -> Result <bool, String> {
 
+	events.phase_start (
+		"load-indexes");
+
 	// open repository
 
 	let repository =
@@ -34,14 +40,29 @@ pub fn gc_indexes (
 				arguments.password_file_path.clone ()),
 		) ?;
 
-	// begin transaction
+	// begin transaction, unless nothing will be changed
 
 	let atomic_file_writer =
-		AtomicFileWriter::new (
-			output,
-			& arguments.repository_path,
-			None,
-		) ?;
+		if arguments.dry_run {
+
+			None
+
+		} else {
+
+			let atomic_file_writer =
+				AtomicFileWriter::new (
+					output,
+					& arguments.repository_path,
+					None,
+				) ?;
+
+			// refuse to change an append-only repository, now we hold the lock
+
+			atomic_file_writer.append_only_check () ?;
+
+			Some (atomic_file_writer)
+
+		};
 
 	// load indexes
 
@@ -60,23 +81,37 @@ pub fn gc_indexes (
 
 	// get list of backup files
 
+	events.phase_start (
+		"read-backups");
+
 	let backup_files =
 		scan_backups (
 			output,
 			& arguments.repository_path,
 		) ?;
 
-	// get a list of chunks used by backups
+	// get a list of chunks used by backups, only expanding backups which
+	// were not seen by a previous run
+
+	let mut backup_references =
+		read_backup_references (
+			output,
+			repository.core (),
+			arguments.rebuild_references);
 
 	let backup_chunk_ids =
 		get_recursive_chunks (
 			output,
 			& repository,
 			& backup_files,
+			& mut backup_references,
 		) ?;
 
 	// process indexes
 
+	events.phase_start (
+		"gc-indexes");
+
 	let output_job =
 		output_job_start! (
 			output,
@@ -87,20 +122,47 @@ pub fn gc_indexes (
 	let mut chunks_removed: u64 = 0;
 	let mut indexes_modified: u64 = 0;
 	let mut indexes_removed: u64 = 0;
+	let mut indexes_in_grace_period: u64 = 0;
+
+	let old_index_count =
+		old_index_ids_and_sizes.len () as u64;
 
 	for (
-		old_index_id,
-		old_index_size,
-	) in old_index_ids_and_sizes {
+		old_index_index,
+		(old_index_id, old_index_size),
+	) in old_index_ids_and_sizes.into_iter ().enumerate () {
 
 		output_job.progress (
 			old_index_progress,
 			total_index_size);
 
+		events.progress_bytes (
+			old_index_index as u64,
+			old_index_count,
+			old_index_progress,
+			total_index_size);
+
 		let old_index_path =
 			repository.index_path (
 				old_index_id);
 
+		// leave new indexes alone, since they may belong to a backup which is
+		// still being written
+
+		if file_in_grace_period (
+			& old_index_path,
+			arguments.grace_period,
+		) ? {
+
+			indexes_in_grace_period += 1;
+
+			old_index_progress +=
+				old_index_size;
+
+			continue;
+
+		}
+
 		let old_index_entries =
 			index_read_path (
 				& old_index_path,
@@ -126,12 +188,16 @@ pub fn gc_indexes (
 
 		) {
 
-			old_index_progress += 1;
+			old_index_progress +=
+				old_index_size;
 
 			continue;
 
 		}
 
+		let old_chunks_removed =
+			chunks_removed;
+
 		// rewrite the index
 
 		let mut new_index_entries: Vec <RawIndexEntry> =
@@ -196,8 +262,73 @@ pub fn gc_indexes (
 
 		}
 
+		let index_chunks_removed =
+			chunks_removed - old_chunks_removed;
+
+		let atomic_file_writer =
+			match atomic_file_writer {
+
+			Some (ref atomic_file_writer) =>
+				atomic_file_writer,
+
+			None => {
+
+				let index_chunks_kept: u64 =
+					new_index_entries.iter ().map (
+						|new_index_entry|
+						new_index_entry.bundle_info.num_chunks ()
+					).sum ();
+
+				if new_index_entries.is_empty () {
+
+					output_message! (
+						output,
+						"Would delete index {}, removing {} chunks",
+						old_index_id,
+						index_chunks_removed);
+
+					events.plan (
+						"delete-index",
+						& old_index_id.to_string (),
+						& [
+							("chunks-removed", index_chunks_removed),
+						]);
+
+					indexes_removed += 1;
+
+				} else {
+
+					output_message! (
+						output,
+						"Would rewrite index {}, removing {} and keeping {} chunks",
+						old_index_id,
+						index_chunks_removed,
+						index_chunks_kept);
+
+					events.plan (
+						"rewrite-index",
+						& old_index_id.to_string (),
+						& [
+							("chunks-removed", index_chunks_removed),
+							("chunks-kept", index_chunks_kept),
+						]);
+
+					indexes_modified += 1;
+
+				}
+
+				old_index_progress +=
+					old_index_size;
+
+				continue;
+
+			},
+
+		};
+
 		atomic_file_writer.delete (
-			old_index_path);
+			old_index_path,
+		) ?;
 
 		if ! new_index_entries.is_empty () {
 
@@ -234,20 +365,83 @@ pub fn gc_indexes (
 
 		}
 
+		events.problem (
+			"unreferenced-chunks",
+			& old_index_id.to_string (),
+			true,
+			& format! (
+				"Removed {} unreferenced chunks from index {}",
+				index_chunks_removed,
+				old_index_id));
+
 		old_index_progress +=
 			old_index_size;
 
 	}
 
+	events.progress_bytes (
+		old_index_count,
+		old_index_count,
+		old_index_progress,
+		total_index_size);
+
 	output_job_replace! (
 		output_job,
-		"Removed {} chunks from {} modified and {} deleted indexes",
+		"{} {} chunks from {} modified and {} deleted indexes",
+		if arguments.dry_run { "Would remove" } else { "Removed" },
 		chunks_removed,
 		indexes_modified,
 		indexes_removed);
 
+	if indexes_in_grace_period > 0 {
+
+		output_message! (
+			output,
+			"Skipped {} index files modified within the grace period",
+			indexes_in_grace_period);
+
+	}
+
+	let atomic_file_writer =
+		match atomic_file_writer {
+
+		Some (atomic_file_writer) =>
+			atomic_file_writer,
+
+		None => {
+
+			repository.close (
+				output);
+
+			events.summary (
+				SummaryStatus::Ok,
+				& [
+					("indexes-scanned", old_index_count),
+					("indexes-to-rewrite", indexes_modified),
+					("indexes-to-delete", indexes_removed),
+					("indexes-in-grace-period", indexes_in_grace_period),
+					("chunks-to-remove", chunks_removed),
+				]);
+
+			return Ok (true);
+
+		},
+
+	};
+
+	// save backup references for the next run
+
+	write_backup_references (
+		repository.core (),
+		& atomic_file_writer,
+		& backup_references,
+	) ?;
+
 	// commit changes
 
+	events.phase_start (
+		"commit");
+
 	let output_job =
 		output_job_start! (
 			output,
@@ -262,6 +456,20 @@ pub fn gc_indexes (
 	repository.close (
 		output);
 
+	events.summary (
+		if chunks_removed == 0 {
+			SummaryStatus::Ok
+		} else {
+			SummaryStatus::ProblemsFixed
+		},
+		& [
+			("indexes-scanned", old_index_count),
+			("indexes-modified", indexes_modified),
+			("indexes-removed", indexes_removed),
+			("indexes-in-grace-period", indexes_in_grace_period),
+			("chunks-removed", chunks_removed),
+		]);
+
 	Ok (true)
 
 }
@@ -274,6 +482,10 @@ command! (
 	arguments = GcIndexesArguments {
 		repository_path: PathBuf,
 		password_file_path: Option <PathBuf>,
+		grace_period: Option <Duration>,
+		rebuild_references: bool,
+		dry_run: bool,
+		output_format: OutputFormat,
 	},
 
 	clap_subcommand = {
@@ -287,8 +499,9 @@ command! (
 
 				.long ("repository")
 				.value_name ("REPOSITORY")
-				.required (true)
-				.help ("Path to the repository")
+				.required (false)
+				.help ("Path to the repository, or the name \
+					of a repository in the config file")
 
 			)
 
@@ -302,6 +515,29 @@ command! (
 
 			)
 
+			.arg (
+				clap::Arg::with_name ("grace-period")
+
+				.long ("grace-period")
+				.value_name ("DURATION")
+				.required (false)
+				.help ("Never rewrite or delete index files modified more \
+					recently than this, such as \"1 day\"")
+
+			)
+
+			.arg (
+				clap::Arg::with_name ("rebuild-references")
+
+				.long ("rebuild-references")
+				.help ("Expand every backup, instead of reusing the chunk \
+					lists saved by a previous run")
+
+			)
+
+			.dry_run_args ()
+			.output_format_args ()
+
 	},
 
 	clap_arguments_parse = |clap_matches| {
@@ -318,12 +554,39 @@ command! (
 					& clap_matches,
 					"password-file"),
 
+			grace_period:
+				args::duration_optional (
+					& clap_matches,
+					"grace-period"),
+
+			rebuild_references:
+				args::bool_flag (
+					& clap_matches,
+					"rebuild-references"),
+
+			dry_run:
+				args::bool_flag (
+					& clap_matches,
+					"dry-run"),
+
+			output_format:
+				output_format (
+					& clap_matches),
+
 		}
 
 	},
 
 	action = |output, arguments| {
-		gc_indexes (output, arguments)
+
+		let events =
+			ConvertEvents::new (
+				"gc-indexes",
+				arguments.output_format);
+
+		events.finish (
+			gc_indexes (output, & events, arguments))
+
 	},
 
 );
@@ -0,0 +1,988 @@
+use std::collections::HashSet;
+use std::fs;
+use std::fs::File;
+use std::io::BufRead;
+use std::io::BufReader;
+use std::io::Read;
+use std::io::Write;
+use std::path::Path;
+use std::vec;
+
+use byteorder::ByteOrder;
+use byteorder::LittleEndian;
+
+use futures::Future;
+use futures_cpupool::CpuPool;
+
+use num_cpus;
+
+use output::Output;
+use output::OutputJob;
+
+use rust_crypto::digest::Digest;
+use rust_crypto::ed25519;
+use rust_crypto::hmac::Hmac;
+use rust_crypto::mac::Mac;
+use rust_crypto::mac::MacResult;
+use rust_crypto::sha2::Sha256;
+
+use rustc_serialize::hex::FromHex;
+use rustc_serialize::hex::ToHex;
+
+use ::convert::events::*;
+use ::convert::manifest_create::*;
+use ::convert::manifest_verify::*;
+use ::convert::utils::*;
+use ::misc::*;
+
+// declare commands
+
+pub fn manifest_command (
+) -> Box <Command> {
+
+	Box::new (
+		ParentCommand::new (
+			"manifest",
+			"Creates and verifies signed manifests of the repository's files",
+			vec! [
+				manifest_create_command (),
+				manifest_verify_command (),
+			],
+		)
+	)
+
+}
+
+/// The directories covered by a manifest. Everything else in the repository,
+/// such as the `info` file and rzbackup's own state, may legitimately change.
+
+pub const MANIFEST_DIRECTORIES: & 'static [& 'static str] = & [
+	"backups",
+	"bundles",
+	"index",
+];
+
+const MANIFEST_HEADER: & 'static str = "rzbackup-manifest 1";
+
+#[ derive (Clone, Debug, Eq, PartialEq) ]
+pub struct ManifestEntry {
+	pub path: String,
+	pub size: u64,
+	pub hash: [u8; 32],
+}
+
+#[ derive (Clone, Debug) ]
+pub enum ManifestSignature {
+	Ed25519 {
+		public_key: [u8; 32],
+		signature: [u8; 64],
+	},
+	HmacSha256 {
+		mac: [u8; 32],
+	},
+}
+
+/// A list of files with their SHA-256 hashes, the root of a Merkle tree built
+/// over them, and a signature. The manifest is a text file, and the signature
+/// covers every line before the signature line.
+
+pub struct Manifest {
+	pub created_time: u64,
+	pub entries: Vec <ManifestEntry>,
+	pub root: [u8; 32],
+	pub signature: ManifestSignature,
+}
+
+/// The key used to sign or verify a manifest. Ed25519 keys are kept in files
+/// containing the 32 byte seed, or the public key, in hex. HMAC uses the
+/// repository's encryption key, which never appears in the manifest.
+
+pub enum ManifestKey {
+	Ed25519Secret ([u8; 32]),
+	Ed25519Public ([u8; 32]),
+	Hmac (Vec <u8>),
+}
+
+impl Manifest {
+
+	/// The text which is signed, everything but the signature line.
+
+	pub fn signed_text (
+		& self,
+	) -> String {
+
+		let mut text =
+			String::new ();
+
+		text.push_str (
+			& format! (
+				"{}\ncreated {}\n",
+				MANIFEST_HEADER,
+				self.created_time));
+
+		for entry in self.entries.iter () {
+
+			text.push_str (
+				& format! (
+					"file {} {} {}\n",
+					entry.hash.to_hex (),
+					entry.size,
+					entry.path));
+
+		}
+
+		text.push_str (
+			& format! (
+				"root {}\n",
+				self.root.to_hex ()));
+
+		text
+
+	}
+
+	pub fn sign (
+		created_time: u64,
+		mut entries: Vec <ManifestEntry>,
+		key: & ManifestKey,
+	) -> Result <Manifest, String> {
+
+		entries.sort_by (
+			|left, right| left.path.cmp (& right.path));
+
+		let root =
+			manifest_merkle_root (
+				& entries);
+
+		let mut manifest = Manifest {
+			created_time: created_time,
+			entries: entries,
+			root: root,
+			signature: ManifestSignature::HmacSha256 {
+				mac: [0u8; 32],
+			},
+		};
+
+		let signed_text =
+			manifest.signed_text ();
+
+		manifest.signature =
+			match * key {
+
+			ManifestKey::Ed25519Secret (ref seed) => {
+
+				let (secret_key, public_key) =
+					ed25519::keypair (
+						seed);
+
+				ManifestSignature::Ed25519 {
+					public_key: public_key,
+					signature: ed25519::signature (
+						signed_text.as_bytes (),
+						& secret_key),
+				}
+
+			},
+
+			ManifestKey::Hmac (ref hmac_key) => {
+
+				let mut mac: [u8; 32] =
+					[0u8; 32];
+
+				manifest_hmac (
+					hmac_key,
+					& signed_text,
+				).raw_result (
+					& mut mac);
+
+				ManifestSignature::HmacSha256 {
+					mac: mac,
+				}
+
+			},
+
+			ManifestKey::Ed25519Public (_) =>
+				return Err (
+					"Can't sign a manifest with a public key".to_string ()),
+
+		};
+
+		Ok (manifest)
+
+	}
+
+	/// Checks the signature, and that the root matches the entries.
+
+	pub fn verify (
+		& self,
+		key: & ManifestKey,
+	) -> Result <(), String> {
+
+		if manifest_merkle_root (& self.entries) != self.root {
+
+			return Err (
+				"Manifest root does not match its entries".to_string ());
+
+		}
+
+		let signed_text =
+			self.signed_text ();
+
+		match (& self.signature, key) {
+
+			(
+				& ManifestSignature::Ed25519 {
+					ref public_key,
+					ref signature,
+				},
+				& ManifestKey::Ed25519Public (ref trusted_public_key),
+			) => {
+
+				if public_key != trusted_public_key {
+
+					return Err (
+						format! (
+							"Manifest was signed with a different key, {}",
+							public_key.to_hex ()));
+
+				}
+
+				if ! ed25519::verify (
+					signed_text.as_bytes (),
+					trusted_public_key,
+					signature) {
+
+					return Err (
+						"Manifest signature is invalid".to_string ());
+
+				}
+
+			},
+
+			(
+				& ManifestSignature::HmacSha256 {
+					ref mac,
+				},
+				& ManifestKey::Hmac (ref hmac_key),
+			) => {
+
+				if manifest_hmac (
+					hmac_key,
+					& signed_text,
+				).result () != MacResult::new (mac) {
+
+					return Err (
+						"Manifest HMAC is invalid".to_string ());
+
+				}
+
+			},
+
+			(& ManifestSignature::Ed25519 { .. }, _) =>
+				return Err (
+					"Manifest is signed with Ed25519, use --public-key-file \
+					to verify it".to_string ()),
+
+			(& ManifestSignature::HmacSha256 { .. }, _) =>
+				return Err (
+					"Manifest is signed with the repository key, use \
+					--password-file to verify it".to_string ()),
+
+		}
+
+		Ok (())
+
+	}
+
+}
+
+pub fn manifest_write (
+	manifest_path: & Path,
+	manifest: & Manifest,
+) -> Result <(), String> {
+
+	let signature_line =
+		match manifest.signature {
+
+		ManifestSignature::Ed25519 {
+			ref public_key,
+			ref signature,
+		} =>
+			format! (
+				"signature ed25519 {} {}\n",
+				public_key.to_hex (),
+				signature.to_hex ()),
+
+		ManifestSignature::HmacSha256 {
+			ref mac,
+		} =>
+			format! (
+				"signature hmac-sha256 {}\n",
+				mac.to_hex ()),
+
+	};
+
+	let mut manifest_file =
+		io_result_with_prefix (
+			|| format! (
+				"Error creating {}: ",
+				manifest_path.to_string_lossy ()),
+			File::create (
+				manifest_path),
+		) ?;
+
+	let manifest_text =
+		format! (
+			"{}{}",
+			manifest.signed_text (),
+			signature_line);
+
+	io_result_with_prefix (
+		|| format! (
+			"Error writing {}: ",
+			manifest_path.to_string_lossy ()),
+		manifest_file.write_all (
+			manifest_text.as_bytes ()),
+	) ?;
+
+	io_result_with_prefix (
+		|| format! (
+			"Error writing {}: ",
+			manifest_path.to_string_lossy ()),
+		manifest_file.sync_all (),
+	) ?;
+
+	Ok (())
+
+}
+
+pub fn manifest_read (
+	manifest_path: & Path,
+) -> Result <Manifest, String> {
+
+	let manifest_file =
+		io_result_with_prefix (
+			|| format! (
+				"Error opening {}: ",
+				manifest_path.to_string_lossy ()),
+			File::open (
+				manifest_path),
+		) ?;
+
+	let invalid_line = |line_number: usize| {
+		format! (
+			"Error reading {}: Invalid line {}",
+			manifest_path.to_string_lossy (),
+			line_number)
+	};
+
+	let mut created_time: Option <u64> = None;
+	let mut entries: Vec <ManifestEntry> = Vec::new ();
+	let mut root: Option <[u8; 32]> = None;
+	let mut signature: Option <ManifestSignature> = None;
+
+	for (line_index, line_result) in BufReader::new (
+		manifest_file,
+	).lines ().enumerate () {
+
+		let line_number = line_index + 1;
+
+		let line =
+			io_result_with_prefix (
+				|| format! (
+					"Error reading {}: ",
+					manifest_path.to_string_lossy ()),
+				line_result,
+			) ?;
+
+		if line_number == 1 {
+
+			if line != MANIFEST_HEADER {
+
+				return Err (
+					format! (
+						"Error reading {}: Not a manifest file",
+						manifest_path.to_string_lossy ()));
+
+			}
+
+			continue;
+
+		}
+
+		// nothing may follow the signature
+
+		if signature.is_some () {
+			return Err (invalid_line (line_number));
+		}
+
+		let parts: Vec <& str> =
+			line.splitn (4, ' ').collect ();
+
+		match (parts [0], parts.len ()) {
+
+			("created", 2) if created_time.is_none () =>
+				created_time = Some (
+					parts [1].parse ().map_err (
+						|_| invalid_line (line_number),
+					) ?),
+
+			("file", 4) if root.is_none () => {
+
+				// entries must be in order, so they can be searched
+
+				if entries.last ().map (
+					|entry| entry.path.as_str () >= parts [3]
+				).unwrap_or (false) {
+					return Err (invalid_line (line_number));
+				}
+
+				entries.push (ManifestEntry {
+					hash: parse_hex_32 (parts [1]).ok_or_else (
+						|| invalid_line (line_number),
+					) ?,
+					size: parts [2].parse ().map_err (
+						|_| invalid_line (line_number),
+					) ?,
+					path: parts [3].to_string (),
+				});
+
+			},
+
+			("root", 2) if root.is_none () =>
+				root = Some (
+					parse_hex_32 (parts [1]).ok_or_else (
+						|| invalid_line (line_number),
+					) ?),
+
+			("signature", 4) if parts [1] == "ed25519" && root.is_some () => {
+
+				let signature_bytes =
+					parts [3].from_hex ().map_err (
+						|_| invalid_line (line_number),
+					) ?;
+
+				if signature_bytes.len () != 64 {
+					return Err (invalid_line (line_number));
+				}
+
+				let mut signature_array: [u8; 64] =
+					[0u8; 64];
+
+				signature_array.copy_from_slice (
+					& signature_bytes);
+
+				signature = Some (
+					ManifestSignature::Ed25519 {
+						public_key: parse_hex_32 (parts [2]).ok_or_else (
+							|| invalid_line (line_number),
+						) ?,
+						signature: signature_array,
+					});
+
+			},
+
+			("signature", 3) if parts [1] == "hmac-sha256" && root.is_some () =>
+				signature = Some (
+					ManifestSignature::HmacSha256 {
+						mac: parse_hex_32 (parts [2]).ok_or_else (
+							|| invalid_line (line_number),
+						) ?,
+					}),
+
+			_ =>
+				return Err (invalid_line (line_number)),
+
+		}
+
+	}
+
+	match (created_time, root, signature) {
+
+		(Some (created_time), Some (root), Some (signature)) =>
+			Ok (Manifest {
+				created_time: created_time,
+				entries: entries,
+				root: root,
+				signature: signature,
+			}),
+
+		_ =>
+			Err (
+				format! (
+					"Error reading {}: Incomplete manifest",
+					manifest_path.to_string_lossy ())),
+
+	}
+
+}
+
+/// Reads a file containing a 32 byte key in hex, such as an Ed25519 seed or
+/// public key.
+
+pub fn manifest_key_read (
+	key_path: & Path,
+) -> Result <[u8; 32], String> {
+
+	let mut key_file =
+		io_result_with_prefix (
+			|| format! (
+				"Error opening {}: ",
+				key_path.to_string_lossy ()),
+			File::open (
+				key_path),
+		) ?;
+
+	let mut key_string =
+		String::new ();
+
+	io_result_with_prefix (
+		|| format! (
+			"Error reading {}: ",
+			key_path.to_string_lossy ()),
+		key_file.read_to_string (
+			& mut key_string),
+	) ?;
+
+	parse_hex_32 (
+		key_string.trim (),
+	).ok_or_else (
+		|| format! (
+			"Error reading {}: Expected 64 hex digits",
+			key_path.to_string_lossy ())
+	)
+
+}
+
+pub fn manifest_public_key (
+	seed: & [u8; 32],
+) -> [u8; 32] {
+
+	ed25519::keypair (seed).1
+
+}
+
+/// Lists the files covered by a manifest, as paths relative to the repository,
+/// separated by `/`, in sorted order.
+
+pub fn manifest_scan_files (
+	repository_path: & Path,
+) -> Result <Vec <String>, String> {
+
+	let mut file_paths: Vec <String> =
+		Vec::new ();
+
+	for directory_name in MANIFEST_DIRECTORIES.iter () {
+
+		let directory_path =
+			repository_path.join (
+				directory_name);
+
+		if ! directory_path.exists () {
+			continue;
+		}
+
+		for file_path in scan_files_recursive (
+			& directory_path,
+		) ? {
+
+			let file_path_string =
+				file_path.to_str ().ok_or_else (
+					|| format! (
+						"Invalid file name: {}",
+						file_path.to_string_lossy ()),
+				) ?;
+
+			if file_path_string.contains ('\n') {
+
+				return Err (
+					format! (
+						"Invalid file name: {}",
+						file_path.to_string_lossy ()));
+
+			}
+
+			file_paths.push (
+				format! (
+					"{}/{}",
+					directory_name,
+					file_path.components ().map (
+						|component|
+						component.as_os_str ().to_string_lossy ().to_string ()
+					).collect::<Vec <String>> ().join ("/")));
+
+		}
+
+	}
+
+	file_paths.sort ();
+
+	Ok (file_paths)
+
+}
+
+/// Calculates the SHA-256 hash of each file, in parallel. The files are read as
+/// they are, so nothing is decrypted.
+
+pub fn manifest_hash_files (
+	output: & Output,
+	events: & ConvertEvents,
+	repository_path: & Path,
+	file_paths: Vec <String>,
+) -> Result <Vec <ManifestEntry>, String> {
+
+	struct Task {
+		path: String,
+		output_job: OutputJob,
+		result: Result <(u64, [u8; 32]), String>,
+	}
+
+	struct State {
+		paths_iterator: vec::IntoIter <String>,
+		entries: Vec <ManifestEntry>,
+		files_count: u64,
+		files_total: u64,
+		bytes_count: u64,
+		bytes_total: u64,
+		output_job: OutputJob,
+	}
+
+	let mut bytes_total: u64 = 0;
+
+	for file_path in file_paths.iter () {
+
+		bytes_total +=
+			io_result_with_prefix (
+				|| format! (
+					"Error getting metadata for {}: ",
+					file_path),
+				fs::metadata (
+					repository_path.join (
+						file_path)),
+			) ?.len ();
+
+	}
+
+	let num_threads =
+		(num_cpus::get () - 1) * 5 / 3 + 1;
+
+	let cpu_pool =
+		CpuPool::new (
+			num_threads);
+
+	let output_job =
+		output_job_start! (
+			output,
+			"Hashing {} files",
+			file_paths.len ());
+
+	let mut state = State {
+		files_total: file_paths.len () as u64,
+		paths_iterator: file_paths.into_iter (),
+		entries: Vec::new (),
+		files_count: 0,
+		bytes_count: 0,
+		bytes_total: bytes_total,
+		output_job: output_job,
+	};
+
+	concurrent_controller (
+		output,
+		num_threads,
+		& mut state,
+
+		|state| {
+
+			if let Some (path) =
+				state.paths_iterator.next () {
+
+				let output =
+					output.clone ();
+
+				let full_path =
+					repository_path.join (
+						& path);
+
+				Some (cpu_pool.spawn_fn (move || {
+
+					let output_job =
+						output_job_start! (
+							output,
+							"Hashing {}",
+							path);
+
+					let result =
+						hash_file (
+							& full_path);
+
+					Ok (Task {
+						path: path,
+						output_job: output_job,
+						result: result,
+					})
+
+				}).boxed ())
+
+			} else { None }
+
+		},
+
+		|state, task_value| {
+
+			let (size, hash) =
+				task_value.result ?;
+
+			state.files_count += 1;
+			state.bytes_count += size;
+
+			state.output_job.progress (
+				state.bytes_count,
+				state.bytes_total);
+
+			events.progress_bytes (
+				state.files_count,
+				state.files_total,
+				state.bytes_count,
+				state.bytes_total);
+
+			state.entries.push (ManifestEntry {
+				path: task_value.path,
+				size: size,
+				hash: hash,
+			});
+
+			task_value.output_job.remove ();
+
+			Ok (())
+
+		},
+
+	) ?;
+
+	state.output_job.complete ();
+
+	let mut entries =
+		state.entries;
+
+	entries.sort_by (
+		|left, right| left.path.cmp (& right.path));
+
+	Ok (entries)
+
+}
+
+/// Builds a binary Merkle tree over the entries, in path order, and returns its
+/// root. Leaves and interior nodes are hashed with different prefixes, and an
+/// odd node at the end of a level is carried up unchanged.
+
+pub fn manifest_merkle_root (
+	entries: & [ManifestEntry],
+) -> [u8; 32] {
+
+	let mut level: Vec <[u8; 32]> =
+		entries.iter ().map (
+			|entry| {
+
+			let mut size_bytes: [u8; 8] =
+				[0u8; 8];
+
+			LittleEndian::write_u64 (
+				& mut size_bytes,
+				entry.size);
+
+			sha256 (& [
+				& [0u8],
+				entry.path.as_bytes (),
+				& [0u8],
+				& size_bytes,
+				& entry.hash,
+			])
+
+		}).collect ();
+
+	if level.is_empty () {
+		return sha256 (& []);
+	}
+
+	while level.len () > 1 {
+
+		level =
+			level.chunks (2).map (
+				|pair|
+				if pair.len () == 2 {
+					sha256 (& [
+						& [1u8],
+						& pair [0],
+						& pair [1],
+					])
+				} else {
+					pair [0]
+				}
+			).collect ();
+
+	}
+
+	level [0]
+
+}
+
+/// Compares the manifest with the files found in the repository, returning the
+/// added, removed and modified paths. Added files don't need to be hashed, so
+/// `current_paths` lists every file, and `current_entries` only the files which
+/// are also in the manifest.
+
+pub fn manifest_compare (
+	manifest: & Manifest,
+	current_paths: & [String],
+	current_entries: & [ManifestEntry],
+) -> (Vec <String>, Vec <String>, Vec <String>) {
+
+	let manifest_paths: HashSet <& str> =
+		manifest.entries.iter ().map (
+			|entry| entry.path.as_str ()
+		).collect ();
+
+	let current_path_set: HashSet <& str> =
+		current_paths.iter ().map (
+			|path| path.as_str ()
+		).collect ();
+
+	let added_paths: Vec <String> =
+		current_paths.iter ().filter (
+			|path| ! manifest_paths.contains (path.as_str ())
+		).cloned ().collect ();
+
+	let removed_paths: Vec <String> =
+		manifest.entries.iter ().filter (
+			|entry| ! current_path_set.contains (entry.path.as_str ())
+		).map (
+			|entry| entry.path.clone ()
+		).collect ();
+
+	let modified_paths: Vec <String> =
+		current_entries.iter ().filter (
+			|current_entry|
+			manifest.entries.binary_search_by (
+				|entry| entry.path.cmp (& current_entry.path),
+			).map (
+				|index| manifest.entries [index] != ** current_entry
+			).unwrap_or (false)
+		).map (
+			|current_entry| current_entry.path.clone ()
+		).collect ();
+
+	(added_paths, removed_paths, modified_paths)
+
+}
+
+fn manifest_hmac (
+	hmac_key: & [u8],
+	signed_text: & str,
+) -> Hmac <Sha256> {
+
+	let mut hmac =
+		Hmac::new (
+			Sha256::new (),
+			hmac_key);
+
+	hmac.input (
+		signed_text.as_bytes ());
+
+	hmac
+
+}
+
+fn hash_file (
+	file_path: & Path,
+) -> Result <(u64, [u8; 32]), String> {
+
+	let mut file =
+		io_result_with_prefix (
+			|| format! (
+				"Error opening {}: ",
+				file_path.to_string_lossy ()),
+			File::open (
+				file_path),
+		) ?;
+
+	let mut digest =
+		Sha256::new ();
+
+	let mut buffer: Vec <u8> =
+		vec! [0u8; 0x10000];
+
+	let mut size: u64 = 0;
+
+	loop {
+
+		let bytes_read =
+			io_result_with_prefix (
+				|| format! (
+					"Error reading {}: ",
+					file_path.to_string_lossy ()),
+				file.read (
+					& mut buffer),
+			) ?;
+
+		if bytes_read == 0 {
+			break;
+		}
+
+		digest.input (
+			& buffer [0 .. bytes_read]);
+
+		size += bytes_read as u64;
+
+	}
+
+	let mut hash: [u8; 32] =
+		[0u8; 32];
+
+	digest.result (
+		& mut hash);
+
+	Ok ((size, hash))
+
+}
+
+fn sha256 (
+	parts: & [& [u8]],
+) -> [u8; 32] {
+
+	let mut digest =
+		Sha256::new ();
+
+	for part in parts.iter () {
+
+		digest.input (
+			part);
+
+	}
+
+	let mut hash: [u8; 32] =
+		[0u8; 32];
+
+	digest.result (
+		& mut hash);
+
+	hash
+
+}
+
+fn parse_hex_32 (
+	hex_string: & str,
+) -> Option <[u8; 32]> {
+
+	hex_string.from_hex ().ok ().and_then (
+		|bytes|
+
+		if bytes.len () == 32 {
+			Some (to_array_32 (& bytes))
+		} else {
+			None
+		}
+
+	)
+
+}
+
+// ex: noet ts=4 filetype=rust
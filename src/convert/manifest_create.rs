@@ -0,0 +1,247 @@
+use std::path::PathBuf;
+use std::time::SystemTime;
+use std::time::UNIX_EPOCH;
+
+use clap;
+
+use output::Output;
+
+use rustc_serialize::hex::ToHex;
+
+use ::convert::events::*;
+use ::convert::manifest::*;
+use ::misc::*;
+use ::zbackup::repository_core::*;
+
+pub fn manifest_create (
+	output: & Output,
+	events: & ConvertEvents,
+	arguments: & ManifestCreateArguments,
+) -> Result <bool, String> {
+
+	// get the signing key
+
+	let manifest_key =
+		if let Some (ref signing_key_file_path) =
+			arguments.signing_key_file_path {
+
+		ManifestKey::Ed25519Secret (
+			manifest_key_read (
+				signing_key_file_path,
+			) ?)
+
+	} else {
+
+		let repository_core =
+			string_result_with_prefix (
+				|| format! (
+					"Error opening repository {}: ",
+					arguments.repository_path.to_string_lossy ()),
+				RepositoryCore::open (
+					& output,
+					& arguments.repository_path,
+					arguments.password_file_path.clone ()),
+			) ?;
+
+		ManifestKey::Hmac (
+			repository_core.encryption_key ().ok_or_else (
+				|| format! (
+					"Repository {} is not encrypted, use --signing-key-file",
+					arguments.repository_path.to_string_lossy ()),
+			) ?.to_vec ())
+
+	};
+
+	// find and hash files
+
+	events.phase_start (
+		"scan");
+
+	let file_paths =
+		manifest_scan_files (
+			& arguments.repository_path,
+		) ?;
+
+	events.phase_start (
+		"hash");
+
+	let entries =
+		manifest_hash_files (
+			output,
+			events,
+			& arguments.repository_path,
+			file_paths,
+		) ?;
+
+	let total_size: u64 =
+		entries.iter ().map (
+			|entry| entry.size
+		).sum ();
+
+	let num_files =
+		entries.len () as u64;
+
+	// sign and write the manifest
+
+	events.phase_start (
+		"sign");
+
+	let created_time =
+		SystemTime::now ().duration_since (
+			UNIX_EPOCH,
+		).map (
+			|duration| duration.as_secs ()
+		).unwrap_or (0);
+
+	let manifest =
+		Manifest::sign (
+			created_time,
+			entries,
+			& manifest_key,
+		) ?;
+
+	manifest_write (
+		& arguments.manifest_path,
+		& manifest,
+	) ?;
+
+	output_message! (
+		output,
+		"Wrote manifest of {} files, {} bytes, with root {}",
+		num_files,
+		total_size,
+		manifest.root.to_hex ());
+
+	if let ManifestKey::Ed25519Secret (ref seed) = manifest_key {
+
+		output_message! (
+			output,
+			"Signed with public key {}",
+			manifest_public_key (seed).to_hex ());
+
+	}
+
+	events.summary (
+		SummaryStatus::Ok,
+		& [
+			("files", num_files),
+			("bytes", total_size),
+		]);
+
+	Ok (true)
+
+}
+
+command! (
+
+	name = create,
+	export = manifest_create_command,
+
+	arguments = ManifestCreateArguments {
+		repository_path: PathBuf,
+		password_file_path: Option <PathBuf>,
+		signing_key_file_path: Option <PathBuf>,
+		manifest_path: PathBuf,
+		output_format: OutputFormat,
+	},
+
+	clap_subcommand = {
+
+		clap::SubCommand::with_name ("create")
+			.about ("Writes a signed manifest of the hashes of every backup, \
+				bundle and index file")
+
+			.arg (
+				clap::Arg::with_name ("repository")
+
+				.long ("repository")
+				.value_name ("REPOSITORY")
+				.required (false)
+				.help ("Path to the repository, or the name \
+					of a repository in the config file")
+
+			)
+
+			.arg (
+				clap::Arg::with_name ("password-file")
+
+				.long ("password-file")
+				.value_name ("PASSWORD-FILE")
+				.required (false)
+				.help ("Path to the password file, the repository key is used \
+					to sign the manifest unless --signing-key-file is given")
+
+			)
+
+			.arg (
+				clap::Arg::with_name ("signing-key-file")
+
+				.long ("signing-key-file")
+				.value_name ("SIGNING-KEY-FILE")
+				.required (false)
+				.help ("Path to a file containing an Ed25519 secret key, as 64 \
+					hex digits, to sign the manifest with")
+
+			)
+
+			.arg (
+				clap::Arg::with_name ("manifest")
+
+				.long ("manifest")
+				.value_name ("MANIFEST")
+				.required (true)
+				.help ("Path to write the manifest to")
+
+			)
+
+			.output_format_args ()
+
+	},
+
+	clap_arguments_parse = |clap_matches| {
+
+		ManifestCreateArguments {
+
+			repository_path:
+				args::path_required (
+					& clap_matches,
+					"repository"),
+
+			password_file_path:
+				args::path_optional (
+					& clap_matches,
+					"password-file"),
+
+			signing_key_file_path:
+				args::path_optional (
+					& clap_matches,
+					"signing-key-file"),
+
+			manifest_path:
+				args::path_required (
+					& clap_matches,
+					"manifest"),
+
+			output_format:
+				output_format (
+					& clap_matches),
+
+		}
+
+	},
+
+	action = |output, arguments| {
+
+		let events =
+			ConvertEvents::new (
+				"manifest-create",
+				arguments.output_format);
+
+		events.finish (
+			manifest_create (output, & events, arguments))
+
+	},
+
+);
+
+// ex: noet ts=4 filetype=rust
@@ -0,0 +1,312 @@
+use std::collections::HashSet;
+use std::path::PathBuf;
+
+use clap;
+
+use output::Output;
+
+use ::convert::events::*;
+use ::convert::manifest::*;
+use ::misc::*;
+use ::zbackup::repository_core::*;
+
+pub fn manifest_verify (
+	output: & Output,
+	events: & ConvertEvents,
+	arguments: & ManifestVerifyArguments,
+) -> Result <bool, String> {
+
+	// read the manifest and check its signature
+
+	events.phase_start (
+		"read-manifest");
+
+	let manifest =
+		manifest_read (
+			& arguments.manifest_path,
+		) ?;
+
+	let manifest_key =
+		if let Some (ref public_key_file_path) =
+			arguments.public_key_file_path {
+
+		ManifestKey::Ed25519Public (
+			manifest_key_read (
+				public_key_file_path,
+			) ?)
+
+	} else {
+
+		let repository_core =
+			string_result_with_prefix (
+				|| format! (
+					"Error opening repository {}: ",
+					arguments.repository_path.to_string_lossy ()),
+				RepositoryCore::open (
+					& output,
+					& arguments.repository_path,
+					arguments.password_file_path.clone ()),
+			) ?;
+
+		ManifestKey::Hmac (
+			repository_core.encryption_key ().ok_or_else (
+				|| format! (
+					"Repository {} is not encrypted, use --public-key-file",
+					arguments.repository_path.to_string_lossy ()),
+			) ?.to_vec ())
+
+	};
+
+	let signature_valid =
+		match manifest.verify (
+			& manifest_key,
+		) {
+
+		Ok (()) => true,
+
+		Err (error) => {
+
+			events.problem (
+				"invalid-signature",
+				& arguments.manifest_path.to_string_lossy (),
+				false,
+				& error);
+
+			output.message (
+				error);
+
+			false
+
+		},
+
+	};
+
+	// hash the files which are in the manifest, others have been added anyway
+
+	events.phase_start (
+		"scan");
+
+	let current_paths =
+		manifest_scan_files (
+			& arguments.repository_path,
+		) ?;
+
+	let manifest_paths: HashSet <& str> =
+		manifest.entries.iter ().map (
+			|entry| entry.path.as_str ()
+		).collect ();
+
+	let paths_to_hash: Vec <String> =
+		current_paths.iter ().filter (
+			|path| manifest_paths.contains (path.as_str ())
+		).cloned ().collect ();
+
+	events.phase_start (
+		"hash");
+
+	let current_entries =
+		manifest_hash_files (
+			output,
+			events,
+			& arguments.repository_path,
+			paths_to_hash,
+		) ?;
+
+	let checked_size: u64 =
+		current_entries.iter ().map (
+			|entry| entry.size
+		).sum ();
+
+	// report differences
+
+	let (added_paths, removed_paths, modified_paths) =
+		manifest_compare (
+			& manifest,
+			& current_paths,
+			& current_entries);
+
+	for (problem_kind, description, paths) in [
+		("added-file", "was added", & added_paths),
+		("removed-file", "was removed", & removed_paths),
+		("modified-file", "was modified", & modified_paths),
+	] {
+
+		for path in paths.iter () {
+
+			let message =
+				format! (
+					"File {} {} since the manifest was created",
+					path,
+					description);
+
+			events.problem (
+				problem_kind,
+				path,
+				false,
+				& message);
+
+			output.message (
+				message);
+
+		}
+
+	}
+
+	let valid =
+		signature_valid
+		&& added_paths.is_empty ()
+		&& removed_paths.is_empty ()
+		&& modified_paths.is_empty ();
+
+	if valid {
+
+		output_message! (
+			output,
+			"All {} files match the manifest",
+			manifest.entries.len ());
+
+	} else {
+
+		output_message! (
+			output,
+			"Found {} added, {} removed and {} modified files",
+			added_paths.len (),
+			removed_paths.len (),
+			modified_paths.len ());
+
+	}
+
+	events.summary (
+		if valid {
+			SummaryStatus::Ok
+		} else {
+			SummaryStatus::ProblemsFound
+		},
+		& [
+			("files-in-manifest", manifest.entries.len () as u64),
+			("files-checked", current_entries.len () as u64),
+			("bytes-checked", checked_size),
+			("files-added", added_paths.len () as u64),
+			("files-removed", removed_paths.len () as u64),
+			("files-modified", modified_paths.len () as u64),
+			("invalid-signatures", if signature_valid { 0 } else { 1 }),
+		]);
+
+	Ok (valid)
+
+}
+
+command! (
+
+	name = verify,
+	export = manifest_verify_command,
+
+	arguments = ManifestVerifyArguments {
+		repository_path: PathBuf,
+		password_file_path: Option <PathBuf>,
+		public_key_file_path: Option <PathBuf>,
+		manifest_path: PathBuf,
+		output_format: OutputFormat,
+	},
+
+	clap_subcommand = {
+
+		clap::SubCommand::with_name ("verify")
+			.about ("Checks a manifest's signature, and reports backup, \
+				bundle and index files which were added, removed or modified \
+				since it was created")
+
+			.arg (
+				clap::Arg::with_name ("repository")
+
+				.long ("repository")
+				.value_name ("REPOSITORY")
+				.required (false)
+				.help ("Path to the repository, or the name \
+					of a repository in the config file")
+
+			)
+
+			.arg (
+				clap::Arg::with_name ("password-file")
+
+				.long ("password-file")
+				.value_name ("PASSWORD-FILE")
+				.required (false)
+				.help ("Path to the password file, for manifests signed with \
+					the repository key")
+
+			)
+
+			.arg (
+				clap::Arg::with_name ("public-key-file")
+
+				.long ("public-key-file")
+				.value_name ("PUBLIC-KEY-FILE")
+				.required (false)
+				.help ("Path to a file containing the Ed25519 public key, as 64 \
+					hex digits, which the manifest should be signed with")
+
+			)
+
+			.arg (
+				clap::Arg::with_name ("manifest")
+
+				.long ("manifest")
+				.value_name ("MANIFEST")
+				.required (true)
+				.help ("Path to the manifest")
+
+			)
+
+			.output_format_args ()
+
+	},
+
+	clap_arguments_parse = |clap_matches| {
+
+		ManifestVerifyArguments {
+
+			repository_path:
+				args::path_required (
+					& clap_matches,
+					"repository"),
+
+			password_file_path:
+				args::path_optional (
+					& clap_matches,
+					"password-file"),
+
+			public_key_file_path:
+				args::path_optional (
+					& clap_matches,
+					"public-key-file"),
+
+			manifest_path:
+				args::path_required (
+					& clap_matches,
+					"manifest"),
+
+			output_format:
+				output_format (
+					& clap_matches),
+
+		}
+
+	},
+
+	action = |output, arguments| {
+
+		let events =
+			ConvertEvents::new (
+				"manifest-verify",
+				arguments.output_format);
+
+		events.finish (
+			manifest_verify (output, & events, arguments))
+
+	},
+
+);
+
+// ex: noet ts=4 filetype=rust
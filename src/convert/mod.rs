@@ -3,6 +3,12 @@
 #[ macro_use ]
 pub mod utils;
 
+pub mod events;
+
+mod add_parity;
+mod append_only;
+mod append_only_disable;
+mod append_only_enable;
 mod balance_bundles;
 mod balance_indexes;
 mod check_backups;
@@ -10,7 +16,12 @@ mod check_bundles;
 mod check_indexes;
 mod gc_bundles;
 mod gc_indexes;
+mod manifest;
+mod manifest_create;
+mod manifest_verify;
 mod rebuild_indexes;
+mod repair_from;
+mod scrub;
 
 // import project dependencies
 
@@ -18,6 +29,10 @@ use ::misc::*;
 
 // import own dependencies
 
+pub use self::add_parity::*;
+pub use self::append_only::*;
+pub use self::append_only_disable::*;
+pub use self::append_only_enable::*;
 pub use self::balance_bundles::*;
 pub use self::balance_indexes::*;
 pub use self::check_backups::*;
@@ -25,7 +40,12 @@ pub use self::check_bundles::*;
 pub use self::check_indexes::*;
 pub use self::gc_bundles::*;
 pub use self::gc_indexes::*;
+pub use self::manifest::*;
+pub use self::manifest_create::*;
+pub use self::manifest_verify::*;
 pub use self::rebuild_indexes::*;
+pub use self::repair_from::*;
+pub use self::scrub::*;
 
 // commands
 
@@ -36,19 +56,31 @@ pub fn convert_command (
 		ParentCommand::new (
 			"convert",
 			"Performs various operations on ZBackup repositories",
-			vec! [
-				balance_bundles_command (),
-				balance_indexes_command (),
-				check_backups_command (),
-				check_bundles_command (),
-				check_indexes_command (),
-				gc_bundles_command (),
-				gc_indexes_command (),
-				rebuild_indexes_command (),
-			],
+			convert_commands (),
 		)
 	)
 
 }
 
+pub fn convert_commands (
+) -> Vec <Box <Command>> {
+
+	vec! [
+		add_parity_command (),
+		append_only_command (),
+		balance_bundles_command (),
+		balance_indexes_command (),
+		check_backups_command (),
+		check_bundles_command (),
+		check_indexes_command (),
+		gc_bundles_command (),
+		gc_indexes_command (),
+		manifest_command (),
+		rebuild_indexes_command (),
+		repair_from_command (),
+		scrub_command (),
+	]
+
+}
+
 // ex: noet ts=4 filetype=rust
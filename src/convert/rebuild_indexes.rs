@@ -14,6 +14,7 @@ use num_cpus;
 use output::Output;
 use output::OutputJob;
 
+use ::convert::events::*;
 use ::convert::utils::*;
 use ::misc::*;
 use ::zbackup::data::*;
@@ -87,8 +88,12 @@ impl <'a> IndexRebuilder <'a> {
 	fn rebuild_indexes (
 		& mut self,
 		output: & Output,
+		events: & ConvertEvents,
 	) -> Result <bool, String> {
 
+		events.phase_start (
+			"scan");
+
 		// begin transaction
 
 		let atomic_file_writer =
@@ -98,6 +103,10 @@ impl <'a> IndexRebuilder <'a> {
 				None,
 			) ?;
 
+		// refuse to change an append-only repository, now we hold the lock
+
+		atomic_file_writer.append_only_check () ?;
+
 		// get list of bundle files
 
 		let bundle_ids =
@@ -124,6 +133,9 @@ impl <'a> IndexRebuilder <'a> {
 				output,
 				"Rebuilding indexes");
 
+		events.phase_start (
+			"read-bundles");
+
 		let mut task_futures: Vec <TaskFuture> =
 			Vec::new ();
 
@@ -209,6 +221,10 @@ impl <'a> IndexRebuilder <'a> {
 						bundle_count,
 						bundle_total);
 
+					events.progress (
+						bundle_count,
+						bundle_total);
+
 					entries_buffer.push (
 						RawIndexEntry {
 
@@ -237,9 +253,8 @@ impl <'a> IndexRebuilder <'a> {
 							atomic_file_writer.clone ();
 
 						let index_entries =
-							mem::replace (
-								& mut entries_buffer,
-								Vec::new ());
+							mem::take (
+								& mut entries_buffer);
 
 						let index_id =
 							IndexId::random ();
@@ -287,6 +302,10 @@ impl <'a> IndexRebuilder <'a> {
 
 		output.unpause ();
 
+		events.progress (
+			bundle_count,
+			bundle_total);
+
 		// write out final index
 
 		if ! entries_buffer.is_empty () {
@@ -295,13 +314,16 @@ impl <'a> IndexRebuilder <'a> {
 				output,
 				& self.repository_core,
 				& atomic_file_writer,
-				& mut entries_buffer,
+				& entries_buffer,
 			) ?;
 
 		}
 
 		// remove old indexes
 
+		events.phase_start (
+			"remove-old-indexes");
+
 		let output_job_remove_indexes =
 			output_job_start! (
 				output,
@@ -317,11 +339,15 @@ impl <'a> IndexRebuilder <'a> {
 			"Removing {} old index files",
 			old_index_ids.len ());
 
+		let old_index_count =
+			old_index_ids.len () as u64;
+
 		for old_index_id in old_index_ids {
 
 			atomic_file_writer.delete (
 				self.repository_core.index_path (
-					old_index_id));
+					old_index_id),
+			) ?;
 
 		}
 
@@ -329,6 +355,9 @@ impl <'a> IndexRebuilder <'a> {
 
 		// commit changes
 
+		events.phase_start (
+			"commit");
+
 		let output_job_commit =
 			output_job_start! (
 				output,
@@ -342,6 +371,13 @@ impl <'a> IndexRebuilder <'a> {
 
 		output_job_main.complete ();
 
+		events.summary (
+			SummaryStatus::Ok,
+			& [
+				("bundles-indexed", bundle_count),
+				("old-indexes-removed", old_index_count),
+			]);
+
 		// TODO not sure how to do this
 
 		//self.repository.close (
@@ -362,6 +398,7 @@ command! (
 		repository_path: PathBuf,
 		password_file_path: Option <PathBuf>,
 		bundles_per_index: u64,
+		output_format: OutputFormat,
 	},
 
 	clap_subcommand = {
@@ -374,8 +411,9 @@ command! (
 
 				.long ("repository")
 				.value_name ("REPOSITORY")
-				.required (true)
-				.help ("Path to the repository")
+				.required (false)
+				.help ("Path to the repository, or the name \
+					of a repository in the config file")
 
 			)
 
@@ -399,6 +437,8 @@ command! (
 
 			)
 
+			.output_format_args ()
+
 	},
 
 	clap_arguments_parse = |clap_matches| {
@@ -420,17 +460,32 @@ command! (
 					& clap_matches,
 					"bundles-per-index"),
 
+			output_format:
+				output_format (
+					& clap_matches),
+
 		}
 
 	},
 
 	action = |output, arguments| {
-		IndexRebuilder::new (
-			output,
-			arguments,
-		) ?.rebuild_indexes (
-			output,
-		)
+
+		let events =
+			ConvertEvents::new (
+				"rebuild-indexes",
+				arguments.output_format);
+
+		events.finish (
+			IndexRebuilder::new (
+				output,
+				arguments,
+			).and_then (
+				|mut index_rebuilder|
+				index_rebuilder.rebuild_indexes (
+					output,
+					& events)
+			))
+
 	},
 
 );
@@ -0,0 +1,751 @@
+use std::collections::HashMap;
+use std::collections::HashSet;
+use std::mem;
+use std::path::PathBuf;
+
+use clap;
+
+use output::Output;
+use output::OutputJob;
+
+use convert::events::*;
+use convert::utils::*;
+use misc::*;
+use misc::args::ClapSubCommandRzbackupArgs;
+use zbackup::data::*;
+use zbackup::disk_format::*;
+use zbackup::repository::*;
+use zbackup::repository_core::*;
+
+pub fn repair_from (
+	output: & Output,
+	events: & ConvertEvents,
+	arguments: & RepairFromArguments,
+) -> Result <bool, String> {
+
+	events.phase_start (
+		"scan");
+
+	// open repository
+
+	let repository_core =
+		string_result_with_prefix (
+			|| format! (
+				"Error opening repository {}: ",
+				arguments.repository_path.to_string_lossy ()),
+			RepositoryCore::open (
+				& output,
+				& arguments.repository_path,
+				arguments.password_file_path.clone ()),
+		) ?;
+
+	// begin transaction
+
+	let atomic_file_writer =
+		AtomicFileWriter::new (
+			output,
+			& arguments.repository_path,
+			None,
+		) ?;
+
+	// refuse to change an append-only repository, now we hold the lock
+
+	atomic_file_writer.append_only_check () ?;
+
+	// get list of bundle and index files
+
+	let present_bundle_ids: HashSet <BundleId> =
+		scan_bundle_files (
+			output,
+			& arguments.repository_path,
+		) ?.into_iter ().collect ();
+
+	scan_indexes_with_sizes! (
+		output,
+		arguments.repository_path,
+		index_ids_and_sizes,
+		total_index_size,
+	);
+
+	// find bundles which are indexed but missing or corrupt
+
+	events.phase_start (
+		"find-lost-bundles");
+
+	let output_job =
+		output_job_start! (
+			output,
+			"Finding missing and corrupt bundles");
+
+	let mut lost_bundles: HashMap <BundleId, (String, Vec <ChunkId>)> =
+		HashMap::new ();
+
+	let mut corrupt_bundle_ids: Vec <BundleId> =
+		Vec::new ();
+
+	let mut lost_chunks: Vec <(ChunkId, u32)> =
+		Vec::new ();
+
+	let mut affected_indexes: Vec <(IndexId, Vec <RawIndexEntry>)> =
+		Vec::new ();
+
+	let mut index_progress: u64 = 0;
+
+	for (index_index, & (index_id, index_size))
+	in index_ids_and_sizes.iter ().enumerate () {
+
+		output_job.progress (
+			index_progress,
+			total_index_size);
+
+		events.progress_bytes (
+			index_index as u64,
+			index_ids_and_sizes.len () as u64,
+			index_progress,
+			total_index_size);
+
+		let index_entries =
+			index_read_path (
+				repository_core.index_path (
+					index_id),
+				repository_core.encryption_key (),
+			) ?;
+
+		let mut index_affected = false;
+
+		for index_entry in index_entries.iter () {
+
+			let bundle_id =
+				index_entry.index_bundle_header.bundle_id ();
+
+			// the same bundle may be listed in more than one index
+
+			if lost_bundles.contains_key (& bundle_id) {
+
+				index_affected = true;
+
+				continue;
+
+			}
+
+			let lost_reason =
+				if ! present_bundle_ids.contains (& bundle_id) {
+
+				Some (
+					"missing".to_string ())
+
+			} else if arguments.verify_bundles {
+
+				check_bundle_chunks (
+					& repository_core,
+					bundle_id,
+					& index_entry.bundle_info,
+				).err ().inspect (
+					|_| {
+
+					corrupt_bundle_ids.push (
+						bundle_id);
+
+				})
+
+			} else {
+				None
+			};
+
+			if let Some (lost_reason) = lost_reason {
+
+				for chunk_record in index_entry.bundle_info.chunks () {
+
+					lost_chunks.push ((
+						chunk_record.chunk_id (),
+						chunk_record.size (),
+					));
+
+				}
+
+				lost_bundles.insert (
+					bundle_id,
+					(
+						lost_reason,
+						index_entry.bundle_info.chunks ().map (
+							|chunk_record| chunk_record.chunk_id ()
+						).collect (),
+					));
+
+				index_affected = true;
+
+			}
+
+		}
+
+		if index_affected {
+
+			affected_indexes.push ((
+				index_id,
+				index_entries,
+			));
+
+		}
+
+		index_progress +=
+			index_size;
+
+	}
+
+	output_job_replace! (
+		output_job,
+		"Found {} lost bundles with {} chunks",
+		lost_bundles.len (),
+		lost_chunks.len ());
+
+	if lost_bundles.is_empty () {
+
+		events.summary (
+			SummaryStatus::Ok,
+			& [
+				("bundles-lost", 0),
+			]);
+
+		return Ok (true);
+
+	}
+
+	// open mirror
+
+	events.phase_start (
+		"load-mirror-indexes");
+
+	let mirror =
+		string_result_with_prefix (
+			|| format! (
+				"Error opening mirror repository {}: ",
+				arguments.mirror_path.to_string_lossy ()),
+			Repository::open (
+				& output,
+				arguments.repository_config.clone (),
+				& arguments.mirror_path,
+				arguments.mirror_password_file_path.clone ()),
+		) ?;
+
+	mirror.load_indexes (
+		output,
+	) ?;
+
+	// fetch lost chunks from the mirror, writing them into new bundles
+
+	events.phase_start (
+		"recover-chunks");
+
+	let output_job =
+		output_job_start! (
+			output,
+			"Recovering chunks from mirror");
+
+	let mut bundle_chunks: Vec <(ChunkId, Vec <u8>)> =
+		Vec::new ();
+
+	let mut new_index_entries: Vec <RawIndexEntry> =
+		Vec::new ();
+
+	let mut recovered_chunk_ids: HashSet <ChunkId> =
+		HashSet::new ();
+
+	let mut unrecoverable_chunk_count: u64 = 0;
+	let mut chunk_progress: u64 = 0;
+
+	let lost_chunk_total =
+		lost_chunks.len () as u64;
+
+	for (chunk_id, chunk_size) in lost_chunks.into_iter () {
+
+		output_job.progress (
+			chunk_progress,
+			lost_chunk_total);
+
+		events.progress (
+			chunk_progress,
+			lost_chunk_total);
+
+		chunk_progress += 1;
+
+		if recovered_chunk_ids.contains (& chunk_id) {
+			continue;
+		}
+
+		let chunk_result =
+			if mirror.has_chunk (& chunk_id) {
+
+			mirror.get_chunk (
+				chunk_id,
+			).and_then (
+				|chunk_data|
+
+				if chunk_data.len () as u64 == chunk_size as u64 {
+
+					Ok (chunk_data)
+
+				} else {
+
+					Err (
+						format! (
+							"Expected {} bytes but mirror has {}",
+							chunk_size,
+							chunk_data.len ()))
+
+				}
+
+			)
+
+		} else {
+
+			Err (
+				"Not present in mirror".to_string ())
+
+		};
+
+		match chunk_result {
+
+			Ok (chunk_data) => {
+
+				bundle_chunks.push ((
+					chunk_id,
+					(* chunk_data).clone (),
+				));
+
+				recovered_chunk_ids.insert (
+					chunk_id);
+
+			},
+
+			Err (error) => {
+
+				let problem_message =
+					format! (
+						"Unable to recover chunk {}: {}",
+						chunk_id,
+						error);
+
+				output.message (
+					problem_message.clone ());
+
+				events.problem (
+					"unrecoverable-chunk",
+					& chunk_id.to_string (),
+					false,
+					& problem_message);
+
+				unrecoverable_chunk_count += 1;
+
+			},
+
+		}
+
+		if bundle_chunks.len () as u64 >= arguments.chunks_per_bundle {
+
+			new_index_entries.push (
+				flush_bundle (
+					& output_job,
+					& repository_core,
+					& atomic_file_writer,
+					& mem::take (
+						& mut bundle_chunks),
+				) ?);
+
+		}
+
+	}
+
+	if ! bundle_chunks.is_empty () {
+
+		new_index_entries.push (
+			flush_bundle (
+				& output_job,
+				& repository_core,
+				& atomic_file_writer,
+				& bundle_chunks,
+			) ?);
+
+	}
+
+	output_job_replace! (
+		output_job,
+		"Recovered {} chunks into {} new bundles, {} could not be recovered",
+		recovered_chunk_ids.len (),
+		new_index_entries.len (),
+		unrecoverable_chunk_count);
+
+	mirror.close (
+		output);
+
+	// rewrite indexes without the lost bundles, and index the new ones
+
+	events.phase_start (
+		"rewrite-indexes");
+
+	let output_job =
+		output_job_start! (
+			output,
+			"Rewriting indexes");
+
+	for (index_id, index_entries) in affected_indexes.into_iter () {
+
+		let remaining_entries: Vec <RawIndexEntry> =
+			index_entries.into_iter ().filter (
+				|index_entry|
+
+				! lost_bundles.contains_key (
+					& index_entry.index_bundle_header.bundle_id ())
+
+			).collect ();
+
+		atomic_file_writer.delete (
+			repository_core.index_path (
+				index_id),
+		) ?;
+
+		if ! remaining_entries.is_empty () {
+
+			flush_index_entries (
+				output,
+				& repository_core,
+				& atomic_file_writer,
+				& remaining_entries,
+			) ?;
+
+		}
+
+	}
+
+	if ! new_index_entries.is_empty () {
+
+		flush_index_entries (
+			output,
+			& repository_core,
+			& atomic_file_writer,
+			& new_index_entries,
+		) ?;
+
+	}
+
+	output_job.remove ();
+
+	// report lost bundles, and whether all their chunks were recovered
+
+	for (bundle_id, & (ref lost_reason, ref chunk_ids))
+	in lost_bundles.iter () {
+
+		let recovered_count =
+			chunk_ids.iter ().filter (
+				|chunk_id| recovered_chunk_ids.contains (chunk_id)
+			).count ();
+
+		events.problem (
+			"lost-bundle",
+			& bundle_id.to_string (),
+			recovered_count == chunk_ids.len (),
+			& format! (
+				"Bundle {} is {}, recovered {} of {} chunks",
+				bundle_id,
+				lost_reason,
+				recovered_count,
+				chunk_ids.len ()));
+
+	}
+
+	// commit changes
+
+	events.phase_start (
+		"commit");
+
+	let output_job =
+		output_job_start! (
+			output,
+			"Committing changes");
+
+	atomic_file_writer.commit () ?;
+
+	output_job.remove ();
+
+	// move corrupt bundles out of the way, now nothing refers to them
+
+	for bundle_id in corrupt_bundle_ids.iter () {
+
+		let bundle_path =
+			repository_core.bundle_path (
+				* bundle_id);
+
+		let bundle_broken_path =
+			repository_core.path ()
+				.join ("bundles-broken")
+				.join (bundle_id.to_string ());
+
+		atomic_file_writer.rename (
+			bundle_path.clone (),
+			bundle_broken_path.clone (),
+		) ?;
+
+		let bundle_parity_path =
+			parity_path (
+				& bundle_path);
+
+		if bundle_parity_path.exists () {
+
+			atomic_file_writer.rename (
+				bundle_parity_path,
+				parity_path (
+					& bundle_broken_path),
+			) ?;
+
+		}
+
+	}
+
+	atomic_file_writer.commit () ?;
+
+	if unrecoverable_chunk_count > 0 {
+
+		output_message! (
+			output,
+			"Unable to recover {} chunks, backups which use them can't be \
+			restored",
+			unrecoverable_chunk_count);
+
+	}
+
+	events.summary (
+		if unrecoverable_chunk_count == 0 {
+			SummaryStatus::ProblemsFixed
+		} else {
+			SummaryStatus::ProblemsFound
+		},
+		& [
+			("bundles-lost", lost_bundles.len () as u64),
+			("bundles-corrupt", corrupt_bundle_ids.len () as u64),
+			("chunks-lost", lost_chunk_total),
+			("chunks-recovered", recovered_chunk_ids.len () as u64),
+			("chunks-unrecoverable", unrecoverable_chunk_count),
+			("bundles-written", new_index_entries.len () as u64),
+		]);
+
+	Ok (unrecoverable_chunk_count == 0)
+
+}
+
+/// Reads a bundle and checks that it holds every chunk its index entry lists.
+
+fn check_bundle_chunks (
+	repository_core: & RepositoryCore,
+	bundle_id: BundleId,
+	bundle_info: & DiskBundleInfo,
+) -> Result <(), String> {
+
+	let bundle_chunk_ids: HashSet <ChunkId> =
+		bundle_read_path (
+			repository_core.bundle_path (
+				bundle_id),
+			repository_core.encryption_key (),
+		) ?.into_iter ().map (
+			|(chunk_id, _chunk_data)| chunk_id
+		).collect ();
+
+	for chunk_record in bundle_info.chunks () {
+
+		if ! bundle_chunk_ids.contains (& chunk_record.chunk_id ()) {
+
+			return Err (
+				format! (
+					"missing chunk {}",
+					chunk_record.chunk_id ()));
+
+		}
+
+	}
+
+	Ok (())
+
+}
+
+fn flush_bundle (
+	output_job: & OutputJob,
+	repository_core: & RepositoryCore,
+	atomic_file_writer: & AtomicFileWriter,
+	bundle_chunks: & Vec <(ChunkId, Vec <u8>)>,
+) -> Result <RawIndexEntry, String> {
+
+	let new_bundle_id =
+		BundleId::random ();
+
+	let mut new_bundle_file =
+		atomic_file_writer.create (
+			repository_core.bundle_path (
+				new_bundle_id),
+		) ?;
+
+	// the chunks are written with this repository's key, whatever the mirror
+	// used
+
+	let new_index_bundle_info =
+		bundle_write_direct (
+			& mut new_bundle_file,
+			repository_core.encryption_key (),
+			& bundle_chunks,
+			|_chunks_written| output_job.tick (),
+		) ?;
+
+	Ok (RawIndexEntry {
+		index_bundle_header: DiskIndexBundleHeader::new (
+			new_bundle_id),
+		bundle_info: new_index_bundle_info,
+	})
+
+}
+
+command! (
+
+	name = repair_from,
+	export = repair_from_command,
+
+	arguments = RepairFromArguments {
+		repository_path: PathBuf,
+		password_file_path: Option <PathBuf>,
+		mirror_path: PathBuf,
+		mirror_password_file_path: Option <PathBuf>,
+		repository_config: RepositoryConfig,
+		chunks_per_bundle: u64,
+		verify_bundles: bool,
+		output_format: OutputFormat,
+	},
+
+	clap_subcommand = {
+
+		clap::SubCommand::with_name ("repair-from")
+			.about ("Recovers chunks from missing or corrupt bundles from a \
+				mirror repository")
+
+			.arg (
+				clap::Arg::with_name ("repository")
+
+				.long ("repository")
+				.value_name ("REPOSITORY")
+				.required (false)
+				.help ("Path to the repository, or the name \
+					of a repository in the config file")
+
+			)
+
+			.arg (
+				clap::Arg::with_name ("password-file")
+
+				.long ("password-file")
+				.value_name ("PASSWORD-FILE")
+				.required (false)
+				.help ("Path to the password file")
+
+			)
+
+			.arg (
+				clap::Arg::with_name ("mirror")
+
+				.long ("mirror")
+				.value_name ("MIRROR")
+				.required (true)
+				.help ("Path to the mirror repository to recover chunks from")
+
+			)
+
+			.arg (
+				clap::Arg::with_name ("mirror-password-file")
+
+				.long ("mirror-password-file")
+				.value_name ("MIRROR-PASSWORD-FILE")
+				.required (false)
+				.help ("Path to the password file for the mirror repository")
+
+			)
+
+			.arg (
+				clap::Arg::with_name ("chunks-per-bundle")
+
+				.long ("chunks-per-bundle")
+				.value_name ("CHUNKS-PER-BUNDLE")
+				.default_value ("256")
+				.help ("Chunks per bundle")
+
+			)
+
+			.arg (
+				clap::Arg::with_name ("verify-bundles")
+
+				.long ("verify-bundles")
+				.help ("Read every bundle, and also recover chunks from those \
+					which are corrupt, instead of only missing ones")
+
+			)
+
+			.repository_config_args ()
+
+			.output_format_args ()
+
+	},
+
+	clap_arguments_parse = |clap_matches| {
+
+		RepairFromArguments {
+
+			repository_path:
+				args::path_required (
+					& clap_matches,
+					"repository"),
+
+			password_file_path:
+				args::path_optional (
+					& clap_matches,
+					"password-file"),
+
+			mirror_path:
+				args::path_required (
+					& clap_matches,
+					"mirror"),
+
+			mirror_password_file_path:
+				args::path_optional (
+					& clap_matches,
+					"mirror-password-file"),
+
+			repository_config:
+				args::repository_config (
+					clap_matches),
+
+			chunks_per_bundle:
+				args::u64_required (
+					& clap_matches,
+					"chunks-per-bundle"),
+
+			verify_bundles:
+				args::bool_flag (
+					& clap_matches,
+					"verify-bundles"),
+
+			output_format:
+				output_format (
+					& clap_matches),
+
+		}
+
+	},
+
+	action = |output, arguments| {
+
+		let events =
+			ConvertEvents::new (
+				"repair-from",
+				arguments.output_format);
+
+		events.finish (
+			repair_from (output, & events, arguments))
+
+	},
+
+);
+
+// ex: noet ts=4 filetype=rust
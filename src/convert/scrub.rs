@@ -0,0 +1,907 @@
+use std::collections::HashMap;
+use std::fs;
+use std::fs::File;
+use std::io::BufRead;
+use std::io::BufReader;
+use std::io::Write;
+use std::path::Path;
+use std::path::PathBuf;
+use std::slice;
+use std::time::Duration;
+use std::time::SystemTime;
+use std::time::UNIX_EPOCH;
+
+use clap;
+
+use futures::Future;
+use futures_cpupool::CpuPool;
+
+use num_cpus;
+
+use output::Output;
+use output::OutputJob;
+
+use ::convert::events::*;
+use ::convert::utils::*;
+use ::misc::*;
+use ::zbackup::disk_format::*;
+use ::zbackup::repository_core::*;
+
+const SCRUB_LEDGER_FILE_NAME: & 'static str = "scrub-ledger";
+
+#[ derive (Clone, Copy, Debug, Eq, Hash, Ord, PartialEq, PartialOrd) ]
+enum ScrubItemKind {
+	Bundle,
+	Index,
+	Backup,
+}
+
+impl ScrubItemKind {
+
+	fn name (& self) -> & 'static str {
+
+		match * self {
+			ScrubItemKind::Bundle => "bundle",
+			ScrubItemKind::Index => "index",
+			ScrubItemKind::Backup => "backup",
+		}
+
+	}
+
+	fn parse (
+		name: & str,
+	) -> Option <ScrubItemKind> {
+
+		match name {
+			"bundle" => Some (ScrubItemKind::Bundle),
+			"index" => Some (ScrubItemKind::Index),
+			"backup" => Some (ScrubItemKind::Backup),
+			_ => None,
+		}
+
+	}
+
+}
+
+struct ScrubItem {
+	kind: ScrubItemKind,
+	name: String,
+	path: PathBuf,
+	size: u64,
+}
+
+/// The last time each item was verified, in seconds since the epoch, and
+/// whether it was valid. Items which failed are verified again first, on the
+/// next run, so they are reported until they are fixed or removed.
+
+#[ derive (Clone, Copy) ]
+struct LedgerEntry {
+	verified_time: u64,
+	valid: bool,
+}
+
+type Ledger = HashMap <(ScrubItemKind, String), LedgerEntry>;
+
+pub fn scrub (
+	output: & Output,
+	events: & ConvertEvents,
+	arguments: & ScrubArguments,
+) -> Result <bool, String> {
+
+	events.phase_start (
+		"scan");
+
+	// open repository
+
+	let repository_core =
+		string_result_with_prefix (
+			|| format! (
+				"Error opening repository {}: ",
+				arguments.repository_path.to_string_lossy ()),
+			RepositoryCore::open (
+				& output,
+				& arguments.repository_path,
+				arguments.password_file_path.clone ()),
+		) ?;
+
+	// get a list of everything we can verify
+
+	let items =
+		scan_scrub_items (
+			output,
+			& repository_core,
+		) ?;
+
+	let total_size: u64 =
+		items.iter ().map (
+			|item| item.size
+		).sum ();
+
+	let mut ledger =
+		read_ledger (
+			& arguments.repository_path,
+		) ?;
+
+	// choose the least recently verified items, within the budget
+
+	let budget =
+		match (arguments.budget, arguments.fraction) {
+
+		(Some (budget), _) =>
+			budget,
+
+		(None, Some (fraction)) =>
+			(total_size as f64 * fraction / 100.0).ceil () as u64,
+
+		(None, None) =>
+			total_size,
+
+	};
+
+	let mut sorted_items: Vec <(Option <u64>, & ScrubItem)> =
+		items.iter ().map (
+			|item| (
+				ledger.get (
+					& (item.kind, item.name.clone ()),
+				).and_then (
+					|ledger_entry|
+					if ledger_entry.valid {
+						Some (ledger_entry.verified_time)
+					} else {
+						None
+					}
+				),
+				item,
+			)
+		).collect ();
+
+	sorted_items.sort_by (
+		|& (left_time, left_item), & (right_time, right_item)|
+
+		left_time.cmp (& right_time).then_with (
+			|| left_item.kind.cmp (& right_item.kind),
+		).then_with (
+			|| left_item.name.cmp (& right_item.name),
+		)
+
+	);
+
+	let mut selected_items: Vec <& ScrubItem> =
+		Vec::new ();
+
+	let mut selected_size: u64 = 0;
+
+	for & (_, item) in sorted_items.iter () {
+
+		if selected_size >= budget {
+			break;
+		}
+
+		selected_items.push (
+			item);
+
+		selected_size +=
+			item.size;
+
+	}
+
+	output_message! (
+		output,
+		"Verifying {} of {} items, {} of {} bytes",
+		selected_items.len (),
+		items.len (),
+		selected_size,
+		total_size);
+
+	// verify items
+
+	let num_threads =
+		(num_cpus::get () - 1) * 5 / 3 + 1;
+
+	let cpu_pool =
+		CpuPool::new (
+			num_threads);
+
+	events.phase_start (
+		"scrub");
+
+	let scrub_time =
+		SystemTime::now ().duration_since (
+			UNIX_EPOCH,
+		).map (
+			|duration| duration.as_secs ()
+		).unwrap_or (0);
+
+	let invalid_count =
+		scrub_items (
+			output,
+			events,
+			& cpu_pool,
+			num_threads,
+			repository_core.clone (),
+			& selected_items,
+			selected_size,
+			scrub_time,
+			& mut ledger,
+		) ?;
+
+	// report items which have not been verified recently
+
+	let mut unverified_count: u64 = 0;
+
+	if let Some (max_age) = arguments.max_age {
+
+		let oldest_time =
+			scrub_time.saturating_sub (
+				max_age.as_secs ());
+
+		for item in items.iter () {
+
+			let verified_recently =
+				ledger.get (
+					& (item.kind, item.name.clone ()),
+				).map (
+					|ledger_entry|
+					ledger_entry.valid
+						&& ledger_entry.verified_time >= oldest_time
+				).unwrap_or (false);
+
+			if verified_recently {
+				continue;
+			}
+
+			events.problem (
+				& format! (
+					"unverified-{}",
+					item.kind.name ()),
+				& item.name,
+				false,
+				& format! (
+					"The {} {} has not been verified within the maximum age",
+					item.kind.name (),
+					item.name));
+
+			unverified_count += 1;
+
+		}
+
+		if unverified_count > 0 {
+
+			output_message! (
+				output,
+				"Found {} items which have not been verified within the \
+				maximum age",
+				unverified_count);
+
+		}
+
+	}
+
+	// begin transaction, only now, so verifying doesn't hold the lock
+
+	events.phase_start (
+		"commit");
+
+	let atomic_file_writer =
+		AtomicFileWriter::new (
+			output,
+			& arguments.repository_path,
+			None,
+		) ?;
+
+	// merge with the ledger as it is now, in case another scrub wrote it
+	// while we were verifying, keeping the most recent entry for each item
+
+	for (key, ledger_entry) in read_ledger (
+		& arguments.repository_path,
+	) ? {
+
+		let keep_current =
+			ledger.get (
+				& key,
+			).map (
+				|our_entry|
+				ledger_entry.verified_time > our_entry.verified_time
+			).unwrap_or (true);
+
+		if keep_current {
+
+			ledger.insert (
+				key,
+				ledger_entry);
+
+		}
+
+	}
+
+	// write the ledger, forgetting items which no longer exist, including
+	// any which were removed while we were verifying
+
+	let new_ledger: Vec <(ScrubItemKind, String, LedgerEntry)> =
+		items.iter ().filter (
+			|item|
+			item.path.exists ()
+		).filter_map (
+			|item|
+			ledger.get (
+				& (item.kind, item.name.clone ()),
+			).map (
+				|ledger_entry|
+				(item.kind, item.name.clone (), * ledger_entry)
+			)
+		).collect ();
+
+	write_ledger (
+		& arguments.repository_path,
+		& atomic_file_writer,
+		& new_ledger,
+	) ?;
+
+	atomic_file_writer.commit () ?;
+
+	events.summary (
+		if invalid_count == 0 && unverified_count == 0 {
+			SummaryStatus::Ok
+		} else {
+			SummaryStatus::ProblemsFound
+		},
+		& [
+			("items-total", items.len () as u64),
+			("bytes-total", total_size),
+			("items-verified", selected_items.len () as u64),
+			("bytes-verified", selected_size),
+			("items-invalid", invalid_count),
+			("items-not-verified-within-max-age", unverified_count),
+		]);
+
+	// return
+
+	Ok (
+		invalid_count == 0
+		&& unverified_count == 0
+	)
+
+}
+
+fn scan_scrub_items (
+	output: & Output,
+	repository_core: & RepositoryCore,
+) -> Result <Vec <ScrubItem>, String> {
+
+	let output_job =
+		output_job_start! (
+			output,
+			"Scanning repository");
+
+	let mut items: Vec <ScrubItem> =
+		Vec::new ();
+
+	for (bundle_id, bundle_size) in scan_bundle_files_with_sizes (
+		repository_core.path (),
+	) ? {
+
+		items.push (ScrubItem {
+			kind: ScrubItemKind::Bundle,
+			name: bundle_id.to_string (),
+			path: repository_core.bundle_path (bundle_id),
+			size: bundle_size,
+		});
+
+	}
+
+	for (index_id, index_size) in scan_index_files_with_sizes (
+		repository_core.path (),
+	) ? {
+
+		items.push (ScrubItem {
+			kind: ScrubItemKind::Index,
+			name: index_id.to_string (),
+			path: repository_core.index_path (index_id),
+			size: index_size,
+		});
+
+	}
+
+	for backup_name in scan_backup_files (
+		repository_core.path (),
+	) ? {
+
+		let backup_name =
+			backup_name.to_string_lossy ().to_string ();
+
+		let backup_path =
+			repository_core.backup_path (
+				& backup_name);
+
+		let backup_metadata =
+			io_result_with_prefix (
+				|| format! (
+					"Error getting metadata for {}: ",
+					backup_path.to_string_lossy ()),
+				fs::metadata (
+					& backup_path),
+			) ?;
+
+		items.push (ScrubItem {
+			kind: ScrubItemKind::Backup,
+			name: backup_name,
+			path: backup_path,
+			size: backup_metadata.len (),
+		});
+
+	}
+
+	output_job_replace! (
+		output_job,
+		"Found {} bundles, indexes and backups",
+		items.len ());
+
+	Ok (items)
+
+}
+
+fn scrub_items (
+	output: & Output,
+	events: & ConvertEvents,
+	cpu_pool: & CpuPool,
+	max_tasks: usize,
+	repository_core: RepositoryCore,
+	items: & [& ScrubItem],
+	total_size: u64,
+	scrub_time: u64,
+	ledger: & mut Ledger,
+) -> Result <u64, String> {
+
+	struct Task {
+		kind: ScrubItemKind,
+		name: String,
+		size: u64,
+		output_job: OutputJob,
+		result: Result <(), String>,
+	}
+
+	struct State <'a, 'b: 'a> {
+		items_iterator: slice::Iter <'a, & 'b ScrubItem>,
+		ledger: & 'a mut Ledger,
+		verified_count: u64,
+		verified_size: u64,
+		invalid_count: u64,
+		output_job: OutputJob,
+	}
+
+	let output_job =
+		output_job_start! (
+			output,
+			"Scrubbing repository");
+
+	let mut state = State {
+		items_iterator: items.iter (),
+		ledger: ledger,
+		verified_count: 0,
+		verified_size: 0,
+		invalid_count: 0,
+		output_job: output_job,
+	};
+
+	// concurrent operation
+
+	concurrent_controller (
+		output,
+		max_tasks,
+		& mut state,
+
+		|state| {
+
+			if let Some (item) =
+				state.items_iterator.next () {
+
+				let output =
+					output.clone ();
+
+				let repository_core =
+					repository_core.clone ();
+
+				let kind = item.kind;
+				let name = item.name.clone ();
+				let path = item.path.clone ();
+				let size = item.size;
+
+				Some (cpu_pool.spawn_fn (move || {
+
+					let output_job =
+						output_job_start! (
+							output,
+							"Verifying {} {}",
+							kind.name (),
+							name);
+
+					let result =
+						verify_item (
+							& repository_core,
+							kind,
+							& path);
+
+					Ok (Task {
+						kind: kind,
+						name: name,
+						size: size,
+						output_job: output_job,
+						result: result,
+					})
+
+				}).boxed ())
+
+			} else { None }
+
+		},
+
+		|state, task_value| {
+
+			state.verified_count += 1;
+
+			state.verified_size +=
+				task_value.size;
+
+			state.output_job.progress (
+				state.verified_size,
+				total_size);
+
+			events.progress_bytes (
+				state.verified_count,
+				items.len () as u64,
+				state.verified_size,
+				total_size);
+
+			let valid =
+				task_value.result.is_ok ();
+
+			if let Err (error) = task_value.result {
+
+				events.problem (
+					& format! (
+						"invalid-{}",
+						task_value.kind.name ()),
+					& task_value.name,
+					false,
+					& error);
+
+				output.message (
+					error);
+
+				state.invalid_count += 1;
+
+			}
+
+			state.ledger.insert (
+				(task_value.kind, task_value.name),
+				LedgerEntry {
+					verified_time: scrub_time,
+					valid: valid,
+				});
+
+			task_value.output_job.remove ();
+
+			Ok (())
+
+		},
+
+	) ?;
+
+	if state.invalid_count > 0 {
+
+		output_job_replace! (
+			state.output_job,
+			"Found {} invalid files",
+			state.invalid_count);
+
+	} else {
+
+		output_job_replace! (
+			state.output_job,
+			"No problems found");
+
+	}
+
+	Ok (state.invalid_count)
+
+}
+
+fn verify_item (
+	repository_core: & RepositoryCore,
+	kind: ScrubItemKind,
+	path: & Path,
+) -> Result <(), String> {
+
+	match kind {
+
+		ScrubItemKind::Bundle =>
+			bundle_read_path (
+				path,
+				repository_core.encryption_key (),
+			).map (|_| ()),
+
+		ScrubItemKind::Index =>
+			index_read_path (
+				path,
+				repository_core.encryption_key (),
+			).map (|_| ()),
+
+		ScrubItemKind::Backup =>
+			backup_read_path (
+				path,
+				repository_core.encryption_key (),
+			).map (|_| ()),
+
+	}
+
+}
+
+/// Reads the ledger of previous verifications. Each line holds the kind of
+/// item, the time it was verified, whether it was valid, and its name, which
+/// comes last since backup names may contain spaces.
+
+fn read_ledger (
+	repository_path: & Path,
+) -> Result <Ledger, String> {
+
+	let ledger_path =
+		repository_path.join (
+			SCRUB_LEDGER_FILE_NAME);
+
+	let mut ledger: Ledger =
+		HashMap::new ();
+
+	if ! ledger_path.exists () {
+		return Ok (ledger);
+	}
+
+	let ledger_file =
+		io_result_with_prefix (
+			|| format! (
+				"Error opening {}: ",
+				ledger_path.to_string_lossy ()),
+			File::open (
+				& ledger_path),
+		) ?;
+
+	for line in BufReader::new (ledger_file).lines () {
+
+		let line =
+			io_result_with_prefix (
+				|| format! (
+					"Error reading {}: ",
+					ledger_path.to_string_lossy ()),
+				line,
+			) ?;
+
+		if line.is_empty () || line.starts_with ("#") {
+			continue;
+		}
+
+		let line_parts: Vec <& str> =
+			line.splitn (4, ' ').collect ();
+
+		let ledger_item =
+			if line_parts.len () == 4 {
+
+			ScrubItemKind::parse (
+				line_parts [0],
+			).and_then (
+				|kind|
+				line_parts [1].parse::<u64> ().ok ().map (
+					|verified_time| (kind, verified_time))
+			).and_then (
+				|(kind, verified_time)|
+				match line_parts [2] {
+					"valid" => Some ((kind, verified_time, true)),
+					"invalid" => Some ((kind, verified_time, false)),
+					_ => None,
+				}
+			)
+
+		} else { None };
+
+		let (kind, verified_time, valid) =
+			ledger_item.ok_or_else (
+				|| format! (
+					"Invalid line in {}: {}",
+					ledger_path.to_string_lossy (),
+					line),
+			) ?;
+
+		ledger.insert (
+			(kind, line_parts [3].to_string ()),
+			LedgerEntry {
+				verified_time: verified_time,
+				valid: valid,
+			});
+
+	}
+
+	Ok (ledger)
+
+}
+
+fn write_ledger (
+	repository_path: & Path,
+	atomic_file_writer: & AtomicFileWriter,
+	ledger: & [(ScrubItemKind, String, LedgerEntry)],
+) -> Result <(), String> {
+
+	let mut ledger_file =
+		atomic_file_writer.create (
+			repository_path.join (
+				SCRUB_LEDGER_FILE_NAME),
+		) ?;
+
+	let mut contents =
+		String::from (
+			"# written by scrub, each line is the kind of item, the time it \
+			was last\n# verified, whether it was valid, and its name\n");
+
+	for & (kind, ref name, ref ledger_entry) in ledger.iter () {
+
+		contents.push_str (
+			& format! (
+				"{} {} {} {}\n",
+				kind.name (),
+				ledger_entry.verified_time,
+				if ledger_entry.valid { "valid" } else { "invalid" },
+				name));
+
+	}
+
+	io_result (
+		ledger_file.write_all (
+			contents.as_bytes ()),
+	)
+
+}
+
+command! (
+
+	name = scrub,
+	export = scrub_command,
+
+	arguments = ScrubArguments {
+		repository_path: PathBuf,
+		password_file_path: Option <PathBuf>,
+		budget: Option <u64>,
+		fraction: Option <f64>,
+		max_age: Option <Duration>,
+		output_format: OutputFormat,
+	},
+
+	clap_subcommand = {
+
+		clap::SubCommand::with_name ("scrub")
+			.about ("Verifies the least recently verified bundles, indexes \
+				and backups, up to a limit, and records the results")
+
+			.arg (
+				clap::Arg::with_name ("repository")
+
+				.long ("repository")
+				.value_name ("REPOSITORY")
+				.required (false)
+				.help ("Path to the repository, or the name \
+					of a repository in the config file")
+
+			)
+
+			.arg (
+				clap::Arg::with_name ("password-file")
+
+				.long ("password-file")
+				.value_name ("PASSWORD-FILE")
+				.required (false)
+				.help ("Path to the password file")
+
+			)
+
+			.arg (
+				clap::Arg::with_name ("budget")
+
+				.long ("budget")
+				.value_name ("SIZE")
+				.required (false)
+				.conflicts_with ("fraction")
+				.help ("Amount of data to verify, such as \"200G\"")
+
+			)
+
+			.arg (
+				clap::Arg::with_name ("fraction")
+
+				.long ("fraction")
+				.value_name ("PERCENTAGE")
+				.required (false)
+				.help ("Percentage of the repository to verify, such as \
+					\"5%\"")
+
+			)
+
+			.arg (
+				clap::Arg::with_name ("max-age")
+
+				.long ("max-age")
+				.value_name ("DURATION")
+				.required (false)
+				.help ("Report anything which has not been verified within \
+					this time, such as \"30 days\"")
+
+			)
+
+			.output_format_args ()
+
+	},
+
+	clap_arguments_parse = |clap_matches| {
+
+		let arguments = ScrubArguments {
+
+			repository_path:
+				args::path_required (
+					& clap_matches,
+					"repository"),
+
+			password_file_path:
+				args::path_optional (
+					& clap_matches,
+					"password-file"),
+
+			budget:
+				args::size_optional (
+					& clap_matches,
+					"budget"),
+
+			fraction:
+				args::string_optional (
+					& clap_matches,
+					"fraction",
+				).map (
+					|fraction_string|
+					parse_percentage (
+						& fraction_string,
+					).unwrap_or_else (
+						|error| args::error_exit (error))
+				),
+
+			max_age:
+				args::duration_optional (
+					& clap_matches,
+					"max-age"),
+
+			output_format:
+				output_format (
+					& clap_matches),
+
+		};
+
+		if arguments.budget.is_none () && arguments.fraction.is_none () {
+
+			args::error_exit (
+				format! (
+					"One of --budget or --fraction is required"));
+
+		}
+
+		arguments
+
+	},
+
+	action = |output, arguments| {
+
+		let events =
+			ConvertEvents::new (
+				"scrub",
+				arguments.output_format);
+
+		events.finish (
+			scrub (output, & events, arguments))
+
+	},
+
+);
+
+// ex: noet ts=4 filetype=rust
@@ -1,9 +1,16 @@
+use std::collections::HashMap;
 use std::collections::HashSet;
 use std::fs;
+use std::fs::File;
 use std::io::Cursor;
+use std::io::Read;
+use std::mem;
 use std::path::Path;
 use std::path::PathBuf;
 use std::slice;
+use std::sync::Arc;
+use std::time::Duration;
+use std::time::SystemTime;
 
 use futures::Future;
 use futures_cpupool::CpuPool;
@@ -14,6 +21,7 @@ use output::Output;
 
 use protobuf::stream::CodedInputStream;
 
+use rust_crypto::digest::Digest;
 use rust_crypto::sha1::Sha1;
 
 use rustc_serialize::hex::ToHex;
@@ -140,36 +148,43 @@ pub fn scan_backup_files <
 	repository_path: RepositoryPath,
 ) -> Result <Vec <PathBuf>, String> {
 
-	let repository_path =
-		repository_path.as_ref ();
+	scan_files_recursive (
+		repository_path.as_ref ().join (
+			"backups"))
 
-	let mut backup_files: Vec <PathBuf> =
-		Vec::new ();
+}
 
-	let backups_root =
-		repository_path.join (
-			"backups");
+/// Lists every file under a directory, as paths relative to it.
+
+pub fn scan_files_recursive <
+	DirectoryPath: AsRef <Path>,
+> (
+	directory_path: DirectoryPath,
+) -> Result <Vec <PathBuf>, String> {
+
+	let mut files: Vec <PathBuf> =
+		Vec::new ();
 
-	scan_backup_files_real (
-		& mut backup_files,
-		& backups_root,
+	scan_files_real (
+		& mut files,
+		directory_path.as_ref (),
 		& PathBuf::new (),
 	) ?;
 
-	Ok (backup_files)
+	Ok (files)
 
 }
 
-fn scan_backup_files_real (
-	backup_files: & mut Vec <PathBuf>,
-	backups_root: & Path,
+fn scan_files_real (
+	files: & mut Vec <PathBuf>,
+	root: & Path,
 	directory: & Path,
 ) -> Result <(), String> {
 
 	for dir_entry_result in (
 		io_result (
 			fs::read_dir (
-				backups_root.join (
+				root.join (
 					directory)))
 	) ? {
 
@@ -186,16 +201,16 @@ fn scan_backup_files_real (
 
 		if entry_metadata.is_dir () {
 
-			scan_backup_files_real (
-				backup_files,
-				backups_root,
+			scan_files_real (
+				files,
+				root,
 				& directory.join (
 					dir_entry.file_name ()),
 			) ?;
 
 		} else if entry_metadata.is_file () {
 
-			backup_files.push (
+			files.push (
 				directory.join (
 					dir_entry.file_name ()));
 
@@ -259,6 +274,12 @@ pub fn scan_bundle_files <
 			let bundle_name =
 				file_name.to_string_lossy ();
 
+			if parity_file_name_matches (
+				& bundle_name,
+			) {
+				continue;
+			}
+
 			match BundleId::parse (
 				& bundle_name,
 			) {
@@ -325,6 +346,12 @@ pub fn scan_bundle_files_with_sizes <
 			let bundle_name =
 				file_name.to_string_lossy ();
 
+			if parity_file_name_matches (
+				& bundle_name,
+			) {
+				continue;
+			}
+
 			let bundle_id =
 				BundleId::parse (
 					bundle_name,
@@ -354,11 +381,84 @@ pub fn scan_bundle_files_with_sizes <
 
 }
 
+/// Checks if a file was modified within the grace period, in which case it may
+/// belong to a backup which a concurrent zbackup process has not finished
+/// writing, and must be left alone.
+
+pub fn file_in_grace_period <
+	FilePath: AsRef <Path>,
+> (
+	file_path: FilePath,
+	grace_period: Option <Duration>,
+) -> Result <bool, String> {
+
+	let file_path =
+		file_path.as_ref ();
+
+	let grace_period =
+		match grace_period {
+			Some (grace_period) => grace_period,
+			None => return Ok (false),
+		};
+
+	let modified_time =
+		io_result_with_prefix (
+			|| format! (
+				"Error reading modification time of {}: ",
+				file_path.to_string_lossy ()),
+			fs::metadata (
+				file_path,
+			).and_then (
+				|metadata| metadata.modified ()
+			),
+		) ?;
+
+	// files with a modification time in the future are treated as new
+
+	Ok (
+		SystemTime::now ().duration_since (
+			modified_time,
+		).map (
+			|age| age < grace_period
+		).unwrap_or (true)
+	)
+
+}
+
+/// Parses a percentage, such as "5%" or "0.5".
+
+pub fn parse_percentage (
+	string_value: & str,
+) -> Result <f64, String> {
+
+	let number_string =
+		string_value.trim ().trim_end_matches ('%').trim ();
+
+	let percentage: f64 =
+		number_string.parse ().map_err (
+			|_| format! (
+				"Invalid percentage: {}",
+				string_value),
+		) ?;
+
+	if ! (percentage > 0.0 && percentage <= 100.0) {
+
+		return Err (
+			format! (
+				"Invalid percentage: {}",
+				string_value));
+
+	}
+
+	Ok (percentage)
+
+}
+
 pub fn flush_index_entries (
 	output: & Output,
 	repository_core: & RepositoryCore,
 	atomic_file_writer: & AtomicFileWriter,
-	index_entries_buffer: & Vec <RawIndexEntry>,
+	index_entries_buffer: & [RawIndexEntry],
 ) -> Result <IndexId, String> {
 
 	let index_id =
@@ -388,11 +488,14 @@ pub fn collect_chunks_from_backup (
 	backup_name: & Path,
 ) -> Result <(), String> {
 
-	collect_chunks_from_backup_real (
+	visit_chunks_from_backup (
 		repository,
-		chunk_ids,
 		backup_name,
 		false,
+		& mut |chunk_id| {
+			chunk_ids.insert (
+				chunk_id);
+		},
 	)
 
 }
@@ -403,20 +506,45 @@ pub fn collect_recursive_chunks_from_backup (
 	backup_name: & Path,
 ) -> Result <(), String> {
 
-	collect_chunks_from_backup_real (
+	visit_chunks_from_backup (
 		repository,
-		chunk_ids,
 		backup_name,
 		true,
+		& mut |chunk_id| {
+			chunk_ids.insert (
+				chunk_id);
+		},
 	)
 
 }
 
-fn collect_chunks_from_backup_real (
+/// Counts the number of times each chunk is read when a backup is restored,
+/// including the chunks used to expand its instructions.
+
+pub fn count_chunks_from_backup (
+	repository: & Repository,
+	chunk_counts: & mut HashMap <ChunkId, u64>,
+	backup_name: & Path,
+) -> Result <(), String> {
+
+	visit_chunks_from_backup (
+		repository,
+		backup_name,
+		false,
+		& mut |chunk_id| {
+			* chunk_counts.entry (
+				chunk_id,
+			).or_insert (0) += 1;
+		},
+	)
+
+}
+
+fn visit_chunks_from_backup (
 	repository: & Repository,
-	chunk_ids: & mut HashSet <ChunkId>,
 	backup_name: & Path,
 	recursive_only: bool,
+	chunk_function: & mut FnMut (ChunkId),
 ) -> Result <(), String> {
 
 	// load backup
@@ -429,11 +557,11 @@ fn collect_chunks_from_backup_real (
 			repository.encryption_key (),
 		) ?;
 
-	// collect chunk ids
+	// visit chunk ids
 
-	collect_chunks_from_instructions (
-		chunk_ids,
+	visit_chunks_from_instructions (
 		& backup_info.backup_data (),
+		chunk_function,
 	) ?;
 
 	// expand backup data
@@ -468,11 +596,11 @@ fn collect_chunks_from_backup_real (
 		let result =
 			temp_output.into_inner ();
 
-		// collect chunk ids
+		// visit chunk ids
 
-		collect_chunks_from_instructions (
-			chunk_ids,
+		visit_chunks_from_instructions (
 			& result,
+			chunk_function,
 		) ?;
 
 		// prepare for next iteration
@@ -492,6 +620,21 @@ pub fn collect_chunks_from_instructions (
 	instructions: & [u8],
 ) -> Result <(), String> {
 
+	visit_chunks_from_instructions (
+		instructions,
+		& mut |chunk_id| {
+			chunk_ids.insert (
+				chunk_id);
+		},
+	)
+
+}
+
+fn visit_chunks_from_instructions (
+	instructions: & [u8],
+	chunk_function: & mut FnMut (ChunkId),
+) -> Result <(), String> {
+
 	let mut instructions_cursor =
 		Cursor::new (
 			& instructions);
@@ -511,7 +654,7 @@ pub fn collect_chunks_from_instructions (
 
 		if backup_instruction.has_chunk_to_emit () {
 
-			chunk_ids.insert (
+			chunk_function (
 				backup_instruction.chunk_to_emit ());
 
 		}
@@ -522,10 +665,17 @@ pub fn collect_chunks_from_instructions (
 
 }
 
+/// Name of the file, in the repository, which holds the chunks referenced by
+/// each backup, so that only new backups need to be expanded to find the
+/// chunks which are in use.
+
+pub const BACKUP_REFERENCES_FILE_NAME: & 'static str = "gc-references";
+
 pub fn get_recursive_chunks (
 	output: & Output,
 	repository: & Repository,
-	backup_files: & Vec <PathBuf>,
+	backup_files: & [PathBuf],
+	backup_references: & mut BackupReferences,
 ) -> Result <HashSet <ChunkId>, String> {
 
 	let output_job =
@@ -535,18 +685,31 @@ pub fn get_recursive_chunks (
 
 	struct State <'a> {
 		backup_files_iterator: slice::Iter <'a, PathBuf>,
+		old_backup_references: BackupReferences,
+		new_backup_references: BackupReferences,
 		backup_chunk_ids: HashSet <ChunkId>,
 		backup_count: u64,
+		expanded_count: u64,
 	}
 
 	let mut state = State {
 		backup_files_iterator: backup_files.iter (),
+		old_backup_references: mem::take (
+			backup_references),
+		new_backup_references: HashMap::new (),
 		backup_chunk_ids: HashSet::new (),
 		backup_count: 0,
+		expanded_count: 0,
 	};
 
 	let backup_total = backup_files.len () as u64;
 
+	let known_checksums: Arc <HashSet <[u8; 20]>> =
+		Arc::new (
+			state.old_backup_references.keys ().map (
+				|& checksum| checksum
+			).collect ());
+
 	let num_threads =
 		(num_cpus::get () - 1) * 5 / 3 + 1;
 
@@ -576,12 +739,29 @@ pub fn get_recursive_chunks (
 				let repository =
 					repository.clone ();
 
+				let known_checksums =
+					known_checksums.clone ();
+
 				let backup_file =
 					backup_file.to_owned ();
 
 				Some (
 					cpu_pool.spawn_fn (move || {
 
+						let checksum =
+							backup_file_checksum (
+								& repository.path ()
+									.join ("backups")
+									.join (& backup_file),
+							) ?;
+
+						// reuse the chunk list if we have seen this backup
+						// before
+
+						if known_checksums.contains (& checksum) {
+							return Ok ((checksum, None));
+						}
+
 						let mut backup_chunk_ids: HashSet <ChunkId> =
 							HashSet::new ();
 
@@ -599,7 +779,7 @@ pub fn get_recursive_chunks (
 
 						output_job.remove ();
 
-						Ok (backup_chunk_ids)
+						Ok ((checksum, Some (backup_chunk_ids)))
 
 					}).boxed ()
 				)
@@ -612,15 +792,45 @@ pub fn get_recursive_chunks (
 
 		},
 
-		|state, backup_chunk_ids| {
+		|state, (checksum, backup_chunk_ids)| {
+
+			let chunk_ids: Vec <ChunkId> =
+				match backup_chunk_ids {
+
+				Some (backup_chunk_ids) => {
+
+					state.expanded_count += 1;
+
+					let mut chunk_ids: Vec <ChunkId> =
+						backup_chunk_ids.into_iter ().collect ();
+
+					chunk_ids.sort ();
 
-			for backup_chunk_id in backup_chunk_ids {
+					chunk_ids
+
+				},
+
+				None =>
+					state.old_backup_references.remove (
+						& checksum,
+					).or_else (
+						|| state.new_backup_references.remove (
+							& checksum),
+					).unwrap (),
+
+			};
+
+			for & chunk_id in chunk_ids.iter () {
 
 				state.backup_chunk_ids.insert (
-					backup_chunk_id);
+					chunk_id);
 
 			}
 
+			state.new_backup_references.insert (
+				checksum,
+				chunk_ids);
+
 			Ok (())
 
 		},
@@ -632,10 +842,127 @@ pub fn get_recursive_chunks (
 		"Found {} chunks required to expand backups",
 		state.backup_chunk_ids.len ());
 
+	if state.expanded_count < backup_total {
+
+		output_message! (
+			output,
+			"Expanded {} new backups, reused chunk lists for {} unchanged",
+			state.expanded_count,
+			backup_total - state.expanded_count);
+
+	}
+
+	// entries for backups which no longer exist are left behind in the old
+	// references, and so are dropped here
+
+	* backup_references =
+		state.new_backup_references;
+
 	Ok (state.backup_chunk_ids)
 
 }
 
+fn backup_file_checksum (
+	backup_path: & Path,
+) -> Result <[u8; 20], String> {
+
+	let mut backup_file =
+		io_result_with_prefix (
+			|| format! (
+				"Error opening {}: ",
+				backup_path.to_string_lossy ()),
+			File::open (
+				backup_path),
+		) ?;
+
+	let mut backup_bytes: Vec <u8> =
+		Vec::new ();
+
+	io_result_with_prefix (
+		|| format! (
+			"Error reading {}: ",
+			backup_path.to_string_lossy ()),
+		backup_file.read_to_end (
+			& mut backup_bytes),
+	) ?;
+
+	let mut sha1_digest =
+		Sha1::new ();
+
+	sha1_digest.input (
+		& backup_bytes);
+
+	let mut checksum: [u8; 20] =
+		[0u8; 20];
+
+	sha1_digest.result (
+		& mut checksum);
+
+	Ok (checksum)
+
+}
+
+/// Reads the chunks referenced by each backup, saved by a previous run. If the
+/// file is missing or can't be read, it is ignored, and every backup will be
+/// expanded again.
+
+pub fn read_backup_references (
+	output: & Output,
+	repository_core: & RepositoryCore,
+	rebuild: bool,
+) -> BackupReferences {
+
+	let references_path =
+		repository_core.path ().join (
+			BACKUP_REFERENCES_FILE_NAME);
+
+	if rebuild || ! references_path.exists () {
+		return HashMap::new ();
+	}
+
+	match references_read_path (
+		& references_path,
+		repository_core.encryption_key (),
+	) {
+
+		Ok (backup_references) =>
+			backup_references,
+
+		Err (error) => {
+
+			output_message! (
+				output,
+				"Ignoring saved backup references: {}",
+				error);
+
+			HashMap::new ()
+
+		},
+
+	}
+
+}
+
+pub fn write_backup_references (
+	repository_core: & RepositoryCore,
+	atomic_file_writer: & AtomicFileWriter,
+	backup_references: & BackupReferences,
+) -> Result <(), String> {
+
+	let mut references_file =
+		atomic_file_writer.create (
+			repository_core.path ().join (
+				BACKUP_REFERENCES_FILE_NAME),
+		) ?;
+
+	references_write_direct (
+		& mut references_file,
+		repository_core.encryption_key (),
+		backup_references,
+	)
+
+}
+
 pub fn scan_backups (
 	output: & Output,
 	repository_path: & Path,
@@ -695,4 +1022,28 @@ macro_rules! scan_indexes_with_sizes {
 }
 
 
+#[ cfg (test) ]
+mod tests {
+
+	use super::*;
+
+	#[ test ]
+	fn parse_percentage_values () {
+
+		assert_eq! (parse_percentage ("5%"), Ok (5.0));
+		assert_eq! (parse_percentage (" 5 % "), Ok (5.0));
+		assert_eq! (parse_percentage ("0.5"), Ok (0.5));
+		assert_eq! (parse_percentage ("100%"), Ok (100.0));
+
+		assert! (parse_percentage ("0%").is_err ());
+		assert! (parse_percentage ("100.1%").is_err ());
+		assert! (parse_percentage ("-5%").is_err ());
+		assert! (parse_percentage ("five").is_err ());
+		assert! (parse_percentage ("%").is_err ());
+		assert! (parse_percentage ("NaN").is_err ());
+
+	}
+
+}
+
 // ex: noet ts=4 filetype=rust
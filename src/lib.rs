@@ -15,6 +15,29 @@
 
 #![ allow (unused_parens) ]
 
+// these lints conflict with the house style, which uses 2015 edition trait
+// objects, spaced out borrows and patterns, field names spelled out in full,
+// explicit loop counters and indexes, and long argument lists
+
+#![ allow (bare_trait_objects) ]
+
+#![ allow (
+	clippy::empty_line_after_doc_comments,
+	clippy::explicit_counter_loop,
+	clippy::map_clone,
+	clippy::module_inception,
+	clippy::needless_borrow,
+	clippy::needless_borrowed_reference,
+	clippy::needless_range_loop,
+	clippy::redundant_closure,
+	clippy::redundant_field_names,
+	clippy::redundant_static_lifetimes,
+	clippy::too_many_arguments,
+	clippy::type_complexity,
+	clippy::useless_format,
+	clippy::write_with_newline,
+) ]
+
 #[ macro_use ]
 extern crate lazy_static;
 
@@ -29,7 +52,6 @@ extern crate errno;
 extern crate futures;
 extern crate futures_cpupool;
 extern crate libc;
-extern crate lru_cache;
 extern crate minilzo;
 extern crate num_cpus;
 extern crate protobuf;
@@ -50,6 +72,9 @@ pub mod commands;
 #[ doc (hidden) ]
 pub mod convert;
 
+#[ doc (hidden) ]
+pub mod nbd;
+
 #[ doc (hidden) ]
 pub mod server;
 
@@ -59,6 +84,7 @@ mod zbackup;
 
 pub use metadata::*;
 pub use misc::AtomicFileWriter;
+pub use zbackup::cache_tier::CachePolicy as ZBackupCachePolicy;
 pub use zbackup::crypto;
 pub use zbackup::data::*;
 pub use zbackup::disk_format;
@@ -1,17 +1,42 @@
 #![ allow (unused_parens) ]
 
+// these lints conflict with the house style, which uses 2015 edition trait
+// objects, spaced out borrows and patterns, field names spelled out in full,
+// explicit loop counters and indexes, and long argument lists
+
+#![ allow (bare_trait_objects) ]
+
+#![ allow (
+	clippy::empty_line_after_doc_comments,
+	clippy::explicit_counter_loop,
+	clippy::map_clone,
+	clippy::module_inception,
+	clippy::needless_borrow,
+	clippy::needless_borrowed_reference,
+	clippy::needless_range_loop,
+	clippy::redundant_closure,
+	clippy::redundant_field_names,
+	clippy::redundant_static_lifetimes,
+	clippy::too_many_arguments,
+	clippy::type_complexity,
+	clippy::useless_format,
+	clippy::write_with_newline,
+) ]
+
 extern crate clap;
 extern crate output;
 extern crate rand;
 extern crate rustc_serialize;
 extern crate rzbackup;
 
+use std::path::PathBuf;
 use std::process;
 
 use rzbackup::client::*;
 use rzbackup::commands::*;
 use rzbackup::convert::*;
 use rzbackup::misc::*;
+use rzbackup::nbd::*;
 use rzbackup::server::*;
 
 fn main () {
@@ -20,9 +45,13 @@ fn main () {
 		output::open ();
 
 	let commands = vec! [
+		audit_command (),
 		client_command (),
+		compare_command (),
+		config_command (),
 		convert_command (),
 		decrypt_command (),
+		nbd_serve_command (),
 		restore_command (),
 		server_command (),
 	];
@@ -76,7 +105,18 @@ fn parse_arguments (
 		clap::App::new ("RZBackup")
 			.version (rzbackup::VERSION)
 			.author (rzbackup::AUTHOR)
-			.about ("Backup tool compatible with ZBackup"),
+			.about ("Backup tool compatible with ZBackup")
+
+			.arg (
+				clap::Arg::with_name ("config")
+
+				.long ("config")
+				.value_name ("CONFIG-FILE")
+				.global (true)
+				.help ("Config file with repositories and default options. \
+					Options given on the command line take precedence.")
+
+			),
 
 		|clap_application, command|
 
@@ -89,6 +129,9 @@ fn parse_arguments (
 	let clap_matches =
 		clap_application.clone ().get_matches ();
 
+	load_config (
+		& clap_matches);
+
 	commands.iter ().map (
 		|command|
 
@@ -110,12 +153,12 @@ fn parse_arguments (
 
 	).unwrap_or_else (|| {
 
-		println! ("");
+		println! ();
 
 		clap_application.print_help ().unwrap ();
 
-		println! ("");
-		println! ("");
+		println! ();
+		println! ();
 
 		process::exit (0);
 
@@ -123,4 +166,51 @@ fn parse_arguments (
 
 }
 
+/// Loads the config file, if one was given. The option may appear before or
+/// after the subcommand names, so each level is checked.
+
+fn load_config (
+	clap_matches: & clap::ArgMatches,
+) {
+
+	let mut config_path: Option <PathBuf> =
+		clap_matches.value_of_os ("config").map (PathBuf::from);
+
+	let mut command_path: Vec <& str> =
+		Vec::new ();
+
+	let mut command_matches =
+		clap_matches;
+
+	while let (command_name, Some (subcommand_matches)) =
+		command_matches.subcommand () {
+
+		command_path.push (
+			command_name);
+
+		if let Some (path) = subcommand_matches.value_of_os ("config") {
+			config_path = Some (PathBuf::from (path));
+		}
+
+		command_matches =
+			subcommand_matches;
+
+	}
+
+	if let Some (config_path) = config_path {
+
+		args::load_config (
+			& config_path,
+			& command_path,
+			command_matches,
+		).unwrap_or_else (
+			|error|
+			args::error_exit (
+				error)
+		);
+
+	}
+
+}
+
 // ex: noet ts=4 filetype=rust
@@ -0,0 +1,231 @@
+use std::fs::File;
+use std::io::Read;
+use std::path::Path;
+
+use rust_crypto::digest::Digest;
+use rust_crypto::sha2::Sha256;
+
+use rustc_serialize::hex::ToHex;
+
+use ::misc::*;
+
+/// While this file exists in the repository, it is append-only, and nothing in
+/// the protected directories may be removed or overwritten. It holds the
+/// SHA-256 hash of the key which is needed to unlock the repository again.
+
+pub const APPEND_ONLY_FILE_NAME: & 'static str = "append-only";
+
+/// Directories whose existing files can't be changed in append-only mode. New
+/// files may still be added to them.
+
+pub const APPEND_ONLY_DIRECTORIES: & 'static [& 'static str] = & [
+	"backups",
+	"bundles",
+	"index",
+];
+
+const APPEND_ONLY_FILE_HEADER: & 'static str = "rzbackup append-only 1";
+
+/// Unlock keys shorter than this are refused, since the hash of a short key is
+/// easily reversed.
+
+pub const APPEND_ONLY_MIN_KEY_SIZE: usize = 16;
+
+#[ inline ]
+pub fn append_only_enabled (
+	repository_path: & Path,
+) -> bool {
+
+	repository_path.join (
+		APPEND_ONLY_FILE_NAME,
+	).exists ()
+
+}
+
+/// Checks if a path is one which append-only mode protects, which includes the
+/// append-only file itself.
+
+pub fn append_only_protects (
+	repository_path: & Path,
+	target_path: & Path,
+) -> bool {
+
+	target_path == repository_path.join (APPEND_ONLY_FILE_NAME)
+	|| APPEND_ONLY_DIRECTORIES.iter ().any (
+		|directory_name|
+		target_path.starts_with (
+			repository_path.join (
+				directory_name))
+	)
+
+}
+
+/// Returns the contents of the append-only file, for the given unlock key.
+
+pub fn append_only_file_contents (
+	unlock_key: & [u8],
+) -> String {
+
+	format! (
+		"{}\nunlock-key-sha256 {}\n",
+		APPEND_ONLY_FILE_HEADER,
+		append_only_key_hash (unlock_key).to_hex ())
+
+}
+
+/// Checks the unlock key against the hash in the repository's append-only
+/// file.
+
+pub fn append_only_verify_key (
+	repository_path: & Path,
+	unlock_key: & [u8],
+) -> Result <(), String> {
+
+	let append_only_path =
+		repository_path.join (
+			APPEND_ONLY_FILE_NAME);
+
+	let mut append_only_file =
+		io_result_with_prefix (
+			|| format! (
+				"Error opening {}: ",
+				append_only_path.to_string_lossy ()),
+			File::open (
+				& append_only_path),
+		) ?;
+
+	let mut append_only_contents =
+		String::new ();
+
+	io_result_with_prefix (
+		|| format! (
+			"Error reading {}: ",
+			append_only_path.to_string_lossy ()),
+		append_only_file.read_to_string (
+			& mut append_only_contents),
+	) ?;
+
+	let mut lines =
+		append_only_contents.lines ();
+
+	if lines.next () != Some (APPEND_ONLY_FILE_HEADER) {
+
+		return Err (
+			format! (
+				"Error reading {}: Invalid header",
+				append_only_path.to_string_lossy ()));
+
+	}
+
+	let expected_hash =
+		lines.next ().and_then (
+			|line| {
+
+			let line_parts: Vec <& str> =
+				line.split (' ').collect ();
+
+			if line_parts.len () == 2
+				&& line_parts [0] == "unlock-key-sha256" {
+
+				Some (line_parts [1].to_string ())
+
+			} else { None }
+
+		}).ok_or_else (
+			|| format! (
+				"Error reading {}: Invalid contents",
+				append_only_path.to_string_lossy ()),
+		) ?;
+
+	// compare every byte, so the time taken says nothing about the key
+
+	let actual_hash =
+		append_only_key_hash (
+			unlock_key,
+		).to_hex ();
+
+	let difference =
+		actual_hash.bytes ().zip (
+			expected_hash.bytes (),
+		).fold (
+			(actual_hash.len () != expected_hash.len ()) as u8,
+			|difference, (actual_byte, expected_byte)|
+			difference | (actual_byte ^ expected_byte)
+		);
+
+	if difference != 0 {
+
+		return Err (
+			format! (
+				"The unlock key does not match the one for repository {}",
+				repository_path.to_string_lossy ()));
+
+	}
+
+	Ok (())
+
+}
+
+/// Reads an unlock key from a file, which must hold at least
+/// `APPEND_ONLY_MIN_KEY_SIZE` bytes. It is not the repository password, and
+/// should be kept away from the machines which write backups.
+
+pub fn append_only_read_key (
+	key_path: & Path,
+) -> Result <Vec <u8>, String> {
+
+	let mut key_file =
+		io_result_with_prefix (
+			|| format! (
+				"Error opening {}: ",
+				key_path.to_string_lossy ()),
+			File::open (
+				key_path),
+		) ?;
+
+	let mut unlock_key: Vec <u8> =
+		Vec::new ();
+
+	io_result_with_prefix (
+		|| format! (
+			"Error reading {}: ",
+			key_path.to_string_lossy ()),
+		key_file.read_to_end (
+			& mut unlock_key),
+	) ?;
+
+	if unlock_key.len () < APPEND_ONLY_MIN_KEY_SIZE {
+
+		return Err (
+			format! (
+				"Unlock key in {} is too short, it must be at least {} bytes",
+				key_path.to_string_lossy (),
+				APPEND_ONLY_MIN_KEY_SIZE));
+
+	}
+
+	Ok (unlock_key)
+
+}
+
+fn append_only_key_hash (
+	unlock_key: & [u8],
+) -> [u8; 32] {
+
+	let mut digest =
+		Sha256::new ();
+
+	digest.input (
+		unlock_key);
+
+	let mut hash: [u8; 32] =
+		[0u8; 32];
+
+	digest.result (
+		& mut hash);
+
+	hash
+
+}
+
+// ex: noet ts=4 filetype=rust
@@ -1,4 +1,7 @@
+use std::path::Path;
 use std::path::PathBuf;
+use std::sync::Arc;
+use std::sync::Mutex;
 use std::time::Duration;
 
 use clap;
@@ -7,27 +10,263 @@ use num_cpus;
 
 use regex::Regex;
 
+use misc::config::*;
+
+use zbackup::cache_tier::CachePolicy;
 use zbackup::repository::*;
 
-pub fn bool_flag (
+/// Settings from the config file given with `--config`, if any. Command line
+/// arguments which are not given explicitly are looked up here, before falling
+/// back to their defaults.
+
+struct ArgsConfig {
+	config_file: Arc <ConfigFile>,
+	section_names: Vec <String>,
+	repository_name: Option <String>,
+}
+
+lazy_static! {
+
+	static ref ARGS_CONFIG: Mutex <Option <ArgsConfig>> =
+		Mutex::new (None);
+
+}
+
+/// Loads the config file for a command, identified by the names of the
+/// subcommands used to invoke it, such as `["convert", "gc-bundles"]`. Values
+/// are then taken from the `[convert.gc-bundles]` section, then `[convert]`.
+///
+/// If the repository is given as the name of a `[repositories.NAME]` section,
+/// instead of a path, its path is taken from the section, and the section is
+/// also used for any values not found in the command's sections.
+
+pub fn load_config (
+	config_path: & Path,
+	command_path: & [& str],
+	clap_matches: & clap::ArgMatches,
+) -> Result <(), String> {
+
+	let config_file =
+		ConfigFile::read (
+			config_path,
+		) ?;
+
+	let section_names: Vec <String> =
+		(0 .. command_path.len ()).map (
+			|index|
+			command_path [0 .. command_path.len () - index].join (".")
+		).collect ();
+
+	* ARGS_CONFIG.lock ().unwrap () =
+		Some (ArgsConfig {
+			config_file: Arc::new (config_file),
+			section_names: section_names,
+			repository_name: None,
+		});
+
+	let repository =
+		string_optional (
+			clap_matches,
+			"repository");
+
+	let mut args_config_lock =
+		ARGS_CONFIG.lock ().unwrap ();
+
+	let args_config =
+		args_config_lock.as_mut ().unwrap ();
+
+	if let Some (repository_name) = repository {
+
+		let section_name =
+			format! (
+				"repositories.{}",
+				repository_name);
+
+		let repository_has_path =
+			args_config.config_file.section (
+				& section_name,
+			).map (
+				|section| section.get ("path").is_some ()
+			);
+
+		match repository_has_path {
+
+			Some (true) => {
+
+				args_config.section_names.push (
+					section_name);
+
+				args_config.repository_name =
+					Some (repository_name);
+
+			},
+
+			Some (false) =>
+				return Err (
+					format! (
+						"No path for repository {}",
+						repository_name)),
+
+			None =>
+				(),
+
+		}
+
+	}
+
+	Ok (())
+
+}
+
+/// Returns the config file loaded with `load_config`, if any.
+
+pub fn config_file (
+) -> Option <Arc <ConfigFile>> {
+
+	ARGS_CONFIG.lock ().unwrap ().as_ref ().map (
+		|args_config| args_config.config_file.clone ())
+
+}
+
+/// Returns the name of the repository section, if the repository was given by
+/// name rather than as a path.
+
+pub fn config_repository_name (
+) -> Option <String> {
+
+	ARGS_CONFIG.lock ().unwrap ().as_ref ().and_then (
+		|args_config| args_config.repository_name.clone ())
+
+}
+
+fn config_value (
+	name: & str,
+) -> Option <ConfigValue> {
+
+	let args_config_lock =
+		ARGS_CONFIG.lock ().unwrap ();
+
+	let args_config =
+		match * args_config_lock {
+			Some (ref args_config) => args_config,
+			None => return None,
+		};
+
+	if name == "repository" {
+
+		if let Some (ref repository_name) = args_config.repository_name {
+
+			return args_config.config_file.section (
+				& format! (
+					"repositories.{}",
+					repository_name),
+			).and_then (
+				|section| section.get ("path"),
+			).cloned ();
+
+		}
+
+	}
+
+	args_config.section_names.iter ().filter_map (
+		|section_name|
+		args_config.config_file.section (
+			section_name)
+	).filter_map (
+		|section|
+		section.get (
+			name)
+	).next ().cloned ()
+
+}
+
+/// Returns true if an argument was given on the command line, or in the config
+/// file, rather than having its default value.
+
+pub fn is_explicit (
 	matches: & clap::ArgMatches,
 	name: & str,
 ) -> bool {
 
-	matches.is_present (
+	matches.occurrences_of (name) > 0
+		|| config_value (name).is_some ()
+
+}
+
+/// Returns the value for an argument from the config file, unless it was given
+/// on the command line. A repository given by name, either way, is always
+/// replaced with the path from its section.
+
+fn config_value_unless_given (
+	matches: & clap::ArgMatches,
+	name: & str,
+) -> Option <ConfigValue> {
+
+	let repository_named =
+		name == "repository"
+		&& config_repository_name ().is_some ();
+
+	if repository_named || matches.occurrences_of (name) == 0 {
+		config_value (name)
+	} else {
+		None
+	}
+
+}
+
+fn value_string (
+	matches: & clap::ArgMatches,
+	name: & str,
+) -> Option <String> {
+
+	if let Some (value) = config_value_unless_given (matches, name) {
+		return Some (value.to_string ());
+	}
+
+	matches.value_of (
 		name,
+	).map (
+		String::from,
 	)
 
 }
 
+pub fn bool_flag (
+	matches: & clap::ArgMatches,
+	name: & str,
+) -> bool {
+
+	if matches.is_present (name) {
+		return true;
+	}
+
+	match config_value (name) {
+
+		Some (ConfigValue::Boolean (value)) =>
+			value,
+
+		Some (_) =>
+			error_exit (
+				format! (
+					"Invalid value for {} in config file, must be true or false",
+					name)),
+
+		None =>
+			false,
+
+	}
+
+}
+
 pub fn u64_required (
 	matches: & clap::ArgMatches,
 	name: & str,
 ) -> u64 {
 
-	matches.value_of (
+	string_required (
+		matches,
 		name,
-	).unwrap ().parse::<u64> ().unwrap_or_else (
+	).parse::<u64> ().unwrap_or_else (
 		|_|
 
 		error_exit (
@@ -44,10 +283,17 @@ pub fn string_required (
 	name: & str,
 ) -> String {
 
-	String::from (
-		matches.value_of (
-			name,
-		).unwrap ()
+	value_string (
+		matches,
+		name,
+	).unwrap_or_else (
+		||
+
+		error_exit (
+			format! (
+				"Missing value for --{}",
+				name))
+
 	)
 
 }
@@ -57,10 +303,9 @@ pub fn string_optional (
 	name: & str,
 ) -> Option <String> {
 
-	matches.value_of (
+	value_string (
+		matches,
 		name,
-	).map (
-		String::from,
 	)
 
 }
@@ -70,10 +315,17 @@ pub fn path_required (
 	name: & str,
 ) -> PathBuf {
 
-	PathBuf::from (
-		matches.value_of_os (
-			name,
-		).unwrap ()
+	path_optional (
+		matches,
+		name,
+	).unwrap_or_else (
+		||
+
+		error_exit (
+			format! (
+				"Missing value for --{}",
+				name))
+
 	)
 
 }
@@ -83,6 +335,12 @@ pub fn path_optional (
 	name: & str,
 ) -> Option <PathBuf> {
 
+	// paths on the command line may not be valid unicode
+
+	if let Some (value) = config_value_unless_given (matches, name) {
+		return Some (PathBuf::from (value.to_string ()));
+	}
+
 	matches.value_of_os (
 		name,
 	).map (
@@ -100,6 +358,85 @@ pub fn duration_required (
 	name: & str,
 ) -> Duration {
 
+	parse_duration (
+		& string_required (
+			matches,
+			name),
+	).unwrap_or_else (
+		|_|
+
+		error_exit (
+			format! (
+				"Invalid value for --{}",
+				name))
+
+	)
+
+}
+
+pub fn duration_optional (
+	matches: & clap::ArgMatches,
+	name: & str,
+) -> Option <Duration> {
+
+	string_optional (
+		matches,
+		name,
+	).map (
+		|string_value|
+
+		parse_duration (
+			& string_value,
+		).unwrap_or_else (
+			|_|
+
+			error_exit (
+				format! (
+					"Invalid value for --{}",
+					name))
+
+		)
+
+	)
+
+}
+
+/// Parses an optional size in bytes, with an optional binary suffix, such as
+/// "512M" or "4G".
+
+pub fn size_optional (
+	matches: & clap::ArgMatches,
+	name: & str,
+) -> Option <u64> {
+
+	string_optional (
+		matches,
+		name,
+	).map (
+		|string_value|
+
+		parse_size (
+			& string_value,
+		).unwrap_or_else (
+			|_|
+
+			error_exit (
+				format! (
+					"Invalid value for --{}",
+					name))
+
+		)
+
+	)
+
+}
+
+/// Parses a duration, such as "30 seconds" or "5m".
+
+pub fn parse_duration (
+	string_value: & str,
+) -> Result <Duration, String> {
+
 	lazy_static! {
 
 		static ref DURATION_REGEX: Regex =
@@ -107,63 +444,108 @@ pub fn duration_required (
 				r"^(0|[1-9][0-9]*)\s*(ms|millisecond|milliseconds|s|sec|secs|second|seconds|m|min|mins|minute|minutes|h|hr|hrs|hour|hours|d|day|days)$",
 			).unwrap ();
 
-    }
-
-	let string_value =
-		string_required (
-			matches,
-			name,
-		);
+	}
 
-	if let Some (captures) =
+	let captures =
 		DURATION_REGEX.captures (
-			& string_value,
-		) {
+			string_value,
+		).ok_or_else (
+			|| format! (
+				"Invalid duration: {}",
+				string_value),
+		) ?;
 
-		let quantity: u64 =
-			captures.get (1).unwrap ().as_str ().parse ().unwrap ();
+	let quantity: u64 =
+		captures.get (1).unwrap ().as_str ().parse ().map_err (
+			|_| format! (
+				"Invalid duration: {}",
+				string_value),
+		) ?;
 
-		let units_str =
-			captures.get (2).unwrap ().as_str ();
+	let units_str =
+		captures.get (2).unwrap ().as_str ();
 
-		match units_str {
+	Ok (match units_str {
 
-			"ms" | "millisecond" | "milliseconds" =>
-				Duration::from_millis (
-					quantity),
+		"ms" | "millisecond" | "milliseconds" =>
+			Duration::from_millis (
+				quantity),
 
-			"s" | "sec" | "secs" | "second" | "seconds" =>
-				Duration::from_secs (
-					quantity),
+		"s" | "sec" | "secs" | "second" | "seconds" =>
+			Duration::from_secs (
+				quantity),
 
-			"m" | "min" | "mins" | "minute" | "minutes" =>
-				Duration::from_secs (
-					quantity * 60),
+		"m" | "min" | "mins" | "minute" | "minutes" =>
+			Duration::from_secs (
+				quantity * 60),
 
-			"h" | "hr" | "hrs" | "hour" | "hours" =>
-				Duration::from_secs (
-					quantity * 60 * 60),
+		"h" | "hr" | "hrs" | "hour" | "hours" =>
+			Duration::from_secs (
+				quantity * 60 * 60),
 
-			"d" | "day" | "days" =>
-				Duration::from_secs (
-					quantity * 60 * 60 * 24),
+		"d" | "day" | "days" =>
+			Duration::from_secs (
+				quantity * 60 * 60 * 24),
 
-			_ =>
-				panic! (
-					"Internal error parsing duration: {}",
-					string_value),
+		_ =>
+			panic! (
+				"Internal error parsing duration: {}",
+				string_value),
 
-		}
+	})
 
-	} else {
+}
 
-		error_exit (
-			format! (
-				"Invalid value for --{}",
-				name))
+/// Parses a size in bytes, with an optional binary suffix, such as "512M" or
+/// "4G".
+
+pub fn parse_size (
+	string_value: & str,
+) -> Result <u64, String> {
+
+	lazy_static! {
+
+		static ref SIZE_REGEX: Regex =
+			Regex::new (
+				r"^(0|[1-9][0-9]*)\s*([kKmMgGtT]?)(i?[bB])?$",
+			).unwrap ();
 
 	}
 
+	let invalid_size = || format! (
+		"Invalid size: {}",
+		string_value);
+
+	let captures =
+		SIZE_REGEX.captures (
+			string_value,
+		).ok_or_else (
+			& invalid_size,
+		) ?;
+
+	let quantity: u64 =
+		captures.get (1).unwrap ().as_str ().parse ().map_err (
+			|_| invalid_size (),
+		) ?;
+
+	let multiplier: u64 =
+		match captures.get (2).unwrap ().as_str () {
+			"" => 1,
+			"k" | "K" => 1 << 10,
+			"m" | "M" => 1 << 20,
+			"g" | "G" => 1 << 30,
+			"t" | "T" => 1 << 40,
+			_ => panic! (
+				"Internal error parsing size: {}",
+				string_value),
+		};
+
+	quantity.checked_mul (
+		multiplier,
+	).ok_or_else (
+		invalid_size,
+	)
+
 }
 
 pub fn error_exit (
@@ -251,6 +633,64 @@ for clap::App <'a, 'b> {
 
 			)
 
+			.arg (
+				clap::Arg::with_name ("memory-cache-size")
+
+				.long ("memory-cache-size")
+				.value_name ("SIZE")
+				.required (false)
+				.help ("Total size of the in-memory caches, such as 4G. This is \
+					split between the uncompressed and compressed caches, \
+					unless they are given their own sizes.")
+
+			)
+
+			.arg (
+				clap::Arg::with_name ("uncompressed-memory-cache-size")
+
+				.long ("uncompressed-memory-cache-size")
+				.value_name ("SIZE")
+				.required (false)
+				.help ("Maximum size of the uncompressed in-memory cache, such \
+					as 512M.")
+
+			)
+
+			.arg (
+				clap::Arg::with_name ("compressed-memory-cache-size")
+
+				.long ("compressed-memory-cache-size")
+				.value_name ("SIZE")
+				.required (false)
+				.help ("Maximum size of the compressed in-memory cache, such as \
+					4G.")
+
+			)
+
+			.arg (
+				clap::Arg::with_name ("filesystem-cache-size")
+
+				.long ("filesystem-cache-size")
+				.value_name ("SIZE")
+				.required (false)
+				.help ("Maximum size of the on-disk cache, such as 100G.")
+
+			)
+
+			.arg (
+				clap::Arg::with_name ("cache-policy")
+
+				.long ("cache-policy")
+				.value_name ("POLICY")
+				.possible_values (& ["lru", "2q"])
+				.default_value ("lru")
+				.help ("Eviction policy for the chunk caches. The 2q policy \
+					keeps chunks which have only been used once in a small \
+					probationary queue, so that large one-off restores do \
+					not flush chunks which are used regularly.")
+
+			)
+
 			.arg (
 				clap::Arg::with_name ("max-threads")
 
@@ -279,34 +719,104 @@ for clap::App <'a, 'b> {
 
 			)
 
+			.arg (
+				clap::Arg::with_name ("index-cache-path")
+
+				.long ("index-cache-path")
+				.value_name ("PATH")
+				.required (false)
+				.help ("Location of a persistent index cache file. If provided, \
+					the compact index table is saved here and memory-mapped on \
+					later startups, and only rebuilt when the repository's \
+					indexes or bundles change.")
+
+			)
+
 
 	}
 
 }
 
+/// Splits a combined memory cache size between the uncompressed and compressed
+/// memory caches, one to seven, like the default entry limits.
+
+pub fn memory_cache_size_split (
+	memory_cache_size: u64,
+) -> (u64, u64) {
+
+	(
+		memory_cache_size / 8,
+		memory_cache_size / 8 * 7,
+	)
+
+}
+
 pub fn repository_config (
 	clap_matches: & clap::ArgMatches,
 ) -> RepositoryConfig {
 
+	let memory_cache_size =
+		size_optional (
+			clap_matches,
+			"memory-cache-size");
+
+	let max_uncompressed_memory_cache_size =
+		size_optional (
+			clap_matches,
+			"uncompressed-memory-cache-size",
+		).or (
+			memory_cache_size.map (|size| memory_cache_size_split (size).0),
+		);
+
+	let max_compressed_memory_cache_size =
+		size_optional (
+			clap_matches,
+			"compressed-memory-cache-size",
+		).or (
+			memory_cache_size.map (|size| memory_cache_size_split (size).1),
+		);
+
+	let max_compressed_filesystem_cache_size =
+		size_optional (
+			clap_matches,
+			"filesystem-cache-size");
+
 	RepositoryConfig {
 
 		max_uncompressed_memory_cache_entries:
-			u64_required (
+			cache_entries (
 				clap_matches,
 				"max-uncompressed-memory-cache-entries",
-			) as usize,
+				max_uncompressed_memory_cache_size),
 
 		max_compressed_memory_cache_entries:
-			u64_required (
+			cache_entries (
 				clap_matches,
 				"max-compressed-memory-cache-entries",
-			) as usize,
+				max_compressed_memory_cache_size),
 
 		max_compressed_filesystem_cache_entries:
-			u64_required (
+			cache_entries (
 				clap_matches,
 				"max-compressed-filesystem-cache-entries",
-			) as usize,
+				max_compressed_filesystem_cache_size),
+
+		max_uncompressed_memory_cache_size:
+			max_uncompressed_memory_cache_size,
+
+		max_compressed_memory_cache_size:
+			max_compressed_memory_cache_size,
+
+		max_compressed_filesystem_cache_size:
+			max_compressed_filesystem_cache_size,
+
+		cache_policy:
+			CachePolicy::parse (
+				& string_required (
+					clap_matches,
+					"cache-policy"),
+			).unwrap_or_else (
+				|error| error_exit (error)),
 
 		max_threads:
 			u64_required (
@@ -320,6 +830,15 @@ pub fn repository_config (
 				"filesystem-cache-path",
 			).to_string_lossy ().to_string (),
 
+		index_cache_path:
+			path_optional (
+				clap_matches,
+				"index-cache-path",
+			).map (
+				|index_cache_path|
+				index_cache_path.to_string_lossy ().to_string ()
+			),
+
 		work_jobs_total: 0, // deprecated and ignored
 		work_jobs_batch: 0, // deprecated and ignored
 
@@ -327,4 +846,367 @@ pub fn repository_config (
 
 }
 
+/// Keys for the repository settings which may be given in a configuration
+/// file. These have the same names as the command line options.
+
+pub const REPOSITORY_CONFIG_KEYS: & 'static [& 'static str] = & [
+	"max-uncompressed-memory-cache-entries",
+	"max-compressed-memory-cache-entries",
+	"max-compressed-filesystem-cache-entries",
+	"memory-cache-size",
+	"uncompressed-memory-cache-size",
+	"compressed-memory-cache-size",
+	"filesystem-cache-size",
+	"cache-policy",
+	"max-threads",
+	"filesystem-cache-path",
+	"index-cache-path",
+];
+
+/// Applies the repository settings in a configuration file section to an
+/// existing config, such as the one built from the command line. Sizes and
+/// entry limits interact in the same way as on the command line.
+
+pub fn repository_config_section (
+	section: & ConfigSection,
+	base: & RepositoryConfig,
+) -> Result <RepositoryConfig, String> {
+
+	let memory_cache_size =
+		section.get_size (
+			"memory-cache-size",
+		) ?;
+
+	let uncompressed_memory_cache_size =
+		section.get_size (
+			"uncompressed-memory-cache-size",
+		) ?.or (
+			memory_cache_size.map (|size| memory_cache_size_split (size).0),
+		);
+
+	let compressed_memory_cache_size =
+		section.get_size (
+			"compressed-memory-cache-size",
+		) ?.or (
+			memory_cache_size.map (|size| memory_cache_size_split (size).1),
+		);
+
+	let filesystem_cache_size =
+		section.get_size (
+			"filesystem-cache-size",
+		) ?;
+
+	let section_cache_entries = |name: & str, size: Option <u64>, base_entries: usize| {
+
+		section.get_u64 (
+			name,
+		).map (
+			|entries|
+
+			entries.map (
+				|entries| entries as usize,
+			).unwrap_or (
+				if size.is_some () {
+					usize::MAX
+				} else {
+					base_entries
+				}
+			)
+
+		)
+
+	};
+
+	Ok (RepositoryConfig {
+
+		max_uncompressed_memory_cache_entries:
+			section_cache_entries (
+				"max-uncompressed-memory-cache-entries",
+				uncompressed_memory_cache_size,
+				base.max_uncompressed_memory_cache_entries,
+			) ?,
+
+		max_compressed_memory_cache_entries:
+			section_cache_entries (
+				"max-compressed-memory-cache-entries",
+				compressed_memory_cache_size,
+				base.max_compressed_memory_cache_entries,
+			) ?,
+
+		max_compressed_filesystem_cache_entries:
+			section_cache_entries (
+				"max-compressed-filesystem-cache-entries",
+				filesystem_cache_size,
+				base.max_compressed_filesystem_cache_entries,
+			) ?,
+
+		max_uncompressed_memory_cache_size:
+			uncompressed_memory_cache_size.or (
+				base.max_uncompressed_memory_cache_size),
+
+		max_compressed_memory_cache_size:
+			compressed_memory_cache_size.or (
+				base.max_compressed_memory_cache_size),
+
+		max_compressed_filesystem_cache_size:
+			filesystem_cache_size.or (
+				base.max_compressed_filesystem_cache_size),
+
+		cache_policy:
+			match section.get_string ("cache-policy") ? {
+				Some (cache_policy) => CachePolicy::parse (& cache_policy) ?,
+				None => base.cache_policy,
+			},
+
+		max_threads:
+			section.get_u64 (
+				"max-threads",
+			) ?.map (
+				|max_threads| max_threads as usize,
+			).unwrap_or (
+				base.max_threads),
+
+		filesystem_cache_path:
+			section.get_string (
+				"filesystem-cache-path",
+			) ?.unwrap_or_else (
+				|| base.filesystem_cache_path.clone ()),
+
+		index_cache_path:
+			section.get_string (
+				"index-cache-path",
+			) ?.or_else (
+				|| base.index_cache_path.clone ()),
+
+		work_jobs_total: 0, // deprecated and ignored
+		work_jobs_batch: 0, // deprecated and ignored
+
+	})
+
+}
+
+/// When a size is given for a cache tier, the default entry limit no longer
+/// applies, unless it has also been given explicitly.
+
+fn cache_entries (
+	clap_matches: & clap::ArgMatches,
+	name: & str,
+	size: Option <u64>,
+) -> usize {
+
+	if size.is_some () && ! is_explicit (clap_matches, name) {
+
+		usize::MAX
+
+	} else {
+
+		u64_required (
+			clap_matches,
+			name,
+		) as usize
+
+	}
+
+}
+
+#[ cfg (test) ]
+mod tests {
+
+	use std::env;
+	use std::fs;
+	use std::fs::File;
+	use std::io::Write;
+	use std::process;
+
+	use super::*;
+
+	fn test_app (
+	) -> clap::App <'static, 'static> {
+
+		clap::App::new ("rzbackup")
+
+			.arg (
+				clap::Arg::with_name ("repository")
+					.long ("repository")
+					.takes_value (true))
+
+			.arg (
+				clap::Arg::with_name ("idle-timeout")
+					.long ("idle-timeout")
+					.takes_value (true))
+
+			.arg (
+				clap::Arg::with_name ("read-timeout")
+					.long ("read-timeout")
+					.takes_value (true))
+
+			.arg (
+				clap::Arg::with_name ("max-threads")
+					.long ("max-threads")
+					.takes_value (true))
+
+	}
+
+	/// Everything which depends on the loaded config file is in one test,
+	/// since it is held in a global.
+
+	#[ test ]
+	fn values_from_config_file () {
+
+		let config_path =
+			env::temp_dir ().join (
+				format! (
+					"rzbackup-args-test-{}.conf",
+					process::id ()));
+
+		File::create (
+			& config_path,
+		).unwrap ().write_all (
+			b"[repositories.main]\n\
+			path = \"/backups/main\"\n\
+			max-threads = 3\n\
+			\n\
+			[server]\n\
+			idle-timeout = \"5 minutes\"\n\
+			read-timeout = \"30 seconds\"\n",
+		).unwrap ();
+
+		// durations from the config file, unless given on the command line
+
+		let matches =
+			test_app ().get_matches_from (
+				vec! [ "rzbackup", "--read-timeout", "10s" ]);
+
+		load_config (
+			& config_path,
+			& [ "server" ],
+			& matches,
+		).unwrap ();
+
+		assert_eq! (
+			duration_optional (& matches, "idle-timeout"),
+			Some (Duration::from_secs (300)));
+
+		assert_eq! (
+			duration_optional (& matches, "read-timeout"),
+			Some (Duration::from_secs (10)));
+
+		assert_eq! (config_repository_name (), None);
+
+		// a repository given by name on the command line is resolved through
+		// its section, which also supplies other values
+
+		let matches =
+			test_app ().get_matches_from (
+				vec! [ "rzbackup", "--repository", "main" ]);
+
+		load_config (
+			& config_path,
+			& [ "server" ],
+			& matches,
+		).unwrap ();
+
+		assert_eq! (config_repository_name (), Some ("main".to_string ()));
+
+		assert_eq! (
+			path_optional (& matches, "repository"),
+			Some (PathBuf::from ("/backups/main")));
+
+		assert_eq! (
+			string_optional (& matches, "max-threads"),
+			Some ("3".to_string ()));
+
+		// a repository given as a path is used as it is
+
+		let matches =
+			test_app ().get_matches_from (
+				vec! [ "rzbackup", "--repository", "/backups/other" ]);
+
+		load_config (
+			& config_path,
+			& [ "server" ],
+			& matches,
+		).unwrap ();
+
+		assert_eq! (config_repository_name (), None);
+
+		assert_eq! (
+			path_optional (& matches, "repository"),
+			Some (PathBuf::from ("/backups/other")));
+
+		* ARGS_CONFIG.lock ().unwrap () = None;
+
+		fs::remove_file (
+			& config_path,
+		).unwrap ();
+
+		assert_eq! (
+			duration_optional (& matches, "idle-timeout"),
+			None);
+
+	}
+
+	#[ test ]
+	fn parse_duration_values () {
+
+		for & (string_value, expected) in [
+			("0", None),
+			("0s", Some (Duration::from_secs (0))),
+			("250ms", Some (Duration::from_millis (250))),
+			("1 millisecond", Some (Duration::from_millis (1))),
+			("30 seconds", Some (Duration::from_secs (30))),
+			("5m", Some (Duration::from_secs (300))),
+			("2 hours", Some (Duration::from_secs (7200))),
+			("7 days", Some (Duration::from_secs (7 * 24 * 60 * 60))),
+			("1d", Some (Duration::from_secs (24 * 60 * 60))),
+			("01s", None),
+			("-1s", None),
+			("1.5h", None),
+			("5 weeks", None),
+			("seconds", None),
+			("", None),
+		].iter () {
+
+			assert_eq! (
+				parse_duration (string_value).ok (),
+				expected,
+				"{}", string_value);
+
+		}
+
+	}
+
+	#[ test ]
+	fn parse_size_values () {
+
+		for & (string_value, expected) in [
+			("0", Some (0)),
+			("512", Some (512)),
+			("1k", Some (1024)),
+			("1K", Some (1024)),
+			("512M", Some (512 << 20)),
+			("4G", Some (4 << 30)),
+			("2T", Some (2 << 40)),
+			("4 G", Some (4 << 30)),
+			("4GB", Some (4 << 30)),
+			("4GiB", Some (4 << 30)),
+			("100b", Some (100)),
+			("4X", None),
+			("-1", None),
+			("1.5G", None),
+			("", None),
+			("99999999999T", None),
+		].iter () {
+
+			assert_eq! (
+				parse_size (string_value).ok (),
+				expected,
+				"{}", string_value);
+
+		}
+
+	}
+
+}
+
 // ex: noet ts=4 filetype=rust
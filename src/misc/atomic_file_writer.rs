@@ -25,10 +25,14 @@ pub struct AtomicFileWriter {
 }
 
 struct AtomicFileWriterState {
+	output: Output,
 	lock_fd: libc::c_int,
+	repository_path: PathBuf,
+	append_only: bool,
 	temp_dir_path: PathBuf,
 	temp_files: Vec <(String, PathBuf)>,
 	delete_files: Vec <PathBuf>,
+	rename_files: Vec <(PathBuf, PathBuf)>,
 }
 
 impl AtomicFileWriter {
@@ -47,7 +51,7 @@ impl AtomicFileWriter {
 		let lock_path_c_str: Vec <u8> =
 			lock_path
 				.as_os_str ().as_bytes ()
-				.clone ().into_iter ()
+				.iter ()
 				.chain (b"\0")
 				.map (|&c| c)
 				.collect ();
@@ -114,26 +118,34 @@ impl AtomicFileWriter {
 				fs::create_dir (
 					temp_dir_path.clone (),
 				),
-			).map_err (
-				|error| {
+			).inspect_err (
+				|_| {
 
 				unsafe {
 					libc::close (lock_fd);
 				}
 
-				error
-
 			}) ?;
 
 		}
 
+		// check for append-only mode, now we hold the lock
+
+		let append_only =
+			append_only_enabled (
+				repository_path);
+
 		Ok (AtomicFileWriter {
 			state: Arc::new (Mutex::new (
 				AtomicFileWriterState {
+					output: output.clone (),
 					lock_fd: lock_fd,
+					repository_path: repository_path.to_owned (),
+					append_only: append_only,
 					temp_dir_path: temp_dir_path,
 					temp_files: Vec::new (),
 					delete_files: Vec::new (),
+					rename_files: Vec::new (),
 				},
 			)),
 		})
@@ -286,6 +298,31 @@ impl AtomicFileWriter {
 
 	}
 
+	/// Returns an error if the repository is append-only, for operations which
+	/// would remove or rewrite existing files. This uses the state found once
+	/// the lock was obtained, so it can't change before the commit.
+
+	pub fn append_only_check (
+		& self,
+	) -> Result <(), String> {
+
+		let self_state =
+			self.state.lock ().unwrap ();
+
+		if self_state.append_only {
+
+			return Err (
+				format! (
+					"Repository {} is append-only, refusing to remove or \
+					overwrite files, use \"convert append-only disable\" first",
+					self_state.repository_path.to_string_lossy ()));
+
+		}
+
+		Ok (())
+
+	}
+
 	pub fn create (
 		& self,
 		target_path: PathBuf,
@@ -294,6 +331,19 @@ impl AtomicFileWriter {
 		let mut self_state =
 			self.state.lock ().unwrap ();
 
+		if self_state.append_only
+			&& target_path.exists ()
+			&& append_only_protects (
+				& self_state.repository_path,
+				& target_path) {
+
+			return Err (
+				format! (
+					"Refusing to overwrite {} in append-only repository",
+					target_path.to_string_lossy ()));
+
+		}
+
 		let temp_file_name: String =
 			rand::thread_rng ()
 				.gen_ascii_chars ()
@@ -327,16 +377,110 @@ impl AtomicFileWriter {
 	pub fn delete (
 		& self,
 		delete_path: PathBuf,
-	) {
+	) -> Result <(), String> {
+
+		let mut self_state =
+			self.state.lock ().unwrap ();
+
+		if self_state.append_only
+			&& append_only_protects (
+				& self_state.repository_path,
+				& delete_path) {
+
+			return Err (
+				format! (
+					"Refusing to delete {} from append-only repository",
+					delete_path.to_string_lossy ()));
+
+		}
+
+		self_state.delete_files.push (
+			delete_path);
+
+		Ok (())
+
+	}
+
+	/// Moves a file, such as a broken bundle, when the changes are committed.
+
+	pub fn rename (
+		& self,
+		source_path: PathBuf,
+		target_path: PathBuf,
+	) -> Result <(), String> {
+
+		let mut self_state =
+			self.state.lock ().unwrap ();
+
+		if self_state.append_only
+			&& (
+				append_only_protects (
+					& self_state.repository_path,
+					& source_path)
+				|| (
+					target_path.exists ()
+					&& append_only_protects (
+						& self_state.repository_path,
+						& target_path)
+				)
+			) {
+
+			return Err (
+				format! (
+					"Refusing to move {} in append-only repository",
+					source_path.to_string_lossy ()));
+
+		}
+
+		self_state.rename_files.push (
+			(source_path, target_path));
+
+		Ok (())
+
+	}
+
+	/// Turns off append-only mode, if the unlock key matches, and deletes the
+	/// append-only file when the changes are committed.
+
+	pub fn append_only_unlock (
+		& self,
+		unlock_key: & [u8],
+	) -> Result <(), String> {
 
 		let mut self_state =
 			self.state.lock ().unwrap ();
 
+		if ! self_state.append_only {
+
+			return Err (
+				format! (
+					"Repository {} is not append-only",
+					self_state.repository_path.to_string_lossy ()));
+
+		}
+
+		append_only_verify_key (
+			& self_state.repository_path,
+			unlock_key,
+		) ?;
+
+		let append_only_path =
+			self_state.repository_path.join (
+				APPEND_ONLY_FILE_NAME);
+
+		self_state.append_only = false;
+
 		self_state.delete_files.push (
-			delete_path)
+			append_only_path);
+
+		Ok (())
 
 	}
 
+	/// Applies all the changes, and records them in the audit log. The result
+	/// is that of the changes themselves, since they can't be undone if only
+	/// the audit log could not be written, so that is reported separately.
+
 	pub fn commit (
 		& self
 	) -> Result <(), String> {
@@ -344,6 +488,38 @@ impl AtomicFileWriter {
 		let mut self_state =
 			self.state.lock ().unwrap ();
 
+		let mut operations: Vec <AuditOperation> =
+			Vec::new ();
+
+		let result =
+			Self::commit_real (
+				& mut self_state,
+				& mut operations);
+
+		// record what was changed, even if it failed part way through
+
+		if let Err (audit_error) =
+			audit_log_append (
+				& self_state.repository_path,
+				operations,
+				result.as_ref ().err ().cloned ()) {
+
+			output_message! (
+				self_state.output,
+				"Changes were not recorded in the audit log: {}",
+				audit_error);
+
+		}
+
+		result
+
+	}
+
+	fn commit_real (
+		self_state: & mut AtomicFileWriterState,
+		operations: & mut Vec <AuditOperation>,
+	) -> Result <(), String> {
+
 		// sync all temp files
 
 		for & (ref temp_file_name, _)
@@ -373,6 +549,13 @@ impl AtomicFileWriter {
 		for & (ref temp_file_name, ref target_path)
 		in self_state.temp_files.iter () {
 
+			let audit_action =
+				if target_path.exists () {
+					AuditAction::Replace
+				} else {
+					AuditAction::Create
+				};
+
 			let parent_dir =
 				target_path.parent ().unwrap ();
 
@@ -395,6 +578,11 @@ impl AtomicFileWriter {
 					target_path)
 			) ?;
 
+			operations.push (
+				AuditOperation::new (
+					audit_action,
+					target_path));
+
 		}
 
 		self_state.temp_files.clear ();
@@ -411,10 +599,50 @@ impl AtomicFileWriter {
 					delete_file_name),
 			) ?;
 
+			operations.push (
+				AuditOperation::new (
+					AuditAction::Delete,
+					delete_file_name));
+
 		}
 
 		self_state.delete_files.clear ();
 
+		// move files
+
+		for & (ref source_path, ref target_path)
+		in self_state.rename_files.iter () {
+
+			let parent_dir =
+				target_path.parent ().unwrap ();
+
+			io_result_with_prefix (
+				|| format! (
+					"Error creating target directory {}: ",
+					parent_dir.to_string_lossy ()),
+				fs::create_dir_all (
+					parent_dir),
+			) ?;
+
+			string_result_with_prefix (
+				|| format! (
+					"Error moving {} to {}: ",
+					source_path.to_string_lossy (),
+					target_path.to_string_lossy ()),
+				rename_or_copy_and_delete (
+					source_path,
+					target_path)
+			) ?;
+
+			operations.push (
+				AuditOperation::rename (
+					source_path,
+					target_path));
+
+		}
+
+		self_state.rename_files.clear ();
+
 		// return
 
 		Ok (())
@@ -430,6 +658,7 @@ impl AtomicFileWriter {
 
 		! self_state.delete_files.is_empty ()
 		|| ! self_state.temp_files.is_empty ()
+		|| ! self_state.rename_files.is_empty ()
 
 	}
 
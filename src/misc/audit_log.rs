@@ -0,0 +1,708 @@
+use std::collections::BTreeMap;
+use std::env;
+use std::ffi::CStr;
+use std::fs::File;
+use std::fs::OpenOptions;
+use std::io;
+use std::io::BufRead;
+use std::io::BufReader;
+use std::io::Read;
+use std::io::Seek;
+use std::io::SeekFrom;
+use std::io::Write;
+use std::path::Path;
+use std::path::PathBuf;
+use std::time::SystemTime;
+use std::time::UNIX_EPOCH;
+
+use libc;
+
+use rustc_serialize::json::Json;
+
+use ::misc::*;
+
+/// Every change rzbackup makes to a repository is recorded in this file, one
+/// JSON object per line, so it is possible to find out later which command
+/// removed or replaced a file.
+
+pub const AUDIT_LOG_FILE_NAME: & 'static str = "rzbackup-audit.log";
+
+#[ derive (Clone, Copy, Debug, Eq, PartialEq) ]
+pub enum AuditAction {
+	Create,
+	Replace,
+	Delete,
+	Rename,
+}
+
+impl AuditAction {
+
+	pub fn name (& self) -> & 'static str {
+
+		match * self {
+			AuditAction::Create => "create",
+			AuditAction::Replace => "replace",
+			AuditAction::Delete => "delete",
+			AuditAction::Rename => "rename",
+		}
+
+	}
+
+	pub fn parse (
+		name: & str,
+	) -> Option <AuditAction> {
+
+		match name {
+			"create" => Some (AuditAction::Create),
+			"replace" => Some (AuditAction::Replace),
+			"delete" => Some (AuditAction::Delete),
+			"rename" => Some (AuditAction::Rename),
+			_ => None,
+		}
+
+	}
+
+}
+
+/// A single change to a file. Renames also have a target path. Paths inside
+/// the repository are recorded relative to it.
+
+#[ derive (Clone, Debug) ]
+pub struct AuditOperation {
+	pub action: AuditAction,
+	pub path: PathBuf,
+	pub target_path: Option <PathBuf>,
+}
+
+impl AuditOperation {
+
+	pub fn new <
+		OperationPath: AsRef <Path>,
+	> (
+		action: AuditAction,
+		path: OperationPath,
+	) -> AuditOperation {
+
+		AuditOperation {
+			action: action,
+			path: path.as_ref ().to_owned (),
+			target_path: None,
+		}
+
+	}
+
+	pub fn rename <
+		SourcePath: AsRef <Path>,
+		TargetPath: AsRef <Path>,
+	> (
+		source_path: SourcePath,
+		target_path: TargetPath,
+	) -> AuditOperation {
+
+		AuditOperation {
+			action: AuditAction::Rename,
+			path: source_path.as_ref ().to_owned (),
+			target_path: Some (target_path.as_ref ().to_owned ()),
+		}
+
+	}
+
+}
+
+/// One line in the audit log, for everything a single commit, or other change,
+/// did to the repository. If it failed part way through, `error` is set, and
+/// the operations are the ones which were completed.
+
+#[ derive (Clone, Debug) ]
+pub struct AuditRecord {
+	pub timestamp: u64,
+	pub host: String,
+	pub pid: u32,
+	pub command_line: Vec <String>,
+	pub version: String,
+	pub operations: Vec <AuditOperation>,
+	pub error: Option <String>,
+}
+
+impl AuditRecord {
+
+	fn to_json (
+		& self,
+		repository_path: & Path,
+	) -> Json {
+
+		let mut record: BTreeMap <String, Json> =
+			BTreeMap::new ();
+
+		record.insert (
+			"timestamp".to_string (),
+			Json::U64 (self.timestamp));
+
+		record.insert (
+			"host".to_string (),
+			Json::String (self.host.clone ()));
+
+		record.insert (
+			"pid".to_string (),
+			Json::U64 (self.pid as u64));
+
+		record.insert (
+			"command-line".to_string (),
+			Json::Array (
+				self.command_line.iter ().map (
+					|argument| Json::String (argument.clone ())
+				).collect ()));
+
+		record.insert (
+			"version".to_string (),
+			Json::String (self.version.clone ()));
+
+		record.insert (
+			"operations".to_string (),
+			Json::Array (
+				self.operations.iter ().map (
+					|operation| {
+
+					let mut operation_json: BTreeMap <String, Json> =
+						BTreeMap::new ();
+
+					operation_json.insert (
+						"action".to_string (),
+						Json::String (operation.action.name ().to_string ()));
+
+					operation_json.insert (
+						"path".to_string (),
+						Json::String (
+							relative_path_string (
+								repository_path,
+								& operation.path)));
+
+					if let Some (ref target_path) = operation.target_path {
+
+						operation_json.insert (
+							"target-path".to_string (),
+							Json::String (
+								relative_path_string (
+									repository_path,
+									target_path)));
+
+					}
+
+					Json::Object (operation_json)
+
+				}).collect ()));
+
+		if let Some (ref error) = self.error {
+
+			record.insert (
+				"error".to_string (),
+				Json::String (error.clone ()));
+
+		}
+
+		Json::Object (record)
+
+	}
+
+	fn from_json (
+		record_json: & Json,
+	) -> Option <AuditRecord> {
+
+		let record =
+			record_json.as_object () ?;
+
+		let operations =
+			record.get ("operations") ?.as_array () ?.iter ().map (
+				|operation_json| {
+
+				let operation =
+					operation_json.as_object () ?;
+
+				Some (AuditOperation {
+					action: AuditAction::parse (
+						operation.get ("action") ?.as_string () ?,
+					) ?,
+					path: PathBuf::from (
+						operation.get ("path") ?.as_string () ?),
+					target_path: match operation.get ("target-path") {
+						Some (target_path) =>
+							Some (PathBuf::from (target_path.as_string () ?)),
+						None => None,
+					},
+				})
+
+			}).collect::<Option <Vec <AuditOperation>>> () ?;
+
+		Some (AuditRecord {
+			timestamp: record.get ("timestamp") ?.as_u64 () ?,
+			host: record.get ("host") ?.as_string () ?.to_string (),
+			pid: record.get ("pid") ?.as_u64 () ? as u32,
+			command_line: record.get ("command-line") ?.as_array () ?.iter ().map (
+				|argument| argument.as_string ().map (str::to_string)
+			).collect::<Option <Vec <String>>> () ?,
+			version: record.get ("version") ?.as_string () ?.to_string (),
+			operations: operations,
+			error: record.get ("error").and_then (
+				|error| error.as_string ().map (str::to_string)),
+		})
+
+	}
+
+	pub fn to_json_string (
+		& self,
+	) -> String {
+
+		self.to_json (
+			Path::new (""),
+		).to_string ()
+
+	}
+
+}
+
+/// Appends a record of the operations to the repository's audit log, along
+/// with details of the current process. Nothing is written if there were no
+/// operations, since nothing was changed.
+
+pub fn audit_log_append (
+	repository_path: & Path,
+	operations: Vec <AuditOperation>,
+	error: Option <String>,
+) -> Result <(), String> {
+
+	if operations.is_empty () {
+		return Ok (());
+	}
+
+	let record = AuditRecord {
+		timestamp: SystemTime::now ().duration_since (
+			UNIX_EPOCH,
+		).map (
+			|duration| duration.as_secs ()
+		).unwrap_or (0),
+		host: host_name (),
+		pid: unsafe { libc::getpid () } as u32,
+		command_line: env::args_os ().map (
+			|argument| argument.to_string_lossy ().to_string ()
+		).collect (),
+		version: ::VERSION.to_string (),
+		operations: operations,
+		error: error,
+	};
+
+	let audit_log_path =
+		repository_path.join (
+			AUDIT_LOG_FILE_NAME);
+
+	let mut audit_log_file =
+		io_result_with_prefix (
+			|| format! (
+				"Error opening audit log {}: ",
+				audit_log_path.to_string_lossy ()),
+			OpenOptions::new ()
+				.create (true)
+				.read (true)
+				.append (true)
+				.open (& audit_log_path),
+		) ?;
+
+	// if the last record was cut short, by a crash or a full disk, start on a
+	// new line so that this one can still be read
+
+	let complete =
+		io_result_with_prefix (
+			|| format! (
+				"Error reading audit log {}: ",
+				audit_log_path.to_string_lossy ()),
+			audit_log_ends_with_newline (
+				& mut audit_log_file),
+		) ?;
+
+	// a single write to a file opened for append, so concurrent records from
+	// different processes don't get mixed up
+
+	let record_line =
+		format! (
+			"{}{}\n",
+			if complete { "" } else { "\n" },
+			record.to_json (
+				repository_path));
+
+	io_result_with_prefix (
+		|| format! (
+			"Error writing audit log {}: ",
+			audit_log_path.to_string_lossy ()),
+		audit_log_file.write_all (
+			record_line.as_bytes ()),
+	) ?;
+
+	io_result_with_prefix (
+		|| format! (
+			"Error writing audit log {}: ",
+			audit_log_path.to_string_lossy ()),
+		audit_log_file.sync_data (),
+	) ?;
+
+	Ok (())
+
+}
+
+/// Reads every record in the repository's audit log, oldest first. A missing
+/// log is treated as empty. Lines which can't be read, such as one left half
+/// written by a crash, are skipped, and their line numbers are returned
+/// separately.
+
+pub fn audit_log_read (
+	repository_path: & Path,
+) -> Result <(Vec <AuditRecord>, Vec <u64>), String> {
+
+	let audit_log_path =
+		repository_path.join (
+			AUDIT_LOG_FILE_NAME);
+
+	if ! audit_log_path.exists () {
+		return Ok ((Vec::new (), Vec::new ()));
+	}
+
+	let audit_log_file =
+		io_result_with_prefix (
+			|| format! (
+				"Error opening audit log {}: ",
+				audit_log_path.to_string_lossy ()),
+			File::open (
+				& audit_log_path),
+		) ?;
+
+	let mut records: Vec <AuditRecord> =
+		Vec::new ();
+
+	let mut invalid_line_numbers: Vec <u64> =
+		Vec::new ();
+
+	// split on bytes, since a damaged line may not be valid UTF-8
+
+	for (line_index, line_result) in BufReader::new (
+		audit_log_file,
+	).split (b'\n').enumerate () {
+
+		let line_bytes =
+			io_result_with_prefix (
+				|| format! (
+					"Error reading audit log {}: ",
+					audit_log_path.to_string_lossy ()),
+				line_result,
+			) ?;
+
+		let line =
+			String::from_utf8_lossy (
+				& line_bytes);
+
+		if line.trim ().is_empty () {
+			continue;
+		}
+
+		match Json::from_str (
+			& line,
+		).ok ().and_then (
+			|record_json|
+			AuditRecord::from_json (
+				& record_json)
+		) {
+
+			Some (record) =>
+				records.push (
+					record),
+
+			None =>
+				invalid_line_numbers.push (
+					line_index as u64 + 1),
+
+		}
+
+	}
+
+	Ok ((records, invalid_line_numbers))
+
+}
+
+fn audit_log_ends_with_newline (
+	audit_log_file: & mut File,
+) -> io::Result <bool> {
+
+	let audit_log_size =
+		audit_log_file.metadata () ?.len ();
+
+	if audit_log_size == 0 {
+		return Ok (true);
+	}
+
+	let mut last_byte: [u8; 1] =
+		[0u8; 1];
+
+	audit_log_file.seek (
+		SeekFrom::Start (
+			audit_log_size - 1),
+	) ?;
+
+	audit_log_file.read_exact (
+		& mut last_byte,
+	) ?;
+
+	Ok (last_byte [0] == b'\n')
+
+}
+
+/// Formats a timestamp as a UTC date and time, such as "2017-07-14 02:40:00".
+
+pub fn audit_format_timestamp (
+	timestamp: u64,
+) -> String {
+
+	let days = (timestamp / 86400) as i64;
+	let seconds_of_day = timestamp % 86400;
+
+	// convert days since the epoch to a civil date
+
+	let shifted_days = days + 719468;
+	let era = shifted_days / 146097;
+	let day_of_era = shifted_days - era * 146097;
+
+	let year_of_era =
+		(day_of_era - day_of_era / 1460 + day_of_era / 36524
+			- day_of_era / 146096) / 365;
+
+	let day_of_year =
+		day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+
+	let shifted_month = (5 * day_of_year + 2) / 153;
+	let day = day_of_year - (153 * shifted_month + 2) / 5 + 1;
+	let month = if shifted_month < 10 { shifted_month + 3 } else { shifted_month - 9 };
+	let year = year_of_era + era * 400 + if month <= 2 { 1 } else { 0 };
+
+	format! (
+		"{:04}-{:02}-{:02} {:02}:{:02}:{:02}",
+		year,
+		month,
+		day,
+		seconds_of_day / 3600,
+		seconds_of_day / 60 % 60,
+		seconds_of_day % 60)
+
+}
+
+fn relative_path_string (
+	repository_path: & Path,
+	path: & Path,
+) -> String {
+
+	path.strip_prefix (
+		repository_path,
+	).unwrap_or (
+		path,
+	).to_string_lossy ().to_string ()
+
+}
+
+fn host_name (
+) -> String {
+
+	let mut buffer: [u8; 256] =
+		[0u8; 256];
+
+	let result = unsafe {
+		libc::gethostname (
+			buffer.as_mut_ptr () as * mut libc::c_char,
+			buffer.len () - 1)
+	};
+
+	if result != 0 {
+		return "unknown".to_string ();
+	}
+
+	unsafe {
+		CStr::from_ptr (
+			buffer.as_ptr () as * const libc::c_char)
+	}.to_string_lossy ().to_string ()
+
+}
+
+#[ cfg (test) ]
+mod tests {
+
+	use std::fs;
+	use std::process;
+
+	use super::*;
+
+	fn temp_repository_path (
+		name: & str,
+	) -> PathBuf {
+
+		let repository_path =
+			env::temp_dir ().join (
+				format! (
+					"rzbackup-audit-log-{}-{}",
+					name,
+					process::id ()));
+
+		fs::remove_dir_all (& repository_path).unwrap_or (());
+		fs::create_dir_all (& repository_path).unwrap ();
+
+		repository_path
+
+	}
+
+	#[ test ]
+	fn append_and_read () {
+
+		let repository_path =
+			temp_repository_path ("append");
+
+		audit_log_append (
+			& repository_path,
+			vec! [
+				AuditOperation::new (
+					AuditAction::Create,
+					repository_path.join ("index/0123")),
+				AuditOperation::rename (
+					repository_path.join ("bundles/01/0123"),
+					repository_path.join ("bundles-broken/0123")),
+			],
+			None,
+		).unwrap ();
+
+		audit_log_append (
+			& repository_path,
+			vec! [],
+			None,
+		).unwrap ();
+
+		audit_log_append (
+			& repository_path,
+			vec! [
+				AuditOperation::new (
+					AuditAction::Delete,
+					repository_path.join ("index/0123")),
+			],
+			Some ("Error deleting".to_string ()),
+		).unwrap ();
+
+		let (records, invalid_line_numbers) =
+			audit_log_read (
+				& repository_path,
+			).unwrap ();
+
+		assert! (invalid_line_numbers.is_empty ());
+		assert_eq! (records.len (), 2);
+
+		assert_eq! (records [0].pid, process::id ());
+		assert_eq! (records [0].version, ::VERSION);
+		assert_eq! (records [0].error, None);
+		assert_eq! (records [0].operations.len (), 2);
+		assert_eq! (records [0].operations [0].action, AuditAction::Create);
+		assert_eq! (records [0].operations [0].path, PathBuf::from ("index/0123"));
+		assert_eq! (records [0].operations [0].target_path, None);
+		assert_eq! (records [0].operations [1].action, AuditAction::Rename);
+		assert_eq! (records [0].operations [1].path, PathBuf::from ("bundles/01/0123"));
+
+		assert_eq! (
+			records [0].operations [1].target_path,
+			Some (PathBuf::from ("bundles-broken/0123")));
+
+		assert_eq! (records [1].operations [0].action, AuditAction::Delete);
+		assert_eq! (records [1].error, Some ("Error deleting".to_string ()));
+
+		fs::remove_dir_all (& repository_path).unwrap ();
+
+	}
+
+	#[ test ]
+	fn truncated_last_line () {
+
+		let repository_path =
+			temp_repository_path ("truncated");
+
+		audit_log_append (
+			& repository_path,
+			vec! [
+				AuditOperation::new (
+					AuditAction::Create,
+					repository_path.join ("index/0123")),
+			],
+			None,
+		).unwrap ();
+
+		// simulate a record cut short by a crash
+
+		OpenOptions::new ()
+			.append (true)
+			.open (repository_path.join (AUDIT_LOG_FILE_NAME))
+			.unwrap ()
+			.write_all (b"{\"timestamp\":12")
+			.unwrap ();
+
+		let (records, invalid_line_numbers) =
+			audit_log_read (
+				& repository_path,
+			).unwrap ();
+
+		assert_eq! (records.len (), 1);
+		assert_eq! (invalid_line_numbers, vec! [2]);
+
+		// the next record starts on a new line, so it can still be read
+
+		audit_log_append (
+			& repository_path,
+			vec! [
+				AuditOperation::new (
+					AuditAction::Delete,
+					repository_path.join ("index/0123")),
+			],
+			None,
+		).unwrap ();
+
+		let (records, invalid_line_numbers) =
+			audit_log_read (
+				& repository_path,
+			).unwrap ();
+
+		assert_eq! (records.len (), 2);
+		assert_eq! (records [1].operations [0].action, AuditAction::Delete);
+		assert_eq! (invalid_line_numbers, vec! [2]);
+
+		fs::remove_dir_all (& repository_path).unwrap ();
+
+	}
+
+	#[ test ]
+	fn missing_log_is_empty () {
+
+		let repository_path =
+			temp_repository_path ("missing");
+
+		let (records, invalid_line_numbers) =
+			audit_log_read (
+				& repository_path,
+			).unwrap ();
+
+		assert! (records.is_empty ());
+		assert! (invalid_line_numbers.is_empty ());
+
+		fs::remove_dir_all (& repository_path).unwrap ();
+
+	}
+
+	#[ test ]
+	fn format_timestamp () {
+
+		assert_eq! (audit_format_timestamp (0), "1970-01-01 00:00:00");
+		assert_eq! (audit_format_timestamp (951782400), "2000-02-29 00:00:00");
+		assert_eq! (audit_format_timestamp (1500000000), "2017-07-14 02:40:00");
+		assert_eq! (audit_format_timestamp (1709251199), "2024-02-29 23:59:59");
+		assert_eq! (audit_format_timestamp (4107542400), "2100-03-01 00:00:00");
+
+	}
+
+}
+
+// ex: noet ts=4 filetype=rust
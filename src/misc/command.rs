@@ -105,12 +105,12 @@ impl Command for ParentCommand {
 
 		).unwrap_or_else (|| {
 
-			println! ("");
+			println! ();
 
 			self.clap_subcommand ().print_help ().unwrap ();
 
-			println! ("");
-			println! ("");
+			println! ();
+			println! ();
 
 			process::exit (0);
 
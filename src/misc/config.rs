@@ -0,0 +1,850 @@
+use std::fmt;
+use std::fs::File;
+use std::io::Read;
+use std::path::Path;
+use std::path::PathBuf;
+use std::time::Duration;
+
+use misc::*;
+
+/// A configuration file, in a subset of TOML. Sections are introduced with
+/// `[name]` headers, which may contain dots, such as `[repositories.main]`,
+/// and contain `key = value` lines. Values are strings, in double or single
+/// quotes, integers, or booleans. Comments start with `#`. Keys before the
+/// first header belong to a section with an empty name.
+
+pub struct ConfigFile {
+	path: PathBuf,
+	sections: Vec <ConfigSection>,
+}
+
+pub struct ConfigSection {
+	name: String,
+	line_number: usize,
+	values: Vec <(String, ConfigValue, usize)>,
+}
+
+#[ derive (Clone, Debug, Eq, PartialEq) ]
+pub enum ConfigValue {
+	String (String),
+	Integer (u64),
+	Boolean (bool),
+}
+
+impl fmt::Display for ConfigValue {
+
+	fn fmt (
+		& self,
+		formatter: & mut fmt::Formatter,
+	) -> fmt::Result {
+
+		match * self {
+			ConfigValue::String (ref value) => write! (formatter, "{}", value),
+			ConfigValue::Integer (value) => write! (formatter, "{}", value),
+			ConfigValue::Boolean (value) => write! (formatter, "{}", value),
+		}
+
+	}
+
+}
+
+impl ConfigFile {
+
+	pub fn read <
+		PathRef: AsRef <Path>,
+	> (
+		path: PathRef,
+	) -> Result <ConfigFile, String> {
+
+		let path =
+			path.as_ref ();
+
+		let mut source =
+			String::new ();
+
+		io_result_with_prefix (
+			|| format! (
+				"Error reading {}: ",
+				path.to_string_lossy ()),
+			File::open (
+				path,
+			).and_then (
+				|mut file|
+				file.read_to_string (
+					& mut source)
+			),
+		) ?;
+
+		ConfigFile::parse (
+			path,
+			& source)
+
+	}
+
+	pub fn parse <
+		PathRef: AsRef <Path>,
+	> (
+		path: PathRef,
+		source: & str,
+	) -> Result <ConfigFile, String> {
+
+		let path =
+			path.as_ref ();
+
+		let mut sections: Vec <ConfigSection> =
+			vec! [
+				ConfigSection {
+					name: String::new (),
+					line_number: 0,
+					values: Vec::new (),
+				},
+			];
+
+		for (line_index, line) in source.lines ().enumerate () {
+
+			let line_number =
+				line_index + 1;
+
+			let error_prefix = || format! (
+				"{}:{}: ",
+				path.to_string_lossy (),
+				line_number);
+
+			let line =
+				strip_comment (line).trim ();
+
+			if line.is_empty () {
+				continue;
+			}
+
+			if line.starts_with ("[") {
+
+				if ! line.ends_with ("]") {
+
+					return Err (
+						format! (
+							"{}Invalid section header",
+							error_prefix ()));
+
+				}
+
+				let name =
+					line [1 .. line.len () - 1].trim ();
+
+				if name.is_empty ()
+					|| name.split ('.').any (|part| ! is_valid_key (part)) {
+
+					return Err (
+						format! (
+							"{}Invalid section name: {}",
+							error_prefix (),
+							name));
+
+				}
+
+				if sections.iter ().any (|section| section.name == name) {
+
+					return Err (
+						format! (
+							"{}Duplicated section: {}",
+							error_prefix (),
+							name));
+
+				}
+
+				sections.push (
+					ConfigSection {
+						name: name.to_string (),
+						line_number: line_number,
+						values: Vec::new (),
+					});
+
+				continue;
+
+			}
+
+			let equals_position =
+				line.find ('=').ok_or_else (
+					|| format! (
+						"{}Expected 'key = value'",
+						error_prefix ()),
+				) ?;
+
+			let key =
+				line [0 .. equals_position].trim ();
+
+			if ! is_valid_key (key) {
+
+				return Err (
+					format! (
+						"{}Invalid key: {}",
+						error_prefix (),
+						key));
+
+			}
+
+			let value =
+				string_result_with_prefix (
+					error_prefix,
+					parse_value (
+						line [equals_position + 1 ..].trim ()),
+				) ?;
+
+			let section =
+				sections.last_mut ().unwrap ();
+
+			if section.values.iter ().any (
+				|& (ref existing_key, _, _)| existing_key == key) {
+
+				return Err (
+					format! (
+						"{}Duplicated key: {}",
+						error_prefix (),
+						key));
+
+			}
+
+			section.values.push (
+				(key.to_string (), value, line_number));
+
+		}
+
+		Ok (ConfigFile {
+			path: path.to_owned (),
+			sections: sections,
+		})
+
+	}
+
+	pub fn path (& self) -> & Path {
+		& self.path
+	}
+
+	/// Returns the named section, or None if it does not appear in the file.
+	/// The empty name refers to keys before the first header, and is always
+	/// present.
+
+	pub fn section (
+		& self,
+		name: & str,
+	) -> Option <& ConfigSection> {
+
+		self.sections.iter ().find (
+			|section| section.name == name)
+
+	}
+
+	/// Returns the sections named `PREFIX.NAME`, along with each NAME, in the
+	/// order they appear in the file.
+
+	pub fn sections_with_prefix <'a> (
+		& 'a self,
+		prefix: & str,
+	) -> Vec <(& 'a str, & 'a ConfigSection)> {
+
+		self.sections.iter ().filter_map (
+			|section| {
+
+			if section.name.len () > prefix.len () + 1
+				&& section.name.starts_with (prefix)
+				&& section.name.as_bytes () [prefix.len ()] == b'.' {
+
+				Some ((
+					& section.name [prefix.len () + 1 ..],
+					section,
+				))
+
+			} else {
+				None
+			}
+
+		}).collect ()
+
+	}
+
+	pub fn sections (
+		& self,
+	) -> & [ConfigSection] {
+		& self.sections
+	}
+
+}
+
+impl ConfigSection {
+
+	pub fn name (& self) -> & str {
+		& self.name
+	}
+
+	pub fn line_number (& self) -> usize {
+		self.line_number
+	}
+
+	pub fn keys (
+		& self,
+	) -> Vec <& str> {
+
+		self.values.iter ().map (
+			|& (ref key, _, _)| key.as_str (),
+		).collect ()
+
+	}
+
+	pub fn get (
+		& self,
+		key: & str,
+	) -> Option <& ConfigValue> {
+
+		self.values.iter ().find (
+			|& & (ref existing_key, _, _)| existing_key == key,
+		).map (
+			|& (_, ref value, _)| value,
+		)
+
+	}
+
+	/// Returns an error naming the first key which is not in the list.
+
+	pub fn check_keys (
+		& self,
+		allowed_keys: & [& str],
+	) -> Result <(), String> {
+
+		for & (ref key, _, line_number) in self.values.iter () {
+
+			if ! allowed_keys.contains (& key.as_str ()) {
+
+				return Err (
+					format! (
+						"Line {}: Unknown key {} in {}",
+						line_number,
+						key,
+						self.description ()));
+
+			}
+
+		}
+
+		Ok (())
+
+	}
+
+	pub fn get_string (
+		& self,
+		key: & str,
+	) -> Result <Option <String>, String> {
+
+		match self.get (key) {
+
+			Some (& ConfigValue::String (ref value)) =>
+				Ok (Some (value.clone ())),
+
+			Some (_) =>
+				Err (self.type_error (key, "a string")),
+
+			None =>
+				Ok (None),
+
+		}
+
+	}
+
+	pub fn get_path (
+		& self,
+		key: & str,
+	) -> Result <Option <PathBuf>, String> {
+
+		self.get_string (
+			key,
+		).map (
+			|value| value.map (PathBuf::from)
+		)
+
+	}
+
+	pub fn get_u64 (
+		& self,
+		key: & str,
+	) -> Result <Option <u64>, String> {
+
+		match self.get (key) {
+
+			Some (& ConfigValue::Integer (value)) =>
+				Ok (Some (value)),
+
+			Some (_) =>
+				Err (self.type_error (key, "an integer")),
+
+			None =>
+				Ok (None),
+
+		}
+
+	}
+
+	pub fn get_bool (
+		& self,
+		key: & str,
+	) -> Result <Option <bool>, String> {
+
+		match self.get (key) {
+
+			Some (& ConfigValue::Boolean (value)) =>
+				Ok (Some (value)),
+
+			Some (_) =>
+				Err (self.type_error (key, "true or false")),
+
+			None =>
+				Ok (None),
+
+		}
+
+	}
+
+	/// Sizes may be given as a number of bytes, or as a string with a suffix,
+	/// in the same format as on the command line.
+
+	pub fn get_size (
+		& self,
+		key: & str,
+	) -> Result <Option <u64>, String> {
+
+		match self.get (key) {
+
+			Some (& ConfigValue::Integer (value)) =>
+				Ok (Some (value)),
+
+			Some (& ConfigValue::String (ref value)) =>
+				args::parse_size (
+					value,
+				).map (Some).map_err (
+					|error| self.value_error (key, error)),
+
+			Some (_) =>
+				Err (self.type_error (key, "a size")),
+
+			None =>
+				Ok (None),
+
+		}
+
+	}
+
+	pub fn get_duration (
+		& self,
+		key: & str,
+	) -> Result <Option <Duration>, String> {
+
+		match self.get (key) {
+
+			Some (& ConfigValue::String (ref value)) =>
+				args::parse_duration (
+					value,
+				).map (Some).map_err (
+					|error| self.value_error (key, error)),
+
+			Some (_) =>
+				Err (self.type_error (key, "a duration, such as \"5 seconds\"")),
+
+			None =>
+				Ok (None),
+
+		}
+
+	}
+
+	fn description (
+		& self,
+	) -> String {
+
+		if self.name.is_empty () {
+			"top level".to_string ()
+		} else {
+			format! (
+				"section [{}]",
+				self.name)
+		}
+
+	}
+
+	fn line_number_of (
+		& self,
+		key: & str,
+	) -> usize {
+
+		self.values.iter ().find (
+			|& & (ref existing_key, _, _)| existing_key == key,
+		).map (
+			|& (_, _, line_number)| line_number,
+		).unwrap_or (self.line_number)
+
+	}
+
+	fn type_error (
+		& self,
+		key: & str,
+		expected: & str,
+	) -> String {
+
+		format! (
+			"Line {}: Value for {} in {} must be {}",
+			self.line_number_of (key),
+			key,
+			self.description (),
+			expected)
+
+	}
+
+	fn value_error (
+		& self,
+		key: & str,
+		error: String,
+	) -> String {
+
+		format! (
+			"Line {}: Invalid value for {} in {}: {}",
+			self.line_number_of (key),
+			key,
+			self.description (),
+			error)
+
+	}
+
+}
+
+fn is_valid_key (
+	key: & str,
+) -> bool {
+
+	! key.is_empty ()
+		&& key.chars ().all (
+			|character|
+			character.is_digit (36)
+				|| character == '-'
+				|| character == '_')
+
+}
+
+/// Removes a trailing comment, ignoring any `#` inside a quoted string.
+
+fn strip_comment (
+	line: & str,
+) -> & str {
+
+	let mut quote: Option <char> =
+		None;
+
+	let mut escaped = false;
+
+	for (index, character) in line.char_indices () {
+
+		match quote {
+
+			Some ('"') if escaped =>
+				escaped = false,
+
+			Some ('"') if character == '\\' =>
+				escaped = true,
+
+			Some (quote_character) if character == quote_character =>
+				quote = None,
+
+			Some (_) =>
+				(),
+
+			None if character == '"' || character == '\'' =>
+				quote = Some (character),
+
+			None if character == '#' =>
+				return & line [0 .. index],
+
+			None =>
+				(),
+
+		}
+
+	}
+
+	line
+
+}
+
+fn parse_value (
+	source: & str,
+) -> Result <ConfigValue, String> {
+
+	if source.is_empty () {
+
+		Err (
+			"Missing value".to_string ())
+
+	} else if source == "true" {
+
+		Ok (ConfigValue::Boolean (true))
+
+	} else if source == "false" {
+
+		Ok (ConfigValue::Boolean (false))
+
+	} else if source.len () >= 2
+		&& source.starts_with ("'")
+		&& source.ends_with ("'") {
+
+		let value =
+			& source [1 .. source.len () - 1];
+
+		if value.contains ('\'') {
+
+			return Err (
+				format! (
+					"Invalid string: {}",
+					source));
+
+		}
+
+		Ok (ConfigValue::String (
+			value.to_string ()))
+
+	} else if source.len () >= 2
+		&& source.starts_with ("\"")
+		&& source.ends_with ("\"") {
+
+		parse_basic_string (
+			& source [1 .. source.len () - 1],
+		).map (
+			ConfigValue::String
+		).ok_or_else (
+			|| format! (
+				"Invalid string: {}",
+				source)
+		)
+
+	} else {
+
+		source.replace ("_", "").parse::<u64> ().map (
+			ConfigValue::Integer,
+		).map_err (
+			|_| format! (
+				"Invalid value: {}",
+				source)
+		)
+
+	}
+
+}
+
+fn parse_basic_string (
+	source: & str,
+) -> Option <String> {
+
+	let mut value =
+		String::new ();
+
+	let mut characters =
+		source.chars ();
+
+	while let Some (character) = characters.next () {
+
+		match character {
+
+			'"' =>
+				return None,
+
+			'\\' => match characters.next () {
+				Some ('"') => value.push ('"'),
+				Some ('\\') => value.push ('\\'),
+				Some ('n') => value.push ('\n'),
+				Some ('t') => value.push ('\t'),
+				_ => return None,
+			},
+
+			_ =>
+				value.push (character),
+
+		}
+
+	}
+
+	Some (value)
+
+}
+
+#[ cfg (test) ]
+mod tests {
+
+	use std::time::Duration;
+
+	use super::*;
+
+	const EXAMPLE: & 'static str = "\
+		# top level comment\n\
+		\n\
+		[repositories.main]\n\
+		path = \"/backups/main\" # trailing comment\n\
+		password-file = '/etc/rzbackup/main # key'\n\
+		memory-cache-size = \"4G\"\n\
+		\n\
+		[server]\n\
+		max-threads = 1_024\n\
+		watch-indexes = true\n\
+		idle-timeout = \"30 seconds\"\n\
+		listen-address = \"unix:/run/rzbackup.sock\"\n\
+		escaped = \"tab\\there \\\"quoted\\\"\"\n\
+		\n\
+		[convert.balance-bundles]\n\
+		chunks-per-bundle = 512\n";
+
+	fn parse (
+		source: & str,
+	) -> Result <ConfigFile, String> {
+
+		ConfigFile::parse (
+			"test.conf",
+			source)
+
+	}
+
+	#[ test ]
+	fn parse_sections_and_values () {
+
+		let config_file =
+			parse (EXAMPLE).unwrap ();
+
+		let section_names: Vec <& str> =
+			config_file.sections ().iter ().map (
+				|section| section.name (),
+			).collect ();
+
+		assert_eq! (
+			section_names,
+			vec! [ "", "repositories.main", "server", "convert.balance-bundles" ]);
+
+		let repository =
+			config_file.section ("repositories.main").unwrap ();
+
+		assert_eq! (repository.line_number (), 3);
+
+		assert_eq! (
+			repository.get_path ("path").unwrap (),
+			Some (PathBuf::from ("/backups/main")));
+
+		assert_eq! (
+			repository.get_string ("password-file").unwrap (),
+			Some ("/etc/rzbackup/main # key".to_string ()));
+
+		assert_eq! (
+			repository.get_size ("memory-cache-size").unwrap (),
+			Some (4 << 30));
+
+		let server =
+			config_file.section ("server").unwrap ();
+
+		assert_eq! (server.get_u64 ("max-threads").unwrap (), Some (1024));
+		assert_eq! (server.get_bool ("watch-indexes").unwrap (), Some (true));
+
+		assert_eq! (
+			server.get_duration ("idle-timeout").unwrap (),
+			Some (Duration::from_secs (30)));
+
+		assert_eq! (
+			server.get ("escaped"),
+			Some (& ConfigValue::String (
+				"tab\there \"quoted\"".to_string ())));
+
+		assert_eq! (server.get ("missing"), None);
+		assert_eq! (server.get_u64 ("missing").unwrap (), None);
+
+		assert! (config_file.section ("client").is_none ());
+		assert! (config_file.section ("").is_some ());
+
+	}
+
+	#[ test ]
+	fn sections_with_prefix () {
+
+		let config_file =
+			parse (
+				"[repositories.main]\n\
+				[repositories.archive]\n\
+				[repositories]\n\
+				[repositoriesx.other]\n",
+			).unwrap ();
+
+		let names: Vec <& str> =
+			config_file.sections_with_prefix (
+				"repositories",
+			).into_iter ().map (
+				|(name, _)| name,
+			).collect ();
+
+		assert_eq! (names, vec! [ "main", "archive" ]);
+
+	}
+
+	#[ test ]
+	fn parse_errors () {
+
+		for & (source, expected_error) in [
+			("[server\n", "test.conf:1: Invalid section header"),
+			("[]\n", "test.conf:1: Invalid section name: "),
+			("[server]\n[server]\n", "test.conf:2: Duplicated section: server"),
+			("key\n", "test.conf:1: Expected 'key = value'"),
+			("bad key = 1\n", "test.conf:1: Invalid key: bad key"),
+			("key =\n", "test.conf:1: Missing value"),
+			("key = 1\nkey = 2\n", "test.conf:2: Duplicated key: key"),
+			("key = \"unterminated\n", "test.conf:1: Invalid value: \"unterminated"),
+			("key = 'it's'\n", "test.conf:1: Invalid string: 'it's'"),
+			("key = \"bad \\x escape\"\n", "test.conf:1: Invalid string: \"bad \\x escape\""),
+			("key = twelve\n", "test.conf:1: Invalid value: twelve"),
+		].iter () {
+
+			assert_eq! (
+				parse (source).err (),
+				Some (expected_error.to_string ()),
+				"{}", source);
+
+		}
+
+	}
+
+	#[ test ]
+	fn value_type_errors () {
+
+		let config_file =
+			parse (
+				"[server]\n\
+				max-threads = \"many\"\n\
+				memory-cache-size = \"lots\"\n\
+				idle-timeout = 30\n\
+				watch-indexes = 1\n",
+			).unwrap ();
+
+		let server =
+			config_file.section ("server").unwrap ();
+
+		assert_eq! (
+			server.get_u64 ("max-threads").err (),
+			Some ("Line 2: Value for max-threads in section [server] must be \
+				an integer".to_string ()));
+
+		assert_eq! (
+			server.get_size ("memory-cache-size").err (),
+			Some ("Line 3: Invalid value for memory-cache-size in section \
+				[server]: Invalid size: lots".to_string ()));
+
+		assert! (server.get_duration ("idle-timeout").is_err ());
+		assert! (server.get_bool ("watch-indexes").is_err ());
+
+		assert_eq! (
+			server.check_keys (
+				& [ "max-threads", "memory-cache-size", "idle-timeout" ],
+			).err (),
+			Some ("Line 5: Unknown key watch-indexes in section [server]"
+				.to_string ()));
+
+	}
+
+}
+
+// ex: noet ts=4 filetype=rust
@@ -0,0 +1,188 @@
+use std::ffi::CString;
+use std::io;
+use std::os::unix::ffi::OsStrExt;
+use std::path::Path;
+use std::time::Duration;
+
+use libc;
+
+// inotify is not exposed by our version of libc, so we declare what we need
+
+extern "C" {
+
+	fn inotify_init1 (
+		flags: libc::c_int,
+	) -> libc::c_int;
+
+	fn inotify_add_watch (
+		fd: libc::c_int,
+		pathname: * const libc::c_char,
+		mask: u32,
+	) -> libc::c_int;
+
+}
+
+const IN_CLOSE_WRITE: u32 = 0x0000_0008;
+const IN_MOVED_FROM: u32 = 0x0000_0040;
+const IN_MOVED_TO: u32 = 0x0000_0080;
+const IN_CREATE: u32 = 0x0000_0100;
+const IN_DELETE: u32 = 0x0000_0200;
+
+const EVENT_BUFFER_SIZE: usize = 0x1000;
+
+/// Watches a single directory for files being created, completed, renamed or
+/// removed, using inotify. This only reports that something has changed, not
+/// what, since callers are expected to rescan the directory.
+
+pub struct DirectoryWatcher {
+	fd: libc::c_int,
+}
+
+impl DirectoryWatcher {
+
+	pub fn new (
+		path: & Path,
+	) -> io::Result <DirectoryWatcher> {
+
+		let path_c =
+			CString::new (
+				path.as_os_str ().as_bytes (),
+			).map_err (
+				|_| io::Error::new (
+					io::ErrorKind::InvalidInput,
+					"Path contains a null byte")
+			) ?;
+
+		let fd = unsafe {
+			inotify_init1 (
+				libc::O_CLOEXEC)
+		};
+
+		if fd < 0 {
+			return Err (io::Error::last_os_error ());
+		}
+
+		let watcher =
+			DirectoryWatcher {
+				fd: fd,
+			};
+
+		let watch_result = unsafe {
+			inotify_add_watch (
+				fd,
+				path_c.as_ptr (),
+				IN_CLOSE_WRITE | IN_MOVED_FROM | IN_MOVED_TO | IN_CREATE
+					| IN_DELETE)
+		};
+
+		if watch_result < 0 {
+			return Err (io::Error::last_os_error ());
+		}
+
+		Ok (watcher)
+
+	}
+
+	/// Blocks until at least one change has been seen.
+
+	pub fn wait (
+		& mut self,
+	) -> io::Result <()> {
+
+		while ! self.wait_timeout (None) ? {}
+
+		Ok (())
+
+	}
+
+	/// Waits for a change for up to the given time, returning true if one was
+	/// seen, and consuming any pending events.
+
+	pub fn wait_timeout (
+		& mut self,
+		timeout: Option <Duration>,
+	) -> io::Result <bool> {
+
+		let timeout_millis =
+			timeout.map (
+				|timeout|
+				timeout.as_secs () as libc::c_int * 1000
+					+ timeout.subsec_nanos () as libc::c_int / 1_000_000
+			).unwrap_or (-1);
+
+		let mut poll_fd =
+			libc::pollfd {
+				fd: self.fd,
+				events: libc::POLLIN,
+				revents: 0,
+			};
+
+		let poll_result = unsafe {
+			libc::poll (
+				& mut poll_fd,
+				1,
+				timeout_millis)
+		};
+
+		if poll_result < 0 {
+
+			let error =
+				io::Error::last_os_error ();
+
+			if error.kind () == io::ErrorKind::Interrupted {
+				return Ok (false);
+			}
+
+			return Err (error);
+
+		}
+
+		if poll_result == 0 {
+			return Ok (false);
+		}
+
+		let mut buffer: [u8; EVENT_BUFFER_SIZE] =
+			[0u8; EVENT_BUFFER_SIZE];
+
+		let read_result = unsafe {
+			libc::read (
+				self.fd,
+				buffer.as_mut_ptr () as * mut libc::c_void,
+				EVENT_BUFFER_SIZE)
+		};
+
+		if read_result < 0 {
+
+			let error =
+				io::Error::last_os_error ();
+
+			if error.kind () == io::ErrorKind::Interrupted {
+				return Ok (false);
+			}
+
+			return Err (error);
+
+		}
+
+		Ok (read_result > 0)
+
+	}
+
+}
+
+impl Drop for DirectoryWatcher {
+
+	fn drop (
+		& mut self,
+	) {
+
+		unsafe {
+			libc::close (
+				self.fd);
+		}
+
+	}
+
+}
+
+// ex: noet ts=4 filetype=rust
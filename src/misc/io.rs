@@ -1,4 +1,3 @@
-use std::error::Error;
 use std::io;
 use std::io::Write;
 
@@ -9,7 +8,7 @@ pub fn io_result <Type> (
 	result.map_err (
 		|io_error|
 
-		io_error.description ().to_string ()
+		io_error.to_string ()
 
 	)
 
@@ -29,7 +28,7 @@ pub fn io_result_with_prefix <
 		format! (
 			"{}{}",
 			prefix_function (),
-			io_error.description ())
+			io_error)
 
 	)
 
@@ -108,4 +107,53 @@ impl <
 
 }
 
+/// Discards everything written to it, keeping count of the number of bytes, to
+/// find out the size of a file without writing it.
+
+#[ derive (Default) ]
+pub struct CountingWriter {
+	count: u64,
+}
+
+impl CountingWriter {
+
+	pub fn new (
+	) -> CountingWriter {
+
+		CountingWriter {
+			count: 0,
+		}
+
+	}
+
+	pub fn count (& self) -> u64 {
+		self.count
+	}
+
+}
+
+impl Write for CountingWriter {
+
+	fn write (
+		& mut self,
+		buffer: & [u8],
+	) -> io::Result <usize> {
+
+		self.count +=
+			buffer.len () as u64;
+
+		Ok (buffer.len ())
+
+	}
+
+	fn flush (
+		& mut self,
+	) -> io::Result <()> {
+
+		Ok (())
+
+	}
+
+}
+
 // ex: noet ts=4 filetype=rust
@@ -0,0 +1,110 @@
+use std::fs::File;
+use std::io;
+use std::os::unix::io::AsRawFd;
+use std::ptr;
+use std::slice;
+
+use libc;
+
+/// A read-only, shared memory mapping of an entire file. The mapping remains
+/// valid after the file is closed, and is unmapped on drop.
+
+pub struct MappedFile {
+	address: * mut libc::c_void,
+	length: usize,
+}
+
+unsafe impl Send for MappedFile {}
+unsafe impl Sync for MappedFile {}
+
+impl MappedFile {
+
+	pub fn open (
+		file: & File,
+	) -> io::Result <MappedFile> {
+
+		let length =
+			file.metadata () ?.len () as usize;
+
+		// mmap does not allow empty mappings
+
+		if length == 0 {
+
+			return Ok (MappedFile {
+				address: ptr::null_mut (),
+				length: 0,
+			});
+
+		}
+
+		let address = unsafe {
+
+			libc::mmap (
+				ptr::null_mut (),
+				length,
+				libc::PROT_READ,
+				libc::MAP_SHARED,
+				file.as_raw_fd (),
+				0)
+
+		};
+
+		if address == libc::MAP_FAILED {
+			return Err (io::Error::last_os_error ());
+		}
+
+		Ok (MappedFile {
+			address: address,
+			length: length,
+		})
+
+	}
+
+	#[ inline ]
+	pub fn as_slice (& self) -> & [u8] {
+
+		if self.length == 0 {
+			return & [];
+		}
+
+		unsafe {
+			slice::from_raw_parts (
+				self.address as * const u8,
+				self.length)
+		}
+
+	}
+
+	#[ inline ]
+	pub fn len (& self) -> usize {
+		self.length
+	}
+
+	#[ inline ]
+	pub fn is_empty (& self) -> bool {
+		self.length == 0
+	}
+
+}
+
+impl Drop for MappedFile {
+
+	fn drop (
+		& mut self,
+	) {
+
+		if self.length != 0 {
+
+			unsafe {
+				libc::munmap (
+					self.address,
+					self.length);
+			}
+
+		}
+
+	}
+
+}
+
+// ex: noet ts=4 filetype=rust
@@ -1,24 +1,41 @@
 pub mod args;
+pub mod config;
 
 #[ macro_use ]
 mod command;
 
+mod append_only;
 mod atomic_file_writer;
+mod audit_log;
 mod cloning_shared_future;
 mod concurrent_controller;
+mod directory_watcher;
 mod error;
 mod fs;
 mod io;
+mod mmap;
 mod protobuf;
+mod reed_solomon;
+mod signals;
+mod socket;
+mod sparse_file_writer;
 
+pub use self::append_only::*;
 pub use self::atomic_file_writer::*;
+pub use self::audit_log::*;
 pub use self::cloning_shared_future::*;
 pub use self::command::*;
 pub use self::concurrent_controller::*;
+pub use self::directory_watcher::*;
 pub use self::error::*;
 pub use self::fs::*;
 pub use self::io::*;
+pub use self::mmap::*;
 pub use self::protobuf::*;
+pub use self::reed_solomon::*;
+pub use self::signals::*;
+pub use self::socket::*;
+pub use self::sparse_file_writer::*;
 //pub use self::task_queue::*;
 
 pub fn to_array_16 (
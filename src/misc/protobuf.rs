@@ -1,6 +1,5 @@
 use protobuf::ProtobufError;
 
-use std::error::Error;
 
 pub fn protobuf_result <Type> (
 	result: Result <Type, ProtobufError>,
@@ -8,7 +7,7 @@ pub fn protobuf_result <Type> (
 
 	result.map_err (
 		|protobuf_error|
-		protobuf_error.description ().to_string ()
+		protobuf_error.to_string ()
 	)
 
 }
@@ -27,7 +26,7 @@ pub fn protobuf_result_with_prefix <
 		format! (
 			"{}{}",
 			prefix_function (),
-			protobuf_error.description ())
+			protobuf_error)
 
 	)
 
@@ -0,0 +1,539 @@
+//! Reed-Solomon erasure coding over GF(2^8), using a Cauchy matrix, so that any
+//! data blocks can be rebuilt from the same number of parity blocks, as long as
+//! it is known which blocks are damaged.
+
+/// Reducing polynomial for GF(2^8), x^8 + x^4 + x^3 + x^2 + 1
+const FIELD_POLYNOMIAL: u32 = 0x11d;
+
+/// Data and parity blocks together can't exceed the size of the field
+pub const REED_SOLOMON_MAX_BLOCKS: usize = 256;
+
+struct GaloisTables {
+	exp: [u8; 512],
+	log: [u8; 256],
+}
+
+lazy_static! {
+
+	static ref GALOIS_TABLES: GaloisTables = {
+
+		let mut tables = GaloisTables {
+			exp: [0u8; 512],
+			log: [0u8; 256],
+		};
+
+		let mut value: u32 = 1;
+
+		for power in 0 .. 255 {
+
+			tables.exp [power] = value as u8;
+			tables.log [value as usize] = power as u8;
+
+			value <<= 1;
+
+			if value & 0x100 != 0 {
+				value ^= FIELD_POLYNOMIAL;
+			}
+
+		}
+
+		for power in 255 .. 512 {
+			tables.exp [power] = tables.exp [power - 255];
+		}
+
+		tables
+
+	};
+
+}
+
+#[ inline ]
+fn galois_multiply (
+	left: u8,
+	right: u8,
+) -> u8 {
+
+	if left == 0 || right == 0 {
+		return 0;
+	}
+
+	GALOIS_TABLES.exp [
+		GALOIS_TABLES.log [left as usize] as usize
+		+ GALOIS_TABLES.log [right as usize] as usize]
+
+}
+
+#[ inline ]
+fn galois_inverse (
+	value: u8,
+) -> u8 {
+
+	if value == 0 {
+		panic! ("Zero has no inverse");
+	}
+
+	GALOIS_TABLES.exp [
+		255 - GALOIS_TABLES.log [value as usize] as usize]
+
+}
+
+/// Adds `coefficient` times `source` to `target`, byte by byte.
+
+fn galois_multiply_add (
+	target: & mut [u8],
+	source: & [u8],
+	coefficient: u8,
+) {
+
+	if coefficient == 0 {
+		return;
+	}
+
+	let mut products: [u8; 256] =
+		[0u8; 256];
+
+	for value in 0 .. 256 {
+
+		products [value] =
+			galois_multiply (
+				value as u8,
+				coefficient);
+
+	}
+
+	for (target_byte, & source_byte)
+	in target.iter_mut ().zip (source.iter ()) {
+
+		* target_byte ^=
+			products [source_byte as usize];
+
+	}
+
+}
+
+pub struct ReedSolomon {
+	data_blocks: usize,
+	parity_blocks: usize,
+}
+
+impl ReedSolomon {
+
+	pub fn new (
+		data_blocks: usize,
+		parity_blocks: usize,
+	) -> Result <ReedSolomon, String> {
+
+		if data_blocks == 0
+			|| data_blocks + parity_blocks > REED_SOLOMON_MAX_BLOCKS {
+
+			return Err (
+				format! (
+					"Invalid Reed-Solomon layout with {} data and {} parity \
+					blocks",
+					data_blocks,
+					parity_blocks));
+
+		}
+
+		Ok (ReedSolomon {
+			data_blocks: data_blocks,
+			parity_blocks: parity_blocks,
+		})
+
+	}
+
+	/// Element of the Cauchy matrix, 1 / (x + y), where the x values for
+	/// parity rows and the y values for data columns are all distinct.
+
+	#[ inline ]
+	fn coefficient (
+		& self,
+		parity_index: usize,
+		data_index: usize,
+	) -> u8 {
+
+		galois_inverse (
+			(self.data_blocks + parity_index) as u8
+				^ data_index as u8)
+
+	}
+
+	/// Calculates the parity blocks for the data blocks, which must all be the
+	/// same size.
+
+	pub fn encode (
+		& self,
+		data: & [& [u8]],
+	) -> Vec <Vec <u8>> {
+
+		assert! (data.len () == self.data_blocks);
+
+		let block_size =
+			data [0].len ();
+
+		(0 .. self.parity_blocks).map (
+			|parity_index| {
+
+			let mut parity: Vec <u8> =
+				vec! [0u8; block_size];
+
+			for (data_index, data_block) in data.iter ().enumerate () {
+
+				galois_multiply_add (
+					& mut parity,
+					data_block,
+					self.coefficient (
+						parity_index,
+						data_index));
+
+			}
+
+			parity
+
+		}).collect ()
+
+	}
+
+	/// Rebuilds missing data blocks. The blocks are the data blocks followed by
+	/// the parity blocks, with `None` for any which are damaged. Missing parity
+	/// blocks are not rebuilt.
+
+	pub fn reconstruct (
+		& self,
+		blocks: & mut [Option <Vec <u8>>],
+	) -> Result <(), String> {
+
+		assert! (blocks.len () == self.data_blocks + self.parity_blocks);
+
+		let missing_data: Vec <usize> =
+			(0 .. self.data_blocks).filter (
+				|& data_index| blocks [data_index].is_none ()
+			).collect ();
+
+		if missing_data.is_empty () {
+			return Ok (());
+		}
+
+		let available_parity: Vec <usize> =
+			(0 .. self.parity_blocks).filter (
+				|& parity_index|
+				blocks [self.data_blocks + parity_index].is_some ()
+			).take (missing_data.len ()).collect ();
+
+		if available_parity.len () < missing_data.len () {
+
+			return Err (
+				format! (
+					"{} damaged data blocks but only {} usable parity blocks",
+					missing_data.len (),
+					available_parity.len ()));
+
+		}
+
+		let block_size =
+			blocks [self.data_blocks + available_parity [0]]
+				.as_ref ().unwrap ().len ();
+
+		// remove the known data blocks from the chosen parity blocks, leaving
+		// only the contribution of the missing ones
+
+		let remainders: Vec <Vec <u8>> =
+			available_parity.iter ().map (
+				|& parity_index| {
+
+				let mut remainder =
+					blocks [self.data_blocks + parity_index]
+						.as_ref ().unwrap ().clone ();
+
+				for data_index in 0 .. self.data_blocks {
+
+					if let Some (ref data_block) = blocks [data_index] {
+
+						galois_multiply_add (
+							& mut remainder,
+							data_block,
+							self.coefficient (
+								parity_index,
+								data_index));
+
+					}
+
+				}
+
+				remainder
+
+			}).collect ();
+
+		// invert the square sub-matrix for the missing blocks, which is always
+		// possible for a Cauchy matrix
+
+		let size =
+			missing_data.len ();
+
+		let mut matrix: Vec <Vec <u8>> =
+			available_parity.iter ().map (
+				|& parity_index|
+				missing_data.iter ().map (
+					|& data_index|
+					self.coefficient (
+						parity_index,
+						data_index)
+				).collect ()
+			).collect ();
+
+		let inverse =
+			galois_invert_matrix (
+				& mut matrix,
+				size,
+			) ?;
+
+		// and apply the inverse to rebuild each missing block
+
+		for (row, & data_index) in missing_data.iter ().enumerate () {
+
+			let mut data_block: Vec <u8> =
+				vec! [0u8; block_size];
+
+			for column in 0 .. size {
+
+				galois_multiply_add (
+					& mut data_block,
+					& remainders [column],
+					inverse [row] [column]);
+
+			}
+
+			blocks [data_index] =
+				Some (data_block);
+
+		}
+
+		Ok (())
+
+	}
+
+}
+
+fn galois_invert_matrix (
+	matrix: & mut [Vec <u8>],
+	size: usize,
+) -> Result <Vec <Vec <u8>>, String> {
+
+	let mut inverse: Vec <Vec <u8>> =
+		(0 .. size).map (
+			|row|
+			(0 .. size).map (
+				|column| if row == column { 1 } else { 0 }
+			).collect ()
+		).collect ();
+
+	for column in 0 .. size {
+
+		// find a pivot
+
+		let pivot_row =
+			(column .. size).find (
+				|& row| matrix [row] [column] != 0,
+			).ok_or_else (
+				|| "Reed-Solomon matrix is singular".to_string (),
+			) ?;
+
+		matrix.swap (column, pivot_row);
+		inverse.swap (column, pivot_row);
+
+		// scale the pivot row to one
+
+		let pivot_inverse =
+			galois_inverse (
+				matrix [column] [column]);
+
+		for index in 0 .. size {
+
+			matrix [column] [index] =
+				galois_multiply (
+					matrix [column] [index],
+					pivot_inverse);
+
+			inverse [column] [index] =
+				galois_multiply (
+					inverse [column] [index],
+					pivot_inverse);
+
+		}
+
+		// and eliminate the column from every other row
+
+		for row in 0 .. size {
+
+			if row == column {
+				continue;
+			}
+
+			let factor =
+				matrix [row] [column];
+
+			if factor == 0 {
+				continue;
+			}
+
+			for index in 0 .. size {
+
+				let matrix_value =
+					galois_multiply (
+						matrix [column] [index],
+						factor);
+
+				let inverse_value =
+					galois_multiply (
+						inverse [column] [index],
+						factor);
+
+				matrix [row] [index] ^= matrix_value;
+				inverse [row] [index] ^= inverse_value;
+
+			}
+
+		}
+
+	}
+
+	Ok (inverse)
+
+}
+
+#[ cfg (test) ]
+mod tests {
+
+	use super::*;
+
+	fn test_data (
+		data_blocks: usize,
+		block_size: usize,
+	) -> Vec <Vec <u8>> {
+
+		(0 .. data_blocks).map (
+			|data_index|
+
+			(0 .. block_size).map (
+				|byte_index|
+				(data_index * 31 + byte_index * 7 + byte_index / 13) as u8
+			).collect ()
+
+		).collect ()
+
+	}
+
+	fn encode_and_damage (
+		reed_solomon: & ReedSolomon,
+		data: & [Vec <u8>],
+		damaged_blocks: & [usize],
+	) -> Vec <Option <Vec <u8>>> {
+
+		let parity =
+			reed_solomon.encode (
+				& data.iter ().map (
+					|data_block| & data_block [..]
+				).collect::<Vec <& [u8]>> ());
+
+		data.iter ().chain (parity.iter ()).enumerate ().map (
+			|(block_index, block)|
+
+			if damaged_blocks.contains (& block_index) {
+				None
+			} else {
+				Some (block.clone ())
+			}
+
+		).collect ()
+
+	}
+
+	#[ test ]
+	fn repair_up_to_parity_blocks () {
+
+		// 5% redundancy, as used by add-parity by default
+
+		let reed_solomon =
+			ReedSolomon::new (200, 10).unwrap ();
+
+		let data =
+			test_data (200, 64);
+
+		for damaged_blocks in [
+			vec! [0],
+			vec! [199],
+			vec! [0, 1, 2, 3, 4, 5, 6, 7, 8, 9],
+			vec! [190, 191, 192, 193, 194, 195, 196, 197, 198, 199],
+			vec! [3, 17, 50, 51, 99, 120, 150, 160, 170, 180],
+			vec! [5, 6, 7, 8, 9, 200, 201, 202, 203, 204],
+		].iter () {
+
+			let mut blocks =
+				encode_and_damage (
+					& reed_solomon,
+					& data,
+					damaged_blocks);
+
+			reed_solomon.reconstruct (
+				& mut blocks,
+			).unwrap ();
+
+			for (data_index, data_block) in data.iter ().enumerate () {
+
+				assert_eq! (
+					blocks [data_index].as_ref (),
+					Some (data_block));
+
+			}
+
+		}
+
+	}
+
+	#[ test ]
+	fn too_much_damage () {
+
+		let reed_solomon =
+			ReedSolomon::new (20, 2).unwrap ();
+
+		let data =
+			test_data (20, 16);
+
+		let mut blocks =
+			encode_and_damage (
+				& reed_solomon,
+				& data,
+				& [1, 2, 3]);
+
+		assert! (
+			reed_solomon.reconstruct (
+				& mut blocks,
+			).is_err ());
+
+		// damaged parity blocks can't be used either
+
+		let mut blocks =
+			encode_and_damage (
+				& reed_solomon,
+				& data,
+				& [1, 2, 20]);
+
+		assert! (
+			reed_solomon.reconstruct (
+				& mut blocks,
+			).is_err ());
+
+	}
+
+	#[ test ]
+	fn invalid_layout () {
+
+		assert! (ReedSolomon::new (0, 1).is_err ());
+		assert! (ReedSolomon::new (250, 7).is_err ());
+		assert! (ReedSolomon::new (250, 6).is_ok ());
+
+	}
+
+}
+
+// ex: noet ts=4 filetype=rust
@@ -0,0 +1,82 @@
+use std::sync::atomic::AtomicBool;
+use std::sync::atomic::Ordering;
+
+use libc;
+
+/// Signals handled by long running commands. The handlers only set a flag,
+/// which must be polled using `take_signal`.
+
+#[ derive (Clone, Copy, Debug, Eq, PartialEq) ]
+pub enum Signal {
+	Terminate,
+	Reload,
+	Status,
+}
+
+static TERMINATE_RECEIVED: AtomicBool = AtomicBool::new (false);
+static RELOAD_RECEIVED: AtomicBool = AtomicBool::new (false);
+static STATUS_RECEIVED: AtomicBool = AtomicBool::new (false);
+
+extern "C" fn signal_handler (
+	signal_number: libc::c_int,
+) {
+
+	match signal_number {
+
+		libc::SIGTERM | libc::SIGINT =>
+			TERMINATE_RECEIVED.store (true, Ordering::SeqCst),
+
+		libc::SIGHUP =>
+			RELOAD_RECEIVED.store (true, Ordering::SeqCst),
+
+		libc::SIGUSR1 =>
+			STATUS_RECEIVED.store (true, Ordering::SeqCst),
+
+		_ => (),
+
+	}
+
+}
+
+/// Installs handlers for SIGTERM and SIGINT, which request termination, SIGHUP,
+/// which requests a reload, and SIGUSR1, which requests a status report.
+
+pub fn install_signal_handlers () {
+
+	for & signal_number in [
+		libc::SIGTERM,
+		libc::SIGINT,
+		libc::SIGHUP,
+		libc::SIGUSR1,
+	].iter () {
+
+		unsafe {
+			libc::signal (
+				signal_number,
+				signal_handler as extern "C" fn (libc::c_int) as libc::sighandler_t);
+		}
+
+	}
+
+}
+
+/// Returns true if the signal has been received since the last call.
+
+pub fn take_signal (
+	signal: Signal,
+) -> bool {
+
+	let flag =
+		match signal {
+			Signal::Terminate => & TERMINATE_RECEIVED,
+			Signal::Reload => & RELOAD_RECEIVED,
+			Signal::Status => & STATUS_RECEIVED,
+		};
+
+	flag.swap (
+		false,
+		Ordering::SeqCst)
+
+}
+
+// ex: noet ts=4 filetype=rust
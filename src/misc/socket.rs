@@ -0,0 +1,464 @@
+use std::env;
+use std::fs;
+use std::io;
+use std::io::Read;
+use std::io::Write;
+use std::mem;
+use std::net::Shutdown;
+use std::net::TcpListener;
+use std::net::TcpStream;
+use std::os::unix::fs::FileTypeExt;
+use std::os::unix::io::FromRawFd;
+use std::os::unix::net::UnixListener;
+use std::os::unix::net::UnixStream;
+use std::path::PathBuf;
+use std::time::Duration;
+
+use libc;
+
+/// Addresses starting with this prefix refer to a unix domain socket, and the
+/// rest of the address is the path. Anything else is a TCP address.
+
+pub const UNIX_ADDRESS_PREFIX: & 'static str = "unix:";
+
+/// First file descriptor passed by systemd socket activation
+
+const LISTEN_FDS_START: libc::c_int = 3;
+
+/// A connected stream socket, either TCP or unix domain.
+
+pub enum SocketStream {
+	Tcp (TcpStream),
+	Unix (UnixStream),
+}
+
+/// A listening stream socket, either TCP or unix domain. A unix domain socket
+/// which was bound by this process is removed when the listener is dropped.
+
+pub enum SocketListener {
+	Tcp (TcpListener),
+	Unix (UnixListener, Option <PathBuf>),
+}
+
+impl SocketStream {
+
+	pub fn connect (
+		address: & str,
+	) -> io::Result <SocketStream> {
+
+		if let Some (unix_path) = address.strip_prefix (UNIX_ADDRESS_PREFIX) {
+
+			Ok (SocketStream::Unix (
+				UnixStream::connect (
+					unix_path,
+				) ?
+			))
+
+		} else {
+
+			Ok (SocketStream::Tcp (
+				TcpStream::connect (
+					address,
+				) ?
+			))
+
+		}
+
+	}
+
+	/// Describes the other end of the connection, for logging. Clients of a
+	/// unix domain socket are normally unnamed, so the socket's own path is
+	/// used instead.
+
+	pub fn peer_description (
+		& self,
+	) -> String {
+
+		match * self {
+
+			SocketStream::Tcp (ref stream) =>
+				stream.peer_addr ().map (
+					|peer_address| peer_address.to_string ()
+				).unwrap_or_else (
+					|_| "unknown".to_string ()
+				),
+
+			SocketStream::Unix (ref stream) =>
+				stream.local_addr ().ok ().and_then (
+					|local_address|
+					local_address.as_pathname ().map (
+						|path| format! (
+							"{}{}",
+							UNIX_ADDRESS_PREFIX,
+							path.to_string_lossy ()))
+				).unwrap_or_else (
+					|| "unix socket".to_string ()
+				),
+
+		}
+
+	}
+
+	pub fn set_read_timeout (
+		& self,
+		timeout: Option <Duration>,
+	) -> io::Result <()> {
+
+		match * self {
+			SocketStream::Tcp (ref stream) => stream.set_read_timeout (timeout),
+			SocketStream::Unix (ref stream) => stream.set_read_timeout (timeout),
+		}
+
+	}
+
+	pub fn set_nonblocking (
+		& self,
+		nonblocking: bool,
+	) -> io::Result <()> {
+
+		match * self {
+			SocketStream::Tcp (ref stream) => stream.set_nonblocking (nonblocking),
+			SocketStream::Unix (ref stream) => stream.set_nonblocking (nonblocking),
+		}
+
+	}
+
+	pub fn try_clone (
+		& self,
+	) -> io::Result <SocketStream> {
+
+		Ok (match * self {
+			SocketStream::Tcp (ref stream) => SocketStream::Tcp (stream.try_clone () ?),
+			SocketStream::Unix (ref stream) => SocketStream::Unix (stream.try_clone () ?),
+		})
+
+	}
+
+	/// Shuts down both directions of the connection, so that any reads or
+	/// writes, including those in progress in other threads, fail.
+
+	pub fn shutdown (
+		& self,
+	) -> io::Result <()> {
+
+		match * self {
+			SocketStream::Tcp (ref stream) => stream.shutdown (Shutdown::Both),
+			SocketStream::Unix (ref stream) => stream.shutdown (Shutdown::Both),
+		}
+
+	}
+
+}
+
+impl Read for & SocketStream {
+
+	fn read (
+		& mut self,
+		buffer: & mut [u8],
+	) -> io::Result <usize> {
+
+		match * * self {
+			SocketStream::Tcp (ref stream) => (& * stream).read (buffer),
+			SocketStream::Unix (ref stream) => (& * stream).read (buffer),
+		}
+
+	}
+
+}
+
+impl Write for & SocketStream {
+
+	fn write (
+		& mut self,
+		buffer: & [u8],
+	) -> io::Result <usize> {
+
+		match * * self {
+			SocketStream::Tcp (ref stream) => (& * stream).write (buffer),
+			SocketStream::Unix (ref stream) => (& * stream).write (buffer),
+		}
+
+	}
+
+	fn flush (
+		& mut self,
+	) -> io::Result <()> {
+
+		match * * self {
+			SocketStream::Tcp (ref stream) => (& * stream).flush (),
+			SocketStream::Unix (ref stream) => (& * stream).flush (),
+		}
+
+	}
+
+}
+
+impl Read for SocketStream {
+
+	fn read (
+		& mut self,
+		buffer: & mut [u8],
+	) -> io::Result <usize> {
+
+		(& * self).read (
+			buffer)
+
+	}
+
+}
+
+impl Write for SocketStream {
+
+	fn write (
+		& mut self,
+		buffer: & [u8],
+	) -> io::Result <usize> {
+
+		(& * self).write (
+			buffer)
+
+	}
+
+	fn flush (
+		& mut self,
+	) -> io::Result <()> {
+
+		(& * self).flush ()
+
+	}
+
+}
+
+impl SocketListener {
+
+	/// Binds to a TCP address, or a unix domain socket path with the "unix:"
+	/// prefix. A stale unix domain socket left at the path is replaced.
+
+	pub fn bind (
+		address: & str,
+	) -> io::Result <SocketListener> {
+
+		if let Some (unix_path) = address.strip_prefix (UNIX_ADDRESS_PREFIX) {
+
+			let path =
+				PathBuf::from (
+					unix_path);
+
+			if let Ok (metadata) = fs::symlink_metadata (& path) {
+
+				if metadata.file_type ().is_socket () {
+
+					if UnixStream::connect (& path).is_ok () {
+
+						return Err (
+							io::Error::new (
+								io::ErrorKind::AddrInUse,
+								"Socket is already in use"));
+
+					}
+
+					fs::remove_file (
+						& path,
+					) ?;
+
+				}
+
+			}
+
+			Ok (SocketListener::Unix (
+				UnixListener::bind (
+					& path,
+				) ?,
+				Some (path),
+			))
+
+		} else {
+
+			Ok (SocketListener::Tcp (
+				TcpListener::bind (
+					address,
+				) ?
+			))
+
+		}
+
+	}
+
+	/// Takes ownership of listening sockets passed by systemd socket
+	/// activation, using the `LISTEN_PID` and `LISTEN_FDS` environment
+	/// variables. Returns an empty list if none were passed to this process.
+
+	pub fn from_listen_fds (
+	) -> io::Result <Vec <SocketListener>> {
+
+		let listen_pid =
+			env::var ("LISTEN_PID").ok ().and_then (
+				|listen_pid| listen_pid.parse::<libc::pid_t> ().ok ());
+
+		let listen_fds =
+			env::var ("LISTEN_FDS").ok ().and_then (
+				|listen_fds| listen_fds.parse::<libc::c_int> ().ok ());
+
+		// don't pass these on to any child processes
+
+		env::remove_var ("LISTEN_PID");
+		env::remove_var ("LISTEN_FDS");
+		env::remove_var ("LISTEN_FDNAMES");
+
+		let num_fds =
+			match (listen_pid, listen_fds) {
+
+			(Some (listen_pid), Some (listen_fds))
+				if listen_pid == unsafe { libc::getpid () } =>
+				listen_fds,
+
+			_ =>
+				return Ok (vec! []),
+
+		};
+
+		let mut listeners: Vec <SocketListener> =
+			Vec::new ();
+
+		for fd in LISTEN_FDS_START .. LISTEN_FDS_START + num_fds {
+
+			if unsafe {
+				libc::fcntl (
+					fd,
+					libc::F_SETFD,
+					libc::FD_CLOEXEC)
+			} < 0 {
+				return Err (io::Error::last_os_error ());
+			}
+
+			let mut socket_address: libc::sockaddr_storage =
+				unsafe { mem::zeroed () };
+
+			let mut socket_address_length =
+				mem::size_of::<libc::sockaddr_storage> () as libc::socklen_t;
+
+			if unsafe {
+				libc::getsockname (
+					fd,
+					& mut socket_address
+						as * mut libc::sockaddr_storage
+						as * mut libc::sockaddr,
+					& mut socket_address_length)
+			} < 0 {
+				return Err (io::Error::last_os_error ());
+			}
+
+			match socket_address.ss_family as libc::c_int {
+
+				libc::AF_UNIX =>
+					listeners.push (
+						SocketListener::Unix (
+							unsafe { UnixListener::from_raw_fd (fd) },
+							None)),
+
+				libc::AF_INET | libc::AF_INET6 =>
+					listeners.push (
+						SocketListener::Tcp (
+							unsafe { TcpListener::from_raw_fd (fd) })),
+
+				_ =>
+					return Err (
+						io::Error::new (
+							io::ErrorKind::InvalidInput,
+							format! (
+								"Unsupported socket type for file descriptor {}",
+								fd))),
+
+			}
+
+		}
+
+		Ok (listeners)
+
+	}
+
+	pub fn accept (
+		& self,
+	) -> io::Result <SocketStream> {
+
+		match * self {
+
+			SocketListener::Tcp (ref listener) =>
+				listener.accept ().map (
+					|(stream, _)| SocketStream::Tcp (stream)),
+
+			SocketListener::Unix (ref listener, _) =>
+				listener.accept ().map (
+					|(stream, _)| SocketStream::Unix (stream)),
+
+		}
+
+	}
+
+	pub fn set_nonblocking (
+		& self,
+		nonblocking: bool,
+	) -> io::Result <()> {
+
+		match * self {
+
+			SocketListener::Tcp (ref listener) =>
+				listener.set_nonblocking (nonblocking),
+
+			SocketListener::Unix (ref listener, _) =>
+				listener.set_nonblocking (nonblocking),
+
+		}
+
+	}
+
+	pub fn description (
+		& self,
+	) -> String {
+
+		match * self {
+
+			SocketListener::Tcp (ref listener) =>
+				listener.local_addr ().map (
+					|local_address| local_address.to_string ()
+				).unwrap_or_else (
+					|_| "unknown".to_string ()
+				),
+
+			SocketListener::Unix (ref listener, _) =>
+				listener.local_addr ().ok ().and_then (
+					|local_address|
+					local_address.as_pathname ().map (
+						|path| format! (
+							"{}{}",
+							UNIX_ADDRESS_PREFIX,
+							path.to_string_lossy ()))
+				).unwrap_or_else (
+					|| "unix socket".to_string ()
+				),
+
+		}
+
+	}
+
+}
+
+impl Drop for SocketListener {
+
+	fn drop (
+		& mut self,
+	) {
+
+		if let SocketListener::Unix (_, Some (ref path)) = * self {
+
+			fs::remove_file (
+				path,
+			).unwrap_or (());
+
+		}
+
+	}
+
+}
+
+// ex: noet ts=4 filetype=rust
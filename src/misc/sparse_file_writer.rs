@@ -0,0 +1,207 @@
+use std::fs::File;
+use std::io;
+use std::io::Seek;
+use std::io::SeekFrom;
+use std::io::Write;
+
+use misc::*;
+
+/// Writes to a file, seeking over any runs of zero bytes at least a block long,
+/// and any writes which consist entirely of zero bytes, instead of writing
+/// them. This leaves holes in the file on filesystems which support them. The
+/// file length is set correctly on close, so trailing zeros are not lost.
+
+pub struct SparseFileWriter {
+	file: File,
+	position: u64,
+	file_position: u64,
+}
+
+/// Shorter runs of zeros are written out, since they can't become holes.
+
+const SPARSE_BLOCK_SIZE: usize = 4096;
+
+impl SparseFileWriter {
+
+	pub fn wrap (
+		file: File,
+	) -> SparseFileWriter {
+
+		SparseFileWriter {
+			file: file,
+			position: 0,
+			file_position: 0,
+		}
+
+	}
+
+	fn write_data (
+		& mut self,
+		data: & [u8],
+	) -> io::Result <()> {
+
+		if data.is_empty () {
+			return Ok (());
+		}
+
+		if self.file_position != self.position {
+
+			self.file.seek (
+				SeekFrom::Start (
+					self.position),
+			) ?;
+
+			self.file_position =
+				self.position;
+
+		}
+
+		self.file.write_all (
+			data,
+		) ?;
+
+		self.position +=
+			data.len () as u64;
+
+		self.file_position =
+			self.position;
+
+		Ok (())
+
+	}
+
+}
+
+impl Write for SparseFileWriter {
+
+	fn write (
+		& mut self,
+		buffer: & [u8],
+	) -> io::Result <usize> {
+
+		let mut data_start = 0;
+		let mut offset = 0;
+
+		while offset < buffer.len () {
+
+			if buffer [offset] != 0 {
+				offset += 1;
+				continue;
+			}
+
+			let zeros_length =
+				buffer [offset .. ].iter ().take_while (
+					|byte| ** byte == 0,
+				).count ();
+
+			if zeros_length >= SPARSE_BLOCK_SIZE
+				|| zeros_length == buffer.len () {
+
+				self.write_data (
+					& buffer [data_start .. offset],
+				) ?;
+
+				self.position +=
+					zeros_length as u64;
+
+				data_start =
+					offset + zeros_length;
+
+			}
+
+			offset += zeros_length;
+
+		}
+
+		self.write_data (
+			& buffer [data_start .. ],
+		) ?;
+
+		Ok (buffer.len ())
+
+	}
+
+	fn flush (
+		& mut self,
+	) -> io::Result <()> {
+
+		self.file.flush ()
+
+	}
+
+}
+
+impl CloseableWrite for SparseFileWriter {
+
+	fn close (
+		& mut self,
+	) -> Result <(), io::Error> {
+
+		self.file.flush () ?;
+
+		self.file.set_len (
+			self.position,
+		) ?;
+
+		self.file.sync_all ()
+
+	}
+
+}
+
+#[ cfg (test) ]
+mod tests {
+
+	use std::env;
+	use std::fs;
+	use std::process;
+
+	use super::*;
+
+	#[ test ]
+	fn zero_runs_within_buffers () {
+
+		let path =
+			env::temp_dir ().join (
+				format! (
+					"rzbackup-sparse-file-writer-{}",
+					process::id ()));
+
+		let mut data: Vec <u8> =
+			Vec::new ();
+
+		data.extend_from_slice (b"start");
+		data.extend (vec! [0u8; SPARSE_BLOCK_SIZE * 3]);
+		data.extend_from_slice (b"middle\0\0\0end");
+		data.extend (vec! [0u8; SPARSE_BLOCK_SIZE]);
+
+		{
+
+			let mut writer =
+				SparseFileWriter::wrap (
+					File::create (& path).unwrap ());
+
+			writer.write_all (& data).unwrap ();
+			writer.write_all (& [0u8; 10]).unwrap ();
+			writer.write_all (b"x").unwrap ();
+			writer.write_all (& [0u8; 10]).unwrap ();
+			writer.close ().unwrap ();
+
+		}
+
+		data.extend (vec! [0u8; 10]);
+		data.extend_from_slice (b"x");
+		data.extend (vec! [0u8; 10]);
+
+		let written =
+			fs::read (& path).unwrap ();
+
+		fs::remove_file (& path).unwrap ();
+
+		assert_eq! (written, data);
+
+	}
+
+}
+
+// ex: noet ts=4 filetype=rust
@@ -0,0 +1,634 @@
+//! A minimal, pure-Rust client for the NBD "fixed newstyle" protocol. This only
+//! implements what is needed to talk to our own read-only server, and is used
+//! to test it without the kernel's `nbd-client`.
+
+use std::io;
+use std::io::Read;
+use std::io::Write;
+
+use byteorder::BigEndian;
+use byteorder::ReadBytesExt;
+use byteorder::WriteBytesExt;
+
+use misc::*;
+use nbd::protocol::*;
+
+pub struct NbdClient <Stream: Read + Write> {
+	stream: Stream,
+	export_size: u64,
+	transmission_flags: u16,
+	next_handle: u64,
+}
+
+impl <Stream: Read + Write> NbdClient <Stream> {
+
+	/// Performs the handshake over the provided stream and selects the named
+	/// export using `NBD_OPT_GO`. An empty name selects the default export.
+
+	pub fn connect (
+		stream: Stream,
+		export_name: & str,
+	) -> Result <NbdClient <Stream>, String> {
+
+		let mut stream = stream;
+
+		let (export_size, transmission_flags) =
+			io_result_with_prefix (
+				|| format! (
+					"Error during handshake: "),
+				Self::handshake_start (
+					& mut stream,
+				).and_then (
+					|_| Self::request_export_info (
+						& mut stream,
+						NBD_OPT_GO,
+						export_name)
+				),
+			) ?;
+
+		Ok (NbdClient {
+			stream: stream,
+			export_size: export_size,
+			transmission_flags: transmission_flags,
+			next_handle: 0,
+		})
+
+	}
+
+	/// Performs the handshake and selects the named export using the older
+	/// `NBD_OPT_EXPORT_NAME` option, which the server answers by closing the
+	/// connection if the export does not exist.
+
+	pub fn connect_export_name (
+		stream: Stream,
+		export_name: & str,
+	) -> Result <NbdClient <Stream>, String> {
+
+		let mut stream = stream;
+
+		let (export_size, transmission_flags) =
+			io_result_with_prefix (
+				|| format! (
+					"Error during handshake: "),
+				Self::export_name_impl (
+					& mut stream,
+					export_name),
+			) ?;
+
+		Ok (NbdClient {
+			stream: stream,
+			export_size: export_size,
+			transmission_flags: transmission_flags,
+			next_handle: 0,
+		})
+
+	}
+
+	/// Performs the handshake and returns the size and transmission flags of
+	/// the named export, using `NBD_OPT_INFO`, then aborts the session.
+
+	pub fn export_info (
+		stream: Stream,
+		export_name: & str,
+	) -> Result <(u64, u16), String> {
+
+		let mut stream = stream;
+
+		io_result_with_prefix (
+			|| format! (
+				"Error getting export information: "),
+			Self::handshake_start (
+				& mut stream,
+			).and_then (
+				|_| Self::request_export_info (
+					& mut stream,
+					NBD_OPT_INFO,
+					export_name)
+			).and_then (
+				|export_info| {
+
+				Self::abort (
+					& mut stream,
+				) ?;
+
+				Ok (export_info)
+
+			}),
+		)
+
+	}
+
+	/// Performs the handshake and returns the list of export names, using
+	/// `NBD_OPT_LIST`, then aborts the session.
+
+	pub fn list_exports (
+		stream: Stream,
+	) -> Result <Vec <String>, String> {
+
+		let mut stream = stream;
+
+		io_result_with_prefix (
+			|| format! (
+				"Error listing exports: "),
+			Self::list_exports_impl (
+				& mut stream),
+		)
+
+	}
+
+	fn export_name_impl (
+		stream: & mut Stream,
+		export_name: & str,
+	) -> io::Result <(u64, u16)> {
+
+		let no_zeroes =
+			Self::handshake_start (
+				stream,
+			) ?;
+
+		Self::send_option (
+			stream,
+			NBD_OPT_EXPORT_NAME,
+			export_name.as_bytes (),
+		) ?;
+
+		let export_size =
+			stream.read_u64::<BigEndian> () ?;
+
+		let transmission_flags =
+			stream.read_u16::<BigEndian> () ?;
+
+		if ! no_zeroes {
+
+			stream.read_exact (
+				& mut [0u8; 124],
+			) ?;
+
+		}
+
+		Ok ((export_size, transmission_flags))
+
+	}
+
+	/// Sends `NBD_OPT_INFO` or `NBD_OPT_GO` for the named export, and returns
+	/// its size and transmission flags from the server's reply.
+
+	fn request_export_info (
+		stream: & mut Stream,
+		option: u32,
+		export_name: & str,
+	) -> io::Result <(u64, u16)> {
+
+		let mut data: Vec <u8> =
+			Vec::new ();
+
+		data.write_u32::<BigEndian> (export_name.len () as u32) ?;
+		data.write_all (export_name.as_bytes ()) ?;
+		data.write_u16::<BigEndian> (0) ?;
+
+		Self::send_option (
+			stream,
+			option,
+			& data,
+		) ?;
+
+		let mut export_info: Option <(u64, u16)> = None;
+
+		loop {
+
+			let (reply_type, reply_data) =
+				Self::read_option_reply (
+					stream,
+					option,
+				) ?;
+
+			match reply_type {
+
+				NBD_REP_INFO => {
+
+					let mut reply_data =
+						& reply_data [..];
+
+					if reply_data.read_u16::<BigEndian> () ?
+						== NBD_INFO_EXPORT {
+
+						export_info = Some ((
+							reply_data.read_u64::<BigEndian> () ?,
+							reply_data.read_u16::<BigEndian> () ?,
+						));
+
+					}
+
+				},
+
+				NBD_REP_ACK => {
+
+					return export_info.ok_or_else (
+						|| io::Error::new (
+							io::ErrorKind::InvalidData,
+							"Server did not send export information"));
+
+				},
+
+				_ => {
+
+					return Err (
+						io::Error::other (
+							format! (
+								"Server rejected export {}: error {:#x}",
+								export_name,
+								reply_type)));
+
+				},
+
+			}
+
+		}
+
+	}
+
+	fn list_exports_impl (
+		stream: & mut Stream,
+	) -> io::Result <Vec <String>> {
+
+		Self::handshake_start (
+			stream,
+		) ?;
+
+		Self::send_option (
+			stream,
+			NBD_OPT_LIST,
+			& [],
+		) ?;
+
+		let mut export_names: Vec <String> =
+			Vec::new ();
+
+		loop {
+
+			let (reply_type, reply_data) =
+				Self::read_option_reply (
+					stream,
+					NBD_OPT_LIST,
+				) ?;
+
+			match reply_type {
+
+				NBD_REP_SERVER => {
+
+					let mut reply_data =
+						& reply_data [..];
+
+					let name_length =
+						reply_data.read_u32::<BigEndian> () ? as usize;
+
+					if name_length > reply_data.len () {
+
+						return Err (
+							io::Error::new (
+								io::ErrorKind::InvalidData,
+								"Invalid export name length"));
+
+					}
+
+					export_names.push (
+						String::from_utf8_lossy (
+							& reply_data [0 .. name_length],
+						).into_owned ());
+
+				},
+
+				NBD_REP_ACK =>
+					break,
+
+				_ => {
+
+					return Err (
+						io::Error::other (
+							format! (
+								"Server returned error {:#x}",
+								reply_type)));
+
+				},
+
+			}
+
+		}
+
+		Self::abort (
+			stream,
+		) ?;
+
+		Ok (export_names)
+
+	}
+
+	/// Ends the session during option haggling. The server acknowledges this
+	/// before closing the connection.
+
+	fn abort (
+		stream: & mut Stream,
+	) -> io::Result <()> {
+
+		Self::send_option (
+			stream,
+			NBD_OPT_ABORT,
+			& [],
+		) ?;
+
+		let (reply_type, _) =
+			Self::read_option_reply (
+				stream,
+				NBD_OPT_ABORT,
+			) ?;
+
+		if reply_type != NBD_REP_ACK {
+
+			return Err (
+				io::Error::new (
+					io::ErrorKind::InvalidData,
+					"Server did not acknowledge abort"));
+
+		}
+
+		Ok (())
+
+	}
+
+	/// Reads the server's greeting and sends our flags. Returns true if both
+	/// sides have agreed to omit the padding after `NBD_OPT_EXPORT_NAME`.
+
+	fn handshake_start (
+		stream: & mut Stream,
+	) -> io::Result <bool> {
+
+		if stream.read_u64::<BigEndian> () ? != NBD_MAGIC
+			|| stream.read_u64::<BigEndian> () ? != NBD_IHAVEOPT {
+
+			return Err (
+				io::Error::new (
+					io::ErrorKind::InvalidData,
+					"Server is not a newstyle NBD server"));
+
+		}
+
+		let handshake_flags =
+			stream.read_u16::<BigEndian> () ?;
+
+		if handshake_flags & NBD_FLAG_FIXED_NEWSTYLE == 0 {
+
+			return Err (
+				io::Error::new (
+					io::ErrorKind::InvalidData,
+					"Server does not support fixed newstyle negotiation"));
+
+		}
+
+		let mut client_flags =
+			NBD_FLAG_C_FIXED_NEWSTYLE;
+
+		if handshake_flags & NBD_FLAG_NO_ZEROES != 0 {
+			client_flags |= NBD_FLAG_C_NO_ZEROES;
+		}
+
+		stream.write_u32::<BigEndian> (client_flags) ?;
+
+		Ok (client_flags & NBD_FLAG_C_NO_ZEROES != 0)
+
+	}
+
+	fn send_option (
+		stream: & mut Stream,
+		option: u32,
+		data: & [u8],
+	) -> io::Result <()> {
+
+		stream.write_u64::<BigEndian> (NBD_IHAVEOPT) ?;
+		stream.write_u32::<BigEndian> (option) ?;
+		stream.write_u32::<BigEndian> (data.len () as u32) ?;
+		stream.write_all (data) ?;
+
+		stream.flush ()
+
+	}
+
+	fn read_option_reply (
+		stream: & mut Stream,
+		option: u32,
+	) -> io::Result <(u32, Vec <u8>)> {
+
+		if stream.read_u64::<BigEndian> () ? != NBD_REPLY_MAGIC
+			|| stream.read_u32::<BigEndian> () ? != option {
+
+			return Err (
+				io::Error::new (
+					io::ErrorKind::InvalidData,
+					"Invalid option reply"));
+
+		}
+
+		let reply_type =
+			stream.read_u32::<BigEndian> () ?;
+
+		let length =
+			stream.read_u32::<BigEndian> () ?;
+
+		let mut data: Vec <u8> =
+			vec! [0u8; length as usize];
+
+		stream.read_exact (
+			& mut data,
+		) ?;
+
+		Ok ((reply_type, data))
+
+	}
+
+	#[ inline ]
+	pub fn export_size (& self) -> u64 {
+		self.export_size
+	}
+
+	#[ inline ]
+	pub fn read_only (& self) -> bool {
+		self.transmission_flags & NBD_FLAG_READ_ONLY != 0
+	}
+
+	/// Reads a range of the export. Errors returned by the server are reported
+	/// with their NBD error number.
+
+	pub fn read (
+		& mut self,
+		offset: u64,
+		length: u32,
+	) -> Result <Vec <u8>, String> {
+
+		let handle =
+			self.send_request (
+				NBD_CMD_READ,
+				offset,
+				length,
+			) ?;
+
+		let error =
+			self.read_simple_reply (
+				handle,
+			) ?;
+
+		if error != 0 {
+
+			return Err (
+				format! (
+					"Server returned error {} for read at {}",
+					error,
+					offset));
+
+		}
+
+		let mut data: Vec <u8> =
+			vec! [0u8; length as usize];
+
+		io_result (
+			self.stream.read_exact (
+				& mut data),
+		) ?;
+
+		Ok (data)
+
+	}
+
+	/// Writes a range of the export, and returns the server's error number,
+	/// which is zero on success.
+
+	pub fn write (
+		& mut self,
+		offset: u64,
+		data: & [u8],
+	) -> Result <u32, String> {
+
+		let handle =
+			self.send_request (
+				NBD_CMD_WRITE,
+				offset,
+				data.len () as u32,
+			) ?;
+
+		io_result (
+			self.stream.write_all (
+				data),
+		) ?;
+
+		io_result (
+			self.stream.flush (),
+		) ?;
+
+		self.read_simple_reply (
+			handle)
+
+	}
+
+	/// Sends a command with no payload and returns the server's error number,
+	/// which is zero on success. Used for `NBD_CMD_FLUSH` and `NBD_CMD_TRIM`.
+
+	pub fn command (
+		& mut self,
+		command_type: u16,
+		offset: u64,
+		length: u32,
+	) -> Result <u32, String> {
+
+		let handle =
+			self.send_request (
+				command_type,
+				offset,
+				length,
+			) ?;
+
+		self.read_simple_reply (
+			handle)
+
+	}
+
+	pub fn disconnect (
+		mut self,
+	) -> Result <(), String> {
+
+		self.send_request (
+			NBD_CMD_DISC,
+			0,
+			0,
+		) ?;
+
+		Ok (())
+
+	}
+
+	fn send_request (
+		& mut self,
+		command_type: u16,
+		offset: u64,
+		length: u32,
+	) -> Result <u64, String> {
+
+		let handle =
+			self.next_handle;
+
+		self.next_handle += 1;
+
+		let mut request: Vec <u8> =
+			Vec::new ();
+
+		io_result (request.write_u32::<BigEndian> (NBD_REQUEST_MAGIC)) ?;
+		io_result (request.write_u16::<BigEndian> (0)) ?;
+		io_result (request.write_u16::<BigEndian> (command_type)) ?;
+		io_result (request.write_u64::<BigEndian> (handle)) ?;
+		io_result (request.write_u64::<BigEndian> (offset)) ?;
+		io_result (request.write_u32::<BigEndian> (length)) ?;
+
+		io_result (
+			self.stream.write_all (
+				& request),
+		) ?;
+
+		io_result (
+			self.stream.flush (),
+		) ?;
+
+		Ok (handle)
+
+	}
+
+	fn read_simple_reply (
+		& mut self,
+		handle: u64,
+	) -> Result <u32, String> {
+
+		let magic =
+			io_result (
+				self.stream.read_u32::<BigEndian> (),
+			) ?;
+
+		let error =
+			io_result (
+				self.stream.read_u32::<BigEndian> (),
+			) ?;
+
+		let reply_handle =
+			io_result (
+				self.stream.read_u64::<BigEndian> (),
+			) ?;
+
+		if magic != NBD_SIMPLE_REPLY_MAGIC || reply_handle != handle {
+
+			return Err (
+				"Invalid reply from server".to_string ());
+
+		}
+
+		Ok (error)
+
+	}
+
+}
+
+// ex: noet ts=4 filetype=rust
@@ -0,0 +1,10 @@
+#[ cfg (test) ]
+mod client;
+
+mod nbd_serve;
+mod protocol;
+
+pub use self::nbd_serve::*;
+pub use self::protocol::*;
+
+// ex: noet ts=4 filetype=rust
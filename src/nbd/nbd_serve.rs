@@ -0,0 +1,309 @@
+use std::net::TcpListener;
+use std::net::TcpStream;
+use std::path::PathBuf;
+use std::thread;
+
+use clap;
+
+use output;
+use output::Output;
+
+use misc::*;
+use misc::args::ClapSubCommandRzbackupArgs;
+use nbd::protocol::*;
+use zbackup::randaccess::*;
+use zbackup::repository::*;
+
+pub fn nbd_serve (
+	output: & Output,
+	arguments: & NbdServeArguments,
+) -> Result <bool, String> {
+
+	let repository =
+		string_result_with_prefix (
+			|| format! (
+				"Error opening repository: "),
+			Repository::open (
+				& output,
+				arguments.repository_config.clone (),
+				& arguments.repository_path,
+				arguments.password_file_path.as_ref (),
+			),
+		) ?;
+
+	// check the backup exists before we start listening
+
+	let export_size =
+		string_result_with_prefix (
+			|| format! (
+				"Error opening backup {}: ",
+				arguments.backup_name),
+			RandomAccess::new (
+				output,
+				& repository,
+				& arguments.backup_name),
+		) ?.size ();
+
+	let export_name =
+		arguments.export_name.clone ().unwrap_or_else (
+			|| arguments.backup_name.clone ());
+
+	let listener =
+		io_result_with_prefix (
+			|| format! (
+				"Error listening on {}: ",
+				arguments.listen_address),
+			TcpListener::bind (
+				& arguments.listen_address),
+		) ?;
+
+	output_message! (
+		output,
+		"Serving {} as NBD export {} on {}, {} bytes",
+		arguments.backup_name,
+		export_name,
+		arguments.listen_address,
+		export_size);
+
+	for stream in listener.incoming () {
+
+		match stream {
+
+			Ok (stream) => {
+
+				let repository =
+					repository.clone ();
+
+				let backup_name =
+					arguments.backup_name.clone ();
+
+				let export_name =
+					export_name.clone ();
+
+				thread::spawn (
+					move || {
+
+						handle_nbd_client (
+							& repository,
+							& backup_name,
+							& export_name,
+							stream)
+
+					}
+				);
+
+			},
+
+			Err (error) => {
+
+				println! (
+					"Connection failed: {}",
+					error);
+
+			},
+
+		}
+
+	}
+
+	// clean up and return
+
+	repository.close (
+		output);
+
+	Ok (true)
+
+}
+
+fn handle_nbd_client (
+	repository: & Repository,
+	backup_name: & str,
+	export_name: & str,
+	stream: TcpStream,
+) {
+
+	let peer_address =
+		stream.peer_addr ().unwrap ();
+
+	println! (
+		"Connection from: {}",
+		peer_address);
+
+	match handle_nbd_client_real (
+		repository,
+		backup_name,
+		export_name,
+		stream) {
+
+		Ok (_) => {
+
+			println! (
+				"Disconnection from: {}",
+				peer_address);
+
+		},
+
+		Err (error) => {
+
+			println! (
+				"Error from: {}: {}",
+				peer_address,
+				error);
+
+		},
+
+	}
+
+}
+
+fn handle_nbd_client_real (
+	repository: & Repository,
+	backup_name: & str,
+	export_name: & str,
+	stream: TcpStream,
+) -> Result <(), String> {
+
+	io_result (
+		stream.set_nodelay (
+			true),
+	) ?;
+
+	let mut random_access =
+		RandomAccess::new (
+			& output::null (),
+			repository,
+			backup_name,
+		) ?;
+
+	let export_size =
+		random_access.size ();
+
+	let mut stream =
+		stream;
+
+	nbd_serve_connection (
+		& mut stream,
+		export_name,
+		export_size,
+		& mut random_access,
+	)
+
+}
+
+command! (
+
+	name = nbd_serve,
+	export = nbd_serve_command,
+
+	arguments = NbdServeArguments {
+		repository_path: PathBuf,
+		password_file_path: Option <PathBuf>,
+		repository_config: RepositoryConfig,
+		backup_name: String,
+		export_name: Option <String>,
+		listen_address: String,
+	},
+
+	clap_subcommand = {
+
+		clap::SubCommand::with_name ("nbd-serve")
+			.about ("Exports a backup read-only over the NBD protocol")
+
+			.arg (
+				clap::Arg::with_name ("repository")
+
+				.long ("repository")
+				.value_name ("REPOSITORY")
+				.required (true)
+				.help ("Path to the repository")
+
+			)
+
+			.arg (
+				clap::Arg::with_name ("password-file")
+
+				.long ("password-file")
+				.value_name ("PASSWORD-FILE")
+				.required (false)
+				.help ("Path to the password file")
+
+			)
+
+			.arg (
+				clap::Arg::with_name ("backup-name")
+
+				.long ("backup-name")
+				.value_name ("BACKUP-NAME")
+				.required (true)
+				.help ("Name of backup to export")
+
+			)
+
+			.arg (
+				clap::Arg::with_name ("export-name")
+
+				.long ("export-name")
+				.value_name ("EXPORT-NAME")
+				.required (false)
+				.help ("Name of the NBD export, defaults to the backup name")
+
+			)
+
+			.arg (
+				clap::Arg::with_name ("listen-address")
+
+				.long ("listen-address")
+				.value_name ("ADDRESS:PORT")
+				.default_value ("localhost:10809")
+				.help ("Address to listen on, in host:port or ip:port format.")
+
+			)
+
+			.repository_config_args ()
+
+	},
+
+	clap_arguments_parse = |clap_matches| {
+
+		NbdServeArguments {
+
+			repository_path:
+				args::path_required (
+					clap_matches,
+					"repository"),
+
+			password_file_path:
+				args::path_optional (
+					clap_matches,
+					"password-file"),
+
+			repository_config:
+				args::repository_config (
+					clap_matches),
+
+			backup_name:
+				args::string_required (
+					clap_matches,
+					"backup-name"),
+
+			export_name:
+				args::string_optional (
+					clap_matches,
+					"export-name"),
+
+			listen_address:
+				args::string_required (
+					clap_matches,
+					"listen-address"),
+
+		}
+
+	},
+
+	action = |output, arguments| {
+		nbd_serve (output, arguments)
+	},
+
+);
+
+// ex: noet ts=4 filetype=rust
@@ -0,0 +1,925 @@
+//! Server side of the NBD "fixed newstyle" protocol, read-only. This is generic
+//! over the stream and the data source, so it can be used with any `Read` and
+//! `Seek` implementation, and tested without a real socket.
+
+use std::io;
+use std::io::Read;
+use std::io::Seek;
+use std::io::SeekFrom;
+use std::io::Write;
+
+use byteorder::BigEndian;
+use byteorder::ReadBytesExt;
+use byteorder::WriteBytesExt;
+
+use misc::*;
+
+// handshake
+
+pub const NBD_MAGIC: u64 = 0x4e42444d41474943;
+pub const NBD_IHAVEOPT: u64 = 0x49484156454f5054;
+pub const NBD_REPLY_MAGIC: u64 = 0x0003e889045565a9;
+
+pub const NBD_FLAG_FIXED_NEWSTYLE: u16 = 1 << 0;
+pub const NBD_FLAG_NO_ZEROES: u16 = 1 << 1;
+
+pub const NBD_FLAG_C_FIXED_NEWSTYLE: u32 = 1 << 0;
+pub const NBD_FLAG_C_NO_ZEROES: u32 = 1 << 1;
+
+// options
+
+pub const NBD_OPT_EXPORT_NAME: u32 = 1;
+pub const NBD_OPT_ABORT: u32 = 2;
+pub const NBD_OPT_LIST: u32 = 3;
+pub const NBD_OPT_INFO: u32 = 6;
+pub const NBD_OPT_GO: u32 = 7;
+
+pub const NBD_REP_ACK: u32 = 1;
+pub const NBD_REP_SERVER: u32 = 2;
+pub const NBD_REP_INFO: u32 = 3;
+pub const NBD_REP_ERR_UNSUP: u32 = (1 << 31) + 1;
+pub const NBD_REP_ERR_INVALID: u32 = (1 << 31) + 3;
+pub const NBD_REP_ERR_UNKNOWN: u32 = (1 << 31) + 6;
+
+pub const NBD_INFO_EXPORT: u16 = 0;
+
+// transmission
+
+pub const NBD_FLAG_HAS_FLAGS: u16 = 1 << 0;
+pub const NBD_FLAG_READ_ONLY: u16 = 1 << 1;
+pub const NBD_FLAG_SEND_FLUSH: u16 = 1 << 2;
+
+pub const NBD_REQUEST_MAGIC: u32 = 0x25609513;
+pub const NBD_SIMPLE_REPLY_MAGIC: u32 = 0x67446698;
+
+pub const NBD_CMD_READ: u16 = 0;
+pub const NBD_CMD_WRITE: u16 = 1;
+pub const NBD_CMD_DISC: u16 = 2;
+pub const NBD_CMD_FLUSH: u16 = 3;
+pub const NBD_CMD_TRIM: u16 = 4;
+
+pub const NBD_EPERM: u32 = 1;
+pub const NBD_EIO: u32 = 5;
+pub const NBD_EINVAL: u32 = 22;
+
+pub const NBD_TRANSMISSION_FLAGS: u16 =
+	NBD_FLAG_HAS_FLAGS | NBD_FLAG_READ_ONLY | NBD_FLAG_SEND_FLUSH;
+
+const MAX_OPTION_LENGTH: u32 = 4096;
+const MAX_READ_LENGTH: u32 = 32 * 1024 * 1024;
+
+/// Serves a single export over a single connection, performing the handshake
+/// and then handling requests until the client disconnects. Returns normally
+/// if the client aborts or disconnects cleanly.
+
+pub fn nbd_serve_connection <
+	Stream: Read + Write,
+	Source: Read + Seek,
+> (
+	stream: & mut Stream,
+	export_name: & str,
+	export_size: u64,
+	source: & mut Source,
+) -> Result <(), String> {
+
+	if ! io_result_with_prefix (
+		|| format! (
+			"Error during handshake: "),
+		nbd_handshake (
+			stream,
+			export_name,
+			export_size),
+	) ? {
+		return Ok (());
+	}
+
+	io_result_with_prefix (
+		|| format! (
+			"Error during transmission: "),
+		nbd_transmission (
+			stream,
+			export_size,
+			source),
+	)
+
+}
+
+fn nbd_handshake <
+	Stream: Read + Write,
+> (
+	stream: & mut Stream,
+	export_name: & str,
+	export_size: u64,
+) -> io::Result <bool> {
+
+	stream.write_u64::<BigEndian> (NBD_MAGIC) ?;
+	stream.write_u64::<BigEndian> (NBD_IHAVEOPT) ?;
+
+	stream.write_u16::<BigEndian> (
+		NBD_FLAG_FIXED_NEWSTYLE | NBD_FLAG_NO_ZEROES) ?;
+
+	stream.flush () ?;
+
+	let client_flags =
+		stream.read_u32::<BigEndian> () ?;
+
+	if client_flags & NBD_FLAG_C_FIXED_NEWSTYLE == 0 {
+
+		return Err (
+			io::Error::new (
+				io::ErrorKind::InvalidData,
+				"Client does not support fixed newstyle negotiation"));
+
+	}
+
+	let no_zeroes =
+		client_flags & NBD_FLAG_C_NO_ZEROES != 0;
+
+	// option haggling
+
+	loop {
+
+		let magic =
+			stream.read_u64::<BigEndian> () ?;
+
+		if magic != NBD_IHAVEOPT {
+
+			return Err (
+				io::Error::new (
+					io::ErrorKind::InvalidData,
+					"Invalid option magic"));
+
+		}
+
+		let option =
+			stream.read_u32::<BigEndian> () ?;
+
+		let length =
+			stream.read_u32::<BigEndian> () ?;
+
+		if length > MAX_OPTION_LENGTH {
+
+			return Err (
+				io::Error::new (
+					io::ErrorKind::InvalidData,
+					"Option data too long"));
+
+		}
+
+		let mut data: Vec <u8> =
+			vec! [0u8; length as usize];
+
+		stream.read_exact (
+			& mut data,
+		) ?;
+
+		match option {
+
+			NBD_OPT_EXPORT_NAME => {
+
+				// there is no way to report an error here except by closing
+				// the connection
+
+				if ! export_name_matches (& data, export_name) {
+					return Ok (false);
+				}
+
+				stream.write_u64::<BigEndian> (export_size) ?;
+				stream.write_u16::<BigEndian> (NBD_TRANSMISSION_FLAGS) ?;
+
+				if ! no_zeroes {
+					stream.write_all (& [0u8; 124]) ?;
+				}
+
+				stream.flush () ?;
+
+				return Ok (true);
+
+			},
+
+			NBD_OPT_ABORT => {
+
+				nbd_option_reply (
+					stream,
+					option,
+					NBD_REP_ACK,
+					& []) ?;
+
+				return Ok (false);
+
+			},
+
+			NBD_OPT_LIST => {
+
+				if ! data.is_empty () {
+
+					nbd_option_reply (
+						stream,
+						option,
+						NBD_REP_ERR_INVALID,
+						& []) ?;
+
+					continue;
+
+				}
+
+				let mut reply: Vec <u8> =
+					Vec::new ();
+
+				reply.write_u32::<BigEndian> (
+					export_name.len () as u32) ?;
+
+				reply.write_all (
+					export_name.as_bytes ()) ?;
+
+				nbd_option_reply (
+					stream,
+					option,
+					NBD_REP_SERVER,
+					& reply) ?;
+
+				nbd_option_reply (
+					stream,
+					option,
+					NBD_REP_ACK,
+					& []) ?;
+
+			},
+
+			NBD_OPT_INFO | NBD_OPT_GO => {
+
+				let requested_name =
+					match parse_info_request (& data) {
+
+					Some (requested_name) =>
+						requested_name,
+
+					None => {
+
+						nbd_option_reply (
+							stream,
+							option,
+							NBD_REP_ERR_INVALID,
+							& []) ?;
+
+						continue;
+
+					},
+
+				};
+
+				if ! export_name_matches (requested_name, export_name) {
+
+					nbd_option_reply (
+						stream,
+						option,
+						NBD_REP_ERR_UNKNOWN,
+						& []) ?;
+
+					continue;
+
+				}
+
+				let mut reply: Vec <u8> =
+					Vec::new ();
+
+				reply.write_u16::<BigEndian> (NBD_INFO_EXPORT) ?;
+				reply.write_u64::<BigEndian> (export_size) ?;
+				reply.write_u16::<BigEndian> (NBD_TRANSMISSION_FLAGS) ?;
+
+				nbd_option_reply (
+					stream,
+					option,
+					NBD_REP_INFO,
+					& reply) ?;
+
+				nbd_option_reply (
+					stream,
+					option,
+					NBD_REP_ACK,
+					& []) ?;
+
+				if option == NBD_OPT_GO {
+					return Ok (true);
+				}
+
+			},
+
+			_ => {
+
+				nbd_option_reply (
+					stream,
+					option,
+					NBD_REP_ERR_UNSUP,
+					& []) ?;
+
+			},
+
+		}
+
+	}
+
+}
+
+fn nbd_transmission <
+	Stream: Read + Write,
+	Source: Read + Seek,
+> (
+	stream: & mut Stream,
+	export_size: u64,
+	source: & mut Source,
+) -> io::Result <()> {
+
+	let mut buffer: Vec <u8> =
+		Vec::new ();
+
+	loop {
+
+		let magic =
+			stream.read_u32::<BigEndian> () ?;
+
+		if magic != NBD_REQUEST_MAGIC {
+
+			return Err (
+				io::Error::new (
+					io::ErrorKind::InvalidData,
+					"Invalid request magic"));
+
+		}
+
+		let _command_flags =
+			stream.read_u16::<BigEndian> () ?;
+
+		let command_type =
+			stream.read_u16::<BigEndian> () ?;
+
+		let handle =
+			stream.read_u64::<BigEndian> () ?;
+
+		let offset =
+			stream.read_u64::<BigEndian> () ?;
+
+		let length =
+			stream.read_u32::<BigEndian> () ?;
+
+		match command_type {
+
+			NBD_CMD_READ => {
+
+				if length > MAX_READ_LENGTH
+					|| offset > export_size
+					|| length as u64 > export_size - offset {
+
+					nbd_simple_reply (
+						stream,
+						NBD_EINVAL,
+						handle,
+						& []) ?;
+
+					continue;
+
+				}
+
+				buffer.resize (
+					length as usize,
+					0u8);
+
+				let read_result =
+					source.seek (
+						SeekFrom::Start (offset),
+					).and_then (
+						|_| source.read_exact (& mut buffer)
+					);
+
+				match read_result {
+
+					Ok (()) =>
+						nbd_simple_reply (
+							stream,
+							0,
+							handle,
+							& buffer) ?,
+
+					Err (_) =>
+						nbd_simple_reply (
+							stream,
+							NBD_EIO,
+							handle,
+							& []) ?,
+
+				}
+
+			},
+
+			NBD_CMD_WRITE => {
+
+				// discard the payload, the export is read-only
+
+				io::copy (
+					& mut (& mut * stream).take (length as u64),
+					& mut io::sink (),
+				) ?;
+
+				nbd_simple_reply (
+					stream,
+					NBD_EPERM,
+					handle,
+					& []) ?;
+
+			},
+
+			NBD_CMD_TRIM => {
+
+				nbd_simple_reply (
+					stream,
+					NBD_EPERM,
+					handle,
+					& []) ?;
+
+			},
+
+			NBD_CMD_FLUSH => {
+
+				nbd_simple_reply (
+					stream,
+					0,
+					handle,
+					& []) ?;
+
+			},
+
+			NBD_CMD_DISC => {
+
+				return Ok (());
+
+			},
+
+			_ => {
+
+				nbd_simple_reply (
+					stream,
+					NBD_EINVAL,
+					handle,
+					& []) ?;
+
+			},
+
+		}
+
+	}
+
+}
+
+fn nbd_option_reply <
+	Stream: Write,
+> (
+	stream: & mut Stream,
+	option: u32,
+	reply_type: u32,
+	data: & [u8],
+) -> io::Result <()> {
+
+	stream.write_u64::<BigEndian> (NBD_REPLY_MAGIC) ?;
+	stream.write_u32::<BigEndian> (option) ?;
+	stream.write_u32::<BigEndian> (reply_type) ?;
+	stream.write_u32::<BigEndian> (data.len () as u32) ?;
+	stream.write_all (data) ?;
+
+	stream.flush ()
+
+}
+
+fn nbd_simple_reply <
+	Stream: Write,
+> (
+	stream: & mut Stream,
+	error: u32,
+	handle: u64,
+	data: & [u8],
+) -> io::Result <()> {
+
+	stream.write_u32::<BigEndian> (NBD_SIMPLE_REPLY_MAGIC) ?;
+	stream.write_u32::<BigEndian> (error) ?;
+	stream.write_u64::<BigEndian> (handle) ?;
+	stream.write_all (data) ?;
+
+	stream.flush ()
+
+}
+
+fn parse_info_request (
+	data: & [u8],
+) -> Option <& [u8]> {
+
+	if data.len () < 6 {
+		return None;
+	}
+
+	let name_length =
+		((data [0] as usize) << 24)
+		| ((data [1] as usize) << 16)
+		| ((data [2] as usize) << 8)
+		| (data [3] as usize);
+
+	if data.len () < 4 + name_length + 2 {
+		return None;
+	}
+
+	let num_requests =
+		((data [4 + name_length] as usize) << 8)
+		| (data [4 + name_length + 1] as usize);
+
+	if data.len () != 4 + name_length + 2 + num_requests * 2 {
+		return None;
+	}
+
+	Some (& data [4 .. 4 + name_length])
+
+}
+
+fn export_name_matches (
+	requested_name: & [u8],
+	export_name: & str,
+) -> bool {
+
+	// an empty name requests the default export
+
+	requested_name.is_empty ()
+		|| requested_name == export_name.as_bytes ()
+
+}
+
+#[ cfg (test) ]
+mod tests {
+
+	use std::io;
+	use std::io::Cursor;
+	use std::io::Read;
+	use std::io::Write;
+	use std::sync::mpsc;
+	use std::thread;
+
+	use nbd::client::NbdClient;
+
+	use super::*;
+
+	const EXPORT_NAME: & 'static str = "test-backup";
+	const EXPORT_SIZE: u64 = 64 * 1024;
+
+	/// One end of an in-memory, bidirectional stream. Each write is passed to
+	/// the other end as a single message, and reading returns end of file once
+	/// the other end has been dropped.
+
+	struct MemoryStream {
+		sender: mpsc::Sender <Vec <u8>>,
+		receiver: mpsc::Receiver <Vec <u8>>,
+		buffer: Vec <u8>,
+		position: usize,
+	}
+
+	fn memory_stream_pair (
+	) -> (MemoryStream, MemoryStream) {
+
+		let (left_sender, right_receiver) = mpsc::channel ();
+		let (right_sender, left_receiver) = mpsc::channel ();
+
+		(
+			MemoryStream {
+				sender: left_sender,
+				receiver: left_receiver,
+				buffer: Vec::new (),
+				position: 0,
+			},
+			MemoryStream {
+				sender: right_sender,
+				receiver: right_receiver,
+				buffer: Vec::new (),
+				position: 0,
+			},
+		)
+
+	}
+
+	impl Read for MemoryStream {
+
+		fn read (
+			& mut self,
+			buffer: & mut [u8],
+		) -> io::Result <usize> {
+
+			while self.position == self.buffer.len () {
+
+				match self.receiver.recv () {
+
+					Ok (message) => {
+						self.buffer = message;
+						self.position = 0;
+					},
+
+					Err (_) =>
+						return Ok (0),
+
+				}
+
+			}
+
+			let length =
+				(self.buffer.len () - self.position).min (buffer.len ());
+
+			buffer [0 .. length].copy_from_slice (
+				& self.buffer [self.position .. self.position + length]);
+
+			self.position += length;
+
+			Ok (length)
+
+		}
+
+	}
+
+	impl Write for MemoryStream {
+
+		fn write (
+			& mut self,
+			buffer: & [u8],
+		) -> io::Result <usize> {
+
+			self.sender.send (
+				buffer.to_vec (),
+			).map_err (
+				|_| io::Error::new (
+					io::ErrorKind::BrokenPipe,
+					"Other end of stream closed"),
+			) ?;
+
+			Ok (buffer.len ())
+
+		}
+
+		fn flush (
+			& mut self,
+		) -> io::Result <()> {
+			Ok (())
+		}
+
+	}
+
+	fn export_data (
+	) -> Vec <u8> {
+
+		(0 .. EXPORT_SIZE).map (
+			|offset| (offset * 7 % 251) as u8
+		).collect ()
+
+	}
+
+	/// Runs the server over one end of an in-memory stream, in a background
+	/// thread, and returns the other end, along with a handle which returns
+	/// the server's result once the client is finished.
+
+	fn start_server (
+	) -> (MemoryStream, thread::JoinHandle <Result <(), String>>) {
+
+		let (client_stream, mut server_stream) =
+			memory_stream_pair ();
+
+		let server_thread =
+			thread::spawn (
+				move || {
+
+				let mut source =
+					Cursor::new (
+						export_data ());
+
+				nbd_serve_connection (
+					& mut server_stream,
+					EXPORT_NAME,
+					EXPORT_SIZE,
+					& mut source)
+
+			});
+
+		(client_stream, server_thread)
+
+	}
+
+	#[ test ]
+	fn go_and_read () {
+
+		let (client_stream, server_thread) =
+			start_server ();
+
+		let mut client =
+			NbdClient::connect (
+				client_stream,
+				EXPORT_NAME,
+			).unwrap ();
+
+		assert_eq! (client.export_size (), EXPORT_SIZE);
+		assert! (client.read_only ());
+
+		assert_eq! (
+			client.read (1000, 5000).unwrap (),
+			& export_data () [1000 .. 6000]);
+
+		assert_eq! (
+			client.read (EXPORT_SIZE - 100, 100).unwrap (),
+			& export_data () [EXPORT_SIZE as usize - 100 ..]);
+
+		client.disconnect ().unwrap ();
+
+		server_thread.join ().unwrap ().unwrap ();
+
+	}
+
+	#[ test ]
+	fn go_default_export () {
+
+		let (client_stream, server_thread) =
+			start_server ();
+
+		let client =
+			NbdClient::connect (
+				client_stream,
+				"",
+			).unwrap ();
+
+		assert_eq! (client.export_size (), EXPORT_SIZE);
+
+		client.disconnect ().unwrap ();
+
+		server_thread.join ().unwrap ().unwrap ();
+
+	}
+
+	#[ test ]
+	fn go_unknown_export () {
+
+		let (client_stream, server_thread) =
+			start_server ();
+
+		assert! (
+			NbdClient::connect (
+				client_stream,
+				"other-backup",
+			).is_err ());
+
+		// the client has gone away, so the server sees the stream close
+
+		assert! (
+			server_thread.join ().unwrap ().is_err ());
+
+	}
+
+	#[ test ]
+	fn export_name_and_read () {
+
+		let (client_stream, server_thread) =
+			start_server ();
+
+		let mut client =
+			NbdClient::connect_export_name (
+				client_stream,
+				EXPORT_NAME,
+			).unwrap ();
+
+		assert_eq! (client.export_size (), EXPORT_SIZE);
+		assert! (client.read_only ());
+
+		assert_eq! (
+			client.read (0, 4096).unwrap (),
+			& export_data () [0 .. 4096]);
+
+		client.disconnect ().unwrap ();
+
+		server_thread.join ().unwrap ().unwrap ();
+
+	}
+
+	#[ test ]
+	fn export_name_unknown_closes_connection () {
+
+		let (client_stream, server_thread) =
+			start_server ();
+
+		assert! (
+			NbdClient::connect_export_name (
+				client_stream,
+				"other-backup",
+			).is_err ());
+
+		server_thread.join ().unwrap ().unwrap ();
+
+	}
+
+	#[ test ]
+	fn info_then_abort () {
+
+		let (client_stream, server_thread) =
+			start_server ();
+
+		let (export_size, transmission_flags) =
+			NbdClient::export_info (
+				client_stream,
+				EXPORT_NAME,
+			).unwrap ();
+
+		assert_eq! (export_size, EXPORT_SIZE);
+		assert_eq! (transmission_flags, NBD_TRANSMISSION_FLAGS);
+
+		server_thread.join ().unwrap ().unwrap ();
+
+	}
+
+	#[ test ]
+	fn list_then_abort () {
+
+		let (client_stream, server_thread) =
+			start_server ();
+
+		assert_eq! (
+			NbdClient::list_exports (
+				client_stream,
+			).unwrap (),
+			vec! [ EXPORT_NAME.to_string () ]);
+
+		server_thread.join ().unwrap ().unwrap ();
+
+	}
+
+	#[ test ]
+	fn read_past_end () {
+
+		let (client_stream, server_thread) =
+			start_server ();
+
+		let mut client =
+			NbdClient::connect (
+				client_stream,
+				EXPORT_NAME,
+			).unwrap ();
+
+		// crossing the end, and starting after it, are both invalid
+
+		let error =
+			client.read (EXPORT_SIZE - 100, 200).unwrap_err ();
+
+		assert! (
+			error.contains (
+				& format! ("error {} ", NBD_EINVAL)),
+			"{}", error);
+
+		assert! (
+			client.read (EXPORT_SIZE + 4096, 1).is_err ());
+
+		// the connection is still usable afterwards
+
+		assert_eq! (
+			client.read (0, 10).unwrap (),
+			& export_data () [0 .. 10]);
+
+		client.disconnect ().unwrap ();
+
+		server_thread.join ().unwrap ().unwrap ();
+
+	}
+
+	#[ test ]
+	fn write_and_trim_rejected () {
+
+		let (client_stream, server_thread) =
+			start_server ();
+
+		let mut client =
+			NbdClient::connect (
+				client_stream,
+				EXPORT_NAME,
+			).unwrap ();
+
+		assert_eq! (
+			client.write (0, & [0xff; 512]).unwrap (),
+			NBD_EPERM);
+
+		assert_eq! (
+			client.command (NBD_CMD_TRIM, 0, 512).unwrap (),
+			NBD_EPERM);
+
+		assert_eq! (
+			client.command (NBD_CMD_FLUSH, 0, 0).unwrap (),
+			0);
+
+		// the write payload was discarded, and the data is unchanged
+
+		assert_eq! (
+			client.read (0, 512).unwrap (),
+			& export_data () [0 .. 512]);
+
+		client.disconnect ().unwrap ();
+
+		server_thread.join ().unwrap ().unwrap ();
+
+	}
+
+}
+
+// ex: noet ts=4 filetype=rust
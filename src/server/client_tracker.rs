@@ -0,0 +1,148 @@
+use std::collections::HashMap;
+use std::sync::Arc;
+use std::sync::Condvar;
+use std::sync::Mutex;
+
+use misc::*;
+
+/// Keeps track of the connections being handled by client threads, so that at
+/// shutdown, any which are still open can be cut off, and the server can wait
+/// for their threads to finish before closing the repositories they use.
+
+#[ derive (Clone) ]
+pub struct ClientTracker {
+	state: Arc <(Mutex <ClientTrackerState>, Condvar)>,
+}
+
+struct ClientTrackerState {
+	streams: HashMap <u64, SocketStream>,
+	next_id: u64,
+}
+
+/// Held by a client thread for as long as it handles a connection.
+
+pub struct ClientHandle {
+	state: Arc <(Mutex <ClientTrackerState>, Condvar)>,
+	id: u64,
+}
+
+impl ClientTracker {
+
+	pub fn new (
+	) -> ClientTracker {
+
+		ClientTracker {
+			state: Arc::new ((
+				Mutex::new (
+					ClientTrackerState {
+						streams: HashMap::new (),
+						next_id: 0,
+					}),
+				Condvar::new (),
+			)),
+		}
+
+	}
+
+	/// Registers a connection, given a second handle on its socket.
+
+	pub fn add (
+		& self,
+		stream: SocketStream,
+	) -> ClientHandle {
+
+		let & (ref state_mutex, _) =
+			& * self.state;
+
+		let mut state =
+			state_mutex.lock ().unwrap ();
+
+		let id =
+			state.next_id;
+
+		state.next_id += 1;
+
+		state.streams.insert (
+			id,
+			stream);
+
+		ClientHandle {
+			state: self.state.clone (),
+			id: id,
+		}
+
+	}
+
+	/// Shuts down the sockets of all remaining connections, so that their
+	/// threads fail on their next read or write, and returns how many there
+	/// were.
+
+	pub fn shutdown (
+		& self,
+	) -> usize {
+
+		let & (ref state_mutex, _) =
+			& * self.state;
+
+		let state =
+			state_mutex.lock ().unwrap ();
+
+		for stream in state.streams.values () {
+
+			stream.shutdown ().unwrap_or (
+				() // do nothing
+			);
+
+		}
+
+		state.streams.len ()
+
+	}
+
+	/// Waits for every client thread to finish.
+
+	pub fn wait (
+		& self,
+	) {
+
+		let & (ref state_mutex, ref state_condvar) =
+			& * self.state;
+
+		let mut state =
+			state_mutex.lock ().unwrap ();
+
+		while ! state.streams.is_empty () {
+
+			state =
+				state_condvar.wait (
+					state,
+				).unwrap ();
+
+		}
+
+	}
+
+}
+
+impl Drop for ClientHandle {
+
+	fn drop (
+		& mut self,
+	) {
+
+		let & (ref state_mutex, ref state_condvar) =
+			& * self.state;
+
+		let mut state =
+			state_mutex.lock ().unwrap ();
+
+		state.streams.remove (
+			& self.id);
+
+		state_condvar.notify_all ();
+
+	}
+
+}
+
+// ex: noet ts=4 filetype=rust
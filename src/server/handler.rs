@@ -1,29 +1,86 @@
+use std::io;
 use std::io::BufRead;
 use std::io::BufReader;
 use std::io::BufWriter;
 use std::io::Write;
-use std::net::TcpStream;
+use std::time::Duration;
 
 use output;
 use output::Output;
 
+use ::server::restore_limiter::*;
 use ::zbackup::repository::*;
 use ::misc::*;
 
+/// Everything shared between the connections handled by a server.
+
+#[ derive (Clone) ]
+pub struct ClientContext {
+	pub repositories: Vec <ServerRepository>,
+	pub restore_limiter: RestoreLimiter,
+	pub idle_timeout: Option <Duration>,
+	pub read_timeout: Option <Duration>,
+}
+
+/// A repository served by the server, with the name clients use to refer to
+/// it.
+
+#[ derive (Clone) ]
+pub struct ServerRepository {
+	pub name: String,
+	pub repository: Repository,
+}
+
+impl ClientContext {
+
+	/// Finds a repository by name. The name may be omitted if the server only
+	/// has a single repository.
+
+	pub fn repository (
+		& self,
+		name: Option <& str>,
+	) -> Result <& Repository, String> {
+
+		match name {
+
+			Some (name) =>
+				self.repositories.iter ().find (
+					|server_repository| server_repository.name == name,
+				).map (
+					|server_repository| & server_repository.repository,
+				).ok_or_else (
+					|| format! (
+						"Repository not found: {}",
+						name),
+				),
+
+			None if self.repositories.len () == 1 =>
+				Ok (& self.repositories [0].repository),
+
+			None =>
+				Err (
+					"Repository name required".to_string ()),
+
+		}
+
+	}
+
+}
+
 pub fn handle_client (
-	repository: & Repository,
-	stream: TcpStream,
+	context: & ClientContext,
+	stream: SocketStream,
 ) {
 
 	let peer_address =
-		stream.peer_addr ().unwrap ();
+		stream.peer_description ();
 
 	println! (
 		"Connection from: {}",
 		peer_address);
 
 	match handle_client_real (
-		repository,
+		context,
 		stream) {
 
 		Ok (_) => {
@@ -48,8 +105,8 @@ pub fn handle_client (
 }
 
 fn handle_client_real (
-	repository: & Repository,
-	stream: TcpStream,
+	context: & ClientContext,
+	stream: SocketStream,
 ) -> Result <(), String> {
 
 	let mut reader =
@@ -58,10 +115,28 @@ fn handle_client_real (
 
 	loop {
 
+		// wait for a command to start, then for the rest of it
+
+		io_result (
+			stream.set_read_timeout (
+				context.idle_timeout),
+		) ?;
+
+		timeout_result (
+			"Idle timeout",
+			reader.fill_buf ().map (|_| ()),
+		) ?;
+
+		io_result (
+			stream.set_read_timeout (
+				context.read_timeout),
+		) ?;
+
 		let mut line =
 			String::new ();
 
-		io_result (
+		timeout_result (
+			"Read timeout",
 			reader.read_line (
 				& mut line),
 		) ?;
@@ -105,15 +180,16 @@ fn handle_client_real (
 
 			handle_reindex (
 				& output,
-				repository,
+				context,
 				& stream,
+				rest,
 			) ?;
 
 		} else if command == "restore" {
 
 			handle_restore (
 				& output,
-				repository,
+				context,
 				& stream,
 				rest,
 			) ?;
@@ -124,7 +200,7 @@ fn handle_client_real (
 
 			handle_status (
 				& output,
-				repository,
+				context,
 				& stream,
 			) ?;
 
@@ -143,29 +219,95 @@ fn handle_client_real (
 
 }
 
+/// Converts a read timeout into a clearer error message. Timeouts are reported
+/// as "would block" or "timed out", depending on the platform.
+
+fn timeout_result <Type> (
+	message: & str,
+	result: Result <Type, io::Error>,
+) -> Result <Type, String> {
+
+	if let Err (ref error) = result {
+
+		if error.kind () == io::ErrorKind::WouldBlock
+			|| error.kind () == io::ErrorKind::TimedOut {
+
+			return Err (
+				message.to_string ());
+
+		}
+
+	}
+
+	io_result (
+		result)
+
+}
+
+/// Reindexes the named repository, or all repositories if no name is given.
+
 fn handle_reindex (
 	output: & Output,
-	repository: & Repository,
-	stream: & TcpStream,
+	context: & ClientContext,
+	stream: & SocketStream,
+	repository_name: & str,
 ) -> Result <(), String> {
 
-	output.message (
-		"Will reindex");
-
 	let mut writer =
 		BufWriter::new (
 			stream);
 
-	repository.reload_indexes (
-		output,
-	).map_err (
-		|error|
+	let repositories: Vec <& ServerRepository> =
+		if repository_name.is_empty () {
 
-		format! (
-			"Error during reindex: {}",
-			error)
+		context.repositories.iter ().collect ()
 
-	) ?;
+	} else {
+
+		match context.repositories.iter ().find (
+			|server_repository|
+			server_repository.name == repository_name) {
+
+			Some (server_repository) =>
+				vec! [ server_repository ],
+
+			None => {
+
+				io_result (
+					writer.write_fmt (
+						format_args! (
+							"ERROR Repository not found: {}\n",
+							repository_name)),
+				) ?;
+
+				return Ok (());
+
+			},
+
+		}
+
+	};
+
+	for server_repository in repositories {
+
+		output.message_format (
+			format_args! (
+				"Will reindex: {}",
+				server_repository.name));
+
+		server_repository.repository.reload_indexes (
+			output,
+		).map_err (
+			|error|
+
+			format! (
+				"Error during reindex of {}: {}",
+				server_repository.name,
+				error)
+
+		) ?;
+
+	}
 
 	io_result (
 		writer.write_fmt (
@@ -177,32 +319,135 @@ fn handle_reindex (
 
 }
 
+/// Restores a backup, given as `REPOSITORY PATH`, or just `PATH` if the
+/// server only has a single repository. Backup paths always start with a
+/// slash, so the two forms can be told apart.
+
 fn handle_restore (
 	output: & Output,
-	repository: & Repository,
-	stream: & TcpStream,
-	path: & str,
+	context: & ClientContext,
+	stream: & SocketStream,
+	arguments: & str,
 ) -> Result <(), String> {
 
+	let (repository_name, path) =
+		if arguments.starts_with ("/") {
+
+		(None, arguments)
+
+	} else {
+
+		let parts: Vec <& str> =
+			arguments.splitn (2, ' ').collect ();
+
+		(
+			Some (parts [0]),
+			if parts.len () > 1 { parts [1].trim () } else { "" },
+		)
+
+	};
+
 	output.message_format (
 		format_args! (
-			"Will restore: {}",
+			"Will restore: {}{}",
+			repository_name.map (
+				|repository_name| format! ("{} ", repository_name),
+			).unwrap_or_default (),
 			path));
 
 	let mut writer =
 		BufWriter::new (
 			stream);
 
+	let repository =
+		match context.repository (
+			repository_name) {
+
+		Ok (repository) =>
+			repository,
+
+		Err (error) => {
+
+			io_result (
+				writer.write_fmt (
+					format_args! (
+						"ERROR {}\n",
+						error)),
+			) ?;
+
+			return Err (
+				format! (
+					"Restore not started: {}",
+					error));
+
+		},
+
+	};
+
+	// wait for our turn, telling the client where it is in the queue
+
+	let restore_permit_result =
+		context.restore_limiter.acquire (
+			& mut |position| {
+
+			io_result (
+				writer.write_fmt (
+					format_args! (
+						"QUEUED {}\n",
+						position)),
+			) ?;
+
+			io_result (
+				writer.flush (),
+			)
+
+		});
+
+	let _restore_permit =
+		match restore_permit_result {
+
+		Ok (restore_permit) =>
+			restore_permit,
+
+		Err (error) => {
+
+			// the client may have gone already, so ignore errors
+
+			writer.write_fmt (
+				format_args! (
+					"ERROR {}\n",
+					error),
+			).unwrap_or (());
+
+			return Err (
+				format! (
+					"Restore not started: {}",
+					error));
+
+		},
+
+	};
+
 	io_result (
 		writer.write_fmt (
 			format_args! (
 				"OK\n")),
 	) ?;
 
+	// if the client goes away, the write fails and the restore is abandoned,
+	// which also drops any queued bundle loads it was waiting for
+
 	repository.restore (
 		output,
 		path,
 		& mut writer,
+	).map_err (
+		|error|
+
+		format! (
+			"Restore cancelled: {}",
+			error)
+
 	) ?;
 
 	Ok (())
@@ -211,8 +456,8 @@ fn handle_restore (
 
 fn handle_status (
 	output: & Output,
-	repository: & Repository,
-	stream: & TcpStream,
+	context: & ClientContext,
+	stream: & SocketStream,
 ) -> Result <(), String> {
 
 	output.message_format (
@@ -223,14 +468,142 @@ fn handle_status (
 		BufWriter::new (
 			stream);
 
-	let status =
-		repository.status ();
-
 	io_result (write! (
 		writer,
 		"OK\n",
 	)) ?;
 
+	write_status (
+		& mut writer,
+		context,
+	)
+
+}
+
+/// Writes the server's status in a YAML-like format. This is returned by the
+/// status command, and also written to the log on SIGUSR1. With more than one
+/// repository, the status of each is nested under its name.
+
+pub fn write_status (
+	writer: & mut Write,
+	context: & ClientContext,
+) -> Result <(), String> {
+
+	let restore_limiter_status =
+		context.restore_limiter.status ();
+
+	if context.repositories.len () == 1 {
+
+		write_repository_status (
+			writer,
+			& context.repositories [0].repository,
+		) ?;
+
+	} else {
+
+		io_result (write! (
+			writer,
+			"\n",
+		)) ?;
+
+		io_result (write! (
+			writer,
+			"repositories:\n",
+		)) ?;
+
+		for server_repository in context.repositories.iter () {
+
+			let mut repository_status: Vec <u8> =
+				Vec::new ();
+
+			write_repository_status (
+				& mut repository_status,
+				& server_repository.repository,
+			) ?;
+
+			io_result (write! (
+				writer,
+				"\n",
+			)) ?;
+
+			io_result (write! (
+				writer,
+				"  {}:\n",
+				server_repository.name,
+			)) ?;
+
+			for line in String::from_utf8_lossy (
+				& repository_status,
+			).lines () {
+
+				if line.is_empty () {
+
+					io_result (write! (
+						writer,
+						"\n",
+					)) ?;
+
+				} else {
+
+					io_result (write! (
+						writer,
+						"    {}\n",
+						line,
+					)) ?;
+
+				}
+
+			}
+
+		}
+
+	}
+
+	io_result (write! (
+		writer,
+		"restores:\n",
+	)) ?;
+
+	io_result (write! (
+		writer,
+		"\n",
+	)) ?;
+
+	io_result (write! (
+		writer,
+		"  max-concurrent: {}\n",
+		restore_limiter_status.max_restores,
+	)) ?;
+
+	io_result (write! (
+		writer,
+		"  running: {}\n",
+		restore_limiter_status.running,
+	)) ?;
+
+	io_result (write! (
+		writer,
+		"  queued: {}\n",
+		restore_limiter_status.queued,
+	)) ?;
+
+	io_result (write! (
+		writer,
+		"\n",
+	)) ?;
+
+	Ok (())
+
+}
+
+fn write_repository_status (
+	writer: & mut Write,
+	repository: & Repository,
+) -> Result <(), String> {
+
+	let status =
+		repository.status ();
+
 	io_result (write! (
 		writer,
 		"\n",
@@ -275,6 +648,35 @@ fn handle_status (
 		"\n",
 	)) ?;
 
+	io_result (write! (
+		writer,
+		"  uncompressed-memory-bytes: {}\n",
+		status.chunk_cache.uncompressed_memory_bytes,
+	)) ?;
+
+	io_result (write! (
+		writer,
+		"  compressed-memory-bytes: {}\n",
+		status.chunk_cache.compressed_memory_bytes,
+	)) ?;
+
+	io_result (write! (
+		writer,
+		"  live-filesystem-bytes: {}\n",
+		status.chunk_cache.live_filesystem_bytes,
+	)) ?;
+
+	io_result (write! (
+		writer,
+		"  dead-filesystem-bytes: {}\n",
+		status.chunk_cache.dead_filesystem_bytes,
+	)) ?;
+
+	io_result (write! (
+		writer,
+		"\n",
+	)) ?;
+
 	io_result (write! (
 		writer,
 		"  uncompressed-memory-hits: {}\n",
@@ -310,6 +712,84 @@ fn handle_status (
 		"\n",
 	)) ?;
 
+	io_result (write! (
+		writer,
+		"  filesystem-persistent: {}\n",
+		status.chunk_cache.filesystem_persistent,
+	)) ?;
+
+	io_result (write! (
+		writer,
+		"  adopted-filesystem-items: {}\n",
+		status.chunk_cache.adopted_filesystem_items,
+	)) ?;
+
+	io_result (write! (
+		writer,
+		"\n",
+	)) ?;
+
+	io_result (write! (
+		writer,
+		"  cache-policy: {}\n",
+		status.chunk_cache.cache_policy.name (),
+	)) ?;
+
+	io_result (write! (
+		writer,
+		"\n",
+	)) ?;
+
+	for & (tier_name, tier_stats) in [
+		("uncompressed-memory", & status.chunk_cache.uncompressed_memory_stats),
+		("compressed-memory", & status.chunk_cache.compressed_memory_stats),
+		("live-filesystem", & status.chunk_cache.live_filesystem_stats),
+		("dead-filesystem", & status.chunk_cache.dead_filesystem_stats),
+	].iter () {
+
+		io_result (write! (
+			writer,
+			"  {}-stats:\n",
+			tier_name,
+		)) ?;
+
+		io_result (write! (
+			writer,
+			"    main-hits: {}\n",
+			tier_stats.main_hits,
+		)) ?;
+
+		io_result (write! (
+			writer,
+			"    probation-hits: {}\n",
+			tier_stats.probation_hits,
+		)) ?;
+
+		io_result (write! (
+			writer,
+			"    ghost-hits: {}\n",
+			tier_stats.ghost_hits,
+		)) ?;
+
+		io_result (write! (
+			writer,
+			"    misses: {}\n",
+			tier_stats.misses,
+		)) ?;
+
+		io_result (write! (
+			writer,
+			"    evictions: {}\n",
+			tier_stats.evictions,
+		)) ?;
+
+		io_result (write! (
+			writer,
+			"\n",
+		)) ?;
+
+	}
+
 	io_result (write! (
 		writer,
 		"bundle-loader:\n",
@@ -326,6 +806,12 @@ fn handle_status (
 		status.bundle_loader.num_loads,
 	)) ?;
 
+	io_result (write! (
+		writer,
+		"  num-cancelled: {}\n",
+		status.bundle_loader.num_cancelled,
+	)) ?;
+
 	io_result (write! (
 		writer,
 		"\n",
@@ -410,7 +896,7 @@ fn handle_status (
 }
 
 fn handle_command_not_recognised (
-	stream: & TcpStream,
+	stream: & SocketStream,
 	command_name: & str,
 ) -> Result <(), String> {
 
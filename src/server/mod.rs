@@ -1,6 +1,9 @@
+mod client_tracker;
 mod server;
 mod handler;
+mod restore_limiter;
 
+pub use self::restore_limiter::*;
 pub use self::server::*;
 
 // ex: noet ts=4 filetype=rust
@@ -0,0 +1,231 @@
+use std::collections::VecDeque;
+use std::sync::Arc;
+use std::sync::Condvar;
+use std::sync::Mutex;
+use std::time::Duration;
+
+/// Limits the number of restores which run at the same time. Further restores
+/// wait in a queue, in the order they arrived, and are told their position in
+/// the queue when it changes, and periodically otherwise, so that a client
+/// which has gone away can be detected.
+
+#[ derive (Clone) ]
+pub struct RestoreLimiter {
+	max_restores: usize,
+	state: Arc <(Mutex <RestoreLimiterState>, Condvar)>,
+}
+
+struct RestoreLimiterState {
+	shutdown: bool,
+	running: usize,
+	queue: VecDeque <u64>,
+	next_ticket: u64,
+}
+
+/// Allows a restore to run until dropped.
+
+pub struct RestorePermit {
+	state: Arc <(Mutex <RestoreLimiterState>, Condvar)>,
+}
+
+pub struct RestoreLimiterStatus {
+	pub max_restores: usize,
+	pub running: usize,
+	pub queued: usize,
+}
+
+const QUEUE_POSITION_INTERVAL: u64 = 10;
+
+impl RestoreLimiter {
+
+	/// Creates a new limiter. A limit of zero means restores are never queued.
+
+	pub fn new (
+		max_restores: usize,
+	) -> RestoreLimiter {
+
+		RestoreLimiter {
+			max_restores: max_restores,
+			state: Arc::new ((
+				Mutex::new (
+					RestoreLimiterState {
+						shutdown: false,
+						running: 0,
+						queue: VecDeque::new (),
+						next_ticket: 0,
+					}),
+				Condvar::new (),
+			)),
+		}
+
+	}
+
+	/// Waits for a restore to be allowed to start. While waiting, the queue
+	/// position, starting from one, is passed to the provided function. If
+	/// this returns an error, or the limiter is shut down, the restore leaves
+	/// the queue and an error is returned.
+
+	pub fn acquire (
+		& self,
+		report_position: & mut FnMut (usize) -> Result <(), String>,
+	) -> Result <RestorePermit, String> {
+
+		let & (ref state_mutex, ref state_condvar) =
+			& * self.state;
+
+		let mut state =
+			state_mutex.lock ().unwrap ();
+
+		let ticket =
+			state.next_ticket;
+
+		state.next_ticket += 1;
+
+		state.queue.push_back (
+			ticket);
+
+		let mut last_position: Option <usize> =
+			None;
+
+		loop {
+
+			if state.shutdown {
+
+				state.queue.retain (
+					|& queued_ticket| queued_ticket != ticket);
+
+				state_condvar.notify_all ();
+
+				return Err (
+					"Server shutting down".to_string ());
+
+			}
+
+			let position =
+				state.queue.iter ().position (
+					|& queued_ticket| queued_ticket == ticket,
+				).unwrap ();
+
+			if position == 0
+				&& (self.max_restores == 0
+					|| state.running < self.max_restores) {
+
+				state.queue.pop_front ();
+
+				state.running += 1;
+
+				state_condvar.notify_all ();
+
+				return Ok (RestorePermit {
+					state: self.state.clone (),
+				});
+
+			}
+
+			// report position without holding the lock
+
+			if last_position != Some (position) {
+
+				drop (state);
+
+				let report_result =
+					report_position (
+						position + 1);
+
+				state =
+					state_mutex.lock ().unwrap ();
+
+				if let Err (error) = report_result {
+
+					state.queue.retain (
+						|& queued_ticket| queued_ticket != ticket);
+
+					state_condvar.notify_all ();
+
+					return Err (error);
+
+				}
+
+				last_position = Some (position);
+
+				continue;
+
+			}
+
+			let (new_state, wait_result) =
+				state_condvar.wait_timeout (
+					state,
+					Duration::from_secs (
+						QUEUE_POSITION_INTERVAL),
+				).unwrap ();
+
+			state = new_state;
+
+			if wait_result.timed_out () {
+				last_position = None;
+			}
+
+		}
+
+	}
+
+	/// Rejects all queued and future restores. Running restores are not
+	/// affected.
+
+	pub fn shutdown (
+		& self,
+	) {
+
+		let & (ref state_mutex, ref state_condvar) =
+			& * self.state;
+
+		let mut state =
+			state_mutex.lock ().unwrap ();
+
+		state.shutdown = true;
+
+		state_condvar.notify_all ();
+
+	}
+
+	pub fn status (
+		& self,
+	) -> RestoreLimiterStatus {
+
+		let & (ref state_mutex, _) =
+			& * self.state;
+
+		let state =
+			state_mutex.lock ().unwrap ();
+
+		RestoreLimiterStatus {
+			max_restores: self.max_restores,
+			running: state.running,
+			queued: state.queue.len (),
+		}
+
+	}
+
+}
+
+impl Drop for RestorePermit {
+
+	fn drop (
+		& mut self,
+	) {
+
+		let & (ref state_mutex, ref state_condvar) =
+			& * self.state;
+
+		let mut state =
+			state_mutex.lock ().unwrap ();
+
+		state.running -= 1;
+
+		state_condvar.notify_all ();
+
+	}
+
+}
+
+// ex: noet ts=4 filetype=rust
@@ -1,35 +1,138 @@
-use std::error::Error;
-use std::net::TcpListener;
+use std::io;
 use std::path::PathBuf;
 use std::thread;
+use std::time::Duration;
+use std::time::Instant;
 
 use clap;
 
+use output;
 use output::Output;
 
+pub use server::handler::ClientContext;
+pub use server::handler::ServerRepository;
 pub use server::handler::handle_client;
+pub use server::handler::write_status;
+
+use server::client_tracker::*;
+use server::restore_limiter::*;
 
 use zbackup::repository::*;
 use misc::*;
 use misc::args::ClapSubCommandRzbackupArgs;
 
+const POLL_INTERVAL_MILLIS: u64 = 100;
+
 pub fn run_server (
 	output: & Output,
 	arguments: & ServerArguments,
 ) -> Result <bool, String> {
 
-	let repository =
-		string_result_with_prefix (
+	let repository_arguments =
+		server_repository_arguments (
+			arguments,
+		) ?;
+
+	let mut repositories: Vec <ServerRepository> =
+		Vec::new ();
+
+	for repository_arguments in repository_arguments {
+
+		output_message! (
+			output,
+			"Opening repository {}: {}",
+			repository_arguments.name,
+			repository_arguments.path.to_string_lossy ());
+
+		let repository =
+			string_result_with_prefix (
+				|| format! (
+					"Error opening repository {}: ",
+					repository_arguments.name),
+				Repository::open (
+					& output,
+					repository_arguments.config.clone (),
+					& repository_arguments.path,
+					repository_arguments.password_file_path.as_ref (),
+				),
+			) ?;
+
+		repositories.push (
+			ServerRepository {
+				name: repository_arguments.name,
+				repository: repository,
+			});
+
+	}
+
+	if arguments.watch_indexes {
+
+		for server_repository in repositories.iter () {
+
+			let index_path =
+				server_repository.repository.path ().join ("index");
+
+			let watcher =
+				io_result_with_prefix (
+					|| format! (
+						"Error watching {}: ",
+						index_path.to_string_lossy ()),
+					DirectoryWatcher::new (
+						& index_path),
+				) ?;
+
+			let server_repository =
+				server_repository.clone ();
+
+			let watch_indexes_delay =
+				arguments.watch_indexes_delay;
+
+			thread::spawn (
+				move || {
+
+					run_index_watcher (
+						server_repository,
+						watcher,
+						watch_indexes_delay)
+
+				}
+			);
+
+		}
+
+	}
+
+	// use sockets passed by systemd, if any, otherwise bind our own
+
+	let mut listeners =
+		io_result_with_prefix (
 			|| format! (
-				"Error opening repository: "),
-			Repository::open (
-				& output,
-				arguments.repository_config.clone (),
-				& arguments.repository_path,
-				arguments.password_file_path.as_ref (),
-			),
+				"Error using sockets from LISTEN_FDS: "),
+			SocketListener::from_listen_fds (),
 		) ?;
 
+	if listeners.is_empty () {
+
+		listeners.push (
+			io_result_with_prefix (
+				|| format! (
+					"Error listening on {}: ",
+					arguments.listen_address),
+				SocketListener::bind (
+					& arguments.listen_address),
+			) ?);
+
+	}
+
+	for listener in listeners.iter () {
+
+		output_message! (
+			output,
+			"Listening on {}",
+			listener.description ());
+
+	}
+
 	output.message (
 		"RZBackup startup complete");
 
@@ -37,15 +140,26 @@ pub fn run_server (
 		|| format! (
 			"RZBackup server encountered error: "),
 		run_server_listener (
-			repository.clone (),
-			& arguments.listen_address,
+			ClientContext {
+				repositories: repositories.clone (),
+				restore_limiter: RestoreLimiter::new (
+					arguments.max_concurrent_restores),
+				idle_timeout: arguments.idle_timeout,
+				read_timeout: arguments.read_timeout,
+			},
+			listeners,
+			arguments.shutdown_timeout,
 		),
 	) ?;
 
 	// clean up and return
 
-	repository.close (
-		output);
+	for server_repository in repositories {
+
+		server_repository.repository.close (
+			output);
+
+	}
 
 	output.message (
 		"RZBackup server terminating normally");
@@ -54,64 +168,531 @@ pub fn run_server (
 
 }
 
+/// Reloads the indexes whenever the index directory changes. Changes are
+/// collected until none have been seen for the given delay, since a backup run
+/// will typically write several index files.
+
+fn run_index_watcher (
+	server_repository: ServerRepository,
+	mut watcher: DirectoryWatcher,
+	delay: Duration,
+) {
+
+	loop {
+
+		let wait_result =
+			watcher.wait ().and_then (
+				|_| {
+
+				while watcher.wait_timeout (Some (delay)) ? {}
+
+				Ok (())
+
+			});
+
+		if let Err (error) = wait_result {
+
+			println! (
+				"Stopped watching indexes for {}: {}",
+				server_repository.name,
+				error);
+
+			return;
+
+		}
+
+		reindex (
+			& server_repository,
+			"Index directory changed, reindexing");
+
+	}
+
+}
+
+/// Accepts connections until SIGTERM or SIGINT is received, then stops
+/// accepting, rejects queued restores, and waits for running restores to
+/// finish, for up to the shutdown timeout. After that, any connections which
+/// are still open are shut down, and this waits for their threads to finish,
+/// so the repositories can be closed safely. SIGHUP triggers a reindex, and
+/// SIGUSR1 writes the server's status to the log.
+
 pub fn run_server_listener (
-	repository: Repository,
-	bind_address: & str,
+	context: ClientContext,
+	listeners: Vec <SocketListener>,
+	shutdown_timeout: Duration,
 ) -> Result <(), String> {
 
-	let listener =
+	// poll for connections, so that we notice signals
+
+	for listener in listeners.iter () {
+
 		io_result (
-			TcpListener::bind (
-				bind_address),
+			listener.set_nonblocking (
+				true),
 		) ?;
 
-	for stream
-	in listener.incoming () {
+	}
+
+	install_signal_handlers ();
+
+	let client_tracker =
+		ClientTracker::new ();
+
+	loop {
+
+		if take_signal (Signal::Terminate) {
+			break;
+		}
 
-		match stream {
+		if take_signal (Signal::Reload) {
 
-			Ok (stream) => {
+			let repositories =
+				context.repositories.clone ();
 
-				let repository_copy =
-					repository.clone ();
+			thread::spawn (
+				move || {
 
-				thread::spawn (
-					move || {
+					for server_repository in repositories.iter () {
 
-						handle_client (
-							& repository_copy,
-							stream)
+						reindex (
+							server_repository,
+							"Received SIGHUP, reindexing");
 
 					}
-				);
-			},
 
-			Err (error) => {
+				}
+			);
+
+		}
+
+		if take_signal (Signal::Status) {
+
+			let stdout =
+				io::stdout ();
+
+			let mut stdout_lock =
+				stdout.lock ();
+
+			write_status (
+				& mut stdout_lock,
+				& context,
+			).unwrap_or_else (
+				|error|
 
 				println! (
-					"Connection failed: {}",
-					error.description ());
+					"Error writing status: {}",
+					error)
 
-			},
+			);
+
+		}
+
+		let mut accepted = false;
+
+		for listener in listeners.iter () {
+
+			match listener.accept () {
+
+				Ok (stream) => {
+
+					accepted = true;
+
+					// accepted sockets should block, whatever the listener does
+
+					if let Err (error) =
+						stream.set_nonblocking (
+							false) {
+
+						println! (
+							"Connection failed: {}",
+							error);
+
+						continue;
+
+					}
+
+					// keep a handle on the socket, to shut it down at exit
+
+					let client_handle =
+						match stream.try_clone () {
+
+						Ok (stream_copy) =>
+							client_tracker.add (
+								stream_copy),
+
+						Err (error) => {
+
+							println! (
+								"Connection failed: {}",
+								error);
+
+							continue;
+
+						},
+
+					};
+
+					let context_copy =
+						context.clone ();
+
+					thread::spawn (
+						move || {
+
+							handle_client (
+								& context_copy,
+								stream);
+
+							drop (client_handle);
+
+						}
+					);
+
+				},
+
+				Err (ref error)
+					if error.kind () == io::ErrorKind::WouldBlock =>
+					(),
+
+				Err (error) => {
+
+					println! (
+						"Connection failed: {}",
+						error);
+
+				},
+
+			}
+
+		}
+
+		if ! accepted {
+
+			thread::sleep (
+				Duration::from_millis (
+					POLL_INTERVAL_MILLIS));
 
 		}
 
 	};
 
+	// drain running restores
+
+	drop (listeners);
+
+	context.restore_limiter.shutdown ();
+
+	let deadline =
+		Instant::now () + shutdown_timeout;
+
+	let mut reported = false;
+
+	loop {
+
+		let running =
+			context.restore_limiter.status ().running;
+
+		if running == 0 {
+			break;
+		}
+
+		if Instant::now () >= deadline
+			|| take_signal (Signal::Terminate) {
+
+			println! (
+				"Cancelling {} running restores",
+				running);
+
+			break;
+
+		}
+
+		if ! reported {
+
+			println! (
+				"Waiting for {} running restores to finish",
+				running);
+
+			reported = true;
+
+		}
+
+		thread::sleep (
+			Duration::from_millis (
+				POLL_INTERVAL_MILLIS));
+
+	}
+
+	// cut off remaining connections, including idle ones and cancelled
+	// restores, and wait for their threads, which may be using the repositories
+
+	let open_connections =
+		client_tracker.shutdown ();
+
+	if open_connections > 0 {
+
+		println! (
+			"Closing {} remaining connections",
+			open_connections);
+
+	}
+
+	client_tracker.wait ();
+
 	Ok (())
 
 }
 
+fn reindex (
+	server_repository: & ServerRepository,
+	reason: & str,
+) {
+
+	println! (
+		"{}: {}",
+		reason,
+		server_repository.name);
+
+	match server_repository.repository.reload_indexes (
+		& output::null ()) {
+
+		Ok (()) =>
+			println! (
+				"Reindex complete: {}",
+				server_repository.name),
+
+		Err (error) =>
+			println! (
+				"Error during reindex of {}: {}",
+				server_repository.name,
+				error),
+
+	}
+
+}
+
+/// Settings for a single repository, from the command line or a config file.
+
+struct ServerRepositoryArguments {
+	name: String,
+	path: PathBuf,
+	password_file_path: Option <PathBuf>,
+	config: RepositoryConfig,
+}
+
+/// Name given to the repository when it is specified on the command line as a
+/// path.
+
+const DEFAULT_REPOSITORY_NAME: & 'static str = "default";
+
+/// Works out which repositories to serve. Without a config file, or if a
+/// repository is given, this is a single repository, whose path has already
+/// been looked up if it was given by name. Otherwise, each
+/// `[repositories.NAME]` section in the config file describes a repository,
+/// and the server's own repository settings are used as defaults for all of
+/// them.
+///
+/// The `memory-cache-size` in the `[server]` section is then a budget for all
+/// of the in-memory caches together. Repositories which set their own memory
+/// cache sizes have these taken from the budget first, and the remainder is
+/// divided equally between the rest.
+
+fn server_repository_arguments (
+	arguments: & ServerArguments,
+) -> Result <Vec <ServerRepositoryArguments>, String> {
+
+	let config_file =
+		match (args::config_file (), & arguments.repository_path) {
+
+		(Some (config_file), & None) =>
+			config_file,
+
+		(_, & Some (ref repository_path)) =>
+			return Ok (vec! [
+				ServerRepositoryArguments {
+					name: args::config_repository_name ().unwrap_or_else (
+						|| DEFAULT_REPOSITORY_NAME.to_string ()),
+					path: repository_path.clone (),
+					password_file_path: arguments.password_file_path.clone (),
+					config: arguments.repository_config.clone (),
+				},
+			]),
+
+		(None, & None) =>
+			return Err (
+				"Either --repository or --config is required".to_string ()),
+
+	};
+
+	let memory_budget =
+		match config_file.section ("server") {
+
+		Some (server_section) =>
+			server_section.get_size (
+				"memory-cache-size",
+			) ?,
+
+		None =>
+			None,
+
+	};
+
+	let repository_sections =
+		config_file.sections_with_prefix (
+			"repositories");
+
+	if repository_sections.is_empty () {
+
+		return Err (
+			format! (
+				"No repositories in {}",
+				config_file.path ().to_string_lossy ()));
+
+	}
+
+	let mut repository_arguments: Vec <ServerRepositoryArguments> =
+		Vec::new ();
+
+	let mut shared_repository_indexes: Vec <usize> =
+		Vec::new ();
+
+	let mut reserved_memory: u64 = 0;
+
+	for (name, section) in repository_sections {
+
+		let mut allowed_keys =
+			vec! [ "path", "password-file" ];
+
+		allowed_keys.extend_from_slice (
+			args::REPOSITORY_CONFIG_KEYS);
+
+		section.check_keys (
+			& allowed_keys,
+		) ?;
+
+		let path =
+			section.get_path (
+				"path",
+			) ?.ok_or_else (
+				|| format! (
+					"No path for repository {}",
+					name),
+			) ?;
+
+		// each repository needs its own filesystem cache
+
+		let mut base_config =
+			arguments.repository_config.clone ();
+
+		base_config.filesystem_cache_path =
+			PathBuf::from (
+				& base_config.filesystem_cache_path,
+			).join (name).to_string_lossy ().to_string ();
+
+		base_config.index_cache_path = None;
+
+		let mut config =
+			args::repository_config_section (
+				section,
+				& base_config,
+			) ?;
+
+		if [
+			"memory-cache-size",
+			"uncompressed-memory-cache-size",
+			"compressed-memory-cache-size",
+		].iter ().any (|key| section.get (key).is_some ()) {
+
+			reserved_memory +=
+				config.max_uncompressed_memory_cache_size.unwrap_or (0)
+				+ config.max_compressed_memory_cache_size.unwrap_or (0);
+
+		} else if memory_budget.is_some () {
+
+			// entry limits only apply to a share if given explicitly
+
+			if section.get ("max-uncompressed-memory-cache-entries").is_none () {
+				config.max_uncompressed_memory_cache_entries =
+					usize::MAX;
+			}
+
+			if section.get ("max-compressed-memory-cache-entries").is_none () {
+				config.max_compressed_memory_cache_entries =
+					usize::MAX;
+			}
+
+			shared_repository_indexes.push (
+				repository_arguments.len ());
+
+		}
+
+		repository_arguments.push (
+			ServerRepositoryArguments {
+				name: name.to_string (),
+				path: path,
+				password_file_path: section.get_path ("password-file") ?,
+				config: config,
+			});
+
+	}
+
+	if let Some (memory_budget) = memory_budget {
+
+		if reserved_memory > memory_budget {
+
+			return Err (
+				format! (
+					"Repository memory cache sizes add up to more than the \
+					server's memory-cache-size"));
+
+		}
+
+		if ! shared_repository_indexes.is_empty () {
+
+			let memory_share =
+				(memory_budget - reserved_memory)
+					/ shared_repository_indexes.len () as u64;
+
+			for & index in shared_repository_indexes.iter () {
+
+				let config =
+					& mut repository_arguments [index].config;
+
+				let (uncompressed_size, compressed_size) =
+					args::memory_cache_size_split (
+						memory_share);
+
+				config.max_uncompressed_memory_cache_size =
+					Some (uncompressed_size);
+
+				config.max_compressed_memory_cache_size =
+					Some (compressed_size);
+
+			}
+
+		}
+
+	}
+
+	Ok (repository_arguments)
+
+}
+
 command! (
 
 	name = server,
 	export = server_command,
 
 	arguments = ServerArguments {
-		repository_path: PathBuf,
+		repository_path: Option <PathBuf>,
 		password_file_path: Option <PathBuf>,
 		repository_config: RepositoryConfig,
 		listen_address: String,
+		watch_indexes: bool,
+		watch_indexes_delay: Duration,
+		max_concurrent_restores: usize,
+		idle_timeout: Option <Duration>,
+		read_timeout: Option <Duration>,
+		shutdown_timeout: Duration,
 	},
 
 	clap_subcommand = {
@@ -125,8 +706,10 @@ command! (
 				.long ("repository")
 				.alias ("repository-path")
 				.value_name ("REPOSITORY")
-				.required (true)
-				.help ("Path to the repository, used to obtain encryption key")
+				.required (false)
+				.help ("Path to the repository, used to obtain encryption key, \
+					or the name of a repository in the config file. If \
+					omitted, all repositories in the config file are served.")
 
 			)
 
@@ -147,7 +730,73 @@ command! (
 				.long ("listen-address")
 				.value_name ("ADDRESS:PORT")
 				.default_value ("localhost:4152")
-				.help ("Address to listen on, in host:port or ip:port format.")
+				.help ("Address to listen on, in host:port or ip:port format, or \
+					unix:/path for a unix domain socket. Ignored if listening \
+					sockets are passed using LISTEN_FDS.")
+
+			)
+
+			.arg (
+				clap::Arg::with_name ("watch-indexes")
+
+				.long ("watch-indexes")
+				.help ("Reindex automatically when index files are added or \
+					removed")
+
+			)
+
+			.arg (
+				clap::Arg::with_name ("watch-indexes-delay")
+
+				.long ("watch-indexes-delay")
+				.value_name ("DURATION")
+				.default_value ("5 seconds")
+				.help ("Time to wait for the index directory to stop changing \
+					before reindexing")
+
+			)
+
+			.arg (
+				clap::Arg::with_name ("max-concurrent-restores")
+
+				.long ("max-concurrent-restores")
+				.value_name ("RESTORES")
+				.default_value ("0")
+				.help ("Maximum number of restores to run at once, further \
+					restores are queued. Zero means no limit.")
+
+			)
+
+			.arg (
+				clap::Arg::with_name ("idle-timeout")
+
+				.long ("idle-timeout")
+				.value_name ("DURATION")
+				.required (false)
+				.help ("Disconnect clients which do not send a command within \
+					this time")
+
+			)
+
+			.arg (
+				clap::Arg::with_name ("read-timeout")
+
+				.long ("read-timeout")
+				.value_name ("DURATION")
+				.required (false)
+				.help ("Disconnect clients which take longer than this to send \
+					the rest of a command")
+
+			)
+
+			.arg (
+				clap::Arg::with_name ("shutdown-timeout")
+
+				.long ("shutdown-timeout")
+				.value_name ("DURATION")
+				.default_value ("30 seconds")
+				.help ("Time to wait for running restores to finish after \
+					SIGTERM or SIGINT")
 
 			)
 
@@ -182,7 +831,7 @@ command! (
 		ServerArguments {
 
 			repository_path:
-				args::path_required (
+				args::path_optional (
 					clap_matches,
 					"repository"),
 
@@ -200,6 +849,37 @@ command! (
 					clap_matches,
 					"listen-address"),
 
+			watch_indexes:
+				args::bool_flag (
+					clap_matches,
+					"watch-indexes"),
+
+			watch_indexes_delay:
+				args::duration_required (
+					clap_matches,
+					"watch-indexes-delay"),
+
+			max_concurrent_restores:
+				args::u64_required (
+					clap_matches,
+					"max-concurrent-restores",
+				) as usize,
+
+			idle_timeout:
+				args::duration_optional (
+					clap_matches,
+					"idle-timeout"),
+
+			read_timeout:
+				args::duration_optional (
+					clap_matches,
+					"read-timeout"),
+
+			shutdown_timeout:
+				args::duration_required (
+					clap_matches,
+					"shutdown-timeout"),
+
 		}
 
 	},
@@ -3,6 +3,7 @@ use std::collections::LinkedList;
 use std::ops::DerefMut;
 use std::sync::Arc;
 use std::sync::Mutex;
+use std::sync::Weak;
 use std::thread;
 use std::thread::JoinHandle;
 
@@ -44,8 +45,16 @@ type ChunkMapFutureReceiver =
 struct ChunkMapFutureChannel {
 	sender: ChunkMapFutureSender,
 	receiver: ChunkMapFutureReceiver,
+	interests: Vec <Weak <()>>,
 }
 
+/// Held by each future returned for a queued bundle. Once every future for a
+/// bundle has been dropped, for example because a restore was abandoned, the
+/// bundle is no longer loaded when its turn comes.
+
+type Interest =
+	Arc <()>;
+
 type Task =
 	CpuFuture <(), ()>;
 
@@ -71,11 +80,13 @@ struct BundleLoaderState {
 	cpu_pool: Option <CpuPool>,
 
 	num_loads: u64,
+	num_cancelled: u64,
 
 }
 
 pub struct BundleLoaderStatus {
 	pub num_loads: u64,
+	pub num_cancelled: u64,
 	pub loading_now: Vec <BundleId>,
 	pub loading_later: Vec <BundleId>,
 }
@@ -114,6 +125,7 @@ impl BundleLoader {
 				cpu_pool: Some (CpuPool::new (num_threads)),
 
 				num_loads: 0,
+				num_cancelled: 0,
 
 			})),
 
@@ -153,8 +165,11 @@ impl BundleLoader {
 
 		}
 
+		let interest: Interest =
+			Arc::new (());
+
 		if let Some (chunk_map_future_channel) =
-			self_state.loading_later.get (
+			self_state.loading_later.get_mut (
 				& bundle_id) {
 
 			// already queued for loading
@@ -164,10 +179,16 @@ impl BundleLoader {
 				"BundleLoader.load_bundle_async_async ({}) - Alreading queued",
 				bundle_id);
 
+			chunk_map_future_channel.interests.push (
+				Arc::downgrade (
+					& interest));
+
 			return chunk_map_future_channel.receiver.clone ().map_err (
 				|_cancelled| "Cancelled".to_string (),
 			).map (
-				|chunk_map_receiver|
+				move |chunk_map_receiver| {
+
+				drop (interest);
 
 				chunk_map_receiver.map_err (
 					|_cancelled| "Cancelled".to_string (),
@@ -175,7 +196,7 @@ impl BundleLoader {
 					|result| result,
 				).boxed ()
 
-			).boxed ()
+			}).boxed ()
 
 		}
 
@@ -190,10 +211,13 @@ impl BundleLoader {
 			self_state.deref_mut (),
 			debug,
 			bundle_id,
+			& interest,
 		).map_err (
 			|_cancelled| "Cancelled".to_string (),
 		).map (
-			|chunk_map_receiver|
+			move |chunk_map_receiver| {
+
+			drop (interest);
 
 			chunk_map_receiver.map_err (
 				|_cancelled| "Cancelled".to_string (),
@@ -201,7 +225,7 @@ impl BundleLoader {
 				|result| result,
 			).boxed ()
 
-		).boxed ()
+		}).boxed ()
 
 	}
 
@@ -210,6 +234,7 @@ impl BundleLoader {
 		self_state: & mut BundleLoaderState,
 		_debug: & Output,
 		bundle_id: BundleId,
+		interest: & Interest,
 	) -> ChunkMapFutureReceiver {
 
 		let (bundle_future_sender, bundle_future_receiver) =
@@ -223,6 +248,7 @@ impl BundleLoader {
 			ChunkMapFutureChannel {
 				sender: bundle_future_sender,
 				receiver: bundle_future_receiver.clone (),
+				interests: vec! [ Arc::downgrade (interest) ],
 			});
 
 		self_state.loading_later_queue.push_back (
@@ -287,6 +313,24 @@ impl BundleLoader {
 			if let Some (bundle_id) =
 				self_state.loading_later_queue.pop_front () {
 
+				// skip bundles which nobody is waiting for any more, which
+				// cancels any remaining futures
+
+				let interested =
+					self_state.loading_later [& bundle_id].interests.iter ().any (
+						|interest| interest.upgrade ().is_some ());
+
+				if ! interested {
+
+					self_state.loading_later.remove (
+						& bundle_id);
+
+					self_state.num_cancelled += 1;
+
+					continue;
+
+				}
+
 				let (bundle_sender, bundle_receiver) =
 					oneshot::channel ();
 
@@ -355,6 +399,24 @@ impl BundleLoader {
 
 	}
 
+	/// Abandons all queued bundle loads, which causes any futures waiting for
+	/// them to fail. Loads which have already started will still complete.
+
+	pub fn close (
+		& self,
+	) {
+
+		let mut self_state =
+			self.state.lock ().unwrap ();
+
+		self_state.num_cancelled +=
+			self_state.loading_later.len () as u64;
+
+		self_state.loading_later.clear ();
+		self_state.loading_later_queue.clear ();
+
+	}
+
 	pub fn status (
 		& self,
 	) -> BundleLoaderStatus {
@@ -365,6 +427,7 @@ impl BundleLoader {
 		BundleLoaderStatus {
 
 			num_loads: self_state.num_loads,
+			num_cancelled: self_state.num_cancelled,
 
 			loading_now:
 				self_state.loading_now.keys ()
@@ -433,7 +496,7 @@ impl BundleLoader {
 		loop {
 
 			match future::select_all (
-				current_tasks.into_iter (),
+				current_tasks,
 			).wait () {
 
 				// handle stop
@@ -0,0 +1,813 @@
+use std::cmp;
+use std::collections::HashMap;
+use std::collections::VecDeque;
+use std::hash::Hash;
+use std::vec;
+
+/// A single tier of the chunk cache. This enforces a limit on the total size
+/// of the items it contains, as well as on their number, and evicts items
+/// according to the configured policy until both limits are satisfied.
+///
+/// With the LRU policy, the least recently used items are evicted first. With
+/// the 2Q policy, new items are placed in a small probationary FIFO queue, and
+/// only move to the main LRU queue if they are inserted again shortly after
+/// being evicted from it, which is tracked using a queue of "ghost" keys. This
+/// means that a single large scan can not flush items which are used often.
+
+pub struct CacheTier <Key: Clone + Eq + Hash, Value: CacheTierItem> {
+
+	policy: CachePolicy,
+	max_entries: usize,
+	max_bytes: Option <u64>,
+
+	main: FifoQueue <Key, Value>,
+	main_bytes: u64,
+
+	probation: FifoQueue <Key, Value>,
+	probation_bytes: u64,
+
+	ghosts: FifoQueue <Key, ()>,
+
+	stats: CacheTierStats,
+
+}
+
+/// Implemented by values stored in a `CacheTier`, to report the number of
+/// bytes they count against the tier's budget.
+
+pub trait CacheTierItem {
+
+	fn cache_size (
+		& self,
+	) -> u64;
+
+}
+
+/// Selects the eviction policy used by every tier of the chunk cache.
+
+#[ derive (Clone, Copy, Debug, Eq, PartialEq) ]
+pub enum CachePolicy {
+	Lru,
+	TwoQueue,
+}
+
+/// Lookup and eviction counters for a single tier. Hits are split between the
+/// probationary and main queues, which is only meaningful for the 2Q policy,
+/// and ghost hits count items which were promoted to the main queue because
+/// they were inserted again soon after eviction.
+
+#[ derive (Clone, Copy, Debug, Default) ]
+pub struct CacheTierStats {
+	pub main_hits: u64,
+	pub probation_hits: u64,
+	pub ghost_hits: u64,
+	pub misses: u64,
+	pub evictions: u64,
+}
+
+/// Items in insertion order. The main queue also moves items to the back when
+/// they are used, which makes it a least recently used queue.
+
+struct FifoQueue <Key: Clone + Eq + Hash, Value> {
+	items: HashMap <Key, (u64, Value)>,
+	order: VecDeque <(u64, Key)>,
+	next_sequence: u64,
+}
+
+// probationary queue gets a quarter of the tier, ghosts track half as many
+// keys as the tier holds
+
+const PROBATION_DIVISOR: u64 = 4;
+const MIN_GHOSTS: usize = 0x40;
+
+impl CachePolicy {
+
+	pub fn parse (
+		name: & str,
+	) -> Result <CachePolicy, String> {
+
+		match name {
+
+			"lru" =>
+				Ok (CachePolicy::Lru),
+
+			"2q" =>
+				Ok (CachePolicy::TwoQueue),
+
+			_ =>
+				Err (
+					format! (
+						"Unknown cache policy: {}",
+						name)),
+
+		}
+
+	}
+
+	pub fn name (
+		& self,
+	) -> & 'static str {
+
+		match * self {
+			CachePolicy::Lru => "lru",
+			CachePolicy::TwoQueue => "2q",
+		}
+
+	}
+
+}
+
+impl <Key: Clone + Eq + Hash, Value: CacheTierItem> CacheTier <Key, Value> {
+
+	pub fn new (
+		policy: CachePolicy,
+		max_entries: usize,
+		max_bytes: Option <u64>,
+	) -> CacheTier <Key, Value> {
+
+		CacheTier {
+
+			policy: policy,
+			max_entries: max_entries,
+			max_bytes: max_bytes,
+
+			main: FifoQueue::new (),
+			main_bytes: 0,
+
+			probation: FifoQueue::new (),
+			probation_bytes: 0,
+
+			ghosts: FifoQueue::new (),
+
+			stats: Default::default (),
+
+		}
+
+	}
+
+	/// Checks if an item is present, without counting a hit or miss, or
+	/// changing the order of eviction.
+
+	#[ inline ]
+	pub fn contains_key (
+		& self,
+		key: & Key,
+	) -> bool {
+
+		self.main.contains_key (key)
+			|| self.probation.contains_key (key)
+
+	}
+
+	/// Returns an item, without counting a hit or miss, or changing the order
+	/// of eviction.
+
+	#[ inline ]
+	pub fn peek (
+		& self,
+		key: & Key,
+	) -> Option <& Value> {
+
+		self.main.get (
+			key,
+		).or_else (
+			|| self.probation.get (key)
+		)
+
+	}
+
+	/// Marks an item as recently used, in the same way as `get_mut`, but
+	/// without counting a hit or miss. This is used to keep other tiers in
+	/// step when an item is found in one of them. Returns true if the item is
+	/// present.
+
+	pub fn touch (
+		& mut self,
+		key: & Key,
+	) -> bool {
+
+		self.main.move_to_back (key).is_some ()
+			|| self.probation.contains_key (key)
+
+	}
+
+	/// Looks up an item, and counts a hit or miss. With the LRU policy, or for
+	/// items in the main queue, this marks the item as recently used. Items in
+	/// the probationary queue keep their position.
+
+	pub fn get_mut (
+		& mut self,
+		key: & Key,
+	) -> Option <& mut Value> {
+
+		if let Some (value) =
+			self.main.move_to_back (
+				key) {
+
+			self.stats.main_hits += 1;
+
+			return Some (value);
+
+		}
+
+		if let Some (value) =
+			self.probation.get_mut (
+				key) {
+
+			self.stats.probation_hits += 1;
+
+			return Some (value);
+
+		}
+
+		self.stats.misses += 1;
+
+		None
+
+	}
+
+	/// Inserts an item, replacing any existing item with the same key, then
+	/// evicts items until the tier is within its limits. Items which are
+	/// larger than the entire budget are not stored.
+
+	pub fn insert (
+		& mut self,
+		key: Key,
+		value: Value,
+	) {
+
+		let was_main =
+			self.main.contains_key (
+				& key);
+
+		self.remove (
+			& key);
+
+		let value_size =
+			value.cache_size ();
+
+		if self.max_entries == 0 {
+			return;
+		}
+
+		if let Some (max_bytes) = self.max_bytes {
+
+			if value_size > max_bytes {
+				return;
+			}
+
+		}
+
+		let to_main =
+			match self.policy {
+
+			CachePolicy::Lru =>
+				true,
+
+			CachePolicy::TwoQueue => {
+
+				if self.ghosts.remove (& key).is_some () {
+
+					self.stats.ghost_hits += 1;
+
+					true
+
+				} else {
+
+					was_main
+
+				}
+
+			},
+
+		};
+
+		if to_main {
+
+			self.main.insert (
+				key,
+				value);
+
+			self.main_bytes += value_size;
+
+		} else {
+
+			self.probation.insert (
+				key,
+				value);
+
+			self.probation_bytes += value_size;
+
+		}
+
+		self.evict ();
+
+	}
+
+	pub fn remove (
+		& mut self,
+		key: & Key,
+	) -> Option <Value> {
+
+		if let Some (value) =
+			self.main.remove (
+				key) {
+
+			self.main_bytes -= value.cache_size ();
+
+			return Some (value);
+
+		}
+
+		if let Some (value) =
+			self.probation.remove (
+				key) {
+
+			self.probation_bytes -= value.cache_size ();
+
+			return Some (value);
+
+		}
+
+		None
+
+	}
+
+	fn evict (
+		& mut self,
+	) {
+
+		while self.over_limit () {
+
+			if self.probation_over_limit () || self.main.len () == 0 {
+
+				match self.probation.pop_front () {
+
+					Some ((key, value)) => {
+
+						self.probation_bytes -= value.cache_size ();
+
+						self.ghosts.insert (
+							key,
+							());
+
+					},
+
+					None =>
+						break,
+
+				}
+
+			} else {
+
+				match self.main.pop_front () {
+
+					Some ((_key, value)) =>
+						self.main_bytes -= value.cache_size (),
+
+					None =>
+						break,
+
+				}
+
+			}
+
+			self.stats.evictions += 1;
+
+		}
+
+		let max_ghosts =
+			cmp::max (
+				self.len () / 2,
+				MIN_GHOSTS);
+
+		while self.ghosts.len () > max_ghosts {
+
+			self.ghosts.pop_front ();
+
+		}
+
+	}
+
+	fn over_limit (
+		& self,
+	) -> bool {
+
+		self.len () > self.max_entries
+			|| self.max_bytes.map (
+				|max_bytes| self.bytes () > max_bytes,
+			).unwrap_or (false)
+
+	}
+
+	fn probation_over_limit (
+		& self,
+	) -> bool {
+
+		self.probation.len () as u64
+				> self.max_entries as u64 / PROBATION_DIVISOR
+			|| self.max_bytes.map (
+				|max_bytes|
+				self.probation_bytes > max_bytes / PROBATION_DIVISOR
+			).unwrap_or (false)
+
+	}
+
+	#[ inline ]
+	pub fn len (& self) -> usize {
+		self.main.len () + self.probation.len ()
+	}
+
+	#[ inline ]
+	pub fn bytes (& self) -> u64 {
+		self.main_bytes + self.probation_bytes
+	}
+
+	#[ inline ]
+	pub fn stats (& self) -> CacheTierStats {
+		self.stats
+	}
+
+	/// Lists the items from first to last to be evicted, without affecting
+	/// their order. Probationary items come first, since they would normally be
+	/// evicted first.
+
+	pub fn iter (
+		& self,
+	) -> vec::IntoIter <(& Key, & Value)> {
+
+		let mut items: Vec <(& Key, & Value)> =
+			self.probation.iter ();
+
+		items.extend (
+			self.main.iter ());
+
+		items.into_iter ()
+
+	}
+
+}
+
+impl <Key: Clone + Eq + Hash, Value> FifoQueue <Key, Value> {
+
+	fn new (
+	) -> FifoQueue <Key, Value> {
+
+		FifoQueue {
+			items: HashMap::new (),
+			order: VecDeque::new (),
+			next_sequence: 0,
+		}
+
+	}
+
+	fn insert (
+		& mut self,
+		key: Key,
+		value: Value,
+	) {
+
+		let sequence =
+			self.next_sequence;
+
+		self.next_sequence += 1;
+
+		self.items.insert (
+			key.clone (),
+			(sequence, value));
+
+		self.order.push_back (
+			(sequence, key));
+
+		self.compact ();
+
+	}
+
+	#[ inline ]
+	fn contains_key (
+		& self,
+		key: & Key,
+	) -> bool {
+
+		self.items.contains_key (
+			key)
+
+	}
+
+	#[ inline ]
+	fn get (
+		& self,
+		key: & Key,
+	) -> Option <& Value> {
+
+		self.items.get (
+			key,
+		).map (
+			|& (_, ref value)| value
+		)
+
+	}
+
+	/// Moves an item to the back of the queue, so it is the last to be
+	/// removed. The old entry in the order queue is left behind, and skipped
+	/// by `pop_front`.
+
+	fn move_to_back (
+		& mut self,
+		key: & Key,
+	) -> Option <& mut Value> {
+
+		if ! self.items.contains_key (key) {
+			return None;
+		}
+
+		let sequence =
+			self.next_sequence;
+
+		self.next_sequence += 1;
+
+		self.items.get_mut (
+			key,
+		).unwrap ().0 = sequence;
+
+		self.order.push_back (
+			(sequence, key.clone ()));
+
+		self.compact ();
+
+		self.get_mut (
+			key)
+
+	}
+
+	#[ inline ]
+	fn get_mut (
+		& mut self,
+		key: & Key,
+	) -> Option <& mut Value> {
+
+		self.items.get_mut (
+			key,
+		).map (
+			|& mut (_, ref mut value)| value
+		)
+
+	}
+
+	fn remove (
+		& mut self,
+		key: & Key,
+	) -> Option <Value> {
+
+		let value =
+			self.items.remove (
+				key,
+			).map (
+				|(_, value)| value
+			);
+
+		self.compact ();
+
+		value
+
+	}
+
+	/// Removes the oldest item. Entries in the order queue whose sequence does
+	/// not match the item are left over from removals, and are skipped.
+
+	fn pop_front (
+		& mut self,
+	) -> Option <(Key, Value)> {
+
+		while let Some ((sequence, key)) =
+			self.order.pop_front () {
+
+			let current =
+				self.items.get (
+					& key,
+				).map (
+					|& (item_sequence, _)| item_sequence == sequence
+				).unwrap_or (false);
+
+			if current {
+
+				let (_, value) =
+					self.items.remove (
+						& key,
+					).unwrap ();
+
+				return Some ((key, value));
+
+			}
+
+		}
+
+		None
+
+	}
+
+	/// Rebuilds the order queue when too many stale entries have built up.
+
+	fn compact (
+		& mut self,
+	) {
+
+		if self.order.len () <= self.items.len () * 2 + MIN_GHOSTS {
+			return;
+		}
+
+		let items =
+			& self.items;
+
+		let order: VecDeque <(u64, Key)> =
+			self.order.drain (..).filter (
+				|& (sequence, ref key)|
+				items.get (key).map (
+					|& (item_sequence, _)| item_sequence == sequence
+				).unwrap_or (false)
+			).collect ();
+
+		self.order = order;
+
+	}
+
+	#[ inline ]
+	fn len (& self) -> usize {
+		self.items.len ()
+	}
+
+	fn iter (
+		& self,
+	) -> Vec <(& Key, & Value)> {
+
+		let items =
+			& self.items;
+
+		self.order.iter ().filter_map (
+			|& (sequence, ref key)|
+
+			match items.get (key) {
+
+				Some (& (item_sequence, ref value))
+					if item_sequence == sequence =>
+					Some ((key, value)),
+
+				_ =>
+					None,
+
+			}
+
+		).collect ()
+
+	}
+
+}
+
+#[ cfg (test) ]
+mod tests {
+
+	use super::*;
+
+	struct TestItem (u64);
+
+	impl CacheTierItem for TestItem {
+
+		fn cache_size (
+			& self,
+		) -> u64 {
+			self.0
+		}
+
+	}
+
+	#[ test ]
+	fn lru_evicts_least_recently_used () {
+
+		let mut tier: CacheTier <u64, TestItem> =
+			CacheTier::new (
+				CachePolicy::Lru,
+				3,
+				None);
+
+		tier.insert (1, TestItem (1));
+		tier.insert (2, TestItem (1));
+		tier.insert (3, TestItem (1));
+
+		assert! (tier.get_mut (& 1).is_some ());
+
+		tier.insert (4, TestItem (1));
+
+		assert! (tier.contains_key (& 1));
+		assert! (! tier.contains_key (& 2));
+		assert! (tier.contains_key (& 3));
+		assert! (tier.contains_key (& 4));
+		assert_eq! (tier.len (), 3);
+		assert_eq! (tier.stats ().evictions, 1);
+
+	}
+
+	#[ test ]
+	fn byte_limit () {
+
+		let mut tier: CacheTier <u64, TestItem> =
+			CacheTier::new (
+				CachePolicy::Lru,
+				100,
+				Some (100));
+
+		tier.insert (1, TestItem (40));
+		tier.insert (2, TestItem (40));
+		tier.insert (3, TestItem (40));
+
+		assert! (! tier.contains_key (& 1));
+		assert_eq! (tier.len (), 2);
+		assert_eq! (tier.bytes (), 80);
+
+		// replacing an item adjusts the total
+
+		tier.insert (2, TestItem (10));
+
+		assert_eq! (tier.bytes (), 50);
+
+		// items bigger than the budget are not stored
+
+		tier.insert (4, TestItem (101));
+
+		assert! (! tier.contains_key (& 4));
+		assert_eq! (tier.bytes (), 50);
+
+	}
+
+	#[ test ]
+	fn two_queue_resists_scans () {
+
+		for & (policy, survives) in [
+			(CachePolicy::Lru, false),
+			(CachePolicy::TwoQueue, true),
+		].iter () {
+
+			let mut tier: CacheTier <u64, TestItem> =
+				CacheTier::new (
+					policy,
+					8,
+					None);
+
+			// fill the tier so that the first item is evicted, then insert it
+			// again, which promotes it with 2q
+
+			for key in 1 .. 10 {
+				tier.insert (key, TestItem (1));
+			}
+
+			assert! (! tier.contains_key (& 1));
+
+			tier.insert (1, TestItem (1));
+
+			// a long scan of items used only once
+
+			for key in 100 .. 200 {
+				tier.insert (key, TestItem (1));
+			}
+
+			assert_eq! (tier.contains_key (& 1), survives);
+			assert_eq! (tier.len (), 8);
+
+			if policy == CachePolicy::TwoQueue {
+				assert_eq! (tier.stats ().ghost_hits, 1);
+			}
+
+		}
+
+	}
+
+	#[ test ]
+	fn peek_and_touch_leave_stats () {
+
+		let mut tier: CacheTier <u64, TestItem> =
+			CacheTier::new (
+				CachePolicy::Lru,
+				2,
+				None);
+
+		tier.insert (1, TestItem (1));
+		tier.insert (2, TestItem (1));
+
+		assert! (tier.peek (& 1).is_some ());
+		assert! (tier.touch (& 1));
+		assert! (! tier.touch (& 3));
+
+		tier.insert (3, TestItem (1));
+
+		assert! (tier.contains_key (& 1));
+		assert! (! tier.contains_key (& 2));
+
+		let stats =
+			tier.stats ();
+
+		assert_eq! (stats.main_hits + stats.probation_hits + stats.misses, 0);
+
+	}
+
+}
+
+// ex: noet ts=4 filetype=rust
@@ -1,16 +1,30 @@
 #![ allow (unused_parens) ]
 
-use std::error::Error;
+use std::collections::HashMap;
 use std::hash::Hash;
+use std::io;
+use std::io::BufRead;
+use std::io::BufReader;
+use std::io::BufWriter;
 use std::io::Read;
 use std::io::Write;
 use std::fs;
 use std::fs::File;
+use std::fs::OpenOptions;
 use std::ops::DerefMut;
+use std::os::unix::fs::MetadataExt;
+use std::os::unix::io::AsRawFd;
 use std::path::Path;
 use std::path::PathBuf;
 use std::sync::Arc;
 use std::sync::Mutex;
+use std::sync::atomic::AtomicBool;
+use std::sync::atomic::Ordering;
+
+use adler32::RollingAdler32;
+
+use byteorder::ByteOrder;
+use byteorder::LittleEndian;
 
 use futures;
 use futures::BoxFuture;
@@ -18,7 +32,8 @@ use futures::Future;
 
 use futures_cpupool::CpuPool;
 
-use lru_cache::LruCache;
+use libc;
+
 
 use minilzo;
 
@@ -27,6 +42,9 @@ use output::Output;
 use rand;
 use rand::Rng;
 
+use zbackup::cache_tier::*;
+use zbackup::data::ChunkId;
+
 /// The chunk cache provides a tiered cache for individual chunks. These are
 /// stored in memory, compressed and uncompressed, and in the filesyste,
 /// compressed. The compression is LZO for speed and the cache uses a simple
@@ -38,6 +56,13 @@ use rand::Rng;
 /// chunks greatly improves efficiency over time, while the smaller cache for
 /// unused chunks improves decompression when the cache is cold or when
 /// restoring new backups.
+///
+/// The filesystem cache is persistent. Each item is stored in a file named
+/// after its key, with a small header including a checksum, and the LRU order
+/// is recorded in an append-only journal. On startup, existing files are
+/// verified and adopted in journal order, and corrupt or truncated files are
+/// discarded. If another process already holds the cache directory's lock, a
+/// private, temporary directory is used instead.
 
 #[ derive (Clone) ]
 pub struct ChunkCache <Key: ChunkCacheKey> {
@@ -47,16 +72,46 @@ pub struct ChunkCache <Key: ChunkCacheKey> {
 }
 
 pub trait ChunkCacheKey: Clone + Eq + Hash + Send + Sync + 'static {
+
+	fn to_filename (
+		& self,
+	) -> String;
+
+	fn from_filename (
+		filename: & str,
+	) -> Option <Self>;
+
 }
 
-impl <Type> ChunkCacheKey for Type
-where Type: Clone + Eq + Hash + Send + Sync + 'static {
+impl ChunkCacheKey for ChunkId {
+
+	fn to_filename (
+		& self,
+	) -> String {
+		self.to_string ()
+	}
+
+	fn from_filename (
+		filename: & str,
+	) -> Option <ChunkId> {
+		ChunkId::parse (filename).ok ()
+	}
+
 }
 
-#[ derive (Clone) ]
 struct ChunkCacheData {
 	path: PathBuf,
 	debug: bool,
+	persistent: bool,
+	shutdown: AtomicBool,
+	lock_file: Option <File>,
+
+	// held while an item file is moved into place or removed, since an item
+	// which has been evicted may be dropped after the same chunk is stored
+	// again, under the same name
+
+	file_lock: Mutex <()>,
+
 }
 
 #[ derive (Clone) ]
@@ -67,10 +122,10 @@ enum MemoryCacheItem {
 
 struct ChunkCacheState <Key: ChunkCacheKey> {
 
-	uncompressed_memory_items: LruCache <Key, Arc <Vec <u8>>>,
-	compressed_memory_items: LruCache <Key, MemoryCacheItem>,
-	live_filesystem_items: LruCache <Key, Arc <FilesystemItem>>,
-	dead_filesystem_items: LruCache <Key, Arc <FilesystemItem>>,
+	uncompressed_memory_items: CacheTier <Key, Arc <Vec <u8>>>,
+	compressed_memory_items: CacheTier <Key, MemoryCacheItem>,
+	live_filesystem_items: CacheTier <Key, Arc <FilesystemItem>>,
+	dead_filesystem_items: CacheTier <Key, Arc <FilesystemItem>>,
 
 	uncompressed_memory_hits: u64,
 	compressed_memory_hits: u64,
@@ -78,11 +133,18 @@ struct ChunkCacheState <Key: ChunkCacheKey> {
 	dead_filesystem_hits: u64,
 	misses: u64,
 
+	cache_policy: CachePolicy,
+
+	journal: Option <BufWriter <File>>,
+	journal_lines: u64,
+	adopted_filesystem_items: u64,
+
 }
 
 struct FilesystemItem {
 	storage_manager: Arc <ChunkCacheData>,
 	filename: String,
+	file_id: ItemFileId,
 	compressed: bool,
 	uncompressed_size: usize,
 	stored_size: usize,
@@ -95,14 +157,41 @@ pub struct ChunkCacheStatus {
 	pub live_filesystem_items: u64,
 	pub dead_filesystem_items: u64,
 
+	pub uncompressed_memory_bytes: u64,
+	pub compressed_memory_bytes: u64,
+	pub live_filesystem_bytes: u64,
+	pub dead_filesystem_bytes: u64,
+
 	pub uncompressed_memory_hits: u64,
 	pub compressed_memory_hits: u64,
 	pub live_filesystem_hits: u64,
 	pub dead_filesystem_hits: u64,
 	pub misses: u64,
 
+	pub filesystem_persistent: bool,
+	pub adopted_filesystem_items: u64,
+
+	pub cache_policy: CachePolicy,
+	pub uncompressed_memory_stats: CacheTierStats,
+	pub compressed_memory_stats: CacheTierStats,
+	pub live_filesystem_stats: CacheTierStats,
+	pub dead_filesystem_stats: CacheTierStats,
+
 }
 
+struct FilesystemItemHeader {
+	compressed: bool,
+	uncompressed_size: usize,
+	stored_size: usize,
+	checksum: u32,
+}
+
+const ITEM_MAGIC: & 'static [u8] = b"RZBCHNK1";
+const ITEM_HEADER_SIZE: usize = 8 + 1 + 8 + 8 + 4;
+
+const JOURNAL_FLUSH_LINES: u64 = 0x100;
+const JOURNAL_MIN_COMPACT_LINES: u64 = 0x1000;
+
 impl <Key: ChunkCacheKey> ChunkCache <Key> {
 
 	#[ inline ]
@@ -113,6 +202,11 @@ impl <Key: ChunkCacheKey> ChunkCache <Key> {
 		compressed_memory_cache_size: usize,
 		live_filesystem_cache_size: usize,
 		dead_filesystem_cache_size: usize,
+		uncompressed_memory_cache_bytes: Option <u64>,
+		compressed_memory_cache_bytes: Option <u64>,
+		live_filesystem_cache_bytes: Option <u64>,
+		dead_filesystem_cache_bytes: Option <u64>,
+		cache_policy: CachePolicy,
 		debug: bool,
 	) -> Result <ChunkCache <Key>, String> {
 
@@ -123,6 +217,11 @@ impl <Key: ChunkCacheKey> ChunkCache <Key> {
 			compressed_memory_cache_size,
 			live_filesystem_cache_size,
 			dead_filesystem_cache_size,
+			uncompressed_memory_cache_bytes,
+			compressed_memory_cache_bytes,
+			live_filesystem_cache_bytes,
+			dead_filesystem_cache_bytes,
+			cache_policy,
 			debug,
 		)
 
@@ -135,6 +234,11 @@ impl <Key: ChunkCacheKey> ChunkCache <Key> {
 		compressed_memory_cache_size: usize,
 		live_filesystem_cache_size: usize,
 		dead_filesystem_cache_size: usize,
+		uncompressed_memory_cache_bytes: Option <u64>,
+		compressed_memory_cache_bytes: Option <u64>,
+		live_filesystem_cache_bytes: Option <u64>,
+		dead_filesystem_cache_bytes: Option <u64>,
+		cache_policy: CachePolicy,
 		debug: bool,
 	) -> Result <ChunkCache <Key>, String> {
 
@@ -148,7 +252,7 @@ impl <Key: ChunkCacheKey> ChunkCache <Key> {
 			format! (
 				"Error creating filesystem cache path: {}: {}",
 				path.to_string_lossy (),
-				error.description ())
+				error)
 
 		) ?;
 
@@ -175,14 +279,77 @@ impl <Key: ChunkCacheKey> ChunkCache <Key> {
 
 		}
 
-		// TODO create a test file to check permissions
+		// take the lock, or fall back to a private directory
+
+		let lock_file =
+			OpenOptions::new ()
+				.read (true)
+				.write (true)
+				.create (true)
+				.truncate (false)
+				.open (
+					path.join ("lock"),
+				).map_err (
+					|error|
+
+					format! (
+						"Error creating filesystem cache lock: {}: {}",
+						path.to_string_lossy (),
+						error)
+
+				) ?;
+
+		let flock_result = unsafe {
+			libc::flock (
+				lock_file.as_raw_fd (),
+				libc::LOCK_EX | libc::LOCK_NB)
+		};
+
+		let (cache_path, persistent, lock_file) =
+			if flock_result == 0 {
+
+			(path.to_owned (), true, Some (lock_file))
+
+		} else {
+
+			let private_name: String =
+				rand::thread_rng ()
+					.gen_ascii_chars ()
+					.take (16)
+					.collect ();
+
+			let private_path =
+				path.join (
+					format! (
+						"private-{}",
+						private_name));
+
+			fs::create_dir (
+				& private_path,
+			).map_err (
+				|error|
+
+				format! (
+					"Error creating private filesystem cache path: {}: {}",
+					private_path.to_string_lossy (),
+					error)
+
+			) ?;
+
+			(private_path, false, None)
 
-		Ok (ChunkCache {
+		};
+
+		let chunk_cache = ChunkCache {
 
 			data: Arc::new (
 				ChunkCacheData {
-					path: path.to_owned (),
+					path: cache_path,
 					debug: debug,
+					persistent: persistent,
+					shutdown: AtomicBool::new (false),
+					lock_file: lock_file,
+					file_lock: Mutex::new (()),
 				}),
 
 			state: Arc::new (
@@ -190,20 +357,28 @@ impl <Key: ChunkCacheKey> ChunkCache <Key> {
 					ChunkCacheState {
 
 				uncompressed_memory_items:
-					LruCache::new (
-						uncompressed_memory_cache_size),
+					CacheTier::new (
+						cache_policy,
+						uncompressed_memory_cache_size,
+						uncompressed_memory_cache_bytes),
 
 				compressed_memory_items:
-					LruCache::new (
-						compressed_memory_cache_size),
+					CacheTier::new (
+						cache_policy,
+						compressed_memory_cache_size,
+						compressed_memory_cache_bytes),
 
 				live_filesystem_items:
-					LruCache::new (
-						live_filesystem_cache_size),
+					CacheTier::new (
+						cache_policy,
+						live_filesystem_cache_size,
+						live_filesystem_cache_bytes),
 
 				dead_filesystem_items:
-					LruCache::new (
-						dead_filesystem_cache_size),
+					CacheTier::new (
+						cache_policy,
+						dead_filesystem_cache_size,
+						dead_filesystem_cache_bytes),
 
 				uncompressed_memory_hits: 0,
 				compressed_memory_hits: 0,
@@ -211,13 +386,403 @@ impl <Key: ChunkCacheKey> ChunkCache <Key> {
 				dead_filesystem_hits: 0,
 				misses: 0,
 
+				cache_policy: cache_policy,
+
+				journal: None,
+				journal_lines: 0,
+				adopted_filesystem_items: 0,
+
 			})),
 
 			cpu_pool:
 				CpuPool::new (
 					num_threads),
 
-		})
+		};
+
+		// adopt existing items and start the journal
+
+		if persistent {
+
+			let mut self_state =
+				chunk_cache.state.lock ().unwrap ();
+
+			chunk_cache.adopt_filesystem_items (
+				self_state.deref_mut (),
+			).map_err (
+				|error|
+
+				format! (
+					"Error loading filesystem cache: {}: {}",
+					path.to_string_lossy (),
+					error)
+
+			) ?;
+
+			Self::journal_compact (
+				& chunk_cache.data,
+				self_state.deref_mut (),
+			).map_err (
+				|error|
+
+				format! (
+					"Error writing filesystem cache journal: {}: {}",
+					path.to_string_lossy (),
+					error)
+
+			) ?;
+
+		}
+
+		Ok (chunk_cache)
+
+	}
+
+	/// Scans the cache directory for items left by a previous process, checks
+	/// their headers, sizes and checksums, and inserts them into the filesystem
+	/// LRUs in the order recorded in the journal. Anything invalid is removed.
+
+	fn adopt_filesystem_items (
+		& self,
+		self_state: & mut ChunkCacheState <Key>,
+	) -> io::Result <()> {
+
+		let mut filesystem_items: HashMap <Key, FilesystemItem> =
+			HashMap::new ();
+
+		for dir_entry in fs::read_dir (& self.data.path) ? {
+
+			let dir_entry = dir_entry ?;
+
+			if ! dir_entry.file_type () ?.is_dir ()
+				|| dir_entry.file_name ().len () != 2 {
+				continue;
+			}
+
+			for item_entry in fs::read_dir (dir_entry.path ()) ? {
+
+				let item_entry = item_entry ?;
+
+				let item_filename =
+					item_entry.file_name ().to_string_lossy ().into_owned ();
+
+				// incomplete writes from a previous process
+
+				if item_filename.ends_with (".tmp") {
+
+					fs::remove_file (
+						item_entry.path (),
+					) ?;
+
+					continue;
+
+				}
+
+				// files which aren't items, or whose names are damaged
+
+				let key =
+					match Key::from_filename (& item_filename) {
+
+					Some (key) => key,
+
+					None => {
+
+						if item_entry.file_type () ?.is_file () {
+
+							fs::remove_file (
+								item_entry.path (),
+							) ?;
+
+						}
+
+						continue;
+
+					},
+
+				};
+
+				match read_item_header (& item_entry.path ()) {
+
+					Ok (header) => {
+
+						filesystem_items.insert (
+							key,
+							FilesystemItem {
+								storage_manager: self.data.clone (),
+								filename: item_filename,
+								file_id:
+									item_file_id (& item_entry.metadata () ?),
+								compressed: header.compressed,
+								uncompressed_size: header.uncompressed_size,
+								stored_size: header.stored_size,
+							});
+
+					},
+
+					Err (_) => {
+
+						fs::remove_file (
+							item_entry.path (),
+						) ?;
+
+					},
+
+				}
+
+			}
+
+		}
+
+		// replay the journal to find the latest state of each item
+
+		let mut journal_states: HashMap <Key, (u64, bool)> =
+			HashMap::new ();
+
+		let journal_path =
+			self.data.path.join ("journal");
+
+		if journal_path.exists () {
+
+			let journal_reader =
+				BufReader::new (
+					File::open (
+						& journal_path,
+					) ?);
+
+			let mut sequence: u64 = 0;
+
+			for line in journal_reader.lines () {
+
+				// a truncated final line is expected after a crash
+
+				let line =
+					match line {
+						Ok (line) => line,
+						Err (_) => break,
+					};
+
+				let live =
+					if line.starts_with ("L ") {
+						true
+					} else if line.starts_with ("D ") {
+						false
+					} else {
+						continue
+					};
+
+				if let Some (key) =
+					Key::from_filename (& line [2 ..]) {
+
+					journal_states.insert (
+						key,
+						(sequence, live));
+
+					sequence += 1;
+
+				}
+
+			}
+
+		}
+
+		// items not in the journal are the oldest, and are assumed dead
+
+		let mut journal_order: Vec <(u64, bool, Key)> =
+			journal_states.into_iter ().filter (
+				|& (ref key, _)|
+				filesystem_items.contains_key (key)
+			).map (
+				|(key, (sequence, live))|
+				(sequence, live, key)
+			).collect ();
+
+		journal_order.sort_by_key (
+			|& (sequence, _, _)| sequence);
+
+		let journal_items: Vec <(bool, Key, FilesystemItem)> =
+			journal_order.into_iter ().map (
+				|(_sequence, live, key)| {
+
+				let filesystem_item =
+					filesystem_items.remove (& key).unwrap ();
+
+				(live, key, filesystem_item)
+
+			}).collect ();
+
+		for (key, filesystem_item) in filesystem_items.drain () {
+
+			self_state.dead_filesystem_items.insert (
+				key,
+				Arc::new (filesystem_item));
+
+		}
+
+		for (live, key, filesystem_item) in journal_items {
+
+			if live {
+
+				self_state.live_filesystem_items.insert (
+					key,
+					Arc::new (filesystem_item));
+
+			} else {
+
+				self_state.dead_filesystem_items.insert (
+					key,
+					Arc::new (filesystem_item));
+
+			}
+
+		}
+
+		self_state.adopted_filesystem_items =
+			(self_state.live_filesystem_items.len ()
+				+ self_state.dead_filesystem_items.len ()) as u64;
+
+		Ok (())
+
+	}
+
+	/// Rewrites the journal so that it contains a single line for every item,
+	/// in LRU order, and reopens it for appending.
+
+	fn journal_compact (
+		data: & ChunkCacheData,
+		self_state: & mut ChunkCacheState <Key>,
+	) -> io::Result <()> {
+
+		if let Some (mut journal) =
+			self_state.journal.take () {
+
+			journal.flush () ?;
+
+		}
+
+		let journal_path =
+			data.path.join ("journal");
+
+		let journal_temp_path =
+			data.path.join ("journal.tmp");
+
+		let mut journal_lines: u64 = 0;
+
+		{
+
+			let mut journal_temp =
+				BufWriter::new (
+					File::create (
+						& journal_temp_path,
+					) ?);
+
+			for (key, _) in self_state.dead_filesystem_items.iter () {
+
+				write! (
+					journal_temp,
+					"D {}\n",
+					key.to_filename (),
+				) ?;
+
+				journal_lines += 1;
+
+			}
+
+			for (key, _) in self_state.live_filesystem_items.iter () {
+
+				write! (
+					journal_temp,
+					"L {}\n",
+					key.to_filename (),
+				) ?;
+
+				journal_lines += 1;
+
+			}
+
+			journal_temp.flush () ?;
+
+			journal_temp.get_ref ().sync_all () ?;
+
+		}
+
+		fs::rename (
+			& journal_temp_path,
+			& journal_path,
+		) ?;
+
+		self_state.journal =
+			Some (
+				BufWriter::new (
+					OpenOptions::new ()
+						.append (true)
+						.open (
+							& journal_path,
+						) ?));
+
+		self_state.journal_lines =
+			journal_lines;
+
+		Ok (())
+
+	}
+
+	/// Records that an item has been used, or inserted, in the journal. This is
+	/// best-effort, since the journal only affects the order in which items are
+	/// evicted after a restart.
+
+	fn journal_record (
+		& self,
+		self_state: & mut ChunkCacheState <Key>,
+		key: & Key,
+		live: bool,
+	) {
+
+		let journal_result =
+			match self_state.journal {
+
+			Some (ref mut journal) =>
+				write! (
+					journal,
+					"{} {}\n",
+					if live { "L" } else { "D" },
+					key.to_filename ()),
+
+			None =>
+				return,
+
+		};
+
+		if journal_result.is_err () {
+
+			self_state.journal = None;
+
+			return;
+
+		}
+
+		self_state.journal_lines += 1;
+
+		let compact_lines =
+			JOURNAL_MIN_COMPACT_LINES + 4 * (
+				self_state.live_filesystem_items.len ()
+				+ self_state.dead_filesystem_items.len ()
+			) as u64;
+
+		if self_state.journal_lines > compact_lines {
+
+			if Self::journal_compact (
+				& self.data,
+				self_state,
+			).is_err () {
+				self_state.journal = None;
+			}
+
+		} else if self_state.journal_lines.is_multiple_of (JOURNAL_FLUSH_LINES)
+			&& self_state.journal.as_mut ().unwrap ().flush ().is_err () {
+
+			self_state.journal = None;
+
+		}
 
 	}
 
@@ -229,13 +794,11 @@ impl <Key: ChunkCacheKey> ChunkCache <Key> {
 	) -> Result <(), String> {
 
 		let entry_filename =
-			rand::thread_rng ()
-				.gen_ascii_chars ()
-				.take (16)
-				.collect ();
+			key.to_filename ();
 
 		let entry_path =
-			self.data.path.join (
+			item_path (
+				& self.data.path,
 				& entry_filename);
 
 		let mut self_state =
@@ -260,7 +823,7 @@ impl <Key: ChunkCacheKey> ChunkCache <Key> {
 				& key);
 
 		let in_dead_filesystem_cache =
-			self_state.live_filesystem_items.contains_key (
+			self_state.dead_filesystem_items.contains_key (
 				& key);
 
 		if (
@@ -318,31 +881,23 @@ impl <Key: ChunkCacheKey> ChunkCache <Key> {
 
 			if ! in_live_filesystem_cache && ! in_dead_filesystem_cache {
 
-				let mut output =
-					File::create (
+				let entry_file_id =
+					write_item_file (
+						& self.data.file_lock,
 						& entry_path,
+						compressed,
+						uncompressed_data.len (),
+						& stored_data,
 					).unwrap_or_else (
 						|error|
 
 						panic! (
-							"Unable to create {}: {}",
+							"Error writing to {}: {}",
 							entry_path.to_string_lossy (),
-							error.description ())
+							error)
 
 					);
 
-				output.write (
-					& stored_data,
-				).unwrap_or_else (
-					|error|
-
-					panic! (
-						"Error writing to {}: {}",
-						entry_path.to_string_lossy (),
-						error.description ())
-
-				);
-
 				// create and store the filesystem item
 
 				let filesystem_item =
@@ -350,12 +905,18 @@ impl <Key: ChunkCacheKey> ChunkCache <Key> {
 
 					storage_manager: self.data.clone (),
 					filename: entry_filename,
+					file_id: entry_file_id,
 					compressed: compressed,
 					stored_size: stored_data.len (),
 					uncompressed_size: uncompressed_data.len (),
 
 				};
 
+				self.journal_record (
+					self_state.deref_mut (),
+					& key,
+					live);
+
 				if live {
 
 					if in_dead_filesystem_cache {
@@ -408,18 +969,19 @@ impl <Key: ChunkCacheKey> ChunkCache <Key> {
 
 				// freshen caches
 
-				self_state.compressed_memory_items.get_mut (
+				self_state.compressed_memory_items.touch (
 					& key);
 
 				let in_live =
-					self_state.live_filesystem_items.get_mut (
-						& key,
-					).is_some ();
+					self_state.live_filesystem_items.touch (
+						& key);
 
-				if ! in_live {
+				if ! in_live && self_state.dead_filesystem_items.contains_key (
+					& key,
+				) {
 
 					let filesystem_item =
-						self_state.dead_filesystem_items.get_mut (
+						self_state.dead_filesystem_items.peek (
 							& key,
 						).unwrap ().clone ();
 
@@ -441,6 +1003,11 @@ impl <Key: ChunkCacheKey> ChunkCache <Key> {
 
 				}
 
+				self.journal_record (
+					self_state.deref_mut (),
+					key,
+					true);
+
 				// update hits
 
 				self_state.uncompressed_memory_hits += 1;
@@ -491,14 +1058,15 @@ impl <Key: ChunkCacheKey> ChunkCache <Key> {
 				// freshen caches
 
 				let in_live =
-					self_state.live_filesystem_items.get_mut (
-						& key,
-					).is_some ();
+					self_state.live_filesystem_items.touch (
+						& key);
 
-				if ! in_live {
+				if ! in_live && self_state.dead_filesystem_items.contains_key (
+					& key,
+				) {
 
 					let filesystem_item =
-						self_state.dead_filesystem_items.get_mut (
+						self_state.dead_filesystem_items.peek (
 							& key,
 						).unwrap ().clone ();
 
@@ -520,6 +1088,11 @@ impl <Key: ChunkCacheKey> ChunkCache <Key> {
 
 				}
 
+				self.journal_record (
+					self_state,
+					key,
+					true);
+
 				// decompress and return future
 
 				let self_clone =
@@ -580,14 +1153,15 @@ impl <Key: ChunkCacheKey> ChunkCache <Key> {
 				// freshen caches
 
 				let in_live =
-					self_state.live_filesystem_items.get_mut (
-						& key,
-					).is_some ();
+					self_state.live_filesystem_items.touch (
+						& key);
 
-				if ! in_live {
+				if ! in_live && self_state.dead_filesystem_items.contains_key (
+					& key,
+				) {
 
 					let filesystem_item =
-						self_state.dead_filesystem_items.get_mut (
+						self_state.dead_filesystem_items.peek (
 							& key,
 						).unwrap ().clone ();
 
@@ -609,6 +1183,11 @@ impl <Key: ChunkCacheKey> ChunkCache <Key> {
 
 				}
 
+				self.journal_record (
+					self_state,
+					key,
+					true);
+
 				// update hits
 
 				self_state.compressed_memory_hits += 1;
@@ -636,30 +1215,35 @@ impl <Key: ChunkCacheKey> ChunkCache <Key> {
 		key: & Key,
 	) -> Option <BoxFuture <Arc <Vec <u8>>, String>> {
 
-		let live_filesystem_item: Option <Arc <FilesystemItem>> =
-			self_state.live_filesystem_items.get_mut (
-				key,
-			).map (|filesystem_item|
-				filesystem_item.clone ()
-			);
+		// count a single hit or miss, against whichever tier has the item
 
 		let is_live =
-			live_filesystem_item.is_some ();
+			self_state.live_filesystem_items.contains_key (
+				key);
+
+		let filesystem_item: Option <Arc <FilesystemItem>> =
+			if is_live || ! self_state.dead_filesystem_items.contains_key (
+				key,
+			) {
+
+				self_state.live_filesystem_items.get_mut (
+					key,
+				).map (|filesystem_item|
+					filesystem_item.clone ()
+				)
 
-		let dead_filesystem_item =
-			if is_live {
-				None
 			} else {
+
 				self_state.dead_filesystem_items.get_mut (
 					key,
 				).map (|filesystem_item|
 					filesystem_item.clone ()
 				)
+
 			};
 
 		if let Some (filesystem_item) =
-			live_filesystem_item.or (
-				dead_filesystem_item) {
+			filesystem_item {
 
 			// move to live
 
@@ -674,6 +1258,11 @@ impl <Key: ChunkCacheKey> ChunkCache <Key> {
 
 			}
 
+			self.journal_record (
+				self_state,
+				key,
+				true);
+
 			// load and return
 
 			let key =
@@ -685,20 +1274,41 @@ impl <Key: ChunkCacheKey> ChunkCache <Key> {
 			Some (self.cpu_pool.spawn_fn (
 				move || {
 
-				let (uncompressed_data, compressed_data) =
-					filesystem_item.get () ?;
-
-				// try and insert in memory cache
+				let get_result =
+					filesystem_item.get ();
 
 				let mut self_state =
 					self_clone.state.lock ().unwrap ();
 
-				if compressed_data.is_some () {
+				// discard corrupt items
+
+				let (uncompressed_data, compressed_data) =
+					match get_result {
+
+					Ok (value) =>
+						value,
+
+					Err (error) => {
+
+						self_clone.discard_filesystem_item (
+							self_state.deref_mut (),
+							& key,
+							& filesystem_item);
+
+						return Err (error);
+
+					},
+
+				};
+
+				// try and insert in memory cache
+
+				if let Some (compressed_data) = compressed_data {
 
 					self_state.compressed_memory_items.insert (
 						key.clone (),
 						MemoryCacheItem::Compressed (
-							compressed_data.unwrap (),
+							compressed_data,
 							uncompressed_data.len ()));
 
 					self_state.uncompressed_memory_items.insert (
@@ -738,6 +1348,34 @@ impl <Key: ChunkCacheKey> ChunkCache <Key> {
 
 	}
 
+	/// Writes out the final LRU order of the filesystem cache, so that it can
+	/// be reused on the next startup, and stops cached files from being removed
+	/// when the cache is dropped. This is normally done when the cache is
+	/// dropped, but can be called explicitly when other references to the
+	/// cache may still exist.
+
+	pub fn close (
+		& self,
+	) {
+
+		let mut self_state =
+			self.state.lock ().unwrap ();
+
+		self.data.shutdown.store (
+			true,
+			Ordering::SeqCst);
+
+		if self.data.persistent {
+
+			Self::journal_compact (
+				& self.data,
+				self_state.deref_mut (),
+			).unwrap_or (());
+
+		}
+
+	}
+
 	pub fn status (
 		& self,
 	) -> ChunkCacheStatus {
@@ -759,6 +1397,18 @@ impl <Key: ChunkCacheKey> ChunkCache <Key> {
 			dead_filesystem_items:
 				self_state.dead_filesystem_items.len () as u64,
 
+			uncompressed_memory_bytes:
+				self_state.uncompressed_memory_items.bytes (),
+
+			compressed_memory_bytes:
+				self_state.compressed_memory_items.bytes (),
+
+			live_filesystem_bytes:
+				self_state.live_filesystem_items.bytes (),
+
+			dead_filesystem_bytes:
+				self_state.dead_filesystem_items.bytes (),
+
 			uncompressed_memory_hits:
 				self_state.uncompressed_memory_hits,
 
@@ -774,12 +1424,165 @@ impl <Key: ChunkCacheKey> ChunkCache <Key> {
 			misses:
 				self_state.misses,
 
+			filesystem_persistent:
+				self.data.persistent,
+
+			adopted_filesystem_items:
+				self_state.adopted_filesystem_items,
+
+			cache_policy:
+				self_state.cache_policy,
+
+			uncompressed_memory_stats:
+				self_state.uncompressed_memory_items.stats (),
+
+			compressed_memory_stats:
+				self_state.compressed_memory_items.stats (),
+
+			live_filesystem_stats:
+				self_state.live_filesystem_items.stats (),
+
+			dead_filesystem_stats:
+				self_state.dead_filesystem_items.stats (),
+
+		}
+
+	}
+
+	fn discard_filesystem_item (
+		& self,
+		self_state: & mut ChunkCacheState <Key>,
+		key: & Key,
+		filesystem_item: & Arc <FilesystemItem>,
+	) {
+
+		let in_live =
+			self_state.live_filesystem_items.peek (
+				key,
+			).map (
+				|live_item| Arc::ptr_eq (live_item, filesystem_item)
+			).unwrap_or (false);
+
+		if in_live {
+
+			self_state.live_filesystem_items.remove (
+				key);
+
+		}
+
+		let in_dead =
+			self_state.dead_filesystem_items.peek (
+				key,
+			).map (
+				|dead_item| Arc::ptr_eq (dead_item, filesystem_item)
+			).unwrap_or (false);
+
+		if in_dead {
+
+			self_state.dead_filesystem_items.remove (
+				key);
+
 		}
 
 	}
 
 }
 
+impl CacheTierItem for Arc <Vec <u8>> {
+
+	fn cache_size (
+		& self,
+	) -> u64 {
+		self.len () as u64
+	}
+
+}
+
+impl CacheTierItem for MemoryCacheItem {
+
+	fn cache_size (
+		& self,
+	) -> u64 {
+
+		match * self {
+
+			MemoryCacheItem::Compressed (ref compressed_data, _) =>
+				compressed_data.len () as u64,
+
+			MemoryCacheItem::Uncompressed (ref uncompressed_data) =>
+				uncompressed_data.len () as u64,
+
+		}
+
+	}
+
+}
+
+impl CacheTierItem for Arc <FilesystemItem> {
+
+	fn cache_size (
+		& self,
+	) -> u64 {
+		(ITEM_HEADER_SIZE + self.stored_size) as u64
+	}
+
+}
+
+impl <Key: ChunkCacheKey> Drop for ChunkCacheState <Key> {
+
+	fn drop (
+		& mut self,
+	) {
+
+		// leave the files in place, and write out the final LRU order
+
+		if let Some (storage_manager) =
+			self.live_filesystem_items.iter ().chain (
+				self.dead_filesystem_items.iter (),
+			).map (
+				|(_, filesystem_item)|
+				filesystem_item.storage_manager.clone ()
+			).next () {
+
+			storage_manager.shutdown.store (
+				true,
+				Ordering::SeqCst);
+
+			if storage_manager.persistent {
+
+				ChunkCache::<Key>::journal_compact (
+					& storage_manager,
+					self,
+				).unwrap_or (());
+
+			}
+
+		}
+
+	}
+
+}
+
+impl Drop for ChunkCacheData {
+
+	fn drop (
+		& mut self,
+	) {
+
+		if ! self.persistent {
+
+			fs::remove_dir_all (
+				& self.path,
+			).unwrap_or (());
+
+		}
+
+		self.lock_file.take ();
+
+	}
+
+}
+
 impl FilesystemItem {
 
 	#[ inline ]
@@ -787,7 +1590,8 @@ impl FilesystemItem {
 		& self,
 	) -> PathBuf {
 
-		self.storage_manager.path.join (
+		item_path (
+			& self.storage_manager.path,
 			& self.filename)
 
 	}
@@ -805,26 +1609,65 @@ impl FilesystemItem {
 				format! (
 					"Error loading storage item {}: {}",
 					self.filename,
-					error.description ())
+					error)
 
 			) ?;
 
-		let mut stored_data =
+		let mut item_data =
 			Vec::with_capacity (
-				self.stored_size);
+				ITEM_HEADER_SIZE + self.stored_size);
 
 		file.read_to_end (
-			& mut stored_data,
+			& mut item_data,
 		).map_err (
 			|error|
 
 			format! (
 				"Error loading storage item {}: {}",
 				self.filename,
-				error.description ())
+				error)
 
 		) ?;
 
+		let header =
+			parse_item_header (
+				& item_data,
+			).map_err (
+				|error|
+
+				format! (
+					"Error loading storage item {}: {}",
+					self.filename,
+					error)
+
+			) ?;
+
+		if item_data.len () != ITEM_HEADER_SIZE + header.stored_size
+			|| header.stored_size != self.stored_size
+			|| header.uncompressed_size != self.uncompressed_size
+			|| header.compressed != self.compressed {
+
+			return Err (
+				format! (
+					"Error loading storage item {}: Truncated or changed",
+					self.filename));
+
+		}
+
+		let stored_data =
+			item_data.split_off (
+				ITEM_HEADER_SIZE);
+
+		if RollingAdler32::from_buffer (& stored_data).hash ()
+			!= header.checksum {
+
+			return Err (
+				format! (
+					"Error loading storage item {}: Checksum mismatch",
+					self.filename));
+
+		}
+
 		if self.compressed {
 
 			let uncompressed_data =
@@ -876,17 +1719,328 @@ impl Drop for FilesystemItem {
 		& mut self,
 	) {
 
-		fs::remove_file (
-			self.path (),
-		).unwrap_or_else (
+		// persistent items are kept when the cache itself is dropped
+
+		if self.storage_manager.persistent
+			&& self.storage_manager.shutdown.load (Ordering::SeqCst) {
+
+			return;
+
+		}
+
+		// the file may already have been removed as corrupt, or replaced by a
+		// new item for the same chunk, which must be left alone
+
+		let _file_lock =
+			self.storage_manager.file_lock.lock ().unwrap ();
+
+		let item_path =
+			self.path ();
+
+		let is_this_item =
+			fs::symlink_metadata (
+				& item_path,
+			).map (
+				|metadata| item_file_id (& metadata) == self.file_id
+			).unwrap_or (false);
+
+		if is_this_item {
+
+			fs::remove_file (
+				& item_path,
+			).unwrap_or (());
+
+		}
+
+	}
+
+}
+
+fn item_path (
+	cache_path: & Path,
+	filename: & str,
+) -> PathBuf {
+
+	cache_path
+		.join (& filename [0 .. 2])
+		.join (filename)
+
+}
+
+/// Identifies one copy of an item file, by its inode number and modification
+/// time, which is enough to tell it apart from a later copy with the same name,
+/// even if the inode number is reused.
+
+type ItemFileId = (u64, i64, i64);
+
+fn item_file_id (
+	metadata: & fs::Metadata,
+) -> ItemFileId {
+
+	(
+		metadata.ino (),
+		metadata.mtime (),
+		metadata.mtime_nsec (),
+	)
+
+}
+
+/// Writes an item file, via a temporary file, and returns the id of this
+/// particular copy of the item.
+
+fn write_item_file (
+	file_lock: & Mutex <()>,
+	item_path: & Path,
+	compressed: bool,
+	uncompressed_size: usize,
+	stored_data: & [u8],
+) -> io::Result <ItemFileId> {
+
+	let item_temp_path =
+		item_path.with_extension ("tmp");
+
+	fs::create_dir_all (
+		item_path.parent ().unwrap (),
+	) ?;
+
+	let mut header: [u8; ITEM_HEADER_SIZE] =
+		[0u8; ITEM_HEADER_SIZE];
+
+	header [0 .. 8].copy_from_slice (
+		ITEM_MAGIC);
+
+	header [8] =
+		if compressed { 1 } else { 0 };
+
+	LittleEndian::write_u64 (
+		& mut header [9 .. 17],
+		uncompressed_size as u64);
+
+	LittleEndian::write_u64 (
+		& mut header [17 .. 25],
+		stored_data.len () as u64);
+
+	LittleEndian::write_u32 (
+		& mut header [25 .. 29],
+		RollingAdler32::from_buffer (stored_data).hash ());
+
+	{
+
+		let mut item_file =
+			File::create (
+				& item_temp_path,
+			) ?;
+
+		item_file.write_all (
+			& header,
+		) ?;
+
+		item_file.write_all (
+			stored_data,
+		) ?;
+
+		item_file.sync_data () ?;
+
+	}
+
+	let file_id =
+		item_file_id (
+			& fs::symlink_metadata (
+				& item_temp_path,
+			) ?);
+
+	let _file_lock =
+		file_lock.lock ().unwrap ();
+
+	fs::rename (
+		& item_temp_path,
+		item_path,
+	) ?;
+
+	Ok (file_id)
+
+}
+
+/// Reads an item file's header, and checks the size and checksum of the data
+/// which follows it.
+
+fn read_item_header (
+	item_path: & Path,
+) -> Result <FilesystemItemHeader, String> {
+
+	let mut item_file =
+		File::open (
+			item_path,
+		).map_err (
 			|error|
+			error.to_string ()
+		) ?;
 
-			panic! (
-				"Error removing storage item {}: {}",
-				self.filename,
-				error.description ())
+	let item_size =
+		item_file.metadata ().map_err (
+			|error|
+			error.to_string ()
+		) ?.len ();
+
+	let mut header_data: [u8; ITEM_HEADER_SIZE] =
+		[0u8; ITEM_HEADER_SIZE];
+
+	item_file.read_exact (
+		& mut header_data,
+	).map_err (
+		|_|
+		"Truncated header".to_string ()
+	) ?;
+
+	let header =
+		parse_item_header (
+			& header_data,
+		) ?;
+
+	if item_size != (ITEM_HEADER_SIZE + header.stored_size) as u64 {
+
+		return Err (
+			"Truncated data".to_string ());
+
+	}
+
+	let mut checksum =
+		RollingAdler32::new ();
+
+	let mut buffer: [u8; 0x10000] =
+		[0u8; 0x10000];
+
+	loop {
+
+		let bytes_read =
+			item_file.read (
+				& mut buffer,
+			).map_err (
+				|error|
+				error.to_string ()
+			) ?;
+
+		if bytes_read == 0 {
+			break;
+		}
+
+		checksum.update_buffer (
+			& buffer [0 .. bytes_read]);
+
+	}
+
+	if checksum.hash () != header.checksum {
+
+		return Err (
+			"Checksum mismatch".to_string ());
+
+	}
+
+	Ok (header)
+
+}
+
+fn parse_item_header (
+	item_data: & [u8],
+) -> Result <FilesystemItemHeader, String> {
+
+	if item_data.len () < ITEM_HEADER_SIZE
+		|| & item_data [0 .. 8] != ITEM_MAGIC
+		|| item_data [8] > 1 {
+
+		return Err (
+			"Invalid header".to_string ());
+
+	}
+
+	Ok (FilesystemItemHeader {
+
+		compressed:
+			item_data [8] == 1,
+
+		uncompressed_size:
+			LittleEndian::read_u64 (
+				& item_data [9 .. 17]) as usize,
+
+		stored_size:
+			LittleEndian::read_u64 (
+				& item_data [17 .. 25]) as usize,
+
+		checksum:
+			LittleEndian::read_u32 (
+				& item_data [25 .. 29]),
+
+	})
+
+}
+
+#[ cfg (test) ]
+mod tests {
+
+	use std::env;
+	use std::process;
+
+	use super::*;
+
+	#[ test ]
+	fn item_file_checksum () {
+
+		let cache_path =
+			env::temp_dir ().join (
+				format! (
+					"rzbackup-chunk-cache-{}",
+					process::id ()));
+
+		let item_path =
+			item_path (
+				& cache_path,
+				"0123456789");
+
+		let file_lock =
+			Mutex::new (());
+
+		write_item_file (
+			& file_lock,
+			& item_path,
+			false,
+			11,
+			b"hello world",
+		).unwrap ();
+
+		assert! (! item_path.with_extension ("tmp").exists ());
+
+		let header =
+			read_item_header (
+				& item_path,
+			).unwrap ();
+
+		assert! (! header.compressed);
+		assert_eq! (header.uncompressed_size, 11);
+		assert_eq! (header.stored_size, 11);
+
+		// damage the data, but not the header
+
+		let mut item_data =
+			fs::read (& item_path).unwrap ();
+
+		let item_data_len = item_data.len ();
+		item_data [item_data_len - 1] ^= 0xff;
+
+		fs::write (& item_path, & item_data).unwrap ();
+
+		assert_eq! (
+			read_item_header (& item_path).err (),
+			Some ("Checksum mismatch".to_string ()));
+
+		// and truncate it
+
+		fs::write (& item_path, & item_data [0 .. item_data_len - 1]).unwrap ();
+
+		assert_eq! (
+			read_item_header (& item_path).err (),
+			Some ("Truncated data".to_string ()));
 
-		);
+		fs::remove_dir_all (& cache_path).unwrap ();
 
 	}
 
@@ -25,9 +25,9 @@ type DecryptorType =
 /// This provides a reader for an encrypted ZBackup file. It is used internally,
 /// but also made available publicly, since it may be useful in some cases.
 
-pub struct CryptoReader {
+pub struct CryptoReader <Source: Read> {
 
-	input: File,
+	input: Source,
 
 	decryptor: DecryptorType,
 
@@ -43,13 +43,13 @@ pub struct CryptoReader {
 
 }
 
-impl CryptoReader {
+impl CryptoReader <File> {
 
 	#[ inline ]
 	pub fn open <PathRef: AsRef <Path>> (
 		path: PathRef,
 		encryption_key: [u8; KEY_SIZE],
-	) -> io::Result <CryptoReader> {
+	) -> io::Result <CryptoReader <File>> {
 
 		Self::open_impl (
 			path.as_ref (),
@@ -61,7 +61,7 @@ impl CryptoReader {
 	pub fn open_impl (
 		path: & Path,
 		encryption_key: [u8; KEY_SIZE],
-	) -> io::Result <CryptoReader> {
+	) -> io::Result <CryptoReader <File>> {
 
 		// open file
 
@@ -70,6 +70,22 @@ impl CryptoReader {
 				path,
 			) ?;
 
+		Ok (
+			Self::wrap (
+				file,
+				encryption_key))
+
+	}
+
+}
+
+impl <Source: Read> CryptoReader <Source> {
+
+	pub fn wrap (
+		input: Source,
+		encryption_key: [u8; KEY_SIZE],
+	) -> CryptoReader <Source> {
+
 		// setup decryptor
 
 		let decryptor =
@@ -81,9 +97,9 @@ impl CryptoReader {
 
 		// return
 
-		Ok (CryptoReader {
+		CryptoReader {
 
-			input: file,
+			input: input,
 
 			decryptor: decryptor,
 
@@ -97,7 +113,7 @@ impl CryptoReader {
 			plaintext_end: 0,
 			plaintext_eof: false,
 
-		})
+		}
 
 	}
 
@@ -138,7 +154,7 @@ impl CryptoReader {
 
 			let mut read_buffer =
 				RefReadBuffer::new (
-					& mut self.ciphertext_buffer [
+					& self.ciphertext_buffer [
 						self.ciphertext_start
 					..
 						self.ciphertext_end
@@ -195,7 +211,7 @@ impl CryptoReader {
 
 }
 
-impl Read for CryptoReader {
+impl <Source: Read> Read for CryptoReader <Source> {
 
 	fn read (
 		& mut self,
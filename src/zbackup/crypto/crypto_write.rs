@@ -112,7 +112,7 @@ impl <Target: Write> CryptoWriter <Target> {
 
 			// write out encrypted data
 
-			self.target.write (
+			self.target.write_all (
 				& self.ciphertext_buffer [
 					0 .. bytes_out],
 			) ?;
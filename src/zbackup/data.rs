@@ -82,11 +82,6 @@ impl BundleId {
 		self.bytes.to_vec ()
 	}
 
-	#[ inline ]
-	pub fn to_string (& self) -> String {
-		self.bytes.to_hex ()
-	}
-
 }
 
 impl fmt::Display for BundleId {
@@ -176,11 +171,6 @@ impl ChunkId {
 		self.bytes.to_vec ()
 	}
 
-	#[ inline ]
-	pub fn to_string (& self) -> String {
-		self.bytes.to_hex ()
-	}
-
 }
 
 impl From <[u8; 24]> for ChunkId {
@@ -228,6 +218,19 @@ impl IndexId {
 		}
 	}
 
+	#[ inline ]
+	pub fn from_slice (
+		bytes: & [u8],
+	) -> Result <IndexId, String> {
+
+		Ok (IndexId {
+			bytes: to_array_24 (
+				bytes.iter ().map (|& byte| byte),
+			) ?,
+		})
+
+	}
+
 	#[ inline ]
 	pub fn parse <
 		IndexIdString: AsRef <str>,
@@ -245,13 +248,13 @@ impl IndexId {
 	}
 
 	#[ inline ]
-	pub fn into_vec (self) -> Vec <u8> {
-		self.bytes.to_vec ()
+	pub fn bytes (& self) -> & [u8] {
+		& self.bytes
 	}
 
 	#[ inline ]
-	pub fn to_string (& self) -> String {
-		self.bytes.to_hex ()
+	pub fn into_vec (self) -> Vec <u8> {
+		self.bytes.to_vec ()
 	}
 
 }
@@ -352,7 +355,7 @@ pub fn parse_array_24 (
 					name,
 					string)
 
-			) ?.into_iter (),
+			) ?,
 		) ?)
 
 	}
@@ -1,7 +1,6 @@
 use std::io;
 use std::io::BufRead;
 use std::io::Read;
-use std::iter;
 
 use adler32::RollingAdler32;
 
@@ -177,9 +176,7 @@ impl <Source: BufRead> BufRead for AdlerRead <Source> {
 	) {
 
 		let mut buffer: Vec <u8> =
-			iter::repeat (0u8)
-				.take (amount)
-				.collect ();
+			vec! [0u8; amount];
 
 		self.source.read_exact (
 			& mut buffer,
@@ -39,6 +39,10 @@ impl DiskBackupInfo {
 		self.raw.get_iterations ()
 	}
 
+	pub fn size (& self) -> u64 {
+		self.raw.get_size ()
+	}
+
 }
 
 #[ inline ]
@@ -1,4 +1,4 @@
-use std::error::Error;
+use std::io::BufRead;
 use std::io::Read;
 use std::io::Write;
 use std::path::Path;
@@ -237,11 +237,31 @@ fn bundle_info_read_path_impl (
 	key: Option <EncryptionKey>,
 ) -> Result <DiskBundleInfo, String> {
 
-	let bundle_info: DiskBundleInfo;
+	bundle_info_read_path_real (
+		bundle_path,
+		key,
+	).or_else (
+		|error|
+		parity_read_fallback (
+			bundle_path,
+			error,
+			|repaired_bytes|
+			bundle_info_read_bytes (
+				bundle_path,
+				repaired_bytes,
+				key))
+	)
+
+}
+
+fn bundle_info_read_path_real (
+	bundle_path: & Path,
+	key: Option <EncryptionKey>,
+) -> Result <DiskBundleInfo, String> {
 
 	// open file
 
-	let mut source =
+	let source =
 		io_result_with_prefix (
 			|| format! (
 				"Error opening {}: ",
@@ -251,6 +271,44 @@ fn bundle_info_read_path_impl (
 				key),
 		) ?;
 
+	bundle_info_read_source (
+		bundle_path,
+		source)
+
+}
+
+/// Reads a bundle which is already in memory, such as one repaired from its
+/// parity file. The path is only used in error messages.
+
+fn bundle_info_read_bytes (
+	bundle_path: & Path,
+	bundle_bytes: & [u8],
+	key: Option <EncryptionKey>,
+) -> Result <DiskBundleInfo, String> {
+
+	let source =
+		io_result_with_prefix (
+			|| format! (
+				"Error reading {}: ",
+				bundle_path.to_string_lossy ()),
+			reader_wrap_with_crypto_and_adler (
+				Box::new (bundle_bytes),
+				key),
+		) ?;
+
+	bundle_info_read_source (
+		bundle_path,
+		source)
+
+}
+
+fn bundle_info_read_source <'a> (
+	bundle_path: & Path,
+	mut source: AdlerRead <Box <BufRead + 'a>>,
+) -> Result <DiskBundleInfo, String> {
+
+	let bundle_info: DiskBundleInfo;
+
 	{
 
 		let mut coded_input_stream =
@@ -327,12 +385,31 @@ pub fn bundle_read_path_impl (
 	key: Option <[u8; KEY_SIZE]>,
 ) -> Result <Vec <(ChunkId, Vec <u8>)>, String> {
 
-	let bundle_info: DiskBundleInfo;
-	let mut chunks: Vec <(ChunkId, Vec <u8>)>;
+	bundle_read_path_real (
+		bundle_path,
+		key,
+	).or_else (
+		|error|
+		parity_read_fallback (
+			bundle_path,
+			error,
+			|repaired_bytes|
+			bundle_read_bytes (
+				bundle_path,
+				repaired_bytes,
+				key))
+	)
+
+}
+
+fn bundle_read_path_real (
+	bundle_path: & Path,
+	key: Option <[u8; KEY_SIZE]>,
+) -> Result <Vec <(ChunkId, Vec <u8>)>, String> {
 
 	// open file
 
-	let mut source =
+	let source =
 		io_result_with_prefix (
 			|| format! (
 				"Error opening {}: ",
@@ -342,6 +419,45 @@ pub fn bundle_read_path_impl (
 				key),
 		) ?;
 
+	bundle_read_source (
+		bundle_path,
+		source)
+
+}
+
+/// Reads a bundle which is already in memory, such as one repaired from its
+/// parity file. The path is only used in error messages.
+
+fn bundle_read_bytes (
+	bundle_path: & Path,
+	bundle_bytes: & [u8],
+	key: Option <[u8; KEY_SIZE]>,
+) -> Result <Vec <(ChunkId, Vec <u8>)>, String> {
+
+	let source =
+		io_result_with_prefix (
+			|| format! (
+				"Error reading {}: ",
+				bundle_path.to_string_lossy ()),
+			reader_wrap_with_crypto_and_adler (
+				Box::new (bundle_bytes),
+				key),
+		) ?;
+
+	bundle_read_source (
+		bundle_path,
+		source)
+
+}
+
+fn bundle_read_source <'a> (
+	bundle_path: & Path,
+	mut source: AdlerRead <Box <BufRead + 'a>>,
+) -> Result <Vec <(ChunkId, Vec <u8>)>, String> {
+
+	let bundle_info: DiskBundleInfo;
+	let mut chunks: Vec <(ChunkId, Vec <u8>)>;
+
 	{
 
 		let mut coded_input_stream =
@@ -598,7 +714,7 @@ pub fn bundle_write_direct <
 
 				format! (
 					"Error writing LZMA data: {}",
-					io_error.description ())
+					io_error)
 
 			) ?;
 
@@ -617,7 +733,7 @@ pub fn bundle_write_direct <
 
 			format! (
 				"Error finishing LZMA compression: {}",
-				lzma_error.description ())
+				lzma_error)
 
 		) ?;
 
@@ -34,15 +34,30 @@ fn file_open_with_crypto_and_adler_impl (
 	encryption_key: Option <EncryptionKey>,
 ) -> io::Result <AdlerRead <Box <BufRead>>> {
 
+	let file =
+		File::open (
+			path,
+		) ?;
+
+	reader_wrap_with_crypto_and_adler (
+		Box::new (file),
+		encryption_key)
+
+}
+
+pub fn reader_wrap_with_crypto_and_adler <'a> (
+	source: Box <Read + 'a>,
+	encryption_key: Option <EncryptionKey>,
+) -> io::Result <AdlerRead <Box <BufRead + 'a>>> {
+
 	Ok (match encryption_key {
 
 		Some (encryption_key) => {
 
 			let mut crypto_reader =
-				CryptoReader::open (
-					path,
-					encryption_key,
-				) ?;
+				CryptoReader::wrap (
+					source,
+					encryption_key);
 
 			let mut initialisation_vector =
 				[0u8; IV_SIZE];
@@ -51,7 +66,7 @@ fn file_open_with_crypto_and_adler_impl (
 				& mut initialisation_vector,
 			) ?;
 
-			let crypto_buf_reader: Box <BufRead> =
+			let crypto_buf_reader: Box <BufRead + 'a> =
 				Box::new (
 					BufReader::new (
 						crypto_reader));
@@ -69,21 +84,13 @@ fn file_open_with_crypto_and_adler_impl (
 
 		None => {
 
-			let file =
-				File::open (
-					path,
-				) ?;
-
-			let file_buf_reader: Box <BufRead> =
+			let source_buf_reader: Box <BufRead + 'a> =
 				Box::new (
 					BufReader::new (
-						file));
+						source));
 
-			let adler_read =
-				AdlerRead::new (
-					file_buf_reader);
-
-			adler_read
+			AdlerRead::new (
+				source_buf_reader)
 
 		},
 
@@ -114,7 +121,7 @@ pub fn writer_wrap_with_crypto_and_adler <'a> (
 					.take (IV_SIZE)
 					.collect ();
 
-			crypto_writer.write (
+			crypto_writer.write_all (
 				& initialisation_vector,
 			) ?;
 
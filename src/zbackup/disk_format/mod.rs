@@ -1,3 +1,11 @@
+// generated code, whose own allow attributes predate current lint names
+
+#[ allow (
+	clippy::all,
+	mismatched_lifetime_syntaxes,
+	renamed_and_removed_lints,
+	static_mut_refs,
+) ]
 pub mod protobuf_types;
 
 mod adler_read;
@@ -9,7 +17,9 @@ mod file_format;
 mod header_format;
 mod index_format;
 mod instruction_format;
+mod parity_format;
 mod protobuf_message;
+mod references_format;
 mod storage_info_format;
 
 pub use self::adler_read::AdlerRead;
@@ -20,6 +30,7 @@ pub use self::adler_write::AdlerWrite;
 pub use self::adler_write::AdlerWriter;
 pub use self::adler_write::adler_write_hash;
 
+pub use self::backup_format::DiskBackupInfo;
 pub use self::backup_format::backup_read_path;
 
 pub use self::bundle_format::DiskBundleInfo;
@@ -30,6 +41,7 @@ pub use self::bundle_format::bundle_write_direct;
 pub use self::encryption_key_info::DiskEncryptionKeyInfoRef;
 
 pub use self::file_format::file_open_with_crypto_and_adler;
+pub use self::file_format::reader_wrap_with_crypto_and_adler;
 pub use self::file_format::writer_wrap_with_crypto_and_adler;
 
 pub use self::header_format::DiskFileHeader;
@@ -42,9 +54,20 @@ pub use self::index_format::index_write_with_id;
 
 pub use self::instruction_format::DiskBackupInstruction;
 
+pub use self::parity_format::PARITY_FILE_EXTENSION;
+pub use self::parity_format::parity_create;
+pub use self::parity_format::parity_file_name_matches;
+pub use self::parity_format::parity_path;
+pub use self::parity_format::parity_read_fallback;
+pub use self::parity_format::parity_repair_path;
+
 pub use self::protobuf_message::protobuf_message_read;
 pub use self::protobuf_message::protobuf_message_write;
 
+pub use self::references_format::BackupReferences;
+pub use self::references_format::references_read_path;
+pub use self::references_format::references_write_direct;
+
 pub use self::storage_info_format::DiskStorageInfo;
 pub use self::storage_info_format::storage_info_read;
 
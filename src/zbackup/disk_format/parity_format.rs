@@ -0,0 +1,646 @@
+use std::fs::File;
+use std::io::Read;
+use std::path::Path;
+use std::path::PathBuf;
+
+use byteorder::ByteOrder;
+use byteorder::LittleEndian;
+
+use rust_crypto::digest::Digest;
+use rust_crypto::sha1::Sha1;
+
+use misc::*;
+
+/// Parity files sit next to the bundle they protect, with this extension. They
+/// are not encrypted, since they are calculated from the encrypted bundle, and
+/// tools which don't know about them simply ignore them.
+
+pub const PARITY_FILE_EXTENSION: & 'static str = "par";
+
+const PARITY_FILE_MAGIC: & 'static [u8] = b"RZBPAR01";
+const PARITY_FILE_HEADER_SIZE: usize = 8 + 8 + 4 + 4 + 4;
+const PARITY_BLOCK_HASH_SIZE: usize = 20;
+const PARITY_BLOCK_ALIGNMENT: u64 = 512;
+
+/// Describes how a bundle is split into blocks for parity calculation
+
+struct ParityLayout {
+	data_size: u64,
+	block_size: usize,
+	data_blocks: usize,
+	parity_blocks: usize,
+}
+
+#[ inline ]
+pub fn parity_path <
+	BundlePath: AsRef <Path>,
+> (
+	bundle_path: BundlePath,
+) -> PathBuf {
+
+	bundle_path.as_ref ().with_extension (
+		PARITY_FILE_EXTENSION)
+
+}
+
+/// Parity files live alongside the bundles, so anything scanning the bundles
+/// directories needs to skip them.
+
+#[ inline ]
+pub fn parity_file_name_matches (
+	file_name: & str,
+) -> bool {
+
+	Path::new (file_name).extension ().map (
+		|extension| extension == PARITY_FILE_EXTENSION
+	).unwrap_or (false)
+
+}
+
+/// Calculates the contents of a parity file for the bundle file's contents,
+/// able to correct damage to roughly `redundancy` percent of the bundle.
+
+pub fn parity_create (
+	bundle_bytes: & [u8],
+	redundancy: f64,
+) -> Result <Vec <u8>, String> {
+
+	// choose the block size so the blocks fit in the field
+
+	let max_data_blocks =
+		((REED_SOLOMON_MAX_BLOCKS - 1) as f64 * 100.0
+			/ (100.0 + redundancy)).floor () as u64;
+
+	let data_size =
+		bundle_bytes.len () as u64;
+
+	let min_block_size =
+		data_size.div_ceil (max_data_blocks);
+
+	let block_size =
+		(min_block_size.div_ceil (PARITY_BLOCK_ALIGNMENT)
+			* PARITY_BLOCK_ALIGNMENT)
+			.max (PARITY_BLOCK_ALIGNMENT) as usize;
+
+	let data_blocks =
+		(data_size as usize).div_ceil (block_size).max (1);
+
+	let parity_blocks =
+		((data_blocks as f64 * redundancy / 100.0).ceil () as usize).max (1);
+
+	let layout = ParityLayout {
+		data_size: data_size,
+		block_size: block_size,
+		data_blocks: data_blocks,
+		parity_blocks: parity_blocks,
+	};
+
+	let reed_solomon =
+		ReedSolomon::new (
+			data_blocks,
+			parity_blocks,
+		) ?;
+
+	// calculate parity
+
+	let data_block_list =
+		split_blocks (
+			& layout,
+			bundle_bytes);
+
+	let parity_block_list =
+		reed_solomon.encode (
+			& data_block_list.iter ().map (
+				|data_block| & data_block [..]
+			).collect::<Vec <& [u8]>> ());
+
+	// write header, block hashes, a hash of both, then the parity blocks
+
+	let mut parity_bytes: Vec <u8> =
+		Vec::with_capacity (
+			PARITY_FILE_HEADER_SIZE
+				+ (data_blocks + parity_blocks + 1) * PARITY_BLOCK_HASH_SIZE
+				+ parity_blocks * block_size);
+
+	let mut header: [u8; PARITY_FILE_HEADER_SIZE] =
+		[0u8; PARITY_FILE_HEADER_SIZE];
+
+	header [0 .. 8].copy_from_slice (
+		PARITY_FILE_MAGIC);
+
+	LittleEndian::write_u64 (
+		& mut header [8 .. 16],
+		data_size);
+
+	LittleEndian::write_u32 (
+		& mut header [16 .. 20],
+		block_size as u32);
+
+	LittleEndian::write_u32 (
+		& mut header [20 .. 24],
+		data_blocks as u32);
+
+	LittleEndian::write_u32 (
+		& mut header [24 .. 28],
+		parity_blocks as u32);
+
+	parity_bytes.extend_from_slice (
+		& header);
+
+	for block in data_block_list.iter ().chain (
+		parity_block_list.iter ()) {
+
+		parity_bytes.extend_from_slice (
+			& block_hash (block));
+
+	}
+
+	let metadata_hash =
+		block_hash (
+			& parity_bytes);
+
+	parity_bytes.extend_from_slice (
+		& metadata_hash);
+
+	for parity_block in parity_block_list.iter () {
+
+		parity_bytes.extend_from_slice (
+			parity_block);
+
+	}
+
+	Ok (parity_bytes)
+
+}
+
+/// Uses the bundle's parity file, if it has one, to correct any damaged
+/// blocks. Returns the corrected contents of the bundle file, or `None` if
+/// there is no parity file, or no damage which it can detect.
+
+pub fn parity_repair_path (
+	bundle_path: & Path,
+) -> Result <Option <Vec <u8>>, String> {
+
+	let parity_path =
+		parity_path (
+			bundle_path);
+
+	if ! parity_path.exists () || ! bundle_path.exists () {
+		return Ok (None);
+	}
+
+	let parity_bytes =
+		read_file (
+			& parity_path,
+		) ?;
+
+	let mut bundle_bytes =
+		read_file (
+			bundle_path,
+		) ?;
+
+	// read and verify header
+
+	if parity_bytes.len () < PARITY_FILE_HEADER_SIZE
+		|| & parity_bytes [0 .. 8] != PARITY_FILE_MAGIC {
+
+		return Err (
+			format! (
+				"Error reading {}: Invalid header",
+				parity_path.to_string_lossy ()));
+
+	}
+
+	let layout = ParityLayout {
+		data_size: LittleEndian::read_u64 (& parity_bytes [8 .. 16]),
+		block_size: LittleEndian::read_u32 (& parity_bytes [16 .. 20]) as usize,
+		data_blocks: LittleEndian::read_u32 (& parity_bytes [20 .. 24]) as usize,
+		parity_blocks: LittleEndian::read_u32 (& parity_bytes [24 .. 28]) as usize,
+	};
+
+	let total_blocks =
+		layout.data_blocks + layout.parity_blocks;
+
+	let hashes_end =
+		PARITY_FILE_HEADER_SIZE + total_blocks * PARITY_BLOCK_HASH_SIZE;
+
+	let metadata_end =
+		hashes_end + PARITY_BLOCK_HASH_SIZE;
+
+	if parity_bytes.len ()
+			!= metadata_end + layout.parity_blocks * layout.block_size
+		|| layout.block_size == 0
+		|| (layout.data_blocks as u64) * (layout.block_size as u64)
+			< layout.data_size
+		|| block_hash (& parity_bytes [0 .. hashes_end])
+			!= parity_bytes [hashes_end .. metadata_end] {
+
+		return Err (
+			format! (
+				"Error reading {}: Invalid header",
+				parity_path.to_string_lossy ()));
+
+	}
+
+	let reed_solomon =
+		ReedSolomon::new (
+			layout.data_blocks,
+			layout.parity_blocks,
+		) ?;
+
+	// find damaged blocks, a truncated bundle just shows up as damaged blocks
+
+	bundle_bytes.resize (
+		layout.data_size as usize,
+		0u8);
+
+	let expected_hash = |block_index: usize| {
+
+		let hash_start =
+			PARITY_FILE_HEADER_SIZE + block_index * PARITY_BLOCK_HASH_SIZE;
+
+		& parity_bytes [hash_start .. hash_start + PARITY_BLOCK_HASH_SIZE]
+
+	};
+
+	let mut blocks: Vec <Option <Vec <u8>>> =
+		split_blocks (
+			& layout,
+			& bundle_bytes,
+		).into_iter ().chain (
+			parity_bytes [metadata_end .. ].chunks (
+				layout.block_size,
+			).map (
+				|parity_block| parity_block.to_vec ()
+			)
+		).enumerate ().map (
+			|(block_index, block)|
+
+			if & block_hash (& block) [..] == expected_hash (block_index) {
+				Some (block)
+			} else {
+				None
+			}
+
+		).collect ();
+
+	let damaged_blocks: Vec <usize> =
+		(0 .. layout.data_blocks).filter (
+			|& block_index| blocks [block_index].is_none ()
+		).collect ();
+
+	if damaged_blocks.is_empty () {
+		return Ok (None);
+	}
+
+	// rebuild them, and check the result
+
+	reed_solomon.reconstruct (
+		& mut blocks,
+	).map_err (
+		|error|
+		format! (
+			"Error repairing {}: {}",
+			bundle_path.to_string_lossy (),
+			error)
+	) ?;
+
+	for & block_index in damaged_blocks.iter () {
+
+		let block =
+			blocks [block_index].as_ref ().unwrap ();
+
+		if & block_hash (block) [..] != expected_hash (block_index) {
+
+			return Err (
+				format! (
+					"Error repairing {}: Block {} is still damaged",
+					bundle_path.to_string_lossy (),
+					block_index));
+
+		}
+
+	}
+
+	let mut repaired_bytes: Vec <u8> =
+		Vec::with_capacity (
+			layout.data_blocks * layout.block_size);
+
+	for block in blocks.into_iter ().take (layout.data_blocks) {
+
+		repaired_bytes.extend_from_slice (
+			& block.unwrap ());
+
+	}
+
+	repaired_bytes.truncate (
+		layout.data_size as usize);
+
+	Ok (Some (repaired_bytes))
+
+}
+
+/// Called when reading a bundle has failed. If the bundle has a parity file
+/// which can repair it, the repaired bundle is read from memory using
+/// `read_function`. The bundle itself is not changed. Otherwise, the original
+/// error is returned.
+
+pub fn parity_read_fallback <
+	Type,
+	ReadFunction: FnOnce (& [u8]) -> Result <Type, String>,
+> (
+	bundle_path: & Path,
+	error: String,
+	read_function: ReadFunction,
+) -> Result <Type, String> {
+
+	let repaired_bytes =
+		match parity_repair_path (
+			bundle_path,
+		) {
+
+		Ok (Some (repaired_bytes)) =>
+			repaired_bytes,
+
+		Ok (None) =>
+			return Err (error),
+
+		Err (parity_error) =>
+			return Err (
+				format! (
+					"{}, and could not repair from parity: {}",
+					error,
+					parity_error)),
+
+	};
+
+	read_function (
+		& repaired_bytes,
+	).map_err (
+		|repaired_error|
+		format! (
+			"{}, and still failed after repair from parity: {}",
+			error,
+			repaired_error))
+
+}
+
+fn split_blocks (
+	layout: & ParityLayout,
+	bytes: & [u8],
+) -> Vec <Vec <u8>> {
+
+	(0 .. layout.data_blocks).map (
+		|block_index| {
+
+		let block_start =
+			(block_index * layout.block_size).min (bytes.len ());
+
+		let block_end =
+			(block_start + layout.block_size).min (bytes.len ());
+
+		let mut block =
+			bytes [block_start .. block_end].to_vec ();
+
+		block.resize (
+			layout.block_size,
+			0u8);
+
+		block
+
+	}).collect ()
+
+}
+
+fn block_hash (
+	bytes: & [u8],
+) -> [u8; PARITY_BLOCK_HASH_SIZE] {
+
+	let mut sha1_digest =
+		Sha1::new ();
+
+	sha1_digest.input (
+		bytes);
+
+	let mut hash: [u8; PARITY_BLOCK_HASH_SIZE] =
+		[0u8; PARITY_BLOCK_HASH_SIZE];
+
+	sha1_digest.result (
+		& mut hash);
+
+	hash
+
+}
+
+fn read_file (
+	path: & Path,
+) -> Result <Vec <u8>, String> {
+
+	let mut file =
+		io_result_with_prefix (
+			|| format! (
+				"Error opening {}: ",
+				path.to_string_lossy ()),
+			File::open (
+				path),
+		) ?;
+
+	let mut bytes: Vec <u8> =
+		Vec::new ();
+
+	io_result_with_prefix (
+		|| format! (
+			"Error reading {}: ",
+			path.to_string_lossy ()),
+		file.read_to_end (
+			& mut bytes),
+	) ?;
+
+	Ok (bytes)
+
+}
+
+#[ cfg (test) ]
+mod tests {
+
+	use std::env;
+	use std::fs;
+	use std::process;
+
+	use zbackup::data::*;
+	use zbackup::disk_format::*;
+
+	use super::*;
+
+	fn test_chunks (
+	) -> Vec <(ChunkId, Vec <u8>)> {
+
+		(0 .. 20u32).map (
+			|chunk_index| {
+
+			let chunk_data: Vec <u8> =
+				(0 .. 2000u32).map (
+					|byte_index|
+					(chunk_index * 97 + byte_index * byte_index / 7) as u8
+				).collect ();
+
+			let mut sha1_digest =
+				Sha1::new ();
+
+			sha1_digest.input (
+				& chunk_data);
+
+			let mut chunk_id_bytes: [u8; 24] =
+				[0u8; 24];
+
+			sha1_digest.result (
+				& mut chunk_id_bytes [0 .. 20]);
+
+			(
+				ChunkId::from_slice (& chunk_id_bytes).unwrap (),
+				chunk_data,
+			)
+
+		}).collect ()
+
+	}
+
+	fn repair_bundle (
+		name: & str,
+		encryption_key: Option <EncryptionKey>,
+	) {
+
+		let temp_path =
+			env::temp_dir ().join (
+				format! (
+					"rzbackup-parity-{}-{}",
+					name,
+					process::id ()));
+
+		fs::create_dir_all (& temp_path).unwrap ();
+
+		let bundle_path =
+			temp_path.join ("bundle");
+
+		let chunks =
+			test_chunks ();
+
+		let mut bundle_bytes: Vec <u8> =
+			Vec::new ();
+
+		bundle_write_direct (
+			& mut bundle_bytes,
+			encryption_key,
+			& chunks,
+			|_| (),
+		).unwrap ();
+
+		fs::write (
+			parity_path (& bundle_path),
+			parity_create (& bundle_bytes, 5.0).unwrap (),
+		).unwrap ();
+
+		// damage about 2% of the bundle, in one place
+
+		let mut damaged_bytes =
+			bundle_bytes.clone ();
+
+		let damage_start = damaged_bytes.len () / 3;
+		let damage_end = damage_start + damaged_bytes.len () / 50;
+
+		for byte in damaged_bytes [damage_start .. damage_end].iter_mut () {
+			* byte ^= 0x55;
+		}
+
+		fs::write (& bundle_path, & damaged_bytes).unwrap ();
+
+		assert_eq! (
+			parity_repair_path (& bundle_path).unwrap (),
+			Some (bundle_bytes.clone ()));
+
+		let read_chunks =
+			bundle_read_path (
+				& bundle_path,
+				encryption_key,
+			).unwrap ();
+
+		assert! (read_chunks == chunks);
+
+		assert_eq! (
+			bundle_info_read_path (
+				& bundle_path,
+				encryption_key,
+			).unwrap ().num_chunks (),
+			chunks.len () as u64);
+
+		// the bundle itself is left alone
+
+		assert_eq! (fs::read (& bundle_path).unwrap (), damaged_bytes);
+
+		// without the parity file, the damage is reported
+
+		fs::remove_file (parity_path (& bundle_path)).unwrap ();
+
+		assert! (
+			bundle_read_path (
+				& bundle_path,
+				encryption_key,
+			).is_err ());
+
+		fs::remove_dir_all (& temp_path).unwrap ();
+
+	}
+
+	#[ test ]
+	fn repair_unencrypted_bundle () {
+
+		repair_bundle (
+			"unencrypted",
+			None);
+
+	}
+
+	#[ test ]
+	fn repair_encrypted_bundle () {
+
+		repair_bundle (
+			"encrypted",
+			Some ([0x42u8; KEY_SIZE]));
+
+	}
+
+	#[ test ]
+	fn undamaged_bundle_needs_no_repair () {
+
+		let temp_path =
+			env::temp_dir ().join (
+				format! (
+					"rzbackup-parity-undamaged-{}",
+					process::id ()));
+
+		fs::create_dir_all (& temp_path).unwrap ();
+
+		let bundle_path =
+			temp_path.join ("bundle");
+
+		let bundle_bytes: Vec <u8> =
+			(0 .. 10000u32).map (|index| index as u8).collect ();
+
+		fs::write (& bundle_path, & bundle_bytes).unwrap ();
+
+		assert_eq! (parity_repair_path (& bundle_path).unwrap (), None);
+
+		fs::write (
+			parity_path (& bundle_path),
+			parity_create (& bundle_bytes, 5.0).unwrap (),
+		).unwrap ();
+
+		assert_eq! (parity_repair_path (& bundle_path).unwrap (), None);
+
+		fs::remove_dir_all (& temp_path).unwrap ();
+
+	}
+
+}
+
+// ex: noet ts=4 filetype=rust
@@ -0,0 +1,328 @@
+use std::collections::HashMap;
+use std::io::Read;
+use std::io::Write;
+use std::path::Path;
+
+use byteorder::LittleEndian;
+use byteorder::ReadBytesExt;
+use byteorder::WriteBytesExt;
+
+use misc::*;
+use zbackup::data::*;
+use zbackup::disk_format::*;
+
+/// The chunks referenced by each backup, keyed by the SHA-1 checksum of the
+/// backup file. Since a backup file never changes once written, an entry stays
+/// valid for as long as a backup with that checksum exists.
+
+pub type BackupReferences = HashMap <[u8; 20], Vec <ChunkId>>;
+
+const REFERENCES_FILE_MAGIC: & 'static [u8] = b"RZBREFS1";
+
+pub fn references_read_path <
+	ReferencesPath: AsRef <Path>,
+> (
+	references_path: ReferencesPath,
+	key: Option <[u8; KEY_SIZE]>,
+) -> Result <BackupReferences, String> {
+
+	references_read_path_impl (
+		references_path.as_ref (),
+		key,
+	)
+
+}
+
+fn references_read_path_impl (
+	references_path: & Path,
+	key: Option <[u8; KEY_SIZE]>,
+) -> Result <BackupReferences, String> {
+
+	let mut backup_references: BackupReferences =
+		HashMap::new ();
+
+	// open file
+
+	let mut source =
+		io_result_with_prefix (
+			|| format! (
+				"Error opening {}: ",
+				references_path.to_string_lossy ()),
+			file_open_with_crypto_and_adler (
+				references_path,
+				key),
+		) ?;
+
+	// read header
+
+	let mut magic: [u8; 8] =
+		[0u8; 8];
+
+	io_result_with_prefix (
+		|| format! (
+			"Error reading {}: ",
+			references_path.to_string_lossy ()),
+		source.read_exact (
+			& mut magic),
+	) ?;
+
+	if & magic [..] != REFERENCES_FILE_MAGIC {
+
+		return Err (
+			format! (
+				"Error reading {}: Invalid header",
+				references_path.to_string_lossy ()));
+
+	}
+
+	let num_backups =
+		io_result_with_prefix (
+			|| format! (
+				"Error reading {}: ",
+				references_path.to_string_lossy ()),
+			source.read_u64::<LittleEndian> (),
+		) ?;
+
+	// read backups
+
+	for _backup_index in 0 .. num_backups {
+
+		let mut checksum: [u8; 20] =
+			[0u8; 20];
+
+		io_result_with_prefix (
+			|| format! (
+				"Error reading {}: ",
+				references_path.to_string_lossy ()),
+			source.read_exact (
+				& mut checksum),
+		) ?;
+
+		let num_chunks =
+			io_result_with_prefix (
+				|| format! (
+					"Error reading {}: ",
+					references_path.to_string_lossy ()),
+				source.read_u64::<LittleEndian> (),
+			) ?;
+
+		let mut chunk_ids: Vec <ChunkId> =
+			Vec::with_capacity (
+				num_chunks as usize);
+
+		for _chunk_index in 0 .. num_chunks {
+
+			let mut chunk_id_bytes: [u8; 24] =
+				[0u8; 24];
+
+			io_result_with_prefix (
+				|| format! (
+					"Error reading {}: ",
+					references_path.to_string_lossy ()),
+				source.read_exact (
+					& mut chunk_id_bytes),
+			) ?;
+
+			chunk_ids.push (
+				ChunkId::from (
+					chunk_id_bytes));
+
+		}
+
+		backup_references.insert (
+			checksum,
+			chunk_ids);
+
+	}
+
+	// verify checksum
+
+	adler_verify_hash_and_eof (
+		|| format! (
+			"Error reading {}: ",
+			references_path.to_string_lossy ()),
+		source,
+	) ?;
+
+	Ok (backup_references)
+
+}
+
+pub fn references_write_direct (
+	target: & mut Write,
+	key: Option <[u8; KEY_SIZE]>,
+	backup_references: & BackupReferences,
+) -> Result <(), String> {
+
+	let mut target =
+		io_result (
+			writer_wrap_with_crypto_and_adler (
+				target,
+				key),
+		) ?;
+
+	// write header
+
+	io_result (
+		target.write_all (
+			REFERENCES_FILE_MAGIC),
+	) ?;
+
+	io_result (
+		target.write_u64::<LittleEndian> (
+			backup_references.len () as u64),
+	) ?;
+
+	// write backups
+
+	for (checksum, chunk_ids) in backup_references.iter () {
+
+		io_result (
+			target.write_all (
+				checksum),
+		) ?;
+
+		io_result (
+			target.write_u64::<LittleEndian> (
+				chunk_ids.len () as u64),
+		) ?;
+
+		let mut chunk_ids_bytes: Vec <u8> =
+			Vec::with_capacity (
+				chunk_ids.len () * 24);
+
+		for chunk_id in chunk_ids.iter () {
+
+			chunk_ids_bytes.extend_from_slice (
+				chunk_id.bytes ());
+
+		}
+
+		io_result (
+			target.write_all (
+				& chunk_ids_bytes),
+		) ?;
+
+	}
+
+	// write checksum
+
+	io_result (
+		target.flush (),
+	) ?;
+
+	adler_write_hash (
+		|| format! (""),
+		& mut target,
+	) ?;
+
+	// close file
+
+	io_result (
+		target.close ()
+	) ?;
+
+	// return
+
+	Ok (())
+
+}
+
+#[ cfg (test) ]
+mod tests {
+
+	use std::env;
+	use std::fs;
+	use std::process;
+
+	use super::*;
+
+	fn round_trip (
+		name: & str,
+		key: Option <[u8; KEY_SIZE]>,
+	) {
+
+		let references_path =
+			env::temp_dir ().join (
+				format! (
+					"rzbackup-references-{}-{}",
+					name,
+					process::id ()));
+
+		let mut backup_references: BackupReferences =
+			HashMap::new ();
+
+		for backup_index in 0 .. 5u8 {
+
+			backup_references.insert (
+				[backup_index; 20],
+				(0 .. backup_index as usize * 100).map (
+					|chunk_index| {
+
+					let mut chunk_id_bytes: [u8; 24] =
+						[backup_index; 24];
+
+					chunk_id_bytes [0] = chunk_index as u8;
+					chunk_id_bytes [1] = (chunk_index >> 8) as u8;
+
+					ChunkId::from (chunk_id_bytes)
+
+				}).collect ());
+
+		}
+
+		let mut references_bytes: Vec <u8> =
+			Vec::new ();
+
+		references_write_direct (
+			& mut references_bytes,
+			key,
+			& backup_references,
+		).unwrap ();
+
+		fs::write (& references_path, & references_bytes).unwrap ();
+
+		assert! (
+			references_read_path (
+				& references_path,
+				key,
+			).unwrap () == backup_references);
+
+		// truncation is detected
+
+		fs::write (
+			& references_path,
+			& references_bytes [0 .. references_bytes.len () - 10],
+		).unwrap ();
+
+		assert! (
+			references_read_path (
+				& references_path,
+				key,
+			).is_err ());
+
+		fs::remove_file (& references_path).unwrap ();
+
+	}
+
+	#[ test ]
+	fn round_trip_unencrypted () {
+
+		round_trip (
+			"unencrypted",
+			None);
+
+	}
+
+	#[ test ]
+	fn round_trip_encrypted () {
+
+		round_trip (
+			"encrypted",
+			Some ([0x42u8; KEY_SIZE]));
+
+	}
+
+}
+
+// ex: noet ts=4 filetype=rust
@@ -35,7 +35,7 @@ impl DiskStorageInfo {
 	}
 
 	#[ inline ]
-	pub fn encryption_key (& self) -> DiskEncryptionKeyInfoRef {
+	pub fn encryption_key (& self) -> DiskEncryptionKeyInfoRef <'_> {
 		DiskEncryptionKeyInfoRef::new (
 			self.raw.get_encryption_key (),
 		)
@@ -1,8 +1,19 @@
+use std::cmp::Ordering;
 use std::collections::HashMap;
 use std::collections::HashSet;
 use std::fs;
+use std::fs::File;
+use std::io::BufWriter;
+use std::io::Write;
+use std::os::unix::fs::MetadataExt;
+use std::path::Path;
+use std::path::PathBuf;
+use std::slice;
 use std::sync::Arc;
 
+use byteorder::ByteOrder;
+use byteorder::LittleEndian;
+
 use futures;
 use futures::BoxFuture;
 use futures::Future;
@@ -12,6 +23,9 @@ use num_cpus;
 
 use output::Output;
 
+use rust_crypto::digest::Digest;
+use rust_crypto::sha1::Sha1;
+
 use rustc_serialize::hex::ToHex;
 
 use misc::*;
@@ -22,12 +36,66 @@ use zbackup::repository_core::*;
 /// The index cache loads and caches information from index files, namely a set
 /// of mappings from chunk IDs to bundle IDs, along with the size of the chunk's
 /// uncompressed data.
+///
+/// Entries are stored as a sorted table of fixed-size records, each containing
+/// the chunk ID, an ordinal into a separate list of bundle IDs, and the size.
+/// This is searched with a binary search, and uses a fraction of the memory of
+/// a hash map. The table can optionally be persisted to a file, which is then
+/// memory-mapped, and reused until the repository's indexes change.
+///
+/// The table also remembers which bundles each index file referred to, along
+/// with the file's size and modification time. This allows a reload to read
+/// only new or changed index files, and to drop the entries from removed ones.
 
 pub struct IndexCache {
 	repository_core: Arc <RepositoryCore>,
-	entries: Option <HashMap <ChunkId, IndexEntry>>,
+	cache_path: Option <PathBuf>,
+	table: Option <IndexTable>,
+}
+
+struct IndexTable {
+	bundle_ids: Vec <BundleId>,
+	indexes: HashMap <IndexId, IndexState>,
+	records: IndexRecords,
+}
+
+enum IndexRecords {
+	Memory (Vec <u8>),
+	Mapped (MappedFile, usize),
+}
+
+#[ derive (Clone, Copy, Eq, PartialEq) ]
+struct IndexFileMetadata {
+	size: u64,
+	mtime: i64,
+	mtime_nsec: i64,
+}
+
+#[ derive (Clone) ]
+struct IndexState {
+	metadata: IndexFileMetadata,
+	bundle_ordinals: Vec <u32>,
+	missing_bundle_ids: Vec <BundleId>,
+}
+
+struct LoadedIndex {
+	index_id: IndexId,
+	entries: Vec <(ChunkId, IndexEntry)>,
+	bundle_ids: Vec <BundleId>,
+	missing_bundle_ids: Vec <BundleId>,
 }
 
+type IndexRecord = [u8; RECORD_SIZE];
+
+const BUNDLE_ID_SIZE: usize = 24;
+const INDEX_ID_SIZE: usize = 24;
+const RECORD_SIZE: usize = 32;
+const RECORD_CHUNK_ID_SIZE: usize = 24;
+
+const CACHE_FILE_MAGIC: & 'static [u8] = b"RZBIDXC2";
+const CACHE_FILE_HEADER_SIZE: usize = 8 + 20 + 8 + 8 + 8 + 8;
+const CACHE_FILE_INDEX_HEADER_SIZE: usize = 24 + 8 + 8 + 8 + 4 + 4;
+
 #[ derive (Clone, Copy, Debug) ]
 pub struct IndexEntry {
 	bundle_id: BundleId,
@@ -50,7 +118,7 @@ impl IndexEntry {
 
 type IndexLoadFuture =
 	BoxFuture <
-		(IndexId, Vec <(ChunkId, IndexEntry)>),
+		LoadedIndex,
 		(IndexId, String),
 	>;
 
@@ -59,11 +127,13 @@ impl IndexCache {
 	#[ inline ]
 	pub fn new (
 		repository_core: Arc <RepositoryCore>,
+		cache_path: Option <PathBuf>,
 	) -> IndexCache {
 
 		IndexCache {
 			repository_core: repository_core,
-			entries: None,
+			cache_path: cache_path,
+			table: None,
 		}
 
 	}
@@ -74,7 +144,7 @@ impl IndexCache {
 		output: & Output,
 	) -> Result <(), String> {
 
-		if self.entries.is_some () {
+		if self.table.is_some () {
 
 			Ok (())
 
@@ -112,18 +182,218 @@ impl IndexCache {
 				) ?
 			);
 
-		let index_ids =
+		let index_files =
 			self.scan_indexes (
 				output,
 			) ?;
 
-		self.entries = Some (
-			self.load_indexes (
-				output,
-				bundle_ids.clone (),
-				& index_ids,
-			) ?
-		);
+		let cache_path =
+			self.cache_path.clone ();
+
+		let signature =
+			Self::signature (
+				& bundle_ids,
+				& index_files);
+
+		// on first load, try and reuse a persisted cache, which is used as is
+		// if nothing has changed, and otherwise as a base to update
+
+		let persisted_table =
+			match cache_path {
+
+			Some (ref cache_path) if self.table.is_none () =>
+				match Self::read_cache_file (
+					cache_path,
+				) {
+
+					Ok (Some ((persisted_signature, table))) => {
+
+						if persisted_signature == signature {
+
+							output_message! (
+								output,
+								"Loaded index cache from {} with {} chunks",
+								cache_path.to_string_lossy (),
+								table.len ());
+
+							self.table = Some (table);
+
+							return Ok (());
+
+						}
+
+						Some (table)
+
+					},
+
+					Ok (None) =>
+						None,
+
+					Err (error) => {
+
+						output_message! (
+							output,
+							"Ignoring index cache {}: {}",
+							cache_path.to_string_lossy (),
+							error);
+
+						None
+
+					},
+
+				},
+
+			_ =>
+				None,
+
+		};
+
+		let table = {
+
+			let base_table =
+				persisted_table.as_ref ().or (
+					self.table.as_ref ());
+
+			// work out which index files need to be read
+
+			let mut keep_index_ids: Vec <IndexId> =
+				Vec::new ();
+
+			let mut load_index_ids: Vec <IndexId> =
+				Vec::new ();
+
+			for (index_id, index_metadata) in index_files.iter () {
+
+				let unchanged =
+					base_table.and_then (
+						|base_table| base_table.indexes.get (index_id)
+					).map (
+						|index_state|
+
+						index_state.metadata == * index_metadata
+							&& ! index_state.missing_bundle_ids.iter ().any (
+								|bundle_id| bundle_ids.contains (bundle_id))
+
+					).unwrap_or (false);
+
+				if unchanged {
+
+					keep_index_ids.push (
+						* index_id);
+
+				} else {
+
+					load_index_ids.push (
+						* index_id);
+
+				}
+
+			}
+
+			let num_removed_indexes =
+				base_table.map (
+					|base_table|
+					base_table.indexes.keys ().filter (
+						|index_id| ! index_files.contains_key (index_id)
+					).count ()
+				).unwrap_or (0);
+
+			let removed_bundles =
+				base_table.map (
+					|base_table|
+					base_table.bundle_ids.iter ().any (
+						|bundle_id| ! bundle_ids.contains (bundle_id))
+				).unwrap_or (false);
+
+			if persisted_table.is_none ()
+				&& base_table.is_some ()
+				&& load_index_ids.is_empty ()
+				&& num_removed_indexes == 0
+				&& ! removed_bundles {
+
+				output_message! (
+					output,
+					"Indexes unchanged");
+
+				return Ok (());
+
+			}
+
+			// load new indexes, and merge them with the existing table
+
+			let loaded_table =
+				self.load_indexes (
+					output,
+					bundle_ids.clone (),
+					& index_files,
+					& load_index_ids,
+				) ?;
+
+			if let Some (base_table) = base_table {
+
+				output_message! (
+					output,
+					"Updating indexes: {} read, {} removed, {} unchanged",
+					load_index_ids.len (),
+					num_removed_indexes,
+					keep_index_ids.len ());
+
+				Self::merge_tables (
+					base_table,
+					& keep_index_ids,
+					& bundle_ids,
+					loaded_table)
+
+			} else {
+
+				loaded_table
+
+			}
+
+		};
+
+		// write out a new persisted cache
+
+		if let Some (cache_path) = cache_path.as_ref () {
+
+			let write_result =
+				Self::write_cache_file (
+					cache_path,
+					signature,
+					& table,
+				).and_then (
+					|_| Self::read_cache_file (
+						cache_path)
+				);
+
+			match write_result {
+
+				Ok (Some ((_, mapped_table))) => {
+
+					self.table = Some (mapped_table);
+
+					return Ok (());
+
+				},
+
+				Ok (None) =>
+					output_message! (
+						output,
+						"Index cache {} was not written correctly",
+						cache_path.to_string_lossy ()),
+
+				Err (error) =>
+					output_message! (
+						output,
+						"Error writing index cache {}: {}",
+						cache_path.to_string_lossy (),
+						error),
+
+			}
+
+		}
+
+		self.table = Some (table);
 
 		Ok (())
 
@@ -133,8 +403,9 @@ impl IndexCache {
 		& self,
 		output: & Output,
 		bundle_ids: Arc <HashSet <BundleId>>,
-		index_ids: & Vec <IndexId>,
-	) -> Result <HashMap <ChunkId, IndexEntry>, String> {
+		index_files: & HashMap <IndexId, IndexFileMetadata>,
+		index_ids: & [IndexId],
+	) -> Result <IndexTable, String> {
 
 		let output_job =
 			output_job_start! (
@@ -160,9 +431,18 @@ impl IndexCache {
 		let mut index_ids_iter =
 			index_ids.iter ();
 
-		let mut all_entries =
+		let mut bundle_ordinals: HashMap <BundleId, u32> =
+			HashMap::new ();
+
+		let mut table_bundle_ids: Vec <BundleId> =
+			Vec::new ();
+
+		let mut table_indexes: HashMap <IndexId, IndexState> =
 			HashMap::new ();
 
+		let mut record_bytes: Vec <u8> =
+			Vec::new ();
+
 		output.pause ();
 
 		loop {
@@ -207,7 +487,7 @@ impl IndexCache {
 			).wait () {
 
 				Ok ((
-					(_index_id, index_entries),
+					loaded_index,
 					_task_index,
 					remaining_index_futures,
 				)) => {
@@ -215,15 +495,39 @@ impl IndexCache {
 					index_futures =
 						remaining_index_futures;
 
-					for (chunk_id, index_entry) in index_entries {
-
-						all_entries.insert (
-							chunk_id,
-							index_entry,
-						);
+					let index_bundle_ordinals: Vec <u32> =
+						loaded_index.bundle_ids.iter ().map (
+							|bundle_id|
+							intern_bundle_id (
+								& mut table_bundle_ids,
+								& mut bundle_ordinals,
+								* bundle_id)
+						).collect ();
+
+					for (chunk_id, index_entry) in loaded_index.entries {
+
+						let bundle_ordinal =
+							intern_bundle_id (
+								& mut table_bundle_ids,
+								& mut bundle_ordinals,
+								index_entry.bundle_id);
+
+						record_bytes.extend_from_slice (
+							& make_record (
+								chunk_id,
+								bundle_ordinal,
+								index_entry.size as u32));
 
 					}
 
+					table_indexes.insert (
+						loaded_index.index_id,
+						IndexState {
+							metadata: index_files [& loaded_index.index_id],
+							bundle_ordinals: index_bundle_ordinals,
+							missing_bundle_ids: loaded_index.missing_bundle_ids,
+						});
+
 					num_indexes_loaded += 1;
 
 				},
@@ -272,195 +576,776 @@ impl IndexCache {
 
 		}
 
+		// sort records for binary search
+
+		sort_records (
+			& mut record_bytes);
+
 		// return
 
-		Ok (all_entries)
+		Ok (IndexTable {
+			bundle_ids: table_bundle_ids,
+			indexes: table_indexes,
+			records: IndexRecords::Memory (record_bytes),
+		})
 
 	}
 
-	fn load_index_future (
-		repository_core: Arc <RepositoryCore>,
-		output: & Output,
-		cpu_pool: & CpuPool,
-		bundle_ids: Arc <HashSet <BundleId>>,
-		index_id: IndexId,
-	) -> IndexLoadFuture {
+	/// Combines the entries from the unchanged index files in an existing table
+	/// with a table of newly loaded ones. Bundles which are no longer referenced
+	/// are dropped, and the remaining ones are renumbered. Both tables are
+	/// already sorted, so they are merged in a single pass, preferring the
+	/// existing entries where a chunk appears in both.
 
-		let index_path =
-			repository_core.index_path (
-				index_id);
+	fn merge_tables (
+		base_table: & IndexTable,
+		keep_index_ids: & [IndexId],
+		bundle_ids: & HashSet <BundleId>,
+		loaded_table: IndexTable,
+	) -> IndexTable {
 
-		let output_job =
-			output_job_start! (
-				output,
-				"Loading index {}",
-				index_id);
+		let mut table_bundle_ids: Vec <BundleId> =
+			Vec::new ();
 
-		cpu_pool.spawn_fn (move || {
+		let mut bundle_ordinals: HashMap <BundleId, u32> =
+			HashMap::new ();
 
-			let index_data =
-				string_result_with_prefix (
-					|| format! (
-						"Error loading index {}",
-						index_id),
-					index_read_path (
-						index_path,
-						repository_core.encryption_key ()),
-				) ?;
+		let mut table_indexes: HashMap <IndexId, IndexState> =
+			HashMap::new ();
 
-			let mut raw_entries: Vec <(ChunkId, IndexEntry)> =
-				Vec::new ();
+		// renumber bundles from unchanged indexes which still exist
 
-			for RawIndexEntry {
-				index_bundle_header,
-				bundle_info,
-			} in index_data {
+		let mut base_ordinals: Vec <Option <u32>> =
+			vec! [None; base_table.bundle_ids.len ()];
+
+		for index_id in keep_index_ids.iter () {
+
+			let base_state =
+				& base_table.indexes [index_id];
+
+			let mut index_state =
+				IndexState {
+					metadata: base_state.metadata,
+					bundle_ordinals: Vec::new (),
+					missing_bundle_ids: base_state.missing_bundle_ids.clone (),
+				};
+
+			for & base_ordinal in base_state.bundle_ordinals.iter () {
 
 				let bundle_id =
-					index_bundle_header.bundle_id ();
+					match base_table.bundle_ids.get (
+						base_ordinal as usize,
+					) {
+						Some (& bundle_id) => bundle_id,
+						None => continue,
+					};
 
 				if ! bundle_ids.contains (& bundle_id) {
+
+					index_state.missing_bundle_ids.push (
+						bundle_id);
+
 					continue;
+
 				}
 
-				for chunk in bundle_info.chunks () {
+				let bundle_ordinal =
+					intern_bundle_id (
+						& mut table_bundle_ids,
+						& mut bundle_ordinals,
+						bundle_id);
 
-					raw_entries.push ((
-						chunk.chunk_id (),
-						IndexEntry {
-							bundle_id: bundle_id,
-							size: chunk.size () as u64,
-						},
-					));
+				base_ordinals [base_ordinal as usize] =
+					Some (bundle_ordinal);
 
-				}
+				index_state.bundle_ordinals.push (
+					bundle_ordinal);
 
 			}
 
-			Ok ((index_id, raw_entries))
+			table_indexes.insert (
+				* index_id,
+				index_state);
 
-		}).map_err (
-			move |error|
+		}
 
-			(index_id, error)
+		// renumber bundles from newly loaded indexes
+
+		let loaded_ordinals: Vec <u32> =
+			loaded_table.bundle_ids.iter ().map (
+				|bundle_id|
+				intern_bundle_id (
+					& mut table_bundle_ids,
+					& mut bundle_ordinals,
+					* bundle_id)
+			).collect ();
+
+		for (index_id, loaded_state) in loaded_table.indexes.iter () {
+
+			table_indexes.insert (
+				* index_id,
+				IndexState {
+					metadata: loaded_state.metadata,
+					bundle_ordinals: loaded_state.bundle_ordinals.iter ().map (
+						|& loaded_ordinal|
+						loaded_ordinals [loaded_ordinal as usize]
+					).collect (),
+					missing_bundle_ids:
+						loaded_state.missing_bundle_ids.clone (),
+				});
 
-		).then (
-			move |result| {
+		}
 
-			output_job.remove ();
+		// merge records
 
-			result
+		let base_records =
+			base_table.records ();
 
-		}).boxed ()
+		let loaded_records =
+			loaded_table.records ();
 
-	}
+		let mut record_bytes: Vec <u8> =
+			Vec::with_capacity (
+				base_records.len () + loaded_records.len ());
 
-	fn scan_bundles (
-		& self,
-		output: & Output,
-	) -> Result <HashSet <BundleId>, String> {
+		let mut base_chunks =
+			base_records.chunks (RECORD_SIZE).filter_map (
+				|record|
+				base_ordinals.get (
+					record_bundle_ordinal (record) as usize,
+				).and_then (
+					|& bundle_ordinal| bundle_ordinal,
+				).map (
+					|bundle_ordinal| (record, bundle_ordinal))
+			).peekable ();
 
-		let output_job =
-			output_job_start! (
-				output,
-				"Scanning bundles");
+		let mut loaded_chunks =
+			loaded_records.chunks (RECORD_SIZE).map (
+				|record|
+				(record, loaded_ordinals [
+					record_bundle_ordinal (record) as usize])
+			).peekable ();
 
-		let mut bundle_ids: HashSet <BundleId> =
-			HashSet::new ();
+		loop {
 
-		for prefix in (0 .. 256).map (
-			|byte| [ byte as u8 ].to_hex ()
-		) {
+			let ordering =
+				match (base_chunks.peek (), loaded_chunks.peek ()) {
 
-			let bundles_directory =
-				self.repository_core.path ()
-					.join ("bundles")
-					.join (prefix);
+				(None, None) =>
+					break,
 
-			if ! bundles_directory.exists () {
-				continue;
-			}
+				(Some (_), None) =>
+					Ordering::Less,
 
-			for dir_entry_result in (
-				io_result (
-					fs::read_dir (
-						bundles_directory))
-			) ? {
+				(None, Some (_)) =>
+					Ordering::Greater,
 
-				let dir_entry = (
-					io_result (
-						dir_entry_result)
-				) ?;
+				(Some (& (base_record, _)), Some (& (loaded_record, _))) =>
+					base_record [0 .. RECORD_CHUNK_ID_SIZE].cmp (
+						& loaded_record [0 .. RECORD_CHUNK_ID_SIZE]),
 
-				match BundleId::parse (
-					dir_entry.file_name ().to_str ().unwrap (),
-				) {
+			};
 
-					Ok (bundle_id) => {
+			let (record, bundle_ordinal) =
+				match ordering {
 
-						bundle_ids.insert (
-							bundle_id);
+				Ordering::Less =>
+					base_chunks.next ().unwrap (),
 
-					},
+				Ordering::Equal => {
+					loaded_chunks.next ();
+					base_chunks.next ().unwrap ()
+				},
 
-					Err (_) =>
-						output.message_format (
-							format_args! (
-								"Ignoring invalid bundle name: {}",
-								dir_entry.file_name ().to_string_lossy ())),
+				Ordering::Greater =>
+					loaded_chunks.next ().unwrap (),
 
-				}
+			};
 
-			}
+			let mut new_record: IndexRecord =
+				[0u8; RECORD_SIZE];
 
-		}
+			new_record.copy_from_slice (
+				record);
 
-		output_job_replace! (
-			output_job,
-			"Found {} bundle files",
-			bundle_ids.len ());
+			LittleEndian::write_u32 (
+				& mut new_record [24 .. 28],
+				bundle_ordinal);
 
-		Ok (bundle_ids)
+			record_bytes.extend_from_slice (
+				& new_record);
+
+		}
+
+		IndexTable {
+			bundle_ids: table_bundle_ids,
+			indexes: table_indexes,
+			records: IndexRecords::Memory (record_bytes),
+		}
 
 	}
 
-	fn scan_indexes (
-		& self,
-		output: & Output,
-	) -> Result <Vec <IndexId>, String> {
+	/// Calculates a signature for the current state of the repository, used to
+	/// decide if a persisted cache file can be reused. This covers the set of
+	/// bundles, and the name, size and modification time of every index file.
 
-		let output_job =
-			output_job_start! (
-				output,
-				"Scanning index files");
+	fn signature (
+		bundle_ids: & HashSet <BundleId>,
+		index_files: & HashMap <IndexId, IndexFileMetadata>,
+	) -> [u8; 20] {
 
-		let mut index_ids =
-			Vec::new ();
+		let mut digest =
+			Sha1::new ();
 
-		for dir_entry_or_error in (
+		let mut bundle_ids_sorted: Vec <& BundleId> =
+			bundle_ids.iter ().collect ();
 
-			io_result (
-				fs::read_dir (
-					self.repository_core.path ()
-						.join ("index")))
+		bundle_ids_sorted.sort_by (
+			|left, right| left.bytes ().cmp (right.bytes ()));
 
-		) ? {
+		for bundle_id in bundle_ids_sorted {
 
-			let dir_entry =
-				io_result (
-					dir_entry_or_error,
-				) ?;
+			digest.input (
+				bundle_id.bytes ());
 
-			if let Some (index_filename) =
-				dir_entry.file_name ().to_str () {
+		}
 
-				if let Ok (index_id) =
-					IndexId::parse (
-						index_filename,
-					) {
+		let mut index_files_sorted: Vec <(& IndexId, & IndexFileMetadata)> =
+			index_files.iter ().collect ();
 
-					index_ids.push (
-						index_id);
+		index_files_sorted.sort_by_key (
+			|& (index_id, _)| index_id.to_string ());
+
+		for (index_id, index_metadata) in index_files_sorted {
+
+			let mut buffer: [u8; 24] =
+				[0u8; 24];
+
+			LittleEndian::write_u64 (
+				& mut buffer [0 .. 8],
+				index_metadata.size);
+
+			LittleEndian::write_i64 (
+				& mut buffer [8 .. 16],
+				index_metadata.mtime);
+
+			LittleEndian::write_i64 (
+				& mut buffer [16 .. 24],
+				index_metadata.mtime_nsec);
+
+			digest.input (
+				index_id.to_string ().as_bytes ());
+
+			digest.input (
+				& buffer);
+
+		}
+
+		let mut signature: [u8; 20] =
+			[0u8; 20];
+
+		digest.result (
+			& mut signature);
+
+		signature
+
+	}
+
+	fn read_cache_file (
+		cache_path: & Path,
+	) -> Result <Option <([u8; 20], IndexTable)>, String> {
+
+		if ! cache_path.exists () {
+			return Ok (None);
+		}
+
+		let cache_file =
+			io_result (
+				File::open (
+					cache_path),
+			) ?;
+
+		let mapped_file =
+			io_result (
+				MappedFile::open (
+					& cache_file),
+			) ?;
+
+		let (signature, bundle_ids, indexes, records_offset) = {
+
+			let data =
+				mapped_file.as_slice ();
+
+			if data.len () < CACHE_FILE_HEADER_SIZE
+				|| & data [0 .. 8] != CACHE_FILE_MAGIC {
+
+				return Err (
+					"Invalid header".to_string ());
+
+			}
+
+			let mut signature: [u8; 20] =
+				[0u8; 20];
+
+			signature.copy_from_slice (
+				& data [8 .. 28]);
+
+			let num_bundles =
+				LittleEndian::read_u64 (
+					& data [28 .. 36]) as usize;
+
+			let num_indexes =
+				LittleEndian::read_u64 (
+					& data [36 .. 44]) as usize;
+
+			let indexes_size =
+				LittleEndian::read_u64 (
+					& data [44 .. 52]) as usize;
+
+			let num_records =
+				LittleEndian::read_u64 (
+					& data [52 .. 60]) as usize;
+
+			// a corrupt header could make these overflow
+
+			let indexes_offset =
+				num_bundles.checked_mul (
+					BUNDLE_ID_SIZE,
+				).and_then (
+					|bundles_size|
+					bundles_size.checked_add (
+						CACHE_FILE_HEADER_SIZE)
+				);
+
+			let records_offset =
+				indexes_offset.and_then (
+					|indexes_offset|
+					indexes_offset.checked_add (
+						indexes_size)
+				);
+
+			let total_size =
+				records_offset.and_then (
+					|records_offset|
+					num_records.checked_mul (
+						RECORD_SIZE,
+					).and_then (
+						|records_size|
+						records_size.checked_add (
+							records_offset)
+					)
+				);
+
+			if total_size != Some (data.len ()) {
+
+				return Err (
+					"Invalid size".to_string ());
+
+			}
+
+			let indexes_offset = indexes_offset.unwrap ();
+			let records_offset = records_offset.unwrap ();
+
+			let bundle_ids: Vec <BundleId> =
+				data [CACHE_FILE_HEADER_SIZE .. indexes_offset]
+					.chunks (BUNDLE_ID_SIZE)
+					.map (|bytes| BundleId::from_slice (bytes).unwrap ())
+					.collect ();
+
+			let indexes =
+				read_cache_file_indexes (
+					& data [indexes_offset .. records_offset],
+					num_indexes,
+					num_bundles,
+				) ?;
+
+			(signature, bundle_ids, indexes, records_offset)
+
+		};
+
+		Ok (Some ((signature, IndexTable {
+			bundle_ids: bundle_ids,
+			indexes: indexes,
+			records: IndexRecords::Mapped (
+				mapped_file,
+				records_offset),
+		})))
+
+	}
+
+	fn write_cache_file (
+		cache_path: & Path,
+		signature: [u8; 20],
+		table: & IndexTable,
+	) -> Result <(), String> {
+
+		let temp_path =
+			cache_path.with_extension ("tmp");
+
+		if let Some (cache_parent) =
+			cache_path.parent () {
+
+			io_result (
+				fs::create_dir_all (
+					cache_parent),
+			) ?;
+
+		}
+
+		let mut index_bytes: Vec <u8> =
+			Vec::new ();
+
+		for (index_id, index_state) in table.indexes.iter () {
+
+			let mut index_header: [u8; CACHE_FILE_INDEX_HEADER_SIZE] =
+				[0u8; CACHE_FILE_INDEX_HEADER_SIZE];
+
+			index_header [0 .. 24].copy_from_slice (
+				index_id.bytes ());
+
+			LittleEndian::write_u64 (
+				& mut index_header [24 .. 32],
+				index_state.metadata.size);
+
+			LittleEndian::write_i64 (
+				& mut index_header [32 .. 40],
+				index_state.metadata.mtime);
+
+			LittleEndian::write_i64 (
+				& mut index_header [40 .. 48],
+				index_state.metadata.mtime_nsec);
+
+			LittleEndian::write_u32 (
+				& mut index_header [48 .. 52],
+				index_state.bundle_ordinals.len () as u32);
+
+			LittleEndian::write_u32 (
+				& mut index_header [52 .. 56],
+				index_state.missing_bundle_ids.len () as u32);
+
+			index_bytes.extend_from_slice (
+				& index_header);
+
+			for & bundle_ordinal in index_state.bundle_ordinals.iter () {
+
+				let mut ordinal_bytes: [u8; 4] =
+					[0u8; 4];
+
+				LittleEndian::write_u32 (
+					& mut ordinal_bytes,
+					bundle_ordinal);
+
+				index_bytes.extend_from_slice (
+					& ordinal_bytes);
+
+			}
+
+			for bundle_id in index_state.missing_bundle_ids.iter () {
+
+				index_bytes.extend_from_slice (
+					bundle_id.bytes ());
+
+			}
+
+		}
+
+		{
+
+			let mut cache_file =
+				BufWriter::new (
+					io_result (
+						File::create (
+							& temp_path),
+					) ?);
+
+			let mut header: [u8; CACHE_FILE_HEADER_SIZE] =
+				[0u8; CACHE_FILE_HEADER_SIZE];
+
+			header [0 .. 8].copy_from_slice (
+				CACHE_FILE_MAGIC);
+
+			header [8 .. 28].copy_from_slice (
+				& signature);
+
+			LittleEndian::write_u64 (
+				& mut header [28 .. 36],
+				table.bundle_ids.len () as u64);
+
+			LittleEndian::write_u64 (
+				& mut header [36 .. 44],
+				table.indexes.len () as u64);
+
+			LittleEndian::write_u64 (
+				& mut header [44 .. 52],
+				index_bytes.len () as u64);
+
+			LittleEndian::write_u64 (
+				& mut header [52 .. 60],
+				table.len () as u64);
+
+			io_result (
+				cache_file.write_all (
+					& header),
+			) ?;
+
+			for bundle_id in table.bundle_ids.iter () {
+
+				io_result (
+					cache_file.write_all (
+						bundle_id.bytes ()),
+				) ?;
+
+			}
+
+			io_result (
+				cache_file.write_all (
+					& index_bytes),
+			) ?;
+
+			io_result (
+				cache_file.write_all (
+					table.records ()),
+			) ?;
+
+			io_result (
+				cache_file.flush (),
+			) ?;
+
+			io_result (
+				cache_file.get_ref ().sync_all (),
+			) ?;
+
+		}
+
+		io_result (
+			fs::rename (
+				& temp_path,
+				cache_path),
+		) ?;
+
+		Ok (())
+
+	}
+
+	fn load_index_future (
+		repository_core: Arc <RepositoryCore>,
+		output: & Output,
+		cpu_pool: & CpuPool,
+		bundle_ids: Arc <HashSet <BundleId>>,
+		index_id: IndexId,
+	) -> IndexLoadFuture {
+
+		let index_path =
+			repository_core.index_path (
+				index_id);
+
+		let output_job =
+			output_job_start! (
+				output,
+				"Loading index {}",
+				index_id);
+
+		cpu_pool.spawn_fn (move || {
+
+			let index_data =
+				string_result_with_prefix (
+					|| format! (
+						"Error loading index {}",
+						index_id),
+					index_read_path (
+						index_path,
+						repository_core.encryption_key ()),
+				) ?;
+
+			let mut loaded_index =
+				LoadedIndex {
+					index_id: index_id,
+					entries: Vec::new (),
+					bundle_ids: Vec::new (),
+					missing_bundle_ids: Vec::new (),
+				};
+
+			for RawIndexEntry {
+				index_bundle_header,
+				bundle_info,
+			} in index_data {
+
+				let bundle_id =
+					index_bundle_header.bundle_id ();
+
+				if ! bundle_ids.contains (& bundle_id) {
+
+					loaded_index.missing_bundle_ids.push (
+						bundle_id);
+
+					continue;
+
+				}
+
+				loaded_index.bundle_ids.push (
+					bundle_id);
+
+				for chunk in bundle_info.chunks () {
+
+					loaded_index.entries.push ((
+						chunk.chunk_id (),
+						IndexEntry {
+							bundle_id: bundle_id,
+							size: chunk.size () as u64,
+						},
+					));
+
+				}
+
+			}
+
+			Ok (loaded_index)
+
+		}).map_err (
+			move |error|
+
+			(index_id, error)
+
+		).then (
+			move |result| {
+
+			output_job.remove ();
+
+			result
+
+		}).boxed ()
+
+	}
+
+	fn scan_bundles (
+		& self,
+		output: & Output,
+	) -> Result <HashSet <BundleId>, String> {
+
+		let output_job =
+			output_job_start! (
+				output,
+				"Scanning bundles");
+
+		let mut bundle_ids: HashSet <BundleId> =
+			HashSet::new ();
+
+		for prefix in (0 .. 256).map (
+			|byte| [ byte as u8 ].to_hex ()
+		) {
+
+			let bundles_directory =
+				self.repository_core.path ()
+					.join ("bundles")
+					.join (prefix);
+
+			if ! bundles_directory.exists () {
+				continue;
+			}
+
+			for dir_entry_result in (
+				io_result (
+					fs::read_dir (
+						bundles_directory))
+			) ? {
+
+				let dir_entry = (
+					io_result (
+						dir_entry_result)
+				) ?;
+
+				if parity_file_name_matches (
+					& dir_entry.file_name ().to_string_lossy (),
+				) {
+					continue;
+				}
+
+				match BundleId::parse (
+					dir_entry.file_name ().to_str ().unwrap (),
+				) {
+
+					Ok (bundle_id) => {
+
+						bundle_ids.insert (
+							bundle_id);
+
+					},
+
+					Err (_) =>
+						output.message_format (
+							format_args! (
+								"Ignoring invalid bundle name: {}",
+								dir_entry.file_name ().to_string_lossy ())),
+
+				}
+
+			}
+
+		}
+
+		output_job_replace! (
+			output_job,
+			"Found {} bundle files",
+			bundle_ids.len ());
+
+		Ok (bundle_ids)
+
+	}
+
+	fn scan_indexes (
+		& self,
+		output: & Output,
+	) -> Result <HashMap <IndexId, IndexFileMetadata>, String> {
+
+		let output_job =
+			output_job_start! (
+				output,
+				"Scanning index files");
+
+		let mut index_files =
+			HashMap::new ();
+
+		for dir_entry_or_error in (
+
+			io_result (
+				fs::read_dir (
+					self.repository_core.path ()
+						.join ("index")))
+
+		) ? {
+
+			let dir_entry =
+				io_result (
+					dir_entry_or_error,
+				) ?;
+
+			if let Some (index_filename) =
+				dir_entry.file_name ().to_str () {
+
+				if let Ok (index_id) =
+					IndexId::parse (
+						index_filename,
+					) {
+
+					let index_path =
+						dir_entry.path ();
+
+					let metadata =
+						io_result_with_prefix (
+							|| format! (
+								"Error reading {}: ",
+								index_path.to_string_lossy ()),
+							fs::metadata (
+								& index_path),
+						) ?;
+
+					index_files.insert (
+						index_id,
+						IndexFileMetadata {
+							size: metadata.len (),
+							mtime: metadata.mtime (),
+							mtime_nsec: metadata.mtime_nsec (),
+						});
 
 				} else {
 
@@ -485,9 +1370,9 @@ impl IndexCache {
 		output_job_replace! (
 			output_job,
 			"Found {} index files",
-			index_ids.len ());
+			index_files.len ());
 
-		Ok (index_ids)
+		Ok (index_files)
 
 	}
 
@@ -496,19 +1381,659 @@ impl IndexCache {
 		chunk_id: & ChunkId,
 	) -> Option <IndexEntry> {
 
-		self.entries.as_ref ().unwrap ().get (
-			chunk_id,
-		).map (
-			|index_entry|
+		self.table.as_ref ().unwrap ().get (
+			chunk_id)
 
-			index_entry.to_owned ()
+	}
 
-		)
+	pub fn loaded (& self) -> bool {
+		self.table.is_some ()
+	}
+
+}
+
+impl IndexTable {
+
+	#[ inline ]
+	fn records (& self) -> & [u8] {
+
+		match self.records {
+
+			IndexRecords::Memory (ref records) =>
+				& records [..],
+
+			IndexRecords::Mapped (ref mapped_file, offset) =>
+				& mapped_file.as_slice () [offset ..],
+
+		}
 
 	}
 
-	pub fn loaded (& self) -> bool {
-		self.entries.is_some ()
+	#[ inline ]
+	fn len (& self) -> usize {
+		self.records ().len () / RECORD_SIZE
+	}
+
+	fn get (
+		& self,
+		chunk_id: & ChunkId,
+	) -> Option <IndexEntry> {
+
+		let records =
+			self.records ();
+
+		let chunk_id_bytes =
+			chunk_id.bytes ();
+
+		let mut low: usize = 0;
+		let mut high: usize = records.len () / RECORD_SIZE;
+
+		while low < high {
+
+			let middle =
+				low + (high - low) / 2;
+
+			let record =
+				& records [
+					middle * RECORD_SIZE ..
+					(middle + 1) * RECORD_SIZE];
+
+			if & record [0 .. RECORD_CHUNK_ID_SIZE] < chunk_id_bytes {
+
+				low = middle + 1;
+
+			} else if & record [0 .. RECORD_CHUNK_ID_SIZE] > chunk_id_bytes {
+
+				high = middle;
+
+			} else {
+
+				let bundle_ordinal =
+					LittleEndian::read_u32 (
+						& record [24 .. 28]);
+
+				let size =
+					LittleEndian::read_u32 (
+						& record [28 .. 32]);
+
+				// records are not checked when a cache file is loaded, since
+				// that would read the whole file, so treat a bad ordinal from a
+				// corrupt file as a miss
+
+				return self.bundle_ids.get (
+					bundle_ordinal as usize,
+				).map (
+					|& bundle_id|
+
+					IndexEntry {
+						bundle_id: bundle_id,
+						size: size as u64,
+					}
+
+				);
+
+			}
+
+		}
+
+		None
+
+	}
+
+}
+
+/// Reads the list of index files from a persisted cache, checking that every
+/// bundle ordinal is within range.
+
+fn read_cache_file_indexes (
+	data: & [u8],
+	num_indexes: usize,
+	num_bundles: usize,
+) -> Result <HashMap <IndexId, IndexState>, String> {
+
+	let mut indexes: HashMap <IndexId, IndexState> =
+		HashMap::new ();
+
+	let mut position: usize = 0;
+
+	for _ in 0 .. num_indexes {
+
+		if data.len () - position < CACHE_FILE_INDEX_HEADER_SIZE {
+
+			return Err (
+				"Invalid index list".to_string ());
+
+		}
+
+		let index_header =
+			& data [position .. position + CACHE_FILE_INDEX_HEADER_SIZE];
+
+		let index_id =
+			IndexId::from_slice (
+				& index_header [0 .. INDEX_ID_SIZE],
+			) ?;
+
+		let metadata =
+			IndexFileMetadata {
+				size: LittleEndian::read_u64 (& index_header [24 .. 32]),
+				mtime: LittleEndian::read_i64 (& index_header [32 .. 40]),
+				mtime_nsec: LittleEndian::read_i64 (& index_header [40 .. 48]),
+			};
+
+		let num_ordinals =
+			LittleEndian::read_u32 (
+				& index_header [48 .. 52]) as usize;
+
+		let num_missing =
+			LittleEndian::read_u32 (
+				& index_header [52 .. 56]) as usize;
+
+		position += CACHE_FILE_INDEX_HEADER_SIZE;
+
+		if data.len () - position
+			< num_ordinals * 4 + num_missing * BUNDLE_ID_SIZE {
+
+			return Err (
+				"Invalid index list".to_string ());
+
+		}
+
+		let bundle_ordinals: Vec <u32> =
+			data [position .. position + num_ordinals * 4]
+				.chunks (4)
+				.map (|bytes| LittleEndian::read_u32 (bytes))
+				.collect ();
+
+		if bundle_ordinals.iter ().any (
+			|& bundle_ordinal| bundle_ordinal as usize >= num_bundles) {
+
+			return Err (
+				"Invalid bundle ordinal".to_string ());
+
+		}
+
+		position += num_ordinals * 4;
+
+		let missing_bundle_ids: Vec <BundleId> =
+			data [position .. position + num_missing * BUNDLE_ID_SIZE]
+				.chunks (BUNDLE_ID_SIZE)
+				.map (|bytes| BundleId::from_slice (bytes).unwrap ())
+				.collect ();
+
+		position += num_missing * BUNDLE_ID_SIZE;
+
+		indexes.insert (
+			index_id,
+			IndexState {
+				metadata: metadata,
+				bundle_ordinals: bundle_ordinals,
+				missing_bundle_ids: missing_bundle_ids,
+			});
+
+	}
+
+	if position != data.len () {
+
+		return Err (
+			"Invalid index list".to_string ());
+
+	}
+
+	Ok (indexes)
+
+}
+
+fn intern_bundle_id (
+	table_bundle_ids: & mut Vec <BundleId>,
+	bundle_ordinals: & mut HashMap <BundleId, u32>,
+	bundle_id: BundleId,
+) -> u32 {
+
+	* bundle_ordinals.entry (
+		bundle_id,
+	).or_insert_with (
+		|| {
+
+		table_bundle_ids.push (
+			bundle_id);
+
+		table_bundle_ids.len () as u32 - 1
+
+	})
+
+}
+
+#[ inline ]
+fn record_bundle_ordinal (
+	record: & [u8],
+) -> u32 {
+
+	LittleEndian::read_u32 (
+		& record [24 .. 28])
+
+}
+
+/// Sorts a table of records by chunk ID, in place, and removes duplicates,
+/// keeping the first record for each chunk.
+
+fn sort_records (
+	record_bytes: & mut Vec <u8>,
+) {
+
+	let num_records = {
+
+		let records =
+			records_mut (
+				record_bytes);
+
+		records.sort_by (
+			|left, right|
+			left [0 .. RECORD_CHUNK_ID_SIZE].cmp (
+				& right [0 .. RECORD_CHUNK_ID_SIZE]));
+
+		let mut num_records: usize = 0;
+
+		for record_index in 0 .. records.len () {
+
+			if num_records > 0
+				&& records [record_index] [0 .. RECORD_CHUNK_ID_SIZE]
+					== records [num_records - 1] [0 .. RECORD_CHUNK_ID_SIZE] {
+
+				continue;
+
+			}
+
+			records [num_records] =
+				records [record_index];
+
+			num_records += 1;
+
+		}
+
+		num_records
+
+	};
+
+	record_bytes.truncate (
+		num_records * RECORD_SIZE);
+
+	record_bytes.shrink_to_fit ();
+
+}
+
+/// Views a table of records as an array, so it can be sorted in place.
+
+fn records_mut (
+	record_bytes: & mut [u8],
+) -> & mut [IndexRecord] {
+
+	let num_records =
+		record_bytes.len () / RECORD_SIZE;
+
+	// records are byte arrays, so have no alignment requirements
+
+	unsafe {
+
+		slice::from_raw_parts_mut (
+			record_bytes.as_mut_ptr () as * mut IndexRecord,
+			num_records)
+
+	}
+
+}
+
+fn make_record (
+	chunk_id: ChunkId,
+	bundle_ordinal: u32,
+	size: u32,
+) -> IndexRecord {
+
+	let mut record: IndexRecord =
+		[0u8; RECORD_SIZE];
+
+	record [0 .. RECORD_CHUNK_ID_SIZE].copy_from_slice (
+		chunk_id.bytes ());
+
+	LittleEndian::write_u32 (
+		& mut record [24 .. 28],
+		bundle_ordinal);
+
+	LittleEndian::write_u32 (
+		& mut record [28 .. 32],
+		size);
+
+	record
+
+}
+
+#[ cfg (test) ]
+mod tests {
+
+	use std::collections::HashMap;
+	use std::env;
+	use std::fs;
+	use std::fs::File;
+	use std::io::Write;
+	use std::path::PathBuf;
+	use std::process;
+
+	use byteorder::ByteOrder;
+	use byteorder::LittleEndian;
+
+	use super::*;
+
+	fn temp_path (
+		name: & str,
+	) -> PathBuf {
+
+		env::temp_dir ().join (
+			format! (
+				"rzbackup-index-cache-test-{}-{}",
+				process::id (),
+				name))
+
+	}
+
+	fn memory_table (
+		bundle_ids: Vec <BundleId>,
+		indexes: HashMap <IndexId, IndexState>,
+		records: & [IndexRecord],
+	) -> IndexTable {
+
+		let mut record_bytes: Vec <u8> =
+			Vec::new ();
+
+		for record in records.iter () {
+
+			record_bytes.extend_from_slice (
+				record);
+
+		}
+
+		sort_records (
+			& mut record_bytes);
+
+		IndexTable {
+			bundle_ids: bundle_ids,
+			indexes: indexes,
+			records: IndexRecords::Memory (record_bytes),
+		}
+
+	}
+
+	#[ test ]
+	fn sort_records_sorts_and_keeps_first () {
+
+		let chunk_ids: Vec <ChunkId> =
+			(0 .. 100).map (|_| ChunkId::random ()).collect ();
+
+		let mut record_bytes: Vec <u8> =
+			Vec::new ();
+
+		for (position, chunk_id) in chunk_ids.iter ().enumerate () {
+
+			record_bytes.extend_from_slice (
+				& make_record (
+					* chunk_id,
+					0,
+					position as u32));
+
+		}
+
+		// duplicates of every tenth chunk, which should be dropped
+
+		for chunk_id in chunk_ids.iter ().step_by (10) {
+
+			record_bytes.extend_from_slice (
+				& make_record (
+					* chunk_id,
+					1,
+					9999));
+
+		}
+
+		sort_records (
+			& mut record_bytes);
+
+		assert_eq! (record_bytes.len (), 100 * RECORD_SIZE);
+
+		let records: Vec <& [u8]> =
+			record_bytes.chunks (RECORD_SIZE).collect ();
+
+		for pair in records.windows (2) {
+			assert! (pair [0] [0 .. 24] < pair [1] [0 .. 24]);
+		}
+
+		for record in records.iter () {
+
+			assert_eq! (record_bundle_ordinal (record), 0);
+
+			let position =
+				chunk_ids.iter ().position (
+					|chunk_id| chunk_id.bytes () == & record [0 .. 24],
+				).unwrap ();
+
+			assert_eq! (
+				LittleEndian::read_u32 (& record [28 .. 32]),
+				position as u32);
+
+		}
+
+	}
+
+	#[ test ]
+	fn cache_file_round_trip () {
+
+		let bundle_ids: Vec <BundleId> =
+			(0 .. 5).map (|_| BundleId::random ()).collect ();
+
+		let chunk_ids: Vec <ChunkId> =
+			(0 .. 1000).map (|_| ChunkId::random ()).collect ();
+
+		let records: Vec <IndexRecord> =
+			chunk_ids.iter ().enumerate ().map (
+				|(position, chunk_id)|
+				make_record (
+					* chunk_id,
+					(position % bundle_ids.len ()) as u32,
+					position as u32 * 3)
+			).collect ();
+
+		let index_ids: Vec <IndexId> =
+			(0 .. 2).map (|_| IndexId::random ()).collect ();
+
+		let missing_bundle_id =
+			BundleId::random ();
+
+		let mut indexes: HashMap <IndexId, IndexState> =
+			HashMap::new ();
+
+		indexes.insert (
+			index_ids [0],
+			IndexState {
+				metadata: IndexFileMetadata {
+					size: 1234,
+					mtime: 1500000000,
+					mtime_nsec: 123456789,
+				},
+				bundle_ordinals: vec! [0, 1, 2],
+				missing_bundle_ids: vec! [ missing_bundle_id ],
+			});
+
+		indexes.insert (
+			index_ids [1],
+			IndexState {
+				metadata: IndexFileMetadata {
+					size: 5678,
+					mtime: 1600000000,
+					mtime_nsec: 0,
+				},
+				bundle_ordinals: vec! [3, 4],
+				missing_bundle_ids: vec! [],
+			});
+
+		let table =
+			memory_table (
+				bundle_ids.clone (),
+				indexes,
+				& records);
+
+		let cache_path =
+			temp_path ("round-trip");
+
+		let signature: [u8; 20] =
+			[7u8; 20];
+
+		IndexCache::write_cache_file (
+			& cache_path,
+			signature,
+			& table,
+		).unwrap ();
+
+		let (read_signature, read_table) =
+			IndexCache::read_cache_file (
+				& cache_path,
+			).unwrap ().unwrap ();
+
+		fs::remove_file (
+			& cache_path,
+		).unwrap ();
+
+		assert_eq! (read_signature, signature);
+		assert! (read_table.bundle_ids == bundle_ids);
+		assert_eq! (read_table.len (), chunk_ids.len ());
+		assert_eq! (read_table.records (), table.records ());
+
+		let read_index =
+			& read_table.indexes [& index_ids [0]];
+
+		assert! (read_index.metadata == IndexFileMetadata {
+			size: 1234,
+			mtime: 1500000000,
+			mtime_nsec: 123456789,
+		});
+
+		assert_eq! (read_index.bundle_ordinals, vec! [0, 1, 2]);
+		assert! (read_index.missing_bundle_ids == vec! [ missing_bundle_id ]);
+
+		assert_eq! (
+			read_table.indexes [& index_ids [1]].bundle_ordinals,
+			vec! [3, 4]);
+
+		for (position, chunk_id) in chunk_ids.iter ().enumerate () {
+
+			let index_entry =
+				read_table.get (
+					chunk_id,
+				).unwrap ();
+
+			assert! (
+				index_entry.bundle_id ()
+					== bundle_ids [position % bundle_ids.len ()]);
+
+			assert_eq! (index_entry.size (), position as u64 * 3);
+
+		}
+
+		assert! (
+			read_table.get (
+				& ChunkId::random (),
+			).is_none ());
+
+	}
+
+	#[ test ]
+	fn bad_bundle_ordinal_is_a_miss () {
+
+		let chunk_id =
+			ChunkId::random ();
+
+		let table =
+			memory_table (
+				vec! [ BundleId::random () ],
+				HashMap::new (),
+				& [ make_record (chunk_id, 7, 100) ]);
+
+		let cache_path =
+			temp_path ("bad-ordinal");
+
+		IndexCache::write_cache_file (
+			& cache_path,
+			[0u8; 20],
+			& table,
+		).unwrap ();
+
+		let (_, read_table) =
+			IndexCache::read_cache_file (
+				& cache_path,
+			).unwrap ().unwrap ();
+
+		fs::remove_file (
+			& cache_path,
+		).unwrap ();
+
+		assert! (
+			read_table.get (
+				& chunk_id,
+			).is_none ());
+
+	}
+
+	#[ test ]
+	fn overflowing_header_is_rejected () {
+
+		let cache_path =
+			temp_path ("overflow");
+
+		let mut header: [u8; CACHE_FILE_HEADER_SIZE] =
+			[0u8; CACHE_FILE_HEADER_SIZE];
+
+		header [0 .. 8].copy_from_slice (
+			CACHE_FILE_MAGIC);
+
+		// bundle count which overflows when multiplied by the id size
+
+		LittleEndian::write_u64 (
+			& mut header [28 .. 36],
+			u64::MAX / 8);
+
+		File::create (
+			& cache_path,
+		).unwrap ().write_all (
+			& header,
+		).unwrap ();
+
+		let result =
+			IndexCache::read_cache_file (
+				& cache_path);
+
+		// record count which overflows when added to the offset
+
+		LittleEndian::write_u64 (
+			& mut header [28 .. 36],
+			0);
+
+		LittleEndian::write_u64 (
+			& mut header [52 .. 60],
+			u64::MAX / RECORD_SIZE as u64);
+
+		File::create (
+			& cache_path,
+		).unwrap ().write_all (
+			& header,
+		).unwrap ();
+
+		let second_result =
+			IndexCache::read_cache_file (
+				& cache_path);
+
+		fs::remove_file (
+			& cache_path,
+		).unwrap ();
+
+		assert_eq! (result.err (), Some ("Invalid size".to_string ()));
+		assert_eq! (second_result.err (), Some ("Invalid size".to_string ()));
+
 	}
 
 }
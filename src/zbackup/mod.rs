@@ -1,4 +1,5 @@
 pub mod bundle_loader;
+pub mod cache_tier;
 pub mod chunk_cache;
 pub mod crypto;
 pub mod data;
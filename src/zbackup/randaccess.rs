@@ -172,6 +172,13 @@ impl <'a> RandomAccess <'a> {
 
 	}
 
+	/// Returns the total size of the backup's contents in bytes.
+
+	#[ inline ]
+	pub fn size (& self) -> u64 {
+		self.size
+	}
+
 }
 
 impl <'a> Read for RandomAccess <'a> {
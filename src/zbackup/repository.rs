@@ -3,8 +3,11 @@
 use std::collections::HashMap;
 use std::collections::HashSet;
 use std::collections::LinkedList;
+use std::fs::File;
 use std::io::Cursor;
 use std::io::Read;
+use std::io::Seek;
+use std::io::SeekFrom;
 use std::io::Write;
 use std::ops::DerefMut;
 use std::path::Path;
@@ -20,7 +23,6 @@ use futures;
 use futures::BoxFuture;
 use futures::Future;
 
-use futures_cpupool::CpuPool;
 
 use num_cpus;
 
@@ -33,6 +35,7 @@ use rustc_serialize::hex::ToHex;
 
 use misc::*;
 use zbackup::bundle_loader::*;
+use zbackup::cache_tier::*;
 use zbackup::chunk_cache::*;
 use zbackup::data::*;
 use zbackup::disk_format::*;
@@ -48,7 +51,6 @@ use zbackup::repository_core::*;
 pub struct Repository {
 	data: Arc <RepositoryData>,
 	state: Arc <Mutex <RepositoryState>>,
-	cpu_pool: CpuPool,
 	bundle_loader: BundleLoader,
 	chunk_cache: ChunkCache <ChunkId>,
 }
@@ -63,8 +65,13 @@ pub struct RepositoryConfig {
 	pub max_uncompressed_memory_cache_entries: usize,
 	pub max_compressed_memory_cache_entries: usize,
 	pub max_compressed_filesystem_cache_entries: usize,
+	pub max_uncompressed_memory_cache_size: Option <u64>,
+	pub max_compressed_memory_cache_size: Option <u64>,
+	pub max_compressed_filesystem_cache_size: Option <u64>,
+	pub cache_policy: CachePolicy,
 	pub max_threads: usize,
 	pub filesystem_cache_path: String,
+	pub index_cache_path: Option <String>,
 	pub work_jobs_total: usize, // deprecated and ignored
 	pub work_jobs_batch: usize, // deprecated and ignored
 }
@@ -111,12 +118,20 @@ impl Repository {
 			max_compressed_filesystem_cache_entries:
 				MAX_COMPRESSED_FILESYSTEM_CACHE_ENTRIES,
 
+			max_uncompressed_memory_cache_size: None,
+			max_compressed_memory_cache_size: None,
+			max_compressed_filesystem_cache_size: None,
+
+			cache_policy: CachePolicy::Lru,
+
 			max_threads:
 				num_cpus::get () * 2,
 
 			filesystem_cache_path:
 				FILESYSTEM_CACHE_PATH.to_owned (),
 
+			index_cache_path: None,
+
 			work_jobs_total: 0, // deprecated and ignored
 			work_jobs_batch: 0, // deprecated and ignored
 
@@ -168,12 +183,6 @@ impl Repository {
 				) ?
 			);
 
-		// create thread pool
-
-		let cpu_pool =
-			CpuPool::new (
-				repository_config.max_threads);
-
 		// create bundle loader
 
 		let bundle_loader =
@@ -189,13 +198,24 @@ impl Repository {
 				repository_config.max_threads,
 				repository_config.max_uncompressed_memory_cache_entries,
 				repository_config.max_compressed_memory_cache_entries,
-				repository_config.max_compressed_filesystem_cache_entries * 7/8,
-				repository_config.max_compressed_filesystem_cache_entries * 1/8,
+				repository_config.max_compressed_filesystem_cache_entries / 8 * 7,
+				repository_config.max_compressed_filesystem_cache_entries / 8,
+				repository_config.max_uncompressed_memory_cache_size,
+				repository_config.max_compressed_memory_cache_size,
+				repository_config.max_compressed_filesystem_cache_size.map (
+					|size| size / 8 * 7),
+				repository_config.max_compressed_filesystem_cache_size.map (
+					|size| size / 8),
+				repository_config.cache_policy,
 				true,
 			) ?;
 
 		// create data
 
+		let index_cache_path =
+			repository_config.index_cache_path.as_ref ().map (
+				|index_cache_path| PathBuf::from (index_cache_path));
+
 		let repository_data =
 			Arc::new (RepositoryData {
 				config: repository_config,
@@ -208,6 +228,7 @@ impl Repository {
 			Arc::new (Mutex::new (RepositoryState {
 				index_cache: IndexCache::new (
 					repository_core.clone (),
+					index_cache_path,
 				),
 				bundles_needed: HashSet::new (),
 			}));
@@ -217,7 +238,6 @@ impl Repository {
 		Ok (Repository {
 			data: repository_data,
 			state: repository_state,
-			cpu_pool: cpu_pool,
 			bundle_loader: bundle_loader,
 			chunk_cache: chunk_cache,
 		})
@@ -248,9 +268,10 @@ impl Repository {
 
 	}
 
-	/// Reload the index files. This forces the indexes to be reloaded, even if
-	/// they have already been loaded. This should be called if new backups have
-	/// been added to an already-open repository.
+	/// Reload the index files. This should be called if new backups have been
+	/// added to an already-open repository. Only index files which are new or
+	/// have changed are read, and entries from removed index files, or for
+	/// bundles which no longer exist, are dropped.
 
 	pub fn reload_indexes (
 		& self,
@@ -276,6 +297,27 @@ impl Repository {
 		backup_name: & str,
 	) -> Result <(Vec <u8>, [u8; 32]), String> {
 
+		let (backup_data, backup_info) =
+			self.read_and_expand_backup_info (
+				output,
+				backup_name,
+			) ?;
+
+		Ok (
+			(
+				backup_data,
+				backup_info.sha256 (),
+			)
+		)
+
+	}
+
+	fn read_and_expand_backup_info (
+		& self,
+		output: & Output,
+		backup_name: & str,
+	) -> Result <(Vec <u8>, DiskBackupInfo), String> {
+
 		self.load_indexes (
 			output,
 		) ?;
@@ -332,7 +374,7 @@ impl Repository {
 		Ok (
 			(
 				input.into_inner (),
-				backup_info.sha256 (),
+				backup_info,
 			)
 		)
 
@@ -348,19 +390,9 @@ impl Repository {
 		target: & mut Write,
 	) -> Result <(), String> {
 
-		if backup_name.is_empty () {
-
-			return Err (
-				"Backup name must not be empty".to_string ());
-
-		}
-
-		if backup_name.chars ().next ().unwrap () != '/' {
-
-			return Err (
-				"Backup name must begin with '/'".to_string ());
-
-		}
+		Self::check_backup_name (
+			backup_name,
+		) ?;
 
 		let (input_bytes, checksum) =
 			self.read_and_expand_backup (
@@ -420,6 +452,350 @@ impl Repository {
 
 	}
 
+	/// This function will restore a named backup over an existing file. The
+	/// target range of each instruction is compared with the file's current
+	/// contents, using the chunk's SHA-1 sum where possible, and only ranges
+	/// which differ are written. The file is then truncated or extended to the
+	/// size of the backup.
+	///
+	/// This is much more efficient than a full restore when the file is an
+	/// older copy of the same data, since unchanged chunks do not need to be
+	/// loaded from the repository at all.
+	///
+	/// The file is modified in place, so on any error, including a checksum
+	/// mismatch at the end, it may be left partly updated.
+
+	pub fn restore_update_existing (
+		& self,
+		output: & Output,
+		backup_name: & str,
+		target: & mut File,
+	) -> Result <(), String> {
+
+		Self::check_backup_name (
+			backup_name,
+		) ?;
+
+		let (input_bytes, backup_info) =
+			self.read_and_expand_backup_info (
+				output,
+				backup_name,
+			) ?;
+
+		let mut input =
+			Cursor::new (
+				input_bytes);
+
+		let mut coded_input_stream =
+			CodedInputStream::new (
+				& mut input);
+
+		let output_job =
+			output_job_start! (
+				output,
+				"Updating {}",
+				backup_name);
+
+		let existing_size =
+			io_result_with_prefix (
+				|| format! (
+					"Error reading existing file: "),
+				target.metadata (),
+			) ?.len ();
+
+		// compare and update each instruction's target range
+
+		let mut sha256_sum =
+			Sha256::new ();
+
+		let mut existing_data: Vec <u8> =
+			Vec::new ();
+
+		let mut position: u64 = 0;
+		let mut count: u64 = 0;
+
+		let mut bytes_written: u64 = 0;
+		let mut ranges_written: u64 = 0;
+		let mut last_written_end: Option <u64> = None;
+
+		while ! protobuf_result (
+			coded_input_stream.eof (),
+		) ? {
+
+			let backup_instruction =
+				DiskBackupInstruction::read (
+					& mut coded_input_stream,
+				) ?;
+
+			if backup_instruction.has_chunk_to_emit () {
+
+				let chunk_id =
+					backup_instruction.chunk_to_emit ();
+
+				let index_entry =
+					self.get_index_entry (
+						chunk_id,
+					) ?;
+
+				let unchanged =
+					Self::read_existing_range (
+						target,
+						existing_size,
+						position,
+						index_entry.size (),
+						& mut existing_data,
+					) ? && {
+
+					let mut sha1_digest =
+						Sha1::new ();
+
+					sha1_digest.input (
+						& existing_data);
+
+					let mut sha1_sum: [u8; 20] =
+						[0u8; 20];
+
+					sha1_digest.result (
+						& mut sha1_sum);
+
+					chunk_id.bytes () [0 .. 16] == sha1_sum [0 .. 16]
+
+				};
+
+				if unchanged {
+
+					sha256_sum.input (
+						& existing_data);
+
+				} else {
+
+					let chunk_data =
+						self.get_chunk (
+							chunk_id,
+						) ?;
+
+					Self::write_existing_range (
+						target,
+						position,
+						& chunk_data,
+					) ?;
+
+					sha256_sum.input (
+						& chunk_data);
+
+					if last_written_end != Some (position) {
+						ranges_written += 1;
+					}
+
+					bytes_written +=
+						chunk_data.len () as u64;
+
+					last_written_end =
+						Some (position + chunk_data.len () as u64);
+
+				}
+
+				position +=
+					index_entry.size ();
+
+			}
+
+			if backup_instruction.has_bytes_to_emit () {
+
+				let bytes =
+					backup_instruction.bytes_to_emit ();
+
+				let unchanged =
+					Self::read_existing_range (
+						target,
+						existing_size,
+						position,
+						bytes.len () as u64,
+						& mut existing_data,
+					) ? && existing_data [..] == bytes [..];
+
+				if ! unchanged {
+
+					Self::write_existing_range (
+						target,
+						position,
+						bytes,
+					) ?;
+
+					if last_written_end != Some (position) {
+						ranges_written += 1;
+					}
+
+					bytes_written +=
+						bytes.len () as u64;
+
+					last_written_end =
+						Some (position + bytes.len () as u64);
+
+				}
+
+				sha256_sum.input (
+					bytes);
+
+				position +=
+					bytes.len () as u64;
+
+			}
+
+			if count & 0x7f == 0x00 {
+				output_job.tick ();
+			}
+
+			count += 1;
+
+		}
+
+		// truncate or extend to the size of the backup
+
+		if position != backup_info.size () {
+
+			return Err (
+				format! (
+					"Expected backup size {} but instructions produced {}",
+					backup_info.size (),
+					position));
+
+		}
+
+		io_result_with_prefix (
+			|| format! (
+				"Error setting size of existing file: "),
+			target.set_len (
+				backup_info.size ()),
+		) ?;
+
+		io_result_with_prefix (
+			|| format! (
+				"Error syncing existing file: "),
+			target.sync_all (),
+		) ?;
+
+		// verify checksum
+
+		let mut sha256_sum_bytes: [u8; 32] =
+			[0u8; 32];
+
+		sha256_sum.result (
+			& mut sha256_sum_bytes);
+
+		if backup_info.sha256 () != sha256_sum_bytes {
+
+			// the file has already been changed in place, so say so
+
+			return Err (
+				format! (
+					"Expected sha256 checksum {} but calculated {}, the \
+					existing file has been modified ({} bytes in {} ranges \
+					written, size set to {}) and does not match the backup",
+					backup_info.sha256 ().to_hex (),
+					sha256_sum_bytes.to_hex (),
+					bytes_written,
+					ranges_written,
+					backup_info.size ()));
+
+		}
+
+		// done
+
+		output_job_replace! (
+			output_job,
+			"Updated {}, wrote {} of {} bytes in {} ranges",
+			backup_name,
+			bytes_written,
+			backup_info.size (),
+			ranges_written);
+
+		Ok (())
+
+	}
+
+	fn read_existing_range (
+		target: & mut File,
+		existing_size: u64,
+		position: u64,
+		size: u64,
+		existing_data: & mut Vec <u8>,
+	) -> Result <bool, String> {
+
+		if position + size > existing_size {
+			return Ok (false);
+		}
+
+		existing_data.resize (
+			size as usize,
+			0u8);
+
+		io_result_with_prefix (
+			|| format! (
+				"Error reading existing file: "),
+			target.seek (
+				SeekFrom::Start (
+					position)),
+		) ?;
+
+		io_result_with_prefix (
+			|| format! (
+				"Error reading existing file: "),
+			target.read_exact (
+				existing_data),
+		) ?;
+
+		Ok (true)
+
+	}
+
+	fn write_existing_range (
+		target: & mut File,
+		position: u64,
+		data: & [u8],
+	) -> Result <(), String> {
+
+		io_result_with_prefix (
+			|| format! (
+				"Error writing existing file: "),
+			target.seek (
+				SeekFrom::Start (
+					position)),
+		) ?;
+
+		io_result_with_prefix (
+			|| format! (
+				"Error writing existing file: "),
+			target.write_all (
+				data),
+		) ?;
+
+		Ok (())
+
+	}
+
+	fn check_backup_name (
+		backup_name: & str,
+	) -> Result <(), String> {
+
+		if backup_name.is_empty () {
+
+			return Err (
+				"Backup name must not be empty".to_string ());
+
+		}
+
+		if ! backup_name.starts_with ('/') {
+
+			return Err (
+				"Backup name must begin with '/'".to_string ());
+
+		}
+
+		Ok (())
+
+	}
+
 	#[ doc (hidden) ]
 	pub fn restore_test (
 		& self,
@@ -500,7 +876,7 @@ impl Repository {
 						chunk_data.iter ().map (
 							move |& value| value
 						).chain (
-							backup_instruction_bytes_to_emit.into_iter ()
+							backup_instruction_bytes_to_emit
 						).collect ())
 
 				).boxed ()
@@ -878,20 +1254,27 @@ impl Repository {
 			let self_clone =
 				self.clone ();
 
-			return futures::done (
-				Ok (chunk_data_future),
-			).or_else (
-				move |_error: String| {
+			// fall back to the bundle if the cached copy is unusable
 
-				let mut self_state =
-					self_clone.state.lock ().unwrap ();
+			return futures::done (Ok (
 
-				self_clone.load_chunk_async_async (
-					& debug_clone,
-					self_state.deref_mut (),
-					chunk_id)
+				chunk_data_future.or_else (
+					move |_error: String| {
 
-			}).boxed ();
+					let mut self_state =
+						self_clone.state.lock ().unwrap ();
+
+					self_clone.load_chunk_async_async (
+						& debug_clone,
+						self_state.deref_mut (),
+						chunk_id,
+					).and_then (
+						|chunk_data_future| chunk_data_future
+					)
+
+				}).boxed ()
+
+			)).boxed ();
 
 		}
 
@@ -1038,7 +1421,7 @@ impl Repository {
 		) {
 
 			Some (value) =>
-				Ok (value.clone ()),
+				Ok (value),
 
 			None =>
 				Err (
@@ -1081,7 +1464,7 @@ impl Repository {
 		& self,
 		output: & Output,
 		backup_name: & str,
-	) -> Result <RandomAccess, String> {
+	) -> Result <RandomAccess <'_>, String> {
 
 		RandomAccess::new (
 			output,
@@ -1090,7 +1473,10 @@ impl Repository {
 
 	}
 
-	/// This method closes the repository, removing all temporary files
+	/// This method closes the repository. Queued bundle loads are abandoned and
+	/// the chunk cache's state is written out, even if other clones of the
+	/// repository still exist. Temporary files are removed once the last clone
+	/// has been dropped.
 
 	pub fn close (
 		self,
@@ -1102,6 +1488,9 @@ impl Repository {
 				output,
 				"Closing repository");
 
+		self.bundle_loader.close ();
+		self.chunk_cache.close ();
+
 		drop (self);
 
 		output_job.complete ();
@@ -143,10 +143,10 @@ impl RepositoryCore {
 		backup_name: & str,
 	) -> PathBuf {
 
-		if backup_name.chars ().next ().unwrap () == '/' {
+		if let Some (relative_name) = backup_name.strip_prefix ('/') {
 
 			self.backup_path (
-				& backup_name [1 .. ])
+				relative_name)
 
 		} else {
 